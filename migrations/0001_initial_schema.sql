@@ -0,0 +1,97 @@
+-- Initial schema: users, the transactional outbox, roles and permissions, tenant
+-- signing keys and refresh tokens. Batches are separated by GO lines.
+
+CREATE TABLE [dbo].[users](
+    [id] UNIQUEIDENTIFIER NOT NULL DEFAULT NEWID(),
+    [UserId] NVARCHAR(50) NOT NULL,
+    [Name] NVARCHAR(50) NOT NULL,
+    [LastName] NVARCHAR(50) NOT NULL,
+    [Email] NVARCHAR(100) NOT NULL,
+    [Age] INT NOT NULL,
+    [Phone] NVARCHAR(20) NOT NULL,
+    [Address] NVARCHAR(100) NULL,
+    [BirthDate] DATE NOT NULL,
+    [PlaceBirth] NVARCHAR(100) NULL,
+    [OrgUnit] NVARCHAR(50) NULL,
+    [PasswordHash] NVARCHAR(200) NULL,
+    [TokenVersion] INT NOT NULL DEFAULT 0,
+    [Status] NVARCHAR(20) NOT NULL DEFAULT 'active',
+
+    CONSTRAINT [PK_users] PRIMARY KEY CLUSTERED ([id] ASC),
+    CONSTRAINT [UQ_users_Email] UNIQUE ([Email])
+    );
+GO
+
+CREATE TABLE [dbo].[outbox](
+    [id] BIGINT IDENTITY(1,1) NOT NULL,
+    [EventType] NVARCHAR(100) NOT NULL,
+    [SchemaVersion] INT NOT NULL,
+    [AggregateId] NVARCHAR(36) NOT NULL,
+    [Payload] NVARCHAR(MAX) NOT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+    [DispatchedAt] DATETIME2 NULL,
+    [Attempts] INT NOT NULL DEFAULT 0,
+
+    CONSTRAINT [PK_outbox] PRIMARY KEY CLUSTERED ([id] ASC)
+    );
+GO
+
+CREATE TABLE [dbo].[roles](
+    [id] INT IDENTITY(1,1) NOT NULL,
+    [Name] NVARCHAR(50) NOT NULL,
+    [Description] NVARCHAR(200) NULL,
+
+    CONSTRAINT [PK_roles] PRIMARY KEY CLUSTERED ([id] ASC),
+    CONSTRAINT [UQ_roles_Name] UNIQUE ([Name])
+    );
+GO
+
+CREATE TABLE [dbo].[role_permissions](
+    [RoleId] INT NOT NULL,
+    [Permission] NVARCHAR(100) NOT NULL,
+
+    CONSTRAINT [PK_role_permissions] PRIMARY KEY CLUSTERED ([RoleId] ASC, [Permission] ASC),
+    CONSTRAINT [FK_role_permissions_roles] FOREIGN KEY ([RoleId]) REFERENCES [dbo].[roles]([id])
+    );
+GO
+
+CREATE TABLE [dbo].[user_roles](
+    [UserId] UNIQUEIDENTIFIER NOT NULL,
+    [RoleId] INT NOT NULL,
+
+    CONSTRAINT [PK_user_roles] PRIMARY KEY CLUSTERED ([UserId] ASC, [RoleId] ASC),
+    CONSTRAINT [FK_user_roles_roles] FOREIGN KEY ([RoleId]) REFERENCES [dbo].[roles]([id])
+    );
+GO
+
+-- Bootstrap role: assign it to the first administrator by inserting into [user_roles].
+INSERT INTO [dbo].[roles] ([Name], [Description]) VALUES ('admin', 'Full administrative access');
+INSERT INTO [dbo].[role_permissions] ([RoleId], [Permission]) SELECT [id], '*' FROM [dbo].[roles] WHERE [Name] = 'admin';
+GO
+
+CREATE TABLE [dbo].[tenant_keys](
+    [Kid] NVARCHAR(50) NOT NULL,
+    [TenantId] NVARCHAR(50) NOT NULL,
+    [Issuer] NVARCHAR(200) NOT NULL,
+    [Secret] NVARCHAR(200) NOT NULL,
+    [Active] BIT NOT NULL DEFAULT 1,
+
+    CONSTRAINT [PK_tenant_keys] PRIMARY KEY CLUSTERED ([Kid] ASC)
+    );
+GO
+
+CREATE TABLE [dbo].[refresh_tokens](
+    [TokenHash] NVARCHAR(64) NOT NULL,
+    [UserId] NVARCHAR(36) NOT NULL,
+    [TenantId] NVARCHAR(50) NULL,
+    [Fingerprint] NVARCHAR(64) NOT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+    [ExpiresAt] DATETIME2 NOT NULL,
+    [RevokedAt] DATETIME2 NULL,
+
+    CONSTRAINT [PK_refresh_tokens] PRIMARY KEY CLUSTERED ([TokenHash] ASC)
+    );
+GO
+
+CREATE INDEX [IX_refresh_tokens_UserId] ON [dbo].[refresh_tokens] ([UserId]);
+GO
@@ -0,0 +1,6 @@
+-- Optional account expiry, and when the user was last reminded of it.
+
+ALTER TABLE [dbo].[users] ADD
+    [ExpiresAt] DATETIME2 NULL,
+    [ExpiryReminderSentAt] DATETIME2 NULL;
+GO
@@ -0,0 +1,14 @@
+-- Per-role request limits. A NULL limit means unlimited; users none of whose roles
+-- has a row get the default from RATE_LIMIT_PER_MINUTE.
+
+CREATE TABLE [dbo].[rate_limits](
+    [RoleId] INT NOT NULL,
+    [RequestsPerMinute] INT NULL,
+
+    CONSTRAINT [PK_rate_limits] PRIMARY KEY CLUSTERED ([RoleId] ASC),
+    CONSTRAINT [FK_rate_limits_roles] FOREIGN KEY ([RoleId]) REFERENCES [dbo].[roles]([id])
+    );
+GO
+
+INSERT INTO [dbo].[rate_limits] ([RoleId], [RequestsPerMinute]) SELECT [id], NULL FROM [dbo].[roles] WHERE [Name] = 'admin';
+GO
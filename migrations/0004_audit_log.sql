@@ -0,0 +1,16 @@
+-- Account activity shown in user timelines. Entries are kept when the user is deleted.
+
+CREATE TABLE [dbo].[audit_log](
+    [id] BIGINT IDENTITY(1,1) NOT NULL,
+    [UserId] NVARCHAR(36) NOT NULL,
+    [Action] NVARCHAR(100) NOT NULL,
+    [ActorId] NVARCHAR(36) NULL,
+    [Details] NVARCHAR(MAX) NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_audit_log] PRIMARY KEY CLUSTERED ([id] ASC)
+    );
+GO
+
+CREATE INDEX [IX_audit_log_UserId_CreatedAt] ON [dbo].[audit_log] ([UserId], [CreatedAt]);
+GO
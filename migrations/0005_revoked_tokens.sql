@@ -0,0 +1,15 @@
+-- Access tokens revoked on their own through /logout, by `jti`. Rows can be dropped
+-- once the token has expired.
+
+CREATE TABLE [dbo].[revoked_tokens](
+    [Jti] NVARCHAR(36) NOT NULL,
+    [UserId] NVARCHAR(36) NOT NULL,
+    [ExpiresAt] DATETIME2 NOT NULL,
+    [RevokedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_revoked_tokens] PRIMARY KEY CLUSTERED ([Jti] ASC)
+    );
+GO
+
+CREATE INDEX [IX_revoked_tokens_ExpiresAt] ON [dbo].[revoked_tokens] ([ExpiresAt]);
+GO
@@ -0,0 +1,7 @@
+-- Lets the typeahead endpoint seek on name and email prefixes.
+
+CREATE INDEX [IX_users_Name] ON [dbo].[users] ([Name]) INCLUDE ([LastName], [Email]);
+GO
+
+CREATE INDEX [IX_users_LastName] ON [dbo].[users] ([LastName]) INCLUDE ([Name], [Email]);
+GO
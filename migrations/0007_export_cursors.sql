@@ -0,0 +1,13 @@
+-- Position of each audit-log exporter, moved only after a batch has been delivered.
+
+CREATE TABLE [dbo].[export_cursors](
+    [Name] NVARCHAR(50) NOT NULL,
+    [LastId] BIGINT NOT NULL DEFAULT 0,
+    [UpdatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_export_cursors] PRIMARY KEY CLUSTERED ([Name] ASC)
+    );
+GO
+
+INSERT INTO [dbo].[export_cursors] ([Name]) VALUES ('siem');
+GO
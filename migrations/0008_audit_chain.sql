@@ -0,0 +1,26 @@
+-- Chains audit entries with SHA-256 hashes and makes sealed entries write-once.
+
+ALTER TABLE [dbo].[audit_log] ADD
+    [ChainSeq] BIGINT NULL,
+    [PrevHash] NVARCHAR(64) NULL,
+    [Hash] NVARCHAR(64) NULL;
+GO
+
+CREATE UNIQUE INDEX [UX_audit_log_ChainSeq] ON [dbo].[audit_log] ([ChainSeq]) WHERE [ChainSeq] IS NOT NULL;
+GO
+
+-- Sealed entries may not be changed; unsealed ones may only be sealed.
+CREATE TRIGGER [dbo].[TR_audit_log_immutable] ON [dbo].[audit_log] AFTER UPDATE
+AS
+BEGIN
+    IF EXISTS (SELECT 1 FROM deleted WHERE [Hash] IS NOT NULL)
+        THROW 51000, 'Sealed audit log entries cannot be modified.', 1;
+END;
+GO
+
+CREATE TRIGGER [dbo].[TR_audit_log_no_delete] ON [dbo].[audit_log] INSTEAD OF DELETE
+AS
+BEGIN
+    THROW 51001, 'Audit log entries cannot be deleted.', 1;
+END;
+GO
@@ -0,0 +1,6 @@
+-- When each user was created and last updated, in UTC.
+
+ALTER TABLE [dbo].[users] ADD
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+    [UpdatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME();
+GO
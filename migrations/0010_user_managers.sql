@@ -0,0 +1,9 @@
+-- Reporting lines: the manager each user reports to, if any.
+
+ALTER TABLE [dbo].[users] ADD
+    [ManagerId] UNIQUEIDENTIFIER NULL
+        CONSTRAINT [FK_users_ManagerId] FOREIGN KEY REFERENCES [dbo].[users] ([id]);
+GO
+
+CREATE INDEX [IX_users_ManagerId] ON [dbo].[users] ([ManagerId]);
+GO
@@ -0,0 +1,10 @@
+-- Refresh token families: every token rotated from the same login shares a FamilyId,
+-- and a rotated token points at its successor so a replayed one can be detected.
+
+ALTER TABLE [dbo].[refresh_tokens] ADD
+    [FamilyId] NVARCHAR(36) NOT NULL DEFAULT CONVERT(NVARCHAR(36), NEWID()),
+    [ReplacedBy] NVARCHAR(64) NULL;
+GO
+
+CREATE INDEX [IX_refresh_tokens_FamilyId] ON [dbo].[refresh_tokens] ([FamilyId]);
+GO
@@ -0,0 +1,4 @@
+-- The User-Agent a refresh token was issued to, shown as the session's device.
+
+ALTER TABLE [dbo].[refresh_tokens] ADD [UserAgent] NVARCHAR(512) NULL;
+GO
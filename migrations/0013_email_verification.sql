@@ -0,0 +1,13 @@
+-- Email verification: new accounts start unverified until the link sent to them is opened.
+-- Accounts created before this migration are considered verified.
+
+ALTER TABLE [dbo].[users] ADD
+    [EmailVerified] BIT NOT NULL CONSTRAINT [DF_users_EmailVerified] DEFAULT 1,
+    [EmailVerificationHash] NVARCHAR(64) NULL;
+GO
+
+ALTER TABLE [dbo].[users] DROP CONSTRAINT [DF_users_EmailVerified];
+GO
+
+ALTER TABLE [dbo].[users] ADD CONSTRAINT [DF_users_EmailVerified] DEFAULT 0 FOR [EmailVerified];
+GO
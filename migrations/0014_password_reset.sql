@@ -0,0 +1,4 @@
+-- Password reset: the digest of the latest reset token sent to each user, cleared once used.
+
+ALTER TABLE [dbo].[users] ADD [PasswordResetHash] NVARCHAR(64) NULL;
+GO
@@ -0,0 +1,13 @@
+-- Named user-list filters and sort orders saved by each account.
+
+CREATE TABLE [dbo].[saved_searches](
+    [id] UNIQUEIDENTIFIER NOT NULL DEFAULT NEWID(),
+    [OwnerId] UNIQUEIDENTIFIER NOT NULL,
+    [Name] NVARCHAR(100) NOT NULL,
+    [Criteria] NVARCHAR(MAX) NOT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_saved_searches] PRIMARY KEY CLUSTERED ([id] ASC),
+    CONSTRAINT [UQ_saved_searches_OwnerId_Name] UNIQUE ([OwnerId], [Name])
+    );
+GO
@@ -0,0 +1,7 @@
+-- Presence: when each user last made an authenticated request, written at most once per interval.
+
+ALTER TABLE [dbo].[users] ADD [LastSeenAt] DATETIME2 NULL;
+GO
+
+CREATE INDEX [IX_users_LastSeenAt] ON [dbo].[users] ([LastSeenAt]);
+GO
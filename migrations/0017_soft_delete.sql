@@ -0,0 +1,4 @@
+-- Soft delete: deleted users keep their row, roles and history until restored.
+
+ALTER TABLE [dbo].[users] ADD [DeletedAt] DATETIME2 NULL;
+GO
@@ -0,0 +1,21 @@
+-- Pending and decided device authorizations (OAuth 2.0 device authorization grant).
+
+CREATE TABLE [dbo].[device_authorizations](
+    [DeviceCodeHash] CHAR(64) NOT NULL,
+    [UserCode] CHAR(9) NOT NULL,
+    [ClientId] NVARCHAR(100) NOT NULL,
+    [UserId] UNIQUEIDENTIFIER NULL,
+    [Status] VARCHAR(20) NOT NULL DEFAULT 'pending',
+    [PollInterval] INT NOT NULL,
+    [LastPolledAt] DATETIME2 NULL,
+    [ExpiresAt] DATETIME2 NOT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_device_authorizations] PRIMARY KEY CLUSTERED ([DeviceCodeHash] ASC)
+    );
+GO
+
+CREATE UNIQUE INDEX [UQ_device_authorizations_UserCode_pending]
+    ON [dbo].[device_authorizations] ([UserCode])
+    WHERE [Status] = 'pending';
+GO
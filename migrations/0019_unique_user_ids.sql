@@ -0,0 +1,4 @@
+-- Two users can no longer share a UserId. Duplicates must be resolved before this runs.
+
+ALTER TABLE [dbo].[users] ADD CONSTRAINT [UQ_users_UserId] UNIQUE ([UserId]);
+GO
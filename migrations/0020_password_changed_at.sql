@@ -0,0 +1,8 @@
+-- When each password was last set, for PASSWORD_MAX_AGE_DAYS. Existing passwords
+-- count from the creation of their account.
+
+ALTER TABLE [dbo].[users] ADD [PasswordChangedAt] DATETIME2 NULL;
+GO
+
+UPDATE [dbo].[users] SET [PasswordChangedAt] = [CreatedAt] WHERE [PasswordHash] IS NOT NULL;
+GO
@@ -0,0 +1,8 @@
+-- The tenant each user was created in, counted against TENANT_USER_QUOTAS in
+-- multi-tenant mode. Users created before have no tenant.
+
+ALTER TABLE [dbo].[users] ADD [TenantId] NVARCHAR(50) NULL;
+GO
+
+CREATE INDEX [IX_users_TenantId] ON [dbo].[users] ([TenantId]) WHERE [TenantId] IS NOT NULL AND [DeletedAt] IS NULL;
+GO
@@ -0,0 +1,14 @@
+-- Billing plans, copied with their entitlements into issued tokens. A user's own plan
+-- takes precedence over the plan of their tenant.
+
+ALTER TABLE [dbo].[users] ADD [Plan] NVARCHAR(50) NULL;
+GO
+
+CREATE TABLE [dbo].[tenant_plans](
+    [TenantId] NVARCHAR(50) NOT NULL,
+    [Plan] NVARCHAR(50) NOT NULL,
+    [UpdatedAt] DATETIME2 NOT NULL CONSTRAINT [DF_tenant_plans_UpdatedAt] DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_tenant_plans] PRIMARY KEY CLUSTERED ([TenantId] ASC)
+);
+GO
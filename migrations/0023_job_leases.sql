@@ -0,0 +1,11 @@
+-- Leases electing the one replica that runs each background job. A lease belongs to
+-- its holder until it expires, after which any replica may take it over.
+
+CREATE TABLE [dbo].[job_leases](
+    [Name] NVARCHAR(100) NOT NULL,
+    [Holder] NVARCHAR(200) NOT NULL,
+    [ExpiresAt] DATETIME2 NOT NULL,
+
+    CONSTRAINT [PK_job_leases] PRIMARY KEY CLUSTERED ([Name] ASC)
+);
+GO
@@ -0,0 +1,7 @@
+-- Accounts locked by an administrator, and accounts whose password must be reset
+-- before the next login.
+
+ALTER TABLE [dbo].[users] ADD
+    [LockedAt] DATETIME2 NULL,
+    [PasswordResetRequired] BIT NOT NULL CONSTRAINT [DF_users_PasswordResetRequired] DEFAULT 0;
+GO
@@ -0,0 +1,16 @@
+-- Failed logins of the last lockout window, counted per email and per client IP to
+-- lock out password guessing.
+
+CREATE TABLE [dbo].[login_failures](
+    [id] BIGINT IDENTITY(1,1) NOT NULL,
+    [Email] NVARCHAR(100) NOT NULL,
+    [ClientIp] NVARCHAR(45) NULL,
+    [FailedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_login_failures] PRIMARY KEY CLUSTERED ([id] ASC)
+);
+GO
+
+CREATE INDEX [IX_login_failures_Email_FailedAt] ON [dbo].[login_failures] ([Email], [FailedAt]);
+CREATE INDEX [IX_login_failures_ClientIp_FailedAt] ON [dbo].[login_failures] ([ClientIp], [FailedAt]);
+GO
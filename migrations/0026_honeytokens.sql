@@ -0,0 +1,6 @@
+-- Decoy accounts that no one should ever touch: any login on or access to them
+-- raises an alert.
+
+ALTER TABLE [dbo].[users] ADD
+    [IsHoneytoken] BIT NOT NULL CONSTRAINT [DF_users_IsHoneytoken] DEFAULT 0;
+GO
@@ -0,0 +1,22 @@
+-- TOTP secrets, encrypted, and the one-time recovery codes of users who enrolled in
+-- two-factor authentication. A secret only guards logins once it is confirmed.
+
+CREATE TABLE [dbo].[mfa_secrets](
+    [UserId] NVARCHAR(36) NOT NULL,
+    [Secret] NVARCHAR(200) NOT NULL,
+    [ConfirmedAt] DATETIME2 NULL,
+    [LastUsedStep] BIGINT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_mfa_secrets] PRIMARY KEY CLUSTERED ([UserId] ASC)
+);
+GO
+
+CREATE TABLE [dbo].[mfa_recovery_codes](
+    [UserId] NVARCHAR(36) NOT NULL,
+    [CodeHash] NVARCHAR(64) NOT NULL,
+    [UsedAt] DATETIME2 NULL,
+
+    CONSTRAINT [PK_mfa_recovery_codes] PRIMARY KEY CLUSTERED ([UserId] ASC, [CodeHash] ASC)
+);
+GO
@@ -0,0 +1,24 @@
+-- Active-active deployments: the region that last wrote each user, and the writes of
+-- two regions found to conflict, with how each conflict was settled.
+
+ALTER TABLE [dbo].[users] ADD
+    [OriginRegion] NVARCHAR(32) NULL;
+GO
+
+CREATE TABLE [dbo].[replication_conflicts](
+    [id] BIGINT IDENTITY(1,1) NOT NULL,
+    [UserId] NVARCHAR(36) NOT NULL,
+    [Operation] NVARCHAR(16) NOT NULL,
+    [StoredRegion] NVARCHAR(32) NULL,
+    [StoredUpdatedAt] DATETIME2 NULL,
+    [IncomingRegion] NVARCHAR(32) NOT NULL,
+    [IncomingUpdatedAt] DATETIME2 NOT NULL,
+    [Resolution] NVARCHAR(20) NOT NULL,
+    [DetectedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_replication_conflicts] PRIMARY KEY CLUSTERED ([id] ASC)
+);
+GO
+
+CREATE INDEX [IX_replication_conflicts_UserId] ON [dbo].[replication_conflicts] ([UserId], [DetectedAt]);
+GO
@@ -0,0 +1,15 @@
+-- Accounts of identity providers (Google, GitHub) linked to users, who can log in
+-- with them instead of a password.
+
+CREATE TABLE [dbo].[oauth_identities](
+    [Provider] NVARCHAR(20) NOT NULL,
+    [Subject] NVARCHAR(255) NOT NULL,
+    [UserId] NVARCHAR(36) NOT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_oauth_identities] PRIMARY KEY CLUSTERED ([Provider] ASC, [Subject] ASC)
+);
+GO
+
+CREATE INDEX [IX_oauth_identities_UserId] ON [dbo].[oauth_identities] ([UserId]);
+GO
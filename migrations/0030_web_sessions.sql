@@ -0,0 +1,16 @@
+-- Server-side sessions of the browser frontends that log in with a session cookie,
+-- stored by the hash of the cookie.
+
+CREATE TABLE [dbo].[web_sessions](
+    [Id] NVARCHAR(64) NOT NULL,
+    [UserId] NVARCHAR(36) NOT NULL,
+    [Claims] NVARCHAR(MAX) NOT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+    [ExpiresAt] DATETIME2 NOT NULL,
+
+    CONSTRAINT [PK_web_sessions] PRIMARY KEY CLUSTERED ([Id] ASC)
+);
+GO
+
+CREATE INDEX [IX_web_sessions_ExpiresAt] ON [dbo].[web_sessions] ([ExpiresAt]);
+GO
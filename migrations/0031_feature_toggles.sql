@@ -0,0 +1,11 @@
+-- Features switched on or off at runtime through the admin endpoint, overriding
+-- DISABLED_FEATURES on every instance.
+
+CREATE TABLE [dbo].[feature_toggles](
+    [Feature] NVARCHAR(50) NOT NULL,
+    [Enabled] BIT NOT NULL,
+    [UpdatedAt] DATETIME2 NOT NULL CONSTRAINT [DF_feature_toggles_UpdatedAt] DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_feature_toggles] PRIMARY KEY CLUSTERED ([Feature] ASC)
+);
+GO
@@ -13,7 +13,406 @@ CREATE TABLE [dbo].[users](
     [Address] NVARCHAR(100) NULL,
     [BirthDate] DATE NOT NULL,
     [PlaceBirth] NVARCHAR(100) NULL,
+    [OrgUnit] NVARCHAR(50) NULL,
+    [PasswordHash] NVARCHAR(200) NULL,
+    [PasswordChangedAt] DATETIME2 NULL,
+    [TokenVersion] INT NOT NULL DEFAULT 0,
+    [Status] NVARCHAR(20) NOT NULL DEFAULT 'active',
+    [ExpiresAt] DATETIME2 NULL,
+    [ExpiryReminderSentAt] DATETIME2 NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+    [UpdatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+    [ManagerId] UNIQUEIDENTIFIER NULL,
+    [EmailVerified] BIT NOT NULL CONSTRAINT [DF_users_EmailVerified] DEFAULT 0,
+    [EmailVerificationHash] NVARCHAR(64) NULL,
+    [PasswordResetHash] NVARCHAR(64) NULL,
+    [LastSeenAt] DATETIME2 NULL,
+    [DeletedAt] DATETIME2 NULL,
+    [TenantId] NVARCHAR(50) NULL,
+    [Plan] NVARCHAR(50) NULL,
+    [LockedAt] DATETIME2 NULL,
+    [PasswordResetRequired] BIT NOT NULL CONSTRAINT [DF_users_PasswordResetRequired] DEFAULT 0,
+    [IsHoneytoken] BIT NOT NULL CONSTRAINT [DF_users_IsHoneytoken] DEFAULT 0,
+    [OriginRegion] NVARCHAR(32) NULL,
 
-    CONSTRAINT [PK_users] PRIMARY KEY CLUSTERED ([id] ASC)
+    CONSTRAINT [PK_users] PRIMARY KEY CLUSTERED ([id] ASC),
+    CONSTRAINT [UQ_users_Email] UNIQUE ([Email]),
+    CONSTRAINT [UQ_users_UserId] UNIQUE ([UserId]),
+    CONSTRAINT [FK_users_ManagerId] FOREIGN KEY ([ManagerId]) REFERENCES [dbo].[users] ([id])
     );
-GO
\ No newline at end of file
+GO
+
+CREATE INDEX [IX_users_ManagerId] ON [dbo].[users] ([ManagerId]);
+GO
+
+CREATE INDEX [IX_users_Name] ON [dbo].[users] ([Name]) INCLUDE ([LastName], [Email]);
+GO
+
+CREATE INDEX [IX_users_LastName] ON [dbo].[users] ([LastName]) INCLUDE ([Name], [Email]);
+GO
+
+CREATE INDEX [IX_users_LastSeenAt] ON [dbo].[users] ([LastSeenAt]);
+GO
+
+CREATE INDEX [IX_users_TenantId] ON [dbo].[users] ([TenantId]) WHERE [TenantId] IS NOT NULL AND [DeletedAt] IS NULL;
+GO
+
+IF OBJECT_ID('[dbo].[outbox]', 'U') IS NOT NULL
+DROP TABLE [dbo].[outbox];
+GO
+
+CREATE TABLE [dbo].[outbox](
+    [id] BIGINT IDENTITY(1,1) NOT NULL,
+    [EventType] NVARCHAR(100) NOT NULL,
+    [SchemaVersion] INT NOT NULL,
+    [AggregateId] NVARCHAR(36) NOT NULL,
+    [Payload] NVARCHAR(MAX) NOT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+    [DispatchedAt] DATETIME2 NULL,
+    [Attempts] INT NOT NULL DEFAULT 0,
+
+    CONSTRAINT [PK_outbox] PRIMARY KEY CLUSTERED ([id] ASC)
+    );
+GO
+
+IF OBJECT_ID('[dbo].[rate_limits]', 'U') IS NOT NULL
+DROP TABLE [dbo].[rate_limits];
+GO
+
+IF OBJECT_ID('[dbo].[user_roles]', 'U') IS NOT NULL
+DROP TABLE [dbo].[user_roles];
+GO
+
+IF OBJECT_ID('[dbo].[role_permissions]', 'U') IS NOT NULL
+DROP TABLE [dbo].[role_permissions];
+GO
+
+IF OBJECT_ID('[dbo].[roles]', 'U') IS NOT NULL
+DROP TABLE [dbo].[roles];
+GO
+
+CREATE TABLE [dbo].[roles](
+    [id] INT IDENTITY(1,1) NOT NULL,
+    [Name] NVARCHAR(50) NOT NULL,
+    [Description] NVARCHAR(200) NULL,
+
+    CONSTRAINT [PK_roles] PRIMARY KEY CLUSTERED ([id] ASC),
+    CONSTRAINT [UQ_roles_Name] UNIQUE ([Name])
+    );
+GO
+
+CREATE TABLE [dbo].[role_permissions](
+    [RoleId] INT NOT NULL,
+    [Permission] NVARCHAR(100) NOT NULL,
+
+    CONSTRAINT [PK_role_permissions] PRIMARY KEY CLUSTERED ([RoleId] ASC, [Permission] ASC),
+    CONSTRAINT [FK_role_permissions_roles] FOREIGN KEY ([RoleId]) REFERENCES [dbo].[roles]([id])
+    );
+GO
+
+CREATE TABLE [dbo].[user_roles](
+    [UserId] UNIQUEIDENTIFIER NOT NULL,
+    [RoleId] INT NOT NULL,
+
+    CONSTRAINT [PK_user_roles] PRIMARY KEY CLUSTERED ([UserId] ASC, [RoleId] ASC),
+    CONSTRAINT [FK_user_roles_roles] FOREIGN KEY ([RoleId]) REFERENCES [dbo].[roles]([id])
+    );
+GO
+
+-- Bootstrap role: assign it to the first administrator by inserting into [user_roles].
+INSERT INTO [dbo].[roles] ([Name], [Description]) VALUES ('admin', 'Full administrative access');
+INSERT INTO [dbo].[role_permissions] ([RoleId], [Permission]) SELECT [id], '*' FROM [dbo].[roles] WHERE [Name] = 'admin';
+GO
+
+CREATE TABLE [dbo].[rate_limits](
+    [RoleId] INT NOT NULL,
+    [RequestsPerMinute] INT NULL,
+
+    CONSTRAINT [PK_rate_limits] PRIMARY KEY CLUSTERED ([RoleId] ASC),
+    CONSTRAINT [FK_rate_limits_roles] FOREIGN KEY ([RoleId]) REFERENCES [dbo].[roles]([id])
+    );
+GO
+
+-- A NULL limit means unlimited.
+INSERT INTO [dbo].[rate_limits] ([RoleId], [RequestsPerMinute]) SELECT [id], NULL FROM [dbo].[roles] WHERE [Name] = 'admin';
+GO
+
+IF OBJECT_ID('[dbo].[tenant_keys]', 'U') IS NOT NULL
+DROP TABLE [dbo].[tenant_keys];
+GO
+
+CREATE TABLE [dbo].[tenant_keys](
+    [Kid] NVARCHAR(50) NOT NULL,
+    [TenantId] NVARCHAR(50) NOT NULL,
+    [Issuer] NVARCHAR(200) NOT NULL,
+    [Secret] NVARCHAR(200) NOT NULL,
+    [Active] BIT NOT NULL DEFAULT 1,
+
+    CONSTRAINT [PK_tenant_keys] PRIMARY KEY CLUSTERED ([Kid] ASC)
+    );
+GO
+
+IF OBJECT_ID('[dbo].[tenant_plans]', 'U') IS NOT NULL
+DROP TABLE [dbo].[tenant_plans];
+GO
+
+CREATE TABLE [dbo].[tenant_plans](
+    [TenantId] NVARCHAR(50) NOT NULL,
+    [Plan] NVARCHAR(50) NOT NULL,
+    [UpdatedAt] DATETIME2 NOT NULL CONSTRAINT [DF_tenant_plans_UpdatedAt] DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_tenant_plans] PRIMARY KEY CLUSTERED ([TenantId] ASC)
+    );
+GO
+
+IF OBJECT_ID('[dbo].[refresh_tokens]', 'U') IS NOT NULL
+DROP TABLE [dbo].[refresh_tokens];
+GO
+
+CREATE TABLE [dbo].[refresh_tokens](
+    [TokenHash] NVARCHAR(64) NOT NULL,
+    [UserId] NVARCHAR(36) NOT NULL,
+    [TenantId] NVARCHAR(50) NULL,
+    [Fingerprint] NVARCHAR(64) NOT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+    [ExpiresAt] DATETIME2 NOT NULL,
+    [RevokedAt] DATETIME2 NULL,
+    [FamilyId] NVARCHAR(36) NOT NULL DEFAULT CONVERT(NVARCHAR(36), NEWID()),
+    [ReplacedBy] NVARCHAR(64) NULL,
+    [UserAgent] NVARCHAR(512) NULL,
+
+    CONSTRAINT [PK_refresh_tokens] PRIMARY KEY CLUSTERED ([TokenHash] ASC)
+    );
+GO
+
+CREATE INDEX [IX_refresh_tokens_UserId] ON [dbo].[refresh_tokens] ([UserId]);
+GO
+
+CREATE INDEX [IX_refresh_tokens_FamilyId] ON [dbo].[refresh_tokens] ([FamilyId]);
+GO
+IF OBJECT_ID('[dbo].[audit_log]', 'U') IS NOT NULL
+DROP TABLE [dbo].[audit_log];
+GO
+
+CREATE TABLE [dbo].[audit_log](
+    [id] BIGINT IDENTITY(1,1) NOT NULL,
+    [UserId] NVARCHAR(36) NOT NULL,
+    [Action] NVARCHAR(100) NOT NULL,
+    [ActorId] NVARCHAR(36) NULL,
+    [Details] NVARCHAR(MAX) NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+    [ChainSeq] BIGINT NULL,
+    [PrevHash] NVARCHAR(64) NULL,
+    [Hash] NVARCHAR(64) NULL,
+
+    CONSTRAINT [PK_audit_log] PRIMARY KEY CLUSTERED ([id] ASC)
+    );
+GO
+
+CREATE INDEX [IX_audit_log_UserId_CreatedAt] ON [dbo].[audit_log] ([UserId], [CreatedAt]);
+GO
+
+CREATE UNIQUE INDEX [UX_audit_log_ChainSeq] ON [dbo].[audit_log] ([ChainSeq]) WHERE [ChainSeq] IS NOT NULL;
+GO
+
+-- Sealed entries may not be changed; unsealed ones may only be sealed.
+CREATE TRIGGER [dbo].[TR_audit_log_immutable] ON [dbo].[audit_log] AFTER UPDATE
+AS
+BEGIN
+    IF EXISTS (SELECT 1 FROM deleted WHERE [Hash] IS NOT NULL)
+        THROW 51000, 'Sealed audit log entries cannot be modified.', 1;
+END;
+GO
+
+CREATE TRIGGER [dbo].[TR_audit_log_no_delete] ON [dbo].[audit_log] INSTEAD OF DELETE
+AS
+BEGIN
+    THROW 51001, 'Audit log entries cannot be deleted.', 1;
+END;
+GO
+IF OBJECT_ID('[dbo].[revoked_tokens]', 'U') IS NOT NULL
+DROP TABLE [dbo].[revoked_tokens];
+GO
+
+CREATE TABLE [dbo].[revoked_tokens](
+    [Jti] NVARCHAR(36) NOT NULL,
+    [UserId] NVARCHAR(36) NOT NULL,
+    [ExpiresAt] DATETIME2 NOT NULL,
+    [RevokedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_revoked_tokens] PRIMARY KEY CLUSTERED ([Jti] ASC)
+    );
+GO
+
+CREATE INDEX [IX_revoked_tokens_ExpiresAt] ON [dbo].[revoked_tokens] ([ExpiresAt]);
+GO
+IF OBJECT_ID('[dbo].[export_cursors]', 'U') IS NOT NULL
+DROP TABLE [dbo].[export_cursors];
+GO
+
+CREATE TABLE [dbo].[export_cursors](
+    [Name] NVARCHAR(50) NOT NULL,
+    [LastId] BIGINT NOT NULL DEFAULT 0,
+    [UpdatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_export_cursors] PRIMARY KEY CLUSTERED ([Name] ASC)
+    );
+GO
+
+INSERT INTO [dbo].[export_cursors] ([Name]) VALUES ('siem');
+GO
+IF OBJECT_ID('[dbo].[saved_searches]', 'U') IS NOT NULL
+DROP TABLE [dbo].[saved_searches];
+GO
+
+CREATE TABLE [dbo].[saved_searches](
+    [id] UNIQUEIDENTIFIER NOT NULL DEFAULT NEWID(),
+    [OwnerId] UNIQUEIDENTIFIER NOT NULL,
+    [Name] NVARCHAR(100) NOT NULL,
+    [Criteria] NVARCHAR(MAX) NOT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_saved_searches] PRIMARY KEY CLUSTERED ([id] ASC),
+    CONSTRAINT [UQ_saved_searches_OwnerId_Name] UNIQUE ([OwnerId], [Name])
+    );
+GO
+
+IF OBJECT_ID('[dbo].[device_authorizations]', 'U') IS NOT NULL
+DROP TABLE [dbo].[device_authorizations];
+GO
+
+CREATE TABLE [dbo].[device_authorizations](
+    [DeviceCodeHash] CHAR(64) NOT NULL,
+    [UserCode] CHAR(9) NOT NULL,
+    [ClientId] NVARCHAR(100) NOT NULL,
+    [UserId] UNIQUEIDENTIFIER NULL,
+    [Status] VARCHAR(20) NOT NULL DEFAULT 'pending',
+    [PollInterval] INT NOT NULL,
+    [LastPolledAt] DATETIME2 NULL,
+    [ExpiresAt] DATETIME2 NOT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_device_authorizations] PRIMARY KEY CLUSTERED ([DeviceCodeHash] ASC)
+    );
+GO
+
+CREATE UNIQUE INDEX [UQ_device_authorizations_UserCode_pending]
+    ON [dbo].[device_authorizations] ([UserCode])
+    WHERE [Status] = 'pending';
+GO
+
+IF OBJECT_ID('[dbo].[job_leases]', 'U') IS NOT NULL
+DROP TABLE [dbo].[job_leases];
+GO
+
+CREATE TABLE [dbo].[job_leases](
+    [Name] NVARCHAR(100) NOT NULL,
+    [Holder] NVARCHAR(200) NOT NULL,
+    [ExpiresAt] DATETIME2 NOT NULL,
+
+    CONSTRAINT [PK_job_leases] PRIMARY KEY CLUSTERED ([Name] ASC)
+    );
+GO
+
+IF OBJECT_ID('[dbo].[login_failures]', 'U') IS NOT NULL
+DROP TABLE [dbo].[login_failures];
+GO
+
+CREATE TABLE [dbo].[login_failures](
+    [id] BIGINT IDENTITY(1,1) NOT NULL,
+    [Email] NVARCHAR(100) NOT NULL,
+    [ClientIp] NVARCHAR(45) NULL,
+    [FailedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_login_failures] PRIMARY KEY CLUSTERED ([id] ASC)
+    );
+GO
+
+CREATE INDEX [IX_login_failures_Email_FailedAt] ON [dbo].[login_failures] ([Email], [FailedAt]);
+CREATE INDEX [IX_login_failures_ClientIp_FailedAt] ON [dbo].[login_failures] ([ClientIp], [FailedAt]);
+GO
+
+IF OBJECT_ID('[dbo].[mfa_secrets]', 'U') IS NOT NULL
+DROP TABLE [dbo].[mfa_secrets];
+GO
+
+CREATE TABLE [dbo].[mfa_secrets](
+    [UserId] NVARCHAR(36) NOT NULL,
+    [Secret] NVARCHAR(200) NOT NULL,
+    [ConfirmedAt] DATETIME2 NULL,
+    [LastUsedStep] BIGINT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_mfa_secrets] PRIMARY KEY CLUSTERED ([UserId] ASC)
+    );
+GO
+
+IF OBJECT_ID('[dbo].[mfa_recovery_codes]', 'U') IS NOT NULL
+DROP TABLE [dbo].[mfa_recovery_codes];
+GO
+
+CREATE TABLE [dbo].[mfa_recovery_codes](
+    [UserId] NVARCHAR(36) NOT NULL,
+    [CodeHash] NVARCHAR(64) NOT NULL,
+    [UsedAt] DATETIME2 NULL,
+
+    CONSTRAINT [PK_mfa_recovery_codes] PRIMARY KEY CLUSTERED ([UserId] ASC, [CodeHash] ASC)
+    );
+GO
+
+IF OBJECT_ID('[dbo].[replication_conflicts]', 'U') IS NOT NULL
+DROP TABLE [dbo].[replication_conflicts];
+GO
+
+CREATE TABLE [dbo].[replication_conflicts](
+    [id] BIGINT IDENTITY(1,1) NOT NULL,
+    [UserId] NVARCHAR(36) NOT NULL,
+    [Operation] NVARCHAR(16) NOT NULL,
+    [StoredRegion] NVARCHAR(32) NULL,
+    [StoredUpdatedAt] DATETIME2 NULL,
+    [IncomingRegion] NVARCHAR(32) NOT NULL,
+    [IncomingUpdatedAt] DATETIME2 NOT NULL,
+    [Resolution] NVARCHAR(20) NOT NULL,
+    [DetectedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_replication_conflicts] PRIMARY KEY CLUSTERED ([id] ASC)
+    );
+GO
+
+CREATE INDEX [IX_replication_conflicts_UserId] ON [dbo].[replication_conflicts] ([UserId], [DetectedAt]);
+GO
+
+IF OBJECT_ID('[dbo].[oauth_identities]', 'U') IS NOT NULL
+DROP TABLE [dbo].[oauth_identities];
+GO
+
+CREATE TABLE [dbo].[oauth_identities](
+    [Provider] NVARCHAR(20) NOT NULL,
+    [Subject] NVARCHAR(255) NOT NULL,
+    [UserId] NVARCHAR(36) NOT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+    CONSTRAINT [PK_oauth_identities] PRIMARY KEY CLUSTERED ([Provider] ASC, [Subject] ASC)
+    );
+GO
+
+CREATE INDEX [IX_oauth_identities_UserId] ON [dbo].[oauth_identities] ([UserId]);
+GO
+
+IF OBJECT_ID('[dbo].[web_sessions]', 'U') IS NOT NULL
+DROP TABLE [dbo].[web_sessions];
+GO
+
+CREATE TABLE [dbo].[web_sessions](
+    [Id] NVARCHAR(64) NOT NULL,
+    [UserId] NVARCHAR(36) NOT NULL,
+    [Claims] NVARCHAR(MAX) NOT NULL,
+    [CreatedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+    [ExpiresAt] DATETIME2 NOT NULL,
+
+    CONSTRAINT [PK_web_sessions] PRIMARY KEY CLUSTERED ([Id] ASC)
+    );
+GO
+
+CREATE INDEX [IX_web_sessions_ExpiresAt] ON [dbo].[web_sessions] ([ExpiresAt]);
+GO
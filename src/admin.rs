@@ -0,0 +1,456 @@
+use actix_web::http::header::LINK;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Mssql, Pool};
+use std::sync::Arc;
+use crate::approvals::{self, ACTIVE};
+use crate::audit;
+use crate::deactivation::{self, CascadePolicy};
+use crate::error::AppError;
+use crate::handlers::user_conflict;
+use crate::honeytokens::{self, HoneytokenFlag};
+use crate::locks;
+use crate::mfa;
+use crate::extractors::{AuthenticatedUser, UserId};
+use crate::models::CreateUserRequest;
+use crate::org_chart::{self, ManagerAssignment, ManagerChange};
+use crate::pagination::{PageMeta, UserQuery};
+use crate::password_reset;
+use crate::plans::{self, PlanAssignment, PlanCatalog};
+use crate::rate_limit::{self, RateLimitInput, RateLimiter};
+use crate::rbac::{self, BulkRoleAssignment, PermissionSet, RoleAssignment, RoleInput};
+use crate::quotas::{self, request_tenant};
+use crate::repository::{UnitOfWork, UserRepository};
+use crate::sessions;
+use crate::validation::ValidJson;
+use crate::visibility::Viewer;
+
+/// Message of the `409 Conflict` returned when a role name is already in use.
+const ROLE_NAME_TAKEN: &str = "A role with that name already exists.";
+
+/// Query parameters of destructive operations. With `?dry_run=true` the operation runs
+/// in a transaction that is rolled back, so the response describes exactly what it
+/// would change without changing anything.
+#[derive(Debug, Default, Deserialize)]
+pub struct DryRun {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Lists every role.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - A JSON response containing the list of roles or an error message.
+pub async fn list_roles(pool: web::Data<Pool<Mssql>>) -> Result<HttpResponse, AppError> {
+    let roles = rbac::list_roles(pool.get_ref()).await?;
+    Ok(HttpResponse::Ok().json(roles))
+}
+
+/// Creates a role.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `input` - A JSON payload with the role name and description.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `201 Created` with the new role, or `409 Conflict` if the name is taken.
+pub async fn create_role(pool: web::Data<Pool<Mssql>>, input: web::Json<RoleInput>) -> Result<HttpResponse, AppError> {
+    let role = rbac::create_role(pool.get_ref(), &input)
+        .await
+        .map_err(|e| AppError::conflict_if_unique(e, "role_name_taken", ROLE_NAME_TAKEN))?;
+    Ok(HttpResponse::Created().json(role))
+}
+
+/// Renames a role or changes its description.
+pub async fn update_role(pool: web::Data<Pool<Mssql>>, path: web::Path<i32>, input: web::Json<RoleInput>) -> Result<HttpResponse, AppError> {
+    let updated = rbac::update_role(pool.get_ref(), path.into_inner(), &input)
+        .await
+        .map_err(|e| AppError::conflict_if_unique(e, "role_name_taken", ROLE_NAME_TAKEN))?;
+
+    if !updated {
+        return Err(AppError::NotFound("role"));
+    }
+    Ok(HttpResponse::Ok().json("Role updated successfully."))
+}
+
+/// Deletes a role, its permissions and all of its assignments.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, `200 OK` with what would be removed
+///   for a [`DryRun`], or `404 Not Found` if the role does not exist.
+pub async fn delete_role(pool: web::Data<Pool<Mssql>>, path: web::Path<i32>, query: web::Query<DryRun>) -> Result<HttpResponse, AppError> {
+    let role_id = path.into_inner();
+    let deletion = rbac::delete_role(pool.get_ref(), role_id, query.dry_run)
+        .await?
+        .ok_or(AppError::NotFound("role"))?;
+    if !query.dry_run {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+    Ok(HttpResponse::Ok().json(json!({ "role_id": role_id, "dry_run": true, "removed": deletion })))
+}
+
+/// Returns the permissions granted by a role.
+pub async fn get_role_permissions(pool: web::Data<Pool<Mssql>>, path: web::Path<i32>) -> Result<HttpResponse, AppError> {
+    let permissions = rbac::role_permissions(pool.get_ref(), path.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(PermissionSet { permissions }))
+}
+
+/// Replaces the permissions granted by a role.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `path` - The id of the role.
+/// * `input` - A JSON payload with the complete list of permissions.
+pub async fn set_role_permissions(pool: web::Data<Pool<Mssql>>, path: web::Path<i32>, input: web::Json<PermissionSet>) -> Result<HttpResponse, AppError> {
+    rbac::set_role_permissions(pool.get_ref(), path.into_inner(), &input.permissions).await?;
+    Ok(HttpResponse::Ok().json("Role permissions updated successfully."))
+}
+
+/// Lists the configured rate limits: the default tier and the roles with their own limit.
+pub async fn list_rate_limits(pool: web::Data<Pool<Mssql>>, limiter: web::Data<RateLimiter>) -> Result<HttpResponse, AppError> {
+    let roles = rate_limit::list_rate_limits(pool.get_ref()).await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "default_requests_per_minute": limiter.default_limit(),
+        "roles": roles,
+    })))
+}
+
+/// Sets the requests per minute allowed to a role's users, or lifts their limit.
+///
+/// The new limit applies to every worker as soon as the response is sent.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `limiter` - The shared rate limiter, reloaded after the change.
+/// * `path` - The id of the role.
+/// * `input` - A JSON payload with `requests_per_minute`, or `null` for no limit.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `200 OK`, `400 Bad Request` if the limit is not positive,
+///   or `404 Not Found` if the role does not exist.
+pub async fn set_rate_limit(pool: web::Data<Pool<Mssql>>, limiter: web::Data<RateLimiter>, path: web::Path<i32>, input: web::Json<RateLimitInput>) -> Result<HttpResponse, AppError> {
+    if input.requests_per_minute.is_some_and(|limit| limit < 1) {
+        return Err(AppError::validation("requests_per_minute must be at least 1, or null for no limit."));
+    }
+
+    if !rate_limit::set_rate_limit(pool.get_ref(), path.into_inner(), input.requests_per_minute).await? {
+        return Err(AppError::NotFound("role"));
+    }
+    limiter.reload(pool.get_ref()).await?;
+    Ok(HttpResponse::Ok().json("Rate limit updated successfully."))
+}
+
+/// Removes a role's own limit, so its users fall back to the default tier.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, or `404 Not Found` if the role had no limit.
+pub async fn clear_rate_limit(pool: web::Data<Pool<Mssql>>, limiter: web::Data<RateLimiter>, path: web::Path<i32>) -> Result<HttpResponse, AppError> {
+    if !rate_limit::clear_rate_limit(pool.get_ref(), path.into_inner()).await? {
+        return Err(AppError::NotFound("rate_limit"));
+    }
+    limiter.reload(pool.get_ref()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Returns the roles assigned to a user.
+pub async fn get_user_roles(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>) -> Result<HttpResponse, AppError> {
+    let roles = rbac::user_roles(pool.get_ref(), &path.to_string()).await?;
+    Ok(HttpResponse::Ok().json(roles))
+}
+
+/// Replaces the roles assigned to a user.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `path` - The id of the user.
+/// * `input` - A JSON payload with the complete list of role ids.
+/// * `caller` - The authenticated administrator, recorded in the user's timeline.
+pub async fn set_user_roles(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>, input: web::Json<RoleAssignment>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    let user_id = path.to_string();
+    let mut work = UnitOfWork::begin(pool.get_ref()).await?;
+    rbac::set_user_roles(&mut work, &user_id, &input.role_ids).await?;
+
+    let details = json!({ "role_ids": input.role_ids });
+    audit::record(&mut *work, &user_id, audit::ROLES_CHANGED, audit::actor(&caller).as_deref(), Some(&details)).await?;
+    work.commit().await?;
+    Ok(HttpResponse::Ok().json("User roles updated successfully."))
+}
+
+/// Adds a role to many users at once, such as everyone in a department being onboarded.
+///
+/// The users are given either as `user_ids` or as a `filter` on their `org_unit`. The
+/// role is added in one transaction, and every user who did not have it yet gets an
+/// entry in their timeline.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `path` - The id of the role.
+/// * `query` - Whether this is a [`DryRun`], which only reports the users the role would be added to.
+/// * `input` - A JSON payload with either `user_ids` or `filter`.
+/// * `caller` - The authenticated administrator, recorded in the users' timelines.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `200 OK` with the ids of the users the role was added to,
+///   `400 Bad Request` if the payload selects no users, or `404 Not Found` if the role does not exist.
+pub async fn assign_role(pool: web::Data<Pool<Mssql>>, path: web::Path<i32>, query: web::Query<DryRun>, input: web::Json<BulkRoleAssignment>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    input.check().map_err(AppError::validation)?;
+
+    let role_id = path.into_inner();
+    let assigned = rbac::assign_role(pool.get_ref(), role_id, &input, audit::actor(&caller).as_deref(), query.dry_run)
+        .await?
+        .ok_or(AppError::NotFound("role"))?;
+    Ok(HttpResponse::Ok().json(json!({ "role_id": role_id, "dry_run": query.dry_run, "assigned": assigned.len(), "user_ids": assigned })))
+}
+
+/// Revokes every session of a user, for compromised-account response.
+///
+/// All tokens issued to the user before the call stop being accepted.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, or `404 Not Found` if the user does not exist.
+pub async fn revoke_sessions(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    let user_id = path.to_string();
+    let mut work = UnitOfWork::begin(pool.get_ref()).await?;
+    if !sessions::revoke_sessions(&mut work, &user_id).await? {
+        return Err(AppError::NotFound("user"));
+    }
+    audit::record(&mut *work, &user_id, audit::SESSIONS_REVOKED, audit::actor(&caller).as_deref(), None).await?;
+    work.commit().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Lists every user, including soft-deleted ones, paginated and filtered like
+/// `GET /protected/users`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `200 OK` with the users and the page metadata.
+pub async fn list_users(req: HttpRequest, users: web::Data<Arc<dyn UserRepository>>, query: web::Query<UserQuery>, viewer: Viewer) -> Result<HttpResponse, AppError> {
+    let mut query = query.into_inner();
+    query.include_deleted = true;
+    let page = users.list(&query).await?;
+    let meta = PageMeta::new(&query, page.total);
+
+    Ok(HttpResponse::Ok()
+        .insert_header((LINK, meta.link_header(req.path(), &query)))
+        .json(json!({
+            "data": viewer.present_all(&page.users),
+            "meta": meta,
+        })))
+}
+
+/// Locks a user's account until it is unlocked: login answers `401` and every
+/// session ends, but the account keeps its roles and status.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, or `404 Not Found` if the user does not exist.
+pub async fn lock_user(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    let user_id = path.to_string();
+    let mut work = UnitOfWork::begin(pool.get_ref()).await?;
+    if !locks::lock_user(&mut work, &user_id).await? {
+        return Err(AppError::NotFound("user"));
+    }
+    audit::record(&mut *work, &user_id, audit::ACCOUNT_LOCKED, audit::actor(&caller).as_deref(), None).await?;
+    work.commit().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Unlocks a user's account, so they can log in again.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, or `404 Not Found` if the user does not exist.
+pub async fn unlock_user(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    let user_id = path.to_string();
+    let mut work = UnitOfWork::begin(pool.get_ref()).await?;
+    if !locks::unlock_user(&mut work, &user_id).await? {
+        return Err(AppError::NotFound("user"));
+    }
+    audit::record(&mut *work, &user_id, audit::ACCOUNT_UNLOCKED, audit::actor(&caller).as_deref(), None).await?;
+    work.commit().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Requires a user to reset their password: every session ends, a reset link is
+/// sent, and logins with the current password are refused until the reset.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, or `404 Not Found` if the user does not exist.
+pub async fn force_password_reset(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    let user_id = path.to_string();
+    let mut work = UnitOfWork::begin(pool.get_ref()).await?;
+    let email = password_reset::require_reset(&mut work, &user_id)
+        .await?
+        .ok_or(AppError::NotFound("user"))?;
+    sessions::revoke_sessions(&mut work, &user_id).await?;
+    audit::record(&mut *work, &user_id, audit::PASSWORD_RESET_FORCED, audit::actor(&caller).as_deref(), None).await?;
+    work.commit().await?;
+
+    password_reset::request_reset(pool.get_ref(), &email).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Turns off two-factor authentication for a user who lost both their authenticator
+/// and their recovery codes. Their secret and recovery codes are deleted, so they log
+/// in with their password alone until they enroll again.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, or `404 Not Found` if the user has not enrolled.
+pub async fn reset_mfa(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    let user_id = path.to_string();
+    let mut work = UnitOfWork::begin(pool.get_ref()).await?;
+    if !mfa::reset(&mut work, &user_id).await? {
+        return Err(AppError::NotFound("mfa_enrollment"));
+    }
+    audit::record(&mut *work, &user_id, audit::MFA_RESET, audit::actor(&caller).as_deref(), None).await?;
+    work.commit().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Flags a user as a honeytoken, a decoy account whose every login or access by id
+/// raises an alert, or clears the flag.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, or `404 Not Found` if the user does not exist.
+pub async fn set_honeytoken(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>, input: web::Json<HoneytokenFlag>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    if !honeytokens::set_honeytoken(pool.get_ref(), &path.to_string(), input.honeytoken, audit::actor(&caller).as_deref()).await? {
+        return Err(AppError::NotFound("user"));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Sets or removes the manager a user reports to.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, `400 Bad Request` if the manager does not
+///   exist or reports to the user, or `404 Not Found` if the user does not exist.
+pub async fn set_manager(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>, input: web::Json<ManagerAssignment>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    let manager_id = input.manager_id.as_ref().map(UserId::to_string);
+    match org_chart::set_manager(pool.get_ref(), &path.to_string(), manager_id.as_deref(), audit::actor(&caller).as_deref()).await? {
+        ManagerChange::Changed => Ok(HttpResponse::NoContent().finish()),
+        ManagerChange::UnknownUser => Err(AppError::NotFound("user")),
+        ManagerChange::UnknownManager => Err(AppError::validation("`manager_id` is not a known user.")),
+        ManagerChange::Cycle => Err(AppError::validation("The manager reports to this user, directly or not.")),
+    }
+}
+
+/// Sets or removes the plan of a user, which takes precedence over the plan of
+/// their tenant. Tokens issued afterwards carry the plan and its entitlements.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, `400 Bad Request` if the plan is not
+///   listed in `PLAN_ENTITLEMENTS`, or `404 Not Found` if the user does not exist.
+pub async fn set_user_plan(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>, input: web::Json<PlanAssignment>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    PlanCatalog::from_env().check(input.plan.as_deref()).map_err(AppError::validation)?;
+    if !plans::set_user_plan(pool.get_ref(), &path.to_string(), input.plan.as_deref(), audit::actor(&caller).as_deref()).await? {
+        return Err(AppError::NotFound("user"));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Sets or removes the plan of a tenant, inherited by its users that have no plan of their own.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, or `400 Bad Request` if the plan is not
+///   listed in `PLAN_ENTITLEMENTS`.
+pub async fn set_tenant_plan(pool: web::Data<Pool<Mssql>>, path: web::Path<String>, input: web::Json<PlanAssignment>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    PlanCatalog::from_env().check(input.plan.as_deref()).map_err(AppError::validation)?;
+    plans::set_tenant_plan(pool.get_ref(), &path, input.plan.as_deref(), audit::actor(&caller).as_deref()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Disables an active user, applying the configured [`CascadePolicy`] to their
+/// sessions and roles.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, `200 OK` with the sessions and roles
+///   that would be removed for a [`DryRun`], or `404 Not Found` if no active user has that id.
+pub async fn disable_user(pool: web::Data<Pool<Mssql>>, policy: web::Data<CascadePolicy>, path: web::Path<UserId>, query: web::Query<DryRun>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    let user_id = path.to_string();
+    let effect = deactivation::disable_user(pool.get_ref(), policy.get_ref(), &user_id, audit::actor(&caller).as_deref(), query.dry_run)
+        .await?
+        .ok_or(AppError::NotFound("active_user"))?;
+    if !query.dry_run {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+    Ok(HttpResponse::Ok().json(json!({ "user_id": user_id, "dry_run": true, "cascade": policy.steps(), "effect": effect })))
+}
+
+/// Creates a user on behalf of an administrator.
+///
+/// Unlike the public `/create_user` endpoint, this reports when the email is
+/// already registered. In multi-tenant mode the user joins the tenant named by the
+/// `X-Tenant-Id` header.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `201 Created` with the id of the new user, `403 Forbidden` if the
+///   tenant has reached its user cap, or `409 Conflict` if the email or `user_id` is taken.
+pub async fn create_user(req: HttpRequest, users: web::Data<Arc<dyn UserRepository>>, new_user: ValidJson<CreateUserRequest>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    let tenant_id = request_tenant(&req)?;
+    quotas::enforce(users.get_ref().as_ref(), tenant_id.as_deref(), 1).await?;
+    let id = users
+        .create(&new_user, None, ACTIVE, tenant_id.as_deref(), audit::actor(&caller).as_deref())
+        .await
+        .map_err(user_conflict)?;
+    Ok(HttpResponse::Created().json(json!({ "id": id })))
+}
+
+/// Lists the self-registered users waiting for approval.
+pub async fn list_approvals(pool: web::Data<Pool<Mssql>>) -> Result<HttpResponse, AppError> {
+    let users = approvals::pending_users(pool.get_ref()).await?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+/// Approves a pending user, who can log in from then on.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, or `404 Not Found` if no pending user has that id.
+pub async fn approve_user(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    let user_id = path.to_string();
+    let mut work = UnitOfWork::begin(pool.get_ref()).await?;
+    if !approvals::decide(&mut work, &user_id, true).await? {
+        return Err(AppError::NotFound("pending_user"));
+    }
+    audit::record(&mut *work, &user_id, audit::REGISTRATION_APPROVED, audit::actor(&caller).as_deref(), None).await?;
+    work.commit().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Rejects a pending user, who will never be able to log in.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, or `404 Not Found` if no pending user has that id.
+pub async fn reject_user(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    let user_id = path.to_string();
+    let mut work = UnitOfWork::begin(pool.get_ref()).await?;
+    if !approvals::decide(&mut work, &user_id, false).await? {
+        return Err(AppError::NotFound("pending_user"));
+    }
+    audit::record(&mut *work, &user_id, audit::REGISTRATION_REJECTED, audit::actor(&caller).as_deref(), None).await?;
+    work.commit().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,140 @@
+use serde_json::json;
+use sqlx::{FromRow, Mssql, Pool, Transaction};
+use std::env;
+use crate::birthdate::Birthdate;
+use crate::models::{Email, Phone, User, UserId, UserResponse};
+use crate::outbox;
+use crate::timestamp::Timestamp;
+
+/// Status of a user who can log in.
+pub const ACTIVE: &str = "active";
+
+/// Status of a self-registered user waiting for an administrator's decision.
+pub const PENDING_APPROVAL: &str = "pending_approval";
+
+/// Status of a self-registered user turned down by an administrator.
+pub const REJECTED: &str = "rejected";
+
+/// Status of a user whose `expires_at` has passed, set by
+/// [`expire_accounts`](crate::expiration::expire_accounts).
+pub const EXPIRED: &str = "expired";
+
+/// Status of a user disabled by an administrator through
+/// [`disable_user`](crate::deactivation::disable_user).
+pub const DISABLED: &str = "disabled";
+
+/// Returns `true` when self-registered users need an administrator's approval before
+/// they can log in, as set by the `REGISTRATION_APPROVAL` environment variable.
+pub fn approval_required() -> bool {
+    env::var("REGISTRATION_APPROVAL")
+        .map(|value| matches!(value.to_lowercase().as_str(), "true" | "1" | "yes"))
+        .unwrap_or(false)
+}
+
+/// Returns the status given to users registering themselves: [`PENDING_APPROVAL`]
+/// when [`approval_required`], [`ACTIVE`] otherwise.
+pub fn registration_status() -> &'static str {
+    if approval_required() {
+        PENDING_APPROVAL
+    } else {
+        ACTIVE
+    }
+}
+
+/// The contact details of a user returned by [`decide`].
+#[derive(Debug, FromRow)]
+struct DecidedUser {
+    email: String,
+    name: String,
+}
+
+/// Lists the users waiting for approval.
+pub async fn pending_users(pool: &Pool<Mssql>) -> Result<Vec<UserResponse>, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        r#"
+        SELECT
+            CAST(id AS VARCHAR(36))         AS "id!",
+            UserId                          AS "user_id!: UserId",
+            Name                            AS "name!",
+            LastName                        AS "last_name!",
+            Email                           AS "email!: Email",
+            Age                             AS "age?",
+            Phone                           AS "phone?: Phone",
+            Address                         AS "address?",
+            CONVERT(VARCHAR, BirthDate, 23) AS "birthdate!: Birthdate",
+            PlaceBirth                      AS "place_birth?",
+            OrgUnit                         AS "org_unit?",
+            CONVERT(VARCHAR(33), ExpiresAt, 126) AS "expires_at?: Timestamp",
+            CONVERT(VARCHAR(33), CreatedAt, 126) AS "created_at?: Timestamp",
+            CONVERT(VARCHAR(33), UpdatedAt, 126) AS "updated_at?: Timestamp",
+            CONVERT(VARCHAR(33), LastSeenAt, 126) AS "last_seen_at?: Timestamp",
+            CONVERT(VARCHAR(33), DeletedAt, 126) AS "deleted_at?: Timestamp",
+            [Plan]                          AS "plan?"
+        FROM [users]
+        WHERE Status = @p1 AND DeletedAt IS NULL
+        ORDER BY Name, LastName
+        "#,
+        PENDING_APPROVAL
+    )
+    .fetch_all(pool)
+    .await
+    .map(|users| users.into_iter().map(UserResponse::from).collect())
+}
+
+/// Approves or rejects a pending user as part of `tx`.
+///
+/// The decision is recorded together with a `user.approved` or `user.rejected`
+/// outbox event, whose payload carries the user's email and name so a notifier
+/// can tell them the outcome.
+///
+/// # Returns
+///
+/// * `Result<bool, sqlx::Error>` - `Ok(false)` if no pending user has that id.
+pub async fn decide(tx: &mut Transaction<'_, Mssql>, user_id: &str, approve: bool) -> Result<bool, sqlx::Error> {
+    let (status, event_type) = if approve {
+        (ACTIVE, outbox::USER_APPROVED)
+    } else {
+        (REJECTED, outbox::USER_REJECTED)
+    };
+
+    let decided = sqlx::query_as!(
+        DecidedUser,
+        r#"
+        UPDATE [users]
+        SET Status = @p2
+        OUTPUT
+            INSERTED.Email AS "email!",
+            INSERTED.Name  AS "name!"
+        WHERE id = @p1 AND Status = @p3
+        "#,
+        user_id,
+        status,
+        PENDING_APPROVAL
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let user = match decided {
+        Some(user) => user,
+        None => return Ok(false),
+    };
+
+    let payload = json!({ "id": user_id, "email": user.email, "name": user.name });
+    outbox::enqueue(tx, event_type, user_id, &payload).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registration_status_follows_env() {
+        env::set_var("REGISTRATION_APPROVAL", "yes");
+        assert_eq!(registration_status(), PENDING_APPROVAL);
+
+        env::remove_var("REGISTRATION_APPROVAL");
+        assert_eq!(registration_status(), ACTIVE);
+    }
+}
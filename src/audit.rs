@@ -0,0 +1,438 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, Mssql, Pool};
+use crate::error::AppError;
+use crate::extractors::{AuthenticatedUser, UserId};
+use crate::pagination::{PageMeta, DEFAULT_PER_PAGE, MAX_PER_PAGE};
+use crate::timestamp::Timestamp;
+
+/// Action recorded when a user logs in.
+pub const LOGIN: &str = "login";
+
+/// Action recorded when a user's account is created.
+pub const ACCOUNT_CREATED: &str = "account.created";
+
+/// Action recorded when a user's account is deleted.
+pub const ACCOUNT_DELETED: &str = "account.deleted";
+
+/// Action recorded when a deleted account is restored.
+pub const ACCOUNT_RESTORED: &str = "account.restored";
+
+/// Action recorded when a user's profile fields are changed; the details hold the
+/// names of the `changed` fields and their old and new values in `changes`.
+pub const PROFILE_UPDATED: &str = "profile.updated";
+
+/// Action recorded when an account is disabled because its `expires_at` passed.
+pub const ACCOUNT_EXPIRED: &str = "account.expired";
+
+/// Action recorded when an administrator replaces a user's roles.
+pub const ROLES_CHANGED: &str = "admin.roles_changed";
+
+/// Action recorded when an administrator adds a role to a user as part of a bulk assignment.
+pub const ROLE_ASSIGNED: &str = "admin.role_assigned";
+
+/// Action recorded when an administrator sets or removes a user's manager.
+pub const MANAGER_CHANGED: &str = "admin.manager_changed";
+
+/// Action recorded when an administrator sets or removes the plan of a user.
+pub const PLAN_CHANGED: &str = "admin.plan_changed";
+
+/// Action recorded when an administrator revokes a user's sessions.
+pub const SESSIONS_REVOKED: &str = "admin.sessions_revoked";
+
+/// Action recorded when an administrator disables a user's account.
+pub const ACCOUNT_DISABLED: &str = "admin.account_disabled";
+
+/// Action recorded when an administrator locks a user's account.
+pub const ACCOUNT_LOCKED: &str = "admin.account_locked";
+
+/// Action recorded when an administrator unlocks a user's account.
+pub const ACCOUNT_UNLOCKED: &str = "admin.account_unlocked";
+
+/// Action recorded when an administrator requires a user to reset their password.
+pub const PASSWORD_RESET_FORCED: &str = "admin.password_reset_forced";
+
+/// Action recorded when an administrator approves a pending registration.
+pub const REGISTRATION_APPROVED: &str = "admin.registration_approved";
+
+/// Action recorded when an administrator rejects a pending registration.
+pub const REGISTRATION_REJECTED: &str = "admin.registration_rejected";
+
+/// Action recorded when a user opens the link sent to verify their email address.
+pub const EMAIL_VERIFIED: &str = "account.email_verified";
+
+/// Action recorded when a user sets a new password through a reset link.
+pub const PASSWORD_RESET: &str = "account.password_reset";
+
+/// Action recorded when a user approves a device through the device authorization
+/// flow; the details hold its `client_id`.
+pub const DEVICE_AUTHORIZED: &str = "account.device_authorized";
+
+/// Action recorded when a login is refused because the password is older than
+/// `PASSWORD_MAX_AGE_DAYS`, which sends the user a reset link.
+pub const PASSWORD_EXPIRED: &str = "security.password_expired";
+
+/// Action recorded when failed logins lock out the email of an account, with the
+/// number of `failures` and the `ip` of the last one.
+pub const ACCOUNT_LOCKED_OUT: &str = "security.account_locked_out";
+
+/// Action recorded when a user confirms their enrollment in two-factor authentication.
+pub const MFA_ENABLED: &str = "account.mfa_enabled";
+
+/// Action recorded when an administrator turns off a user's two-factor authentication.
+pub const MFA_RESET: &str = "admin.mfa_reset";
+
+/// Action recorded when a user's account is linked to an identity provider, with the
+/// `provider`.
+pub const OAUTH_LINKED: &str = "account.oauth_linked";
+
+/// Action recorded when an administrator flags or unflags a user as a honeytoken.
+pub const HONEYTOKEN_CHANGED: &str = "admin.honeytoken_changed";
+
+/// Action recorded when a honeytoken is authenticated as or accessed, with the kind
+/// of `access` and the `trace_id` of the request.
+pub const HONEYTOKEN_TRIPPED: &str = "security.honeytoken_tripped";
+
+/// Action recorded when a refresh token that was already rotated is presented again,
+/// which revokes every refresh token of its family.
+pub const REFRESH_TOKEN_REUSED: &str = "security.refresh_token_reused";
+
+/// Records something that happened to a user's account in the `[audit_log]` table.
+///
+/// Pass a transaction as `executor` to record the entry only if the change it
+/// describes commits.
+///
+/// # Arguments
+///
+/// * `executor` - A pool or an open transaction.
+/// * `user_id` - The user whose account the entry is about.
+/// * `action` - What happened, e.g. [`LOGIN`].
+/// * `actor_id` - Who did it, or `None` for the system and unauthenticated requests.
+/// * `details` - Structured context, such as the names of the changed fields.
+pub async fn record<'c, E>(executor: E, user_id: &str, action: &str, actor_id: Option<&str>, details: Option<&serde_json::Value>) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'c, Database = Mssql>,
+{
+    sqlx::query!(
+        "INSERT INTO [audit_log] (UserId, Action, ActorId, Details) VALUES (@p1, @p2, @p3, @p4)",
+        user_id,
+        action,
+        actor_id,
+        details.map(|details| details.to_string())
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Returns the id of the caller, used as the actor of audit entries.
+pub fn actor(caller: &Option<AuthenticatedUser>) -> Option<String> {
+    caller.as_ref().map(|caller| caller.id().to_string())
+}
+
+/// Query parameters of the timeline endpoint: `?page=2&per_page=50`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TimelineQuery {
+    /// 1-based page number.
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+}
+
+impl TimelineQuery {
+    /// Returns the requested page, starting at 1.
+    pub fn page(&self) -> u32 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    /// Returns the requested page size, clamped to `1..=MAX_PER_PAGE`.
+    pub fn per_page(&self) -> u32 {
+        self.per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE)
+    }
+
+    /// Returns the number of entries to skip.
+    pub fn offset(&self) -> u32 {
+        (self.page() - 1).saturating_mul(self.per_page())
+    }
+}
+
+/// One entry of a user's timeline.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimelineEntry {
+    pub id: i64,
+    pub action: String,
+    /// The user or administrator who acted, `None` for the system.
+    pub actor_id: Option<String>,
+    pub details: Option<serde_json::Value>,
+    /// When it happened, as an RFC 3339 UTC timestamp.
+    pub created_at: Timestamp,
+}
+
+/// An `[audit_log]` row, with the details still serialized.
+#[derive(Debug, FromRow)]
+struct AuditRecord {
+    id: i64,
+    action: String,
+    actor_id: Option<String>,
+    details: Option<String>,
+    created_at: Timestamp,
+}
+
+impl From<AuditRecord> for TimelineEntry {
+    fn from(record: AuditRecord) -> Self {
+        TimelineEntry {
+            id: record.id,
+            action: record.action,
+            actor_id: record.actor_id,
+            details: record.details.and_then(|details| serde_json::from_str(&details).ok()),
+            created_at: record.created_at,
+        }
+    }
+}
+
+/// Returns a page of a user's timeline, newest first, with the total number of entries.
+pub async fn timeline(pool: &Pool<Mssql>, user_id: &str, query: &TimelineQuery) -> Result<(Vec<TimelineEntry>, i64), sqlx::Error> {
+    let records = sqlx::query_as!(
+        AuditRecord,
+        r#"
+        SELECT
+            id                                   AS "id!",
+            Action                               AS "action!",
+            ActorId                              AS "actor_id?",
+            Details                              AS "details?",
+            CONVERT(VARCHAR(33), CreatedAt, 126) AS "created_at!: Timestamp"
+        FROM [audit_log]
+        WHERE UserId = @p1
+        ORDER BY CreatedAt DESC, id DESC
+        OFFSET @p2 ROWS FETCH NEXT @p3 ROWS ONLY
+        "#,
+        user_id,
+        query.offset() as i32,
+        query.per_page() as i32
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT_BIG(*) AS "total!: i64" FROM [audit_log] WHERE UserId = @p1"#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok((records.into_iter().map(TimelineEntry::from).collect(), total))
+}
+
+/// Returns a user's activity timeline: logins, profile edits and the actions
+/// administrators took on the account, newest first.
+///
+/// Entries outlive the account, so the timeline of a deleted user can still be read.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `path` - The id of the user.
+/// * `query` - The page (`page`, `per_page`).
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - A JSON object with the entries in `data` and the page,
+///   page size and total count in `meta`.
+pub async fn get_user_timeline(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>, query: web::Query<TimelineQuery>) -> Result<HttpResponse, AppError> {
+    let (entries, total) = timeline(pool.get_ref(), &path.to_string(), &query).await?;
+    let meta = PageMeta::for_page(query.page(), query.per_page(), total);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "data": entries,
+        "meta": meta,
+    })))
+}
+
+/// Query parameters of the audit log search: `?action=profile.updated&since=2024-01-01T00:00:00Z`.
+///
+/// Every filter is optional and they combine with AND.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditQuery {
+    /// The user the entries are about.
+    pub user_id: Option<String>,
+    /// The user or administrator who acted.
+    pub actor_id: Option<String>,
+    /// The exact action, e.g. [`ACCOUNT_DELETED`].
+    pub action: Option<String>,
+    /// Only entries written at or after this instant.
+    pub since: Option<Timestamp>,
+    /// Only entries written before this instant.
+    pub until: Option<Timestamp>,
+    /// 1-based page number.
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+}
+
+impl AuditQuery {
+    /// Returns the page requested, with the same defaults and limits as the timeline.
+    pub fn paging(&self) -> TimelineQuery {
+        TimelineQuery { page: self.page, per_page: self.per_page }
+    }
+}
+
+/// One entry of the audit log, with the user it is about.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub user_id: String,
+    #[serde(flatten)]
+    pub entry: TimelineEntry,
+}
+
+/// An `[audit_log]` row with its user, the details still serialized.
+#[derive(Debug, FromRow)]
+struct AuditLogRecord {
+    user_id: String,
+    id: i64,
+    action: String,
+    actor_id: Option<String>,
+    details: Option<String>,
+    created_at: Timestamp,
+}
+
+impl From<AuditLogRecord> for AuditEntry {
+    fn from(record: AuditLogRecord) -> Self {
+        AuditEntry {
+            user_id: record.user_id,
+            entry: TimelineEntry::from(AuditRecord {
+                id: record.id,
+                action: record.action,
+                actor_id: record.actor_id,
+                details: record.details,
+                created_at: record.created_at,
+            }),
+        }
+    }
+}
+
+/// Returns a page of the audit entries matching `query`, newest first, with the total
+/// number of matches.
+pub async fn search(pool: &Pool<Mssql>, query: &AuditQuery) -> Result<(Vec<AuditEntry>, i64), sqlx::Error> {
+    let paging = query.paging();
+    let records = sqlx::query_as!(
+        AuditLogRecord,
+        r#"
+        SELECT
+            UserId                               AS "user_id!",
+            id                                   AS "id!",
+            Action                               AS "action!",
+            ActorId                              AS "actor_id?",
+            Details                              AS "details?",
+            CONVERT(VARCHAR(33), CreatedAt, 126) AS "created_at!: Timestamp"
+        FROM [audit_log]
+        WHERE (@p1 IS NULL OR UserId = @p1)
+            AND (@p2 IS NULL OR ActorId = @p2)
+            AND (@p3 IS NULL OR Action = @p3)
+            AND (@p4 IS NULL OR CreatedAt >= @p4)
+            AND (@p5 IS NULL OR CreatedAt < @p5)
+        ORDER BY CreatedAt DESC, id DESC
+        OFFSET @p6 ROWS FETCH NEXT @p7 ROWS ONLY
+        "#,
+        query.user_id,
+        query.actor_id,
+        query.action,
+        query.since,
+        query.until,
+        paging.offset() as i32,
+        paging.per_page() as i32
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let total = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT_BIG(*) AS "total!: i64"
+        FROM [audit_log]
+        WHERE (@p1 IS NULL OR UserId = @p1)
+            AND (@p2 IS NULL OR ActorId = @p2)
+            AND (@p3 IS NULL OR Action = @p3)
+            AND (@p4 IS NULL OR CreatedAt >= @p4)
+            AND (@p5 IS NULL OR CreatedAt < @p5)
+        "#,
+        query.user_id,
+        query.actor_id,
+        query.action,
+        query.since,
+        query.until
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok((records.into_iter().map(AuditEntry::from).collect(), total))
+}
+
+/// Searches the audit log of every user, for administrators: every account creation,
+/// profile change and deletion with who made it and, for changes, the old and new
+/// values of the fields.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `query` - The filters (`user_id`, `actor_id`, `action`, `since`, `until`) and the page
+///   (`page`, `per_page`).
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - A JSON object with the entries in `data` and the page,
+///   page size and total count in `meta`.
+pub async fn get_audit_log(pool: web::Data<Pool<Mssql>>, query: web::Query<AuditQuery>) -> Result<HttpResponse, AppError> {
+    let (entries, total) = search(pool.get_ref(), &query).await?;
+    let paging = query.paging();
+    let meta = PageMeta::for_page(paging.page(), paging.per_page(), total);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "data": entries,
+        "meta": meta,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_defaults_and_clamping() {
+        let query = TimelineQuery::default();
+        assert_eq!((query.page(), query.per_page(), query.offset()), (1, DEFAULT_PER_PAGE, 0));
+
+        let query = TimelineQuery { page: Some(2), per_page: Some(1000) };
+        assert_eq!((query.page(), query.per_page(), query.offset()), (2, MAX_PER_PAGE, MAX_PER_PAGE));
+    }
+
+    #[test]
+    fn test_entry_parses_details() {
+        let entry = TimelineEntry::from(AuditRecord {
+            id: 1,
+            action: PROFILE_UPDATED.to_string(),
+            actor_id: None,
+            details: Some(r#"{"changed":["email"]}"#.to_string()),
+            created_at: "2024-01-31T18:00:00Z".parse().unwrap(),
+        });
+
+        assert_eq!(entry.details.unwrap()["changed"][0], "email");
+    }
+
+    #[test]
+    fn test_audit_entry_serializes_flat() {
+        let query: AuditQuery = serde_urlencoded::from_str("action=account.deleted&since=2024-01-01T00:00:00Z&per_page=5").unwrap();
+        assert_eq!(query.action.as_deref(), Some(ACCOUNT_DELETED));
+        assert_eq!(query.paging().per_page(), 5);
+
+        let entry = AuditEntry::from(AuditLogRecord {
+            user_id: "813b6b04-dfbb-4eed-b820-2372216a2367".to_string(),
+            id: 3,
+            action: ACCOUNT_DELETED.to_string(),
+            actor_id: Some("admin".to_string()),
+            details: None,
+            created_at: "2024-01-31T18:00:00Z".parse().unwrap(),
+        });
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["user_id"], "813b6b04-dfbb-4eed-b820-2372216a2367");
+        assert_eq!(json["action"], ACCOUNT_DELETED);
+        assert_eq!(json["actor_id"], "admin");
+    }
+}
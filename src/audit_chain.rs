@@ -0,0 +1,375 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, Mssql, Pool};
+use std::time::Duration;
+use crate::error::AppError;
+use crate::leader::JobLease;
+
+/// Number of rows sealed or verified per query.
+const BATCH_SIZE: i32 = 1_000;
+
+/// A sealed `[audit_log]` row, with everything its hash covers.
+#[derive(Debug, Clone, FromRow)]
+pub struct ChainedEntry {
+    /// Position in the chain, starting at 1 and without gaps.
+    pub chain_seq: i64,
+    pub id: i64,
+    pub user_id: String,
+    pub action: String,
+    pub actor_id: Option<String>,
+    pub details: Option<String>,
+    pub created_at: String,
+    /// Hash of the entry at `chain_seq - 1`, `None` for the first entry.
+    pub prev_hash: Option<String>,
+    pub hash: String,
+}
+
+/// An `[audit_log]` row waiting to be added to the chain.
+#[derive(Debug, FromRow)]
+struct PendingEntry {
+    id: i64,
+    user_id: String,
+    action: String,
+    actor_id: Option<String>,
+    details: Option<String>,
+    created_at: String,
+}
+
+/// The head of the chain, which the next sealed entry links to.
+#[derive(Debug, FromRow)]
+struct ChainHead {
+    chain_seq: i64,
+    hash: String,
+}
+
+impl ChainedEntry {
+    /// Recomputes the hex SHA-256 of the entry from its columns.
+    ///
+    /// The hash covers the entry's position, its columns and the hash of the previous
+    /// entry, so editing, removing or reordering any sealed entry changes every hash after it.
+    pub fn expected_hash(&self) -> String {
+        let canonical = json!([
+            self.chain_seq,
+            self.id,
+            self.user_id,
+            self.action,
+            self.actor_id,
+            self.details,
+            self.created_at,
+            self.prev_hash,
+        ]);
+        hex::encode(Sha256::digest(canonical.to_string().as_bytes()))
+    }
+}
+
+impl PendingEntry {
+    /// Places the entry at `chain_seq`, after the entry whose hash is `prev_hash`.
+    fn seal(self, chain_seq: i64, prev_hash: Option<String>) -> ChainedEntry {
+        let mut entry = ChainedEntry {
+            chain_seq,
+            id: self.id,
+            user_id: self.user_id,
+            action: self.action,
+            actor_id: self.actor_id,
+            details: self.details,
+            created_at: self.created_at,
+            prev_hash,
+            hash: String::new(),
+        };
+        entry.hash = entry.expected_hash();
+        entry
+    }
+}
+
+/// Adds the audit entries written since the last run to the hash chain, oldest first.
+///
+/// Entries are sealed by this single step rather than when they are recorded, so
+/// recording stays a plain insert inside the caller's transaction. The head of the
+/// chain is locked while sealing, so several instances can run it concurrently.
+///
+/// # Returns
+///
+/// * `Result<usize, sqlx::Error>` - The number of entries sealed.
+pub async fn seal_pending(pool: &Pool<Mssql>) -> Result<usize, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let head = sqlx::query_as!(
+        ChainHead,
+        r#"
+        SELECT TOP 1 ChainSeq AS "chain_seq!", Hash AS "hash!"
+        FROM [audit_log] WITH (UPDLOCK, HOLDLOCK)
+        WHERE ChainSeq IS NOT NULL
+        ORDER BY ChainSeq DESC
+        "#
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+
+    // READPAST skips rows of transactions that have not committed yet; they are sealed on a later run.
+    let pending = sqlx::query_as!(
+        PendingEntry,
+        r#"
+        SELECT TOP (@p1)
+            id                                   AS "id!",
+            UserId                               AS "user_id!",
+            Action                               AS "action!",
+            ActorId                              AS "actor_id?",
+            Details                              AS "details?",
+            CONVERT(VARCHAR(33), CreatedAt, 127) AS "created_at!"
+        FROM [audit_log] WITH (UPDLOCK, READPAST)
+        WHERE ChainSeq IS NULL
+        ORDER BY id
+        "#,
+        BATCH_SIZE
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    let sealed = pending.len();
+    let (mut chain_seq, mut prev_hash) = match head {
+        Some(head) => (head.chain_seq, Some(head.hash)),
+        None => (0, None),
+    };
+    for entry in pending {
+        chain_seq += 1;
+        let entry = entry.seal(chain_seq, prev_hash.take());
+        sqlx::query!(
+            "UPDATE [audit_log] SET ChainSeq = @p2, PrevHash = @p3, Hash = @p4 WHERE id = @p1",
+            entry.id,
+            entry.chain_seq,
+            entry.prev_hash.as_deref(),
+            &entry.hash
+        )
+        .execute(&mut tx)
+        .await?;
+        prev_hash = Some(entry.hash);
+    }
+
+    tx.commit().await?;
+    Ok(sealed)
+}
+
+/// Spawns the background task sealing new audit entries every `interval`, on the
+/// replica holding the `audit_sealer` [`JobLease`].
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use safe_user::audit_chain::spawn_sealer;
+/// use safe_user::config::AppConfig;
+/// use safe_user::db::DbPool;
+///
+/// #[actix_web::main]
+/// async fn main() {
+///     let config = AppConfig::load().unwrap();
+///     let pool = DbPool::connect(&config.database_url).await.unwrap().pool;
+///     spawn_sealer(pool, Duration::from_secs(5));
+/// }
+/// ```
+pub fn spawn_sealer(pool: Pool<Mssql>, interval: Duration) {
+    let lease = JobLease::new(pool.clone(), "audit_sealer", interval);
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if !lease.acquire().await {
+                continue;
+            }
+            if let Err(e) = seal_pending(&pool).await {
+                eprintln!("Error sealing audit log: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Where and why the chain stopped verifying.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BrokenLink {
+    /// Position at which the problem was found.
+    pub chain_seq: i64,
+    /// The `[audit_log]` id of the offending entry, `None` if it is missing.
+    pub id: Option<i64>,
+    pub reason: String,
+}
+
+/// Outcome of [`verify_chain`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainReport {
+    /// `true` if every sealed entry matches its hash and links to the one before it.
+    pub valid: bool,
+    /// Number of entries checked before stopping.
+    pub verified: i64,
+    /// Hash of the last verified entry. Recording it elsewhere (a ticket, a signed
+    /// log) lets later checks also detect entries removed from the end of the chain.
+    pub head_hash: Option<String>,
+    /// Entries not sealed yet, which the check does not cover.
+    pub unsealed: i64,
+    pub broken: Option<BrokenLink>,
+}
+
+/// Walks a run of sealed entries, continuing from `verified` entries ending in `prev_hash`.
+///
+/// # Returns
+///
+/// * `Result<(), BrokenLink>` - The first entry that is out of place or whose hash does not match.
+pub fn verify_entries(entries: &[ChainedEntry], verified: &mut i64, prev_hash: &mut Option<String>) -> Result<(), BrokenLink> {
+    for entry in entries {
+        let expected_seq = *verified + 1;
+        let broken = |id, reason: &str| BrokenLink { chain_seq: expected_seq, id, reason: reason.to_string() };
+
+        if entry.chain_seq != expected_seq {
+            return Err(broken(None, "entry is missing from the chain"));
+        }
+        if entry.prev_hash != *prev_hash {
+            return Err(broken(Some(entry.id), "entry does not link to the previous entry"));
+        }
+        if entry.hash != entry.expected_hash() {
+            return Err(broken(Some(entry.id), "entry was modified after it was sealed"));
+        }
+
+        *verified += 1;
+        *prev_hash = Some(entry.hash.clone());
+    }
+    Ok(())
+}
+
+/// Recomputes the whole chain and reports the first entry that was modified, removed
+/// or reordered after it was sealed.
+pub async fn verify_chain(pool: &Pool<Mssql>) -> Result<ChainReport, sqlx::Error> {
+    let mut verified = 0;
+    let mut prev_hash = None;
+    let mut broken = None;
+
+    loop {
+        let entries = sqlx::query_as!(
+            ChainedEntry,
+            r#"
+            SELECT TOP (@p2)
+                ChainSeq                             AS "chain_seq!",
+                id                                   AS "id!",
+                UserId                               AS "user_id!",
+                Action                               AS "action!",
+                ActorId                              AS "actor_id?",
+                Details                              AS "details?",
+                CONVERT(VARCHAR(33), CreatedAt, 127) AS "created_at!",
+                PrevHash                             AS "prev_hash?",
+                Hash                                 AS "hash!"
+            FROM [audit_log]
+            WHERE ChainSeq > @p1
+            ORDER BY ChainSeq
+            "#,
+            verified,
+            BATCH_SIZE
+        )
+        .fetch_all(pool)
+        .await?;
+
+        if let Err(link) = verify_entries(&entries, &mut verified, &mut prev_hash) {
+            broken = Some(link);
+            break;
+        }
+        if entries.len() < BATCH_SIZE as usize {
+            break;
+        }
+    }
+
+    let unsealed = sqlx::query_scalar!(
+        r#"SELECT COUNT_BIG(*) AS "unsealed!: i64" FROM [audit_log] WHERE ChainSeq IS NULL"#
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(ChainReport {
+        valid: broken.is_none(),
+        verified,
+        head_hash: prev_hash,
+        unsealed,
+        broken,
+    })
+}
+
+/// Verifies the integrity of the audit log.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `200 OK` with the [`ChainReport`] when the chain is
+///   intact, `409 Conflict` with the report when tampering was detected.
+pub async fn verify_audit_log(pool: web::Data<Pool<Mssql>>) -> Result<HttpResponse, AppError> {
+    let report = verify_chain(pool.get_ref()).await?;
+    if report.valid {
+        Ok(HttpResponse::Ok().json(report))
+    } else {
+        Ok(HttpResponse::Conflict().json(report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(len: i64) -> Vec<ChainedEntry> {
+        let mut entries: Vec<ChainedEntry> = Vec::new();
+        for chain_seq in 1..=len {
+            let prev_hash = entries.last().map(|entry| entry.hash.clone());
+            let entry = PendingEntry {
+                id: chain_seq + 100,
+                user_id: "813B6B04-DFBB-4EED-B820-2372216A2367".to_string(),
+                action: "login".to_string(),
+                actor_id: None,
+                details: None,
+                created_at: "2024-01-31T18:00:00".to_string(),
+            };
+            entries.push(entry.seal(chain_seq, prev_hash));
+        }
+        entries
+    }
+
+    fn verify(entries: &[ChainedEntry]) -> Result<(), BrokenLink> {
+        verify_entries(entries, &mut 0, &mut None)
+    }
+
+    #[test]
+    fn test_intact_chain_verifies() {
+        let entries = chain(3);
+        let (mut verified, mut prev_hash) = (0, None);
+
+        verify_entries(&entries, &mut verified, &mut prev_hash).unwrap();
+        assert_eq!(verified, 3);
+        assert_eq!(prev_hash.as_deref(), Some(entries[2].hash.as_str()));
+    }
+
+    #[test]
+    fn test_modified_entry_is_detected() {
+        let mut entries = chain(3);
+        entries[1].action = "profile.updated".to_string();
+
+        let broken = verify(&entries).unwrap_err();
+        assert_eq!((broken.chain_seq, broken.id), (2, Some(102)));
+    }
+
+    #[test]
+    fn test_removed_entry_is_detected() {
+        let mut entries = chain(3);
+        entries.remove(1);
+
+        let broken = verify(&entries).unwrap_err();
+        assert_eq!((broken.chain_seq, broken.id), (2, None));
+    }
+
+    #[test]
+    fn test_rehashed_entry_breaks_the_next_link() {
+        let mut entries = chain(3);
+        entries[1].action = "profile.updated".to_string();
+        entries[1].hash = entries[1].expected_hash();
+
+        let broken = verify(&entries).unwrap_err();
+        assert_eq!((broken.chain_seq, broken.id), (3, Some(103)));
+    }
+}
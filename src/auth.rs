@@ -1,15 +1,179 @@
-use actix_web::{dev::ServiceRequest, Error};
+use actix_web::{dev::ServiceRequest, http::header::USER_AGENT, web, Error, HttpMessage, HttpRequest};
 use actix_web_httpauth::extractors::bearer::{BearerAuth};
 use jsonwebtoken::{DecodingKey, EncodingKey, Validation, Header, encode, decode};
 use chrono::{Utc, Duration};
+use rand::RngCore;
+use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, Mssql, Pool};
 use std::env;
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use uuid::Uuid;
+use utoipa::ToSchema;
+use crate::audit;
+use crate::error::AppError;
+use crate::metrics::{self, Metrics};
+use crate::presence::Presence;
+use crate::read_only::ReadOnlyMode;
+use crate::sessions;
+use crate::tenancy::TenantKeys;
+use crate::tokens::{provider_or_default, TokenError, TokenProvider};
 
 /// This module provides JWT generation and validation functionalities.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    /// The issuer, only set on tokens signed with a tenant key in multi-tenant mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    /// The subject's token version when the token was issued; tokens with an older
+    /// version than the one stored for the user have been revoked.
+    #[serde(default)]
+    pub ver: i32,
+    /// Names of the subject's roles when the token was issued, checked by
+    /// [`RequireRole`](crate::rbac::RequireRole).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    /// Unique id of the token, used to revoke it on its own through `/logout`. Tokens
+    /// issued before the claim was introduced have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    /// Id of the login session the token was issued for, shared by all the refresh
+    /// tokens rotated from that login. Tokens not issued by `/login` or
+    /// `/refresh_token` have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    /// The subject's billing plan when the token was issued, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plan: Option<String>,
+    /// Entitlements granted by the plan in `PLAN_ENTITLEMENTS`, for downstream services
+    /// to gate features on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ent: Vec<String>,
+}
+
+impl Claims {
+    /// Creates the claims of a new access token for `sub`, expiring after [`ACCESS_TOKEN_TTL_SECONDS`].
+    pub fn new(sub: &str, version: i32, roles: &[String]) -> Self {
+        let expiration = Utc::now() + Duration::seconds(ACCESS_TOKEN_TTL_SECONDS);
+        Claims {
+            sub: sub.to_owned(),
+            exp: expiration.timestamp() as usize,
+            iss: None,
+            ver: version,
+            roles: roles.to_vec(),
+            jti: Some(Uuid::new_v4().to_string()),
+            sid: None,
+            plan: None,
+            ent: Vec::new(),
+        }
+    }
+}
+
+/// Lifetime of access tokens, in seconds.
+pub const ACCESS_TOKEN_TTL_SECONDS: i64 = 24 * 60 * 60;
+
+/// Tokens returned to clients by `/login` and `/refresh_token`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    /// Lifetime of the access token, in seconds.
+    pub expires_in: i64,
+}
+
+impl TokenResponse {
+    /// Creates a new bearer `TokenResponse`.
+    pub fn bearer(access_token: String, refresh_token: String) -> Self {
+        TokenResponse {
+            access_token,
+            refresh_token,
+            token_type: "Bearer".to_string(),
+            expires_in: ACCESS_TOKEN_TTL_SECONDS,
+        }
+    }
+}
+
+/// Body of a `/refresh_token` request.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+/// The owner of a refresh token that has just been rotated, and its replacement.
+#[derive(Debug)]
+pub struct RotatedRefreshToken {
+    pub user_id: String,
+    /// The tenant the token was issued for, in multi-tenant mode.
+    pub tenant_id: Option<String>,
+    pub refresh_token: String,
+    /// The login session both tokens belong to.
+    pub session_id: String,
+}
+
+#[derive(Debug, FromRow)]
+struct RefreshTokenOwner {
+    user_id: String,
+    tenant_id: Option<String>,
+    family_id: String,
+    user_agent: Option<String>,
+}
+
+/// Longest `User-Agent` stored with a refresh token.
+const MAX_USER_AGENT_LENGTH: usize = 512;
+
+/// The HS256 signing secret, set once at startup from [`crate::config::AppConfig`].
+static JWT_SECRET: OnceLock<SecretString> = OnceLock::new();
+
+/// Sets the secret used to sign and validate JWTs.
+///
+/// Only the first call has an effect; the secret cannot change while the server runs.
+pub fn set_jwt_secret(secret: SecretString) {
+    let _ = JWT_SECRET.set(secret);
+}
+
+/// Returns the signing secret.
+///
+/// When none was set, a random secret is generated for this process, so tokens can
+/// never be signed with a guessable default.
+fn jwt_secret() -> &'static SecretString {
+    JWT_SECRET.get_or_init(|| {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        SecretString::new(hex::encode(bytes))
+    })
+}
+
+/// The secret the keys of [`derived_key`] come from, set once at startup from
+/// [`crate::config::AppConfig`].
+static DERIVATION_SECRET: OnceLock<SecretString> = OnceLock::new();
+
+/// Sets the secret [`derived_key`] derives its keys from.
+///
+/// Only the first call has an effect; the secret cannot change while the server runs.
+pub fn set_token_derivation_secret(secret: SecretString) {
+    let _ = DERIVATION_SECRET.set(secret);
+}
+
+/// Returns a key for signing tokens other than access tokens, derived from the token
+/// derivation secret and `purpose` so that a token of one kind is never accepted as
+/// another.
+///
+/// Unlike the JWT secret there is no random fallback: the tokens signed with these
+/// keys must stay valid across restarts and replicas.
+///
+/// # Panics
+///
+/// Panics when [`set_token_derivation_secret`] has not been called.
+pub(crate) fn derived_key(purpose: &str) -> Vec<u8> {
+    #[cfg(test)]
+    let _ = DERIVATION_SECRET.set(SecretString::new("test-only token derivation secret".to_string()));
+    let secret = DERIVATION_SECRET.get().expect("the token derivation secret is set at startup");
+    Sha256::digest(format!("{}|{}", purpose, secret.expose_secret()).as_bytes()).to_vec()
 }
 
 /// Generates a JWT for the given subject.
@@ -21,16 +185,29 @@ pub struct Claims {
 /// # Returns
 ///
 /// * `Result<String, jsonwebtoken::errors::Error>` - A result containing the generated JWT as a string or an error.
-pub fn generate_jwt(sub: &String) -> Result<String, jsonwebtoken::errors::Error> {
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "secret".into());
-    let expiration = Utc::now() + Duration::hours(24);
+pub fn generate_jwt(sub: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    generate_versioned_jwt(sub, 0, &[])
+}
 
-    let claims = Claims {
-        sub: sub.to_owned(),
-        exp: expiration.timestamp() as usize,
-    };
+/// Generates a JWT for the given subject bound to one of its token versions.
+///
+/// # Arguments
+///
+/// * `sub` - The subject for which the JWT is generated.
+/// * `version` - The subject's current token version (see [`crate::sessions`]).
+/// * `roles` - The names of the subject's roles, sent in the `roles` claim.
+///
+/// # Returns
+///
+/// * `Result<String, jsonwebtoken::errors::Error>` - A result containing the generated JWT as a string or an error.
+pub fn generate_versioned_jwt(sub: &str, version: i32, roles: &[String]) -> Result<String, jsonwebtoken::errors::Error> {
+    encode_jwt(&Claims::new(sub, version, roles))
+}
 
-    encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_ref()))
+/// Signs `claims` as an HS256 JWT with the configured secret.
+pub fn encode_jwt(claims: &Claims) -> Result<String, jsonwebtoken::errors::Error> {
+    let secret = jwt_secret();
+    encode(&Header::default(), claims, &EncodingKey::from_secret(secret.expose_secret().as_bytes()))
 }
 
 /// Validates a given JWT and returns the claims if the token is valid.
@@ -43,15 +220,248 @@ pub fn generate_jwt(sub: &String) -> Result<String, jsonwebtoken::errors::Error>
 ///
 /// * `Result<Claims, jsonwebtoken::errors::Error>` - A result containing the claims if the token is valid or an error.
 pub fn validate_jwt(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "secret".into());
+    let secret = jwt_secret();
     let validation = Validation::default();
 
-    let token_data = decode::<Claims>(token, &DecodingKey::from_secret(secret.as_ref()), &validation)?;
+    let token_data = decode::<Claims>(token, &DecodingKey::from_secret(secret.expose_secret().as_bytes()), &validation)?;
     Ok(token_data.claims)
 }
 
+/// Returns the refresh token lifetime in days, from `REFRESH_TOKEN_TTL_DAYS` (default 30).
+fn refresh_token_ttl_days() -> i32 {
+    env::var("REFRESH_TOKEN_TTL_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(30)
+}
+
+/// Returns the hex-encoded SHA-256 hash under which a refresh token is stored.
+///
+/// Only hashes are persisted, so a leaked `[refresh_tokens]` table cannot be replayed.
+pub fn hash_refresh_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn new_refresh_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Returns `false` if `REFRESH_TOKEN_BIND_IP` disables binding refresh tokens to the client's network.
+fn bind_refresh_tokens_to_ip() -> bool {
+    env::var("REFRESH_TOKEN_BIND_IP")
+        .map(|value| matches!(value.to_lowercase().as_str(), "true" | "1" | "yes"))
+        .unwrap_or(true)
+}
+
+/// Returns the network a client address belongs to: its /24 for IPv4, its /64 for IPv6.
+///
+/// Binding to the network rather than the exact address lets clients keep their
+/// refresh token across address changes within the same network.
+fn network_of(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            format!("{:x}:{:x}:{:x}:{:x}::/64", segments[0], segments[1], segments[2], segments[3])
+        }
+    }
+}
+
+/// Returns the hashed fingerprint of the client that sent a request.
+///
+/// The fingerprint combines the client's network (unless `REFRESH_TOKEN_BIND_IP`
+/// is disabled) with its `User-Agent`. Refresh tokens are bound to the fingerprint
+/// of the client they were issued to, so a stolen token cannot be replayed from
+/// another context.
+pub fn client_fingerprint(req: &HttpRequest) -> String {
+    let network = match req.peer_addr() {
+        Some(addr) if bind_refresh_tokens_to_ip() => network_of(addr.ip()),
+        _ => String::new(),
+    };
+    let user_agent = req
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+
+    format!("{:x}", Sha256::digest(format!("{}|{}", network, user_agent).as_bytes()))
+}
+
+/// Returns the `User-Agent` of the client, cut to [`MAX_USER_AGENT_LENGTH`] characters,
+/// which is stored with its refresh tokens to tell sessions apart.
+pub fn client_user_agent(req: &HttpRequest) -> Option<String> {
+    let user_agent = req.headers().get(USER_AGENT)?.to_str().ok()?.trim();
+    if user_agent.is_empty() {
+        return None;
+    }
+    Some(user_agent.chars().take(MAX_USER_AGENT_LENGTH).collect())
+}
+
+async fn store_refresh_token<'c, E>(executor: E, token: &str, user_id: &str, tenant_id: Option<&str>, fingerprint: &str, family_id: &str, user_agent: Option<&str>) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'c, Database = Mssql>,
+{
+    sqlx::query!(
+        r#"
+        INSERT INTO [refresh_tokens] (TokenHash, UserId, TenantId, Fingerprint, ExpiresAt, FamilyId, UserAgent)
+        VALUES (@p1, @p2, @p3, @p4, DATEADD(day, @p5, SYSUTCDATETIME()), @p6, @p7)
+        "#,
+        hash_refresh_token(token),
+        user_id,
+        tenant_id,
+        fingerprint,
+        refresh_token_ttl_days(),
+        family_id,
+        user_agent
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+/// Generates and persists the first refresh token of a new login session.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `user_id` - The subject the refresh token is issued to.
+/// * `tenant_id` - The tenant the token belongs to, in multi-tenant mode.
+/// * `fingerprint` - The [`client_fingerprint`] of the client the token is issued to.
+/// * `session_id` - The id of the new session, which becomes the token's family.
+/// * `user_agent` - The [`client_user_agent`] of the client.
+///
+/// # Returns
+///
+/// * `Result<String, sqlx::Error>` - The opaque refresh token to hand to the client.
+pub async fn generate_refresh_token(pool: &Pool<Mssql>, user_id: &str, tenant_id: Option<&str>, fingerprint: &str, session_id: &str, user_agent: Option<&str>) -> Result<String, sqlx::Error> {
+    let token = new_refresh_token();
+    store_refresh_token(pool, &token, user_id, tenant_id, fingerprint, session_id, user_agent).await?;
+    Ok(token)
+}
+
+/// Exchanges a refresh token for a new one.
+///
+/// The presented token is revoked, linked to its replacement and the replacement
+/// stored in a single transaction, so each refresh token can be used only once.
+/// Tokens are only accepted from a client with the fingerprint they were issued to.
+///
+/// Presenting a token that was already rotated means it was copied: either the
+/// client or whoever stole it holds a newer token of the same family. Every
+/// refresh token of the family is then revoked and a
+/// [`REFRESH_TOKEN_REUSED`](audit::REFRESH_TOKEN_REUSED) entry is recorded, forcing the
+/// user to log in again.
+///
+/// # Returns
+///
+/// * `Result<Option<RotatedRefreshToken>, sqlx::Error>` - `Ok(None)` if the token is
+///   unknown, expired, already revoked, reused or presented by a different client.
+pub async fn rotate_refresh_token(pool: &Pool<Mssql>, token: &str, fingerprint: &str) -> Result<Option<RotatedRefreshToken>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let refresh_token = new_refresh_token();
+
+    let owner = sqlx::query_as!(
+        RefreshTokenOwner,
+        r#"
+        UPDATE [refresh_tokens]
+        SET RevokedAt = SYSUTCDATETIME(), ReplacedBy = @p3
+        OUTPUT
+            INSERTED.UserId   AS "user_id!",
+            INSERTED.TenantId AS "tenant_id?",
+            INSERTED.FamilyId AS "family_id!",
+            INSERTED.UserAgent AS "user_agent?"
+        WHERE TokenHash = @p1
+            AND Fingerprint = @p2
+            AND RevokedAt IS NULL
+            AND ExpiresAt > SYSUTCDATETIME()
+        "#,
+        hash_refresh_token(token),
+        fingerprint,
+        hash_refresh_token(&refresh_token)
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+
+    let owner = match owner {
+        Some(owner) => owner,
+        None => {
+            revoke_family_if_reused(&mut tx, token).await?;
+            tx.commit().await?;
+            return Ok(None);
+        }
+    };
+
+    store_refresh_token(&mut tx, &refresh_token, &owner.user_id, owner.tenant_id.as_deref(), fingerprint, &owner.family_id, owner.user_agent.as_deref()).await?;
+    tx.commit().await?;
+
+    Ok(Some(RotatedRefreshToken {
+        user_id: owner.user_id,
+        tenant_id: owner.tenant_id,
+        refresh_token,
+        session_id: owner.family_id,
+    }))
+}
+
+/// Revokes the family of `token` if it is a refresh token that was already rotated.
+async fn revoke_family_if_reused(tx: &mut sqlx::Transaction<'_, Mssql>, token: &str) -> Result<(), sqlx::Error> {
+    let reused = sqlx::query_as!(
+        RefreshTokenOwner,
+        r#"
+        SELECT UserId AS "user_id!", TenantId AS "tenant_id?", FamilyId AS "family_id!", UserAgent AS "user_agent?"
+        FROM [refresh_tokens]
+        WHERE TokenHash = @p1 AND ReplacedBy IS NOT NULL
+        "#,
+        hash_refresh_token(token)
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let reused = match reused {
+        Some(reused) => reused,
+        None => return Ok(()),
+    };
+
+    let revoked = sqlx::query!(
+        "UPDATE [refresh_tokens] SET RevokedAt = SYSUTCDATETIME() WHERE FamilyId = @p1 AND RevokedAt IS NULL",
+        &reused.family_id
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    eprintln!("Refresh token reuse detected for user {}, revoked token family {}", reused.user_id, reused.family_id);
+    let details = serde_json::json!({ "family_id": reused.family_id, "revoked_tokens": revoked });
+    audit::record(&mut *tx, &reused.user_id, audit::REFRESH_TOKEN_REUSED, None, Some(&details)).await
+}
+
+/// Revokes a single refresh token, returning `Ok(false)` if it was not active.
+pub async fn revoke_refresh_token(pool: &Pool<Mssql>, token: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE [refresh_tokens] SET RevokedAt = SYSUTCDATETIME() WHERE TokenHash = @p1 AND RevokedAt IS NULL",
+        hash_refresh_token(token)
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
 /// Middleware function to validate JWT in incoming requests.
 ///
+/// When [`TenantKeys`] are registered as app data (multi-tenant mode), the token is
+/// verified with the tenant key selected by its `kid` header instead of `JWT_SECRET`.
+///
+/// Tokens issued before the subject's sessions were revoked, and tokens revoked on
+/// their own through `/logout`, are rejected.
+///
+/// On success the decoded [`Claims`] are stored in the request extensions so later
+/// middleware (such as [`RequirePermission`](crate::rbac::RequirePermission)) and handlers can identify the caller.
+///
 /// # Arguments
 ///
 /// * `req` - The incoming service request.
@@ -63,12 +473,39 @@ pub fn validate_jwt(token: &str) -> Result<Claims, jsonwebtoken::errors::Error>
 pub async fn jwt_validator(req: ServiceRequest,credentials: BearerAuth) -> Result<ServiceRequest, (Error, ServiceRequest)> {
     let token = credentials.token();
 
-    match validate_jwt(token) {
-        Ok(_claims) => {
+    let pool = req.app_data::<web::Data<Pool<Mssql>>>().map(|pool| pool.get_ref());
+
+    let result = match req.app_data::<web::Data<TenantKeys>>() {
+        Some(keys) => keys.validate(token, pool).await.map_err(TokenError::from),
+        None => provider_or_default(req.app_data::<web::Data<Arc<dyn TokenProvider>>>()).verify(token),
+    };
+
+    let result = match (result, pool) {
+        (Ok(claims), Some(pool)) => match sessions::is_current(pool, &claims).await {
+            Ok(true) => Ok(claims),
+            Ok(false) => Err((metrics::JWT_REVOKED, AppError::auth("token_revoked", "Token has been revoked.").into())),
+            Err(e) => {
+                eprintln!("Error checking token version: {:?}", e);
+                Err((metrics::JWT_ERROR, AppError::auth("invalid_token", "Invalid token.").into()))
+            }
+        },
+        (result, _) => result.map_err(|_| (metrics::JWT_INVALID, AppError::auth("invalid_token", "Invalid token.").into())),
+    };
+
+    match result {
+        Ok(claims) => {
+            if let (Some(presence), Some(pool)) = (req.app_data::<web::Data<Presence>>(), pool) {
+                let read_only = req.app_data::<web::Data<ReadOnlyMode>>().map(|mode| mode.get_ref());
+                presence.touch(pool, &claims.sub, read_only);
+            }
+            req.extensions_mut().insert(claims);
             Ok(req)
         }
-        Err(_) => {
-            Err((actix_web::error::ErrorUnauthorized("Invalid token"), req))
+        Err((reason, e)) => {
+            if let Some(metrics) = req.app_data::<web::Data<Metrics>>() {
+                metrics.observe_jwt_failure(reason);
+            }
+            Err((e, req))
         }
     }
 }
@@ -80,17 +517,70 @@ mod tests {
     #[test]
     fn test_generate_jwt() {
         //Check that it doesn't fail and generate a token
-        let token = generate_jwt(&"tester".to_string()).expect("Failed to generate JWT");
+        let token = generate_jwt("tester").expect("Failed to generate JWT");
         assert!(!token.is_empty(), "Token should not be empty");
     }
 
     #[test]
     fn test_validate_jwt_valid() {
-        let token = generate_jwt(&"tester".to_string()).unwrap();
+        let token = generate_jwt("tester").unwrap();
         let claims = validate_jwt(&token).expect("Failed to validate JWT");
         assert_eq!(claims.sub, "tester");
     }
 
+    #[test]
+    fn test_roles_claim_roundtrip() {
+        let token = generate_versioned_jwt("tester", 3, &["admin".to_string()]).unwrap();
+        let claims = validate_jwt(&token).unwrap();
+        assert_eq!(claims.ver, 3);
+        assert_eq!(claims.roles, vec!["admin"]);
+
+        let claims = validate_jwt(&generate_jwt("tester").unwrap()).unwrap();
+        assert!(claims.roles.is_empty());
+    }
+
+    #[test]
+    fn test_tokens_get_unique_ids() {
+        let first = validate_jwt(&generate_jwt("tester").unwrap()).unwrap();
+        let second = validate_jwt(&generate_jwt("tester").unwrap()).unwrap();
+
+        assert!(first.jti.is_some());
+        assert_ne!(first.jti, second.jti);
+    }
+
+    #[test]
+    fn test_refresh_tokens_are_random_and_hashed() {
+        let first = new_refresh_token();
+        let second = new_refresh_token();
+
+        assert_eq!(first.len(), 64);
+        assert_ne!(first, second, "Refresh tokens must not repeat");
+        assert_ne!(hash_refresh_token(&first), first, "Only the hash may be stored");
+        assert_eq!(hash_refresh_token(&first), hash_refresh_token(&first));
+    }
+
+    #[test]
+    fn test_network_of_masks_host_bits() {
+        assert_eq!(network_of("203.0.113.45".parse().unwrap()), "203.0.113.0/24");
+        assert_eq!(network_of("2001:db8:1:2:3:4:5:6".parse().unwrap()), "2001:db8:1:2::/64");
+    }
+
+    #[test]
+    fn test_client_fingerprint_depends_on_network_and_user_agent() {
+        let fingerprint = |addr: &str, user_agent: &str| {
+            let req = actix_web::test::TestRequest::default()
+                .peer_addr(addr.parse().unwrap())
+                .insert_header((USER_AGENT, user_agent))
+                .to_http_request();
+            client_fingerprint(&req)
+        };
+
+        let original = fingerprint("203.0.113.45:5000", "app/1.0");
+        assert_eq!(original, fingerprint("203.0.113.99:6000", "app/1.0"));
+        assert_ne!(original, fingerprint("198.51.100.45:5000", "app/1.0"));
+        assert_ne!(original, fingerprint("203.0.113.45:5000", "curl/8.0"));
+    }
+
     #[test]
     fn test_validate_jwt_invalid() {
         // A completely invalid token
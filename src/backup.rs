@@ -0,0 +1,485 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Executor, Mssql, Pool, Transaction};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use crate::error::AppError;
+use crate::timestamp::Timestamp;
+
+/// Version of the backup format, bumped when frames change shape.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Directory [`FileStorage`] keeps backups in when `BACKUP_DIR` is not set.
+pub const DEFAULT_BACKUP_DIR: &str = "backups";
+
+/// Rows encrypted together in one frame of a backup.
+const ROWS_PER_FRAME: usize = 500;
+
+/// Tables that are never backed up: the migrations table describes the schema, which
+/// the target database must already have, and job leases only mean something to the
+/// replicas holding them.
+const EXCLUDED_TABLES: [&str; 2] = ["_migrations", "job_leases"];
+
+const NONCE_LENGTH: usize = 12;
+
+/// Somewhere backups are written to and read from, such as a directory or a bucket.
+#[async_trait]
+pub trait BackupStorage: Send + Sync {
+    /// Starts writing the backup `name`. The backup must not exist yet, and is only
+    /// visible once the writer is [finished](BackupWriter::finish).
+    async fn create(&self, name: &str) -> io::Result<Box<dyn BackupWriter>>;
+
+    /// Returns the content of the backup `name`.
+    async fn read(&self, name: &str) -> io::Result<Vec<u8>>;
+}
+
+/// A backup being written by a [`BackupStorage`].
+#[async_trait]
+pub trait BackupWriter: Send {
+    /// Appends `chunk` to the backup.
+    async fn write(&mut self, chunk: &[u8]) -> io::Result<()>;
+
+    /// Makes the backup visible under its name. A writer dropped before leaves no backup.
+    async fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// [`BackupStorage`] keeping each backup as a file of a directory.
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    dir: PathBuf,
+}
+
+impl FileStorage {
+    /// Keeps backups in `dir`, created on the first backup.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileStorage { dir: dir.into() }
+    }
+
+    /// Keeps backups in `BACKUP_DIR`, or [`DEFAULT_BACKUP_DIR`].
+    pub fn from_env() -> Self {
+        FileStorage::new(env::var("BACKUP_DIR").unwrap_or_else(|_| DEFAULT_BACKUP_DIR.to_string()))
+    }
+
+    /// Returns the path of the backup `name`, which must be a plain file name.
+    fn path(&self, name: &str) -> io::Result<PathBuf> {
+        if Path::new(name).file_name().and_then(|file_name| file_name.to_str()) != Some(name) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("`{}` is not a valid backup name", name)));
+        }
+        Ok(self.dir.join(name))
+    }
+}
+
+#[async_trait]
+impl BackupStorage for FileStorage {
+    async fn create(&self, name: &str) -> io::Result<Box<dyn BackupWriter>> {
+        let path = self.path(name)?;
+        if path.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path.display())));
+        }
+        fs::create_dir_all(&self.dir)?;
+
+        let partial = path.with_extension("partial");
+        let file = OpenOptions::new().write(true).create(true).truncate(true).open(&partial)?;
+        Ok(Box::new(FileWriter { file, partial, path }))
+    }
+
+    async fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(name)?)
+    }
+}
+
+/// Writes a backup to a `.partial` file, renamed once complete.
+struct FileWriter {
+    file: File,
+    partial: PathBuf,
+    path: PathBuf,
+}
+
+#[async_trait]
+impl BackupWriter for FileWriter {
+    async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.file.write_all(chunk)
+    }
+
+    async fn finish(self: Box<Self>) -> io::Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.partial, &self.path)
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        // Once finished, the partial file has been renamed and this does nothing.
+        let _ = fs::remove_file(&self.partial);
+    }
+}
+
+/// The 256-bit key backups are encrypted with, using AES-256-GCM.
+///
+/// A backup is a sequence of frames, one per line, each encrypted with a fresh nonce
+/// and authenticated together with its position. Frames that are altered, reordered
+/// or cut from the end make the restore fail rather than load part of a backup.
+pub struct BackupKey {
+    cipher: Aes256Gcm,
+}
+
+impl BackupKey {
+    /// Uses a 32-byte key.
+    pub fn new(key: &[u8]) -> Result<Self, AppError> {
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| AppError::Internal("The backup encryption key must be 32 bytes.".to_string()))?;
+        Ok(BackupKey { cipher })
+    }
+
+    /// Reads the hex-encoded key from `BACKUP_ENCRYPTION_KEY`.
+    pub fn from_env() -> Result<Self, AppError> {
+        let key = env::var("BACKUP_ENCRYPTION_KEY").map_err(|_| AppError::Internal("BACKUP_ENCRYPTION_KEY is required for backups.".to_string()))?;
+        let key = hex::decode(key.trim()).map_err(|_| AppError::Internal("BACKUP_ENCRYPTION_KEY must be hex-encoded.".to_string()))?;
+        BackupKey::new(&key)
+    }
+
+    /// Encrypts `frame` as the `index`-th line of a backup.
+    fn seal(&self, index: u64, frame: &Frame) -> Result<Vec<u8>, AppError> {
+        let plaintext = serde_json::to_vec(frame).map_err(|e| AppError::Internal(e.to_string()))?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: &plaintext, aad: &frame_aad(index) })
+            .map_err(|_| AppError::Internal("Failed to encrypt the backup.".to_string()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        let mut line = STANDARD.encode(sealed).into_bytes();
+        line.push(b'\n');
+        Ok(line)
+    }
+
+    /// Decrypts the `index`-th line of a backup.
+    fn open(&self, index: u64, line: &[u8]) -> Result<Frame, AppError> {
+        let corrupt = || AppError::Internal(format!("Frame {} of the backup is corrupt or was encrypted with another key.", index));
+        let sealed = STANDARD.decode(line).map_err(|_| corrupt())?;
+        if sealed.len() < NONCE_LENGTH {
+            return Err(corrupt());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &frame_aad(index) })
+            .map_err(|_| corrupt())?;
+        serde_json::from_slice(&plaintext).map_err(|_| corrupt())
+    }
+}
+
+fn frame_aad(index: u64) -> Vec<u8> {
+    format!("safe_user-backup:{}:{}", FORMAT_VERSION, index).into_bytes()
+}
+
+/// A line of a backup.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Frame {
+    /// The first frame: the schema the backup was taken from and the tables it holds.
+    Header { format_version: u32, schema_version: i64, created_at: Timestamp, tables: Vec<String> },
+    /// Rows of a table, each as a JSON object keyed by column name.
+    Rows { table: String, rows: Vec<Value> },
+    /// The last frame, whose absence shows that the backup was cut short.
+    End { rows: u64 },
+}
+
+/// The outcome of [`backup`] or [`restore`].
+#[derive(Debug, Serialize)]
+pub struct BackupReport {
+    pub name: String,
+    /// The last migration applied to the database the backup was taken from.
+    pub schema_version: i64,
+    /// When the backup was taken.
+    pub created_at: Timestamp,
+    /// The rows of each table written or loaded.
+    pub rows: BTreeMap<String, u64>,
+}
+
+/// Returns `name` quoted as a T-SQL identifier.
+fn quote(name: &str) -> String {
+    format!("[{}]", name.replace(']', "]]"))
+}
+
+/// Returns the type a column is read with from JSON by `OPENJSON`; the value is
+/// converted to the type of the column on insert.
+fn json_type(data_type: &str, precision: Option<i32>, scale: Option<i32>) -> String {
+    match data_type {
+        "char" | "varchar" | "nchar" | "nvarchar" | "text" | "ntext" | "xml" => "nvarchar(max)".to_string(),
+        "binary" | "varbinary" | "image" => "varbinary(max)".to_string(),
+        "decimal" | "numeric" => format!("decimal({},{})", precision.unwrap_or(18), scale.unwrap_or(0)),
+        other => other.to_string(),
+    }
+}
+
+/// Returns the last migration applied to the database.
+async fn current_schema_version(tx: &mut Transaction<'_, Mssql>) -> Result<i64, AppError> {
+    let version: Option<i64> = sqlx::query_scalar("SELECT MAX(Version) FROM [_migrations]")
+        .fetch_one(&mut *tx)
+        .await?;
+    version.ok_or_else(|| AppError::Internal("The database has no migrations applied; run `--migrate` first.".to_string()))
+}
+
+/// Returns the tables of the database, except [`EXCLUDED_TABLES`].
+async fn user_tables(tx: &mut Transaction<'_, Mssql>) -> Result<Vec<String>, AppError> {
+    let tables: Vec<String> = sqlx::query_scalar(
+        "SELECT TABLE_NAME FROM INFORMATION_SCHEMA.TABLES WHERE TABLE_SCHEMA = 'dbo' AND TABLE_TYPE = 'BASE TABLE' ORDER BY TABLE_NAME",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    Ok(tables.into_iter().filter(|table| !EXCLUDED_TABLES.contains(&table.as_str())).collect())
+}
+
+/// Copies every table of the database to the backup `name` of `storage`, encrypted with `key`.
+///
+/// The tables are read in one transaction holding a shared lock on each table until
+/// the end, so the backup is consistent: writes wait while it runs, reads do not.
+/// Rows are encrypted and written [`ROWS_PER_FRAME`] at a time, so tables are never
+/// held in memory.
+///
+/// # Returns
+///
+/// * `Result<BackupReport, AppError>` - The rows written for each table, or an error if the
+///   database or the storage failed, in which case no backup is left behind.
+pub async fn backup(pool: &Pool<Mssql>, storage: &dyn BackupStorage, key: &BackupKey, name: &str) -> Result<BackupReport, AppError> {
+    let storage_error = |e: io::Error| AppError::Internal(format!("Could not write backup `{}`: {}", name, e));
+    let mut tx = pool.begin().await?;
+    let schema_version = current_schema_version(&mut tx).await?;
+    let tables = user_tables(&mut tx).await?;
+    let created_at = Timestamp::now();
+
+    let mut writer = storage.create(name).await.map_err(storage_error)?;
+    let mut index = 0;
+    let header = Frame::Header { format_version: FORMAT_VERSION, schema_version, created_at, tables: tables.clone() };
+    writer.write(&key.seal(index, &header)?).await.map_err(storage_error)?;
+
+    let mut report = BTreeMap::new();
+    for table in &tables {
+        let sql = format!(
+            "SELECT (SELECT t.* FOR JSON PATH, WITHOUT_ARRAY_WRAPPER, INCLUDE_NULL_VALUES) AS [row] FROM [dbo].{} AS t WITH (TABLOCK, HOLDLOCK)",
+            quote(table)
+        );
+        let mut rows = sqlx::query_scalar::<_, String>(&sql).fetch(&mut tx);
+        let mut batch = Vec::with_capacity(ROWS_PER_FRAME);
+        let mut count = 0;
+
+        loop {
+            let row = rows.try_next().await?;
+            if let Some(row) = &row {
+                batch.push(serde_json::from_str(row).map_err(|e| AppError::Internal(e.to_string()))?);
+            }
+            if batch.len() == ROWS_PER_FRAME || (row.is_none() && !batch.is_empty()) {
+                count += batch.len() as u64;
+                index += 1;
+                let frame = Frame::Rows { table: table.clone(), rows: std::mem::take(&mut batch) };
+                writer.write(&key.seal(index, &frame)?).await.map_err(storage_error)?;
+            }
+            if row.is_none() {
+                break;
+            }
+        }
+        report.insert(table.clone(), count);
+    }
+
+    index += 1;
+    let end = Frame::End { rows: report.values().sum() };
+    writer.write(&key.seal(index, &end)?).await.map_err(storage_error)?;
+    writer.finish().await.map_err(storage_error)?;
+    tx.commit().await?;
+
+    Ok(BackupReport { name: name.to_string(), schema_version, created_at, rows: report })
+}
+
+/// Loads the backup `name` of `storage`, encrypted with `key`, into the database.
+///
+/// The database must be at the schema version the backup was taken from, as left by
+/// `--migrate`, and hold no users or audit entries yet; rows seeded by the migrations,
+/// such as the `admin` role, are replaced by those of the backup. Everything is loaded
+/// in one transaction, so a failed restore leaves the database as it was.
+///
+/// # Returns
+///
+/// * `Result<BackupReport, AppError>` - The rows loaded for each table, or an error if the
+///   backup is corrupt, was taken from another schema version, or the database is not empty.
+pub async fn restore(pool: &Pool<Mssql>, storage: &dyn BackupStorage, key: &BackupKey, name: &str) -> Result<BackupReport, AppError> {
+    let content = storage
+        .read(name)
+        .await
+        .map_err(|e| AppError::Internal(format!("Could not read backup `{}`: {}", name, e)))?;
+    let mut frames = content.split(|byte| *byte == b'\n').filter(|line| !line.is_empty()).enumerate().map(|(index, line)| key.open(index as u64, line));
+
+    let (schema_version, created_at, tables) = match frames.next().transpose()? {
+        Some(Frame::Header { format_version: FORMAT_VERSION, schema_version, created_at, tables }) => (schema_version, created_at, tables),
+        Some(Frame::Header { format_version, .. }) => return Err(AppError::Internal(format!("Backup format {} is not supported.", format_version))),
+        _ => return Err(AppError::Internal("The backup has no header.".to_string())),
+    };
+
+    let mut tx = pool.begin().await?;
+    let target_version = current_schema_version(&mut tx).await?;
+    if target_version != schema_version {
+        return Err(AppError::Internal(format!(
+            "The backup was taken at schema version {} but the database is at version {}; restore it into a database migrated to the same version.",
+            schema_version, target_version
+        )));
+    }
+    let target_tables = user_tables(&mut tx).await?;
+    if let Some(table) = tables.iter().find(|table| !target_tables.contains(table)) {
+        return Err(AppError::Internal(format!("Table `{}` of the backup does not exist in the database.", table)));
+    }
+    for table in ["users", "audit_log"] {
+        let used: i64 = sqlx::query_scalar(&format!("SELECT COUNT_BIG(*) FROM [dbo].{}", quote(table)))
+            .fetch_one(&mut tx)
+            .await?;
+        if used > 0 {
+            return Err(AppError::Internal(format!("The database already has rows in `{}`; restore into an empty database.", table)));
+        }
+    }
+
+    for table in &tables {
+        tx.execute(format!("ALTER TABLE [dbo].{} NOCHECK CONSTRAINT ALL", quote(table)).as_str()).await?;
+        let seeded: i64 = sqlx::query_scalar(&format!("SELECT COUNT_BIG(*) FROM [dbo].{}", quote(table)))
+            .fetch_one(&mut tx)
+            .await?;
+        if seeded > 0 {
+            tx.execute(format!("DELETE FROM [dbo].{}", quote(table)).as_str()).await?;
+        }
+    }
+
+    let mut report: BTreeMap<String, u64> = tables.iter().map(|table| (table.clone(), 0)).collect();
+    let mut current: Option<(String, String, bool)> = None;
+    let mut ended = false;
+    for frame in frames {
+        match frame? {
+            Frame::Rows { table, rows } if !ended && tables.contains(&table) => {
+                if current.as_ref().map(|(name, _, _)| name) != Some(&table) {
+                    if let Some((previous, _, true)) = &current {
+                        tx.execute(format!("SET IDENTITY_INSERT [dbo].{} OFF", quote(previous)).as_str()).await?;
+                    }
+                    let (insert, identity) = insert_statement(&mut tx, &table).await?;
+                    if identity {
+                        tx.execute(format!("SET IDENTITY_INSERT [dbo].{} ON", quote(&table)).as_str()).await?;
+                    }
+                    current = Some((table.clone(), insert, identity));
+                }
+                if let Some((_, insert, _)) = &current {
+                    let json = serde_json::to_string(&rows).map_err(|e| AppError::Internal(e.to_string()))?;
+                    sqlx::query(insert).bind(json).execute(&mut tx).await?;
+                }
+                *report.entry(table).or_default() += rows.len() as u64;
+            }
+            Frame::End { rows } if !ended && rows == report.values().sum::<u64>() => ended = true,
+            _ => return Err(AppError::Internal("The backup has unexpected frames.".to_string())),
+        }
+    }
+    if !ended {
+        return Err(AppError::Internal("The backup is incomplete.".to_string()));
+    }
+
+    if let Some((table, _, true)) = &current {
+        tx.execute(format!("SET IDENTITY_INSERT [dbo].{} OFF", quote(table)).as_str()).await?;
+    }
+    for table in &tables {
+        tx.execute(format!("ALTER TABLE [dbo].{} WITH CHECK CHECK CONSTRAINT ALL", quote(table)).as_str()).await?;
+    }
+    tx.commit().await?;
+
+    Ok(BackupReport { name: name.to_string(), schema_version, created_at, rows: report })
+}
+
+/// Returns the statement inserting a JSON array of rows into `table`, and whether
+/// the table has an identity column. Computed and `rowversion` columns are left to
+/// the database.
+async fn insert_statement(tx: &mut Transaction<'_, Mssql>, table: &str) -> Result<(String, bool), AppError> {
+    let columns: Vec<(String, String, Option<i32>, Option<i32>)> = sqlx::query_as(
+        r#"
+        SELECT COLUMN_NAME, DATA_TYPE, CAST(NUMERIC_PRECISION AS INT), CAST(NUMERIC_SCALE AS INT)
+        FROM INFORMATION_SCHEMA.COLUMNS
+        WHERE TABLE_SCHEMA = 'dbo' AND TABLE_NAME = @p1
+          AND COLUMNPROPERTY(OBJECT_ID(QUOTENAME(TABLE_SCHEMA) + '.' + QUOTENAME(TABLE_NAME)), COLUMN_NAME, 'IsComputed') = 0
+          AND DATA_TYPE NOT IN ('timestamp', 'rowversion')
+        ORDER BY ORDINAL_POSITION
+        "#,
+    )
+    .bind(table)
+    .fetch_all(&mut *tx)
+    .await?;
+    let identity: Option<i32> = sqlx::query_scalar("SELECT CAST(OBJECTPROPERTY(OBJECT_ID(@p1), 'TableHasIdentity') AS INT)")
+        .bind(format!("[dbo].{}", quote(table)))
+        .fetch_one(&mut *tx)
+        .await?;
+
+    let names: Vec<String> = columns.iter().map(|(name, ..)| quote(name)).collect();
+    let schema: Vec<String> = columns
+        .iter()
+        .map(|(name, data_type, precision, scale)| format!("{} {} '$.\"{}\"'", quote(name), json_type(data_type, *precision, *scale), name.replace('\'', "''")))
+        .collect();
+    let insert = format!(
+        "INSERT INTO [dbo].{} ({}) SELECT {} FROM OPENJSON(@p1) WITH ({})",
+        quote(table),
+        names.join(", "),
+        names.join(", "),
+        schema.join(", ")
+    );
+    Ok((insert, identity == Some(1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows() -> Frame {
+        Frame::Rows { table: "users".to_string(), rows: vec![serde_json::json!({ "id": "1", "Name": "Ana" })] }
+    }
+
+    #[test]
+    fn test_frames_are_bound_to_their_key_and_position() {
+        let key = BackupKey::new(&[7u8; 32]).unwrap();
+        let line = key.seal(1, &rows()).unwrap();
+        assert_eq!(line.last(), Some(&b'\n'));
+
+        let line = &line[..line.len() - 1];
+        assert_eq!(key.open(1, line).unwrap(), rows());
+        assert!(key.open(2, line).is_err());
+        assert!(BackupKey::new(&[8u8; 32]).unwrap().open(1, line).is_err());
+        assert!(key.open(1, b"not base64!").is_err());
+        assert!(BackupKey::new(&[7u8; 16]).is_err());
+    }
+
+    #[test]
+    fn test_json_types_read_every_column_losslessly() {
+        assert_eq!(json_type("varchar", None, None), "nvarchar(max)");
+        assert_eq!(json_type("varbinary", None, None), "varbinary(max)");
+        assert_eq!(json_type("decimal", Some(10), Some(2)), "decimal(10,2)");
+        assert_eq!(json_type("datetime2", None, None), "datetime2");
+        assert_eq!(quote("odd]name"), "[odd]]name]");
+    }
+
+    #[actix_web::test]
+    async fn test_file_storage_only_keeps_finished_backups() {
+        let storage = FileStorage::new(env::temp_dir().join(format!("safe_user_backups_{}", uuid::Uuid::new_v4())));
+        assert!(storage.create("../escape").await.is_err());
+
+        let writer = storage.create("dropped.bak").await.unwrap();
+        drop(writer);
+        assert!(storage.read("dropped.bak").await.is_err());
+
+        let mut writer = storage.create("nightly.bak").await.unwrap();
+        writer.write(b"one\n").await.unwrap();
+        writer.write(b"two\n").await.unwrap();
+        writer.finish().await.unwrap();
+        assert_eq!(storage.read("nightly.bak").await.unwrap(), b"one\ntwo\n");
+        assert!(storage.create("nightly.bak").await.is_err());
+
+        let _ = fs::remove_dir_all(&storage.dir);
+    }
+}
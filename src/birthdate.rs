@@ -0,0 +1,160 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::encode::IsNull;
+use sqlx::error::BoxDynError;
+use sqlx::mssql::{MssqlTypeInfo, MssqlValueRef};
+use sqlx::{Decode, Encode, Mssql, Type};
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use crate::validation::BIRTHDATE_FORMAT;
+
+/// Date-only formats accepted on input besides [`BIRTHDATE_FORMAT`]. Formats where
+/// the day and month could be swapped, such as `05/06/1992`, are not accepted.
+const DATE_FORMATS: [&str; 3] = ["%Y/%m/%d", "%d.%m.%Y", "%Y%m%d"];
+
+/// A calendar date of birth, without a time or time zone.
+///
+/// Birthdates are serialized as `YYYY-MM-DD`. On input they accept:
+///
+/// * `YYYY-MM-DD` (`1992-05-31`), `YYYY/MM/DD` (`1992/05/31`), `DD.MM.YYYY` (`31.05.1992`)
+///   and `YYYYMMDD` (`19920531`).
+/// * A date and time, with or without an offset, of which only the date is kept
+///   (`1992-05-31T00:00:00`).
+///
+/// Like [`Timestamp`](crate::timestamp::Timestamp), the `DATE` column is read as a string
+/// (`CONVERT(VARCHAR, BirthDate, 23)`) with a `"birthdate: Birthdate"` override, and
+/// bound as a `YYYY-MM-DD` string, since sqlx does not map dates for SQL Server.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::birthdate::Birthdate;
+///
+/// let birthdate: Birthdate = "31.05.1992".parse().unwrap();
+/// assert_eq!(birthdate.to_string(), "1992-05-31");
+/// assert!("31/05/1992".parse::<Birthdate>().is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Birthdate(pub NaiveDate);
+
+impl Birthdate {
+    /// Returns the wrapped `NaiveDate`.
+    pub fn into_inner(self) -> NaiveDate {
+        self.0
+    }
+
+    /// Returns `true` if the date is later than today in UTC.
+    pub fn is_in_future(&self) -> bool {
+        self.0 > Utc::now().date_naive()
+    }
+}
+
+impl FromStr for Birthdate {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if let Some(date) = std::iter::once(BIRTHDATE_FORMAT)
+            .chain(DATE_FORMATS)
+            .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+        {
+            return Ok(Birthdate(date));
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+            return Ok(Birthdate(time.date_naive()));
+        }
+        match NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f") {
+            Ok(time) => Ok(Birthdate(time.date())),
+            Err(_) => Err(format!("`{}` is not a date, expected YYYY-MM-DD such as 1992-05-31", value)),
+        }
+    }
+}
+
+impl fmt::Display for Birthdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.format(BIRTHDATE_FORMAT))
+    }
+}
+
+impl Deref for Birthdate {
+    type Target = NaiveDate;
+
+    fn deref(&self) -> &NaiveDate {
+        &self.0
+    }
+}
+
+impl From<NaiveDate> for Birthdate {
+    fn from(date: NaiveDate) -> Self {
+        Birthdate(date)
+    }
+}
+
+impl From<Birthdate> for NaiveDate {
+    fn from(birthdate: Birthdate) -> Self {
+        birthdate.0
+    }
+}
+
+impl Serialize for Birthdate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Birthdate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Type<Mssql> for Birthdate {
+    fn type_info() -> MssqlTypeInfo {
+        <String as Type<Mssql>>::type_info()
+    }
+
+    fn compatible(ty: &MssqlTypeInfo) -> bool {
+        <String as Type<Mssql>>::compatible(ty)
+    }
+}
+
+impl Encode<'_, Mssql> for Birthdate {
+    fn produces(&self) -> Option<MssqlTypeInfo> {
+        <String as Encode<Mssql>>::produces(&self.to_string())
+    }
+
+    fn encode_by_ref(&self, buf: &mut Vec<u8>) -> IsNull {
+        <String as Encode<Mssql>>::encode_by_ref(&self.to_string(), buf)
+    }
+}
+
+impl<'r> Decode<'r, Mssql> for Birthdate {
+    fn decode(value: MssqlValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(<String as Decode<Mssql>>::decode(value)?.parse()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_every_accepted_format() {
+        for input in ["1992-05-31", "1992/05/31", "31.05.1992", "19920531", "1992-05-31T23:30:00", "1992-05-31T23:30:00-05:00", " 1992-05-31 "] {
+            assert_eq!(input.parse::<Birthdate>().unwrap().to_string(), "1992-05-31", "{}", input);
+        }
+        for input in ["05/31/1992", "1992-02-30", "yesterday", ""] {
+            assert!(input.parse::<Birthdate>().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_serde_roundtrip_and_ordering() {
+        let birthdate: Birthdate = serde_json::from_str(r#""19920531""#).unwrap();
+        assert_eq!(serde_json::to_string(&birthdate).unwrap(), r#""1992-05-31""#);
+        assert!(birthdate < "2001-01-01".parse().unwrap());
+        assert!(!birthdate.is_in_future());
+    }
+}
@@ -0,0 +1,123 @@
+use async_trait::async_trait;
+use sha1::{Digest, Sha1};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Range endpoint queried when `BREACH_CHECK_URL` is not set.
+pub const DEFAULT_RANGE_URL: &str = "https://api.pwnedpasswords.com/range/";
+
+/// Number of hex characters of the SHA-1 hash sent to the range endpoint.
+const PREFIX_LENGTH: usize = 5;
+
+/// Time allowed for a range lookup, so a slow service does not stall registration.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Error returned when a breach lookup fails.
+pub type BreachError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A source of passwords known to have leaked in data breaches.
+#[async_trait]
+pub trait BreachCheck: Send + Sync {
+    /// Returns `true` if `password` has appeared in a breach.
+    async fn is_breached(&self, password: &str) -> Result<bool, BreachError>;
+}
+
+/// Looks passwords up in a Pwned Passwords range API using k-anonymity.
+///
+/// Only the first five hex characters of the password's SHA-1 hash leave the server.
+/// The service answers with every known hash suffix sharing that prefix, and the
+/// match is made locally, so neither the password nor its full hash is disclosed.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::breach::PwnedPasswords;
+///
+/// let (prefix, suffix) = PwnedPasswords::split("password");
+/// assert_eq!(prefix, "5BAA6");
+/// assert!(PwnedPasswords::contains(&format!("{}:3861493\r\n", suffix), &suffix));
+/// ```
+pub struct PwnedPasswords {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl PwnedPasswords {
+    /// Creates a checker querying `url` followed by the hash prefix.
+    pub fn new(url: String) -> Self {
+        PwnedPasswords {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+
+    /// Splits the uppercase hex SHA-1 of `password` into the prefix sent to the
+    /// service and the suffix matched locally.
+    pub fn split(password: &str) -> (String, String) {
+        let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = hash.split_at(PREFIX_LENGTH);
+        (prefix.to_string(), suffix.to_string())
+    }
+
+    /// Returns `true` if a range response lists `suffix` with a non-zero count.
+    ///
+    /// Padding entries, which have a count of `0`, never match.
+    pub fn contains(body: &str, suffix: &str) -> bool {
+        body.lines().any(|line| match line.trim().split_once(':') {
+            Some((candidate, count)) => candidate.eq_ignore_ascii_case(suffix) && count.trim().parse::<u64>().is_ok_and(|count| count > 0),
+            None => false,
+        })
+    }
+}
+
+#[async_trait]
+impl BreachCheck for PwnedPasswords {
+    async fn is_breached(&self, password: &str) -> Result<bool, BreachError> {
+        let (prefix, suffix) = PwnedPasswords::split(password);
+        let body = self.client
+            .get(format!("{}{}", self.url, prefix))
+            .header("Add-Padding", "true")
+            .timeout(LOOKUP_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(PwnedPasswords::contains(&body, &suffix))
+    }
+}
+
+/// Returns the breach check selected by `BREACH_CHECK`, or `None` when it is unset or `off`.
+///
+/// `BREACH_CHECK=pwned` queries the range API at `BREACH_CHECK_URL`, which defaults
+/// to [`DEFAULT_RANGE_URL`] and can point at a self-hosted mirror.
+pub fn breach_check_from_env() -> Option<Arc<dyn BreachCheck>> {
+    match env::var("BREACH_CHECK").map(|check| check.to_lowercase()).as_deref() {
+        Ok("pwned") => {
+            let url = env::var("BREACH_CHECK_URL").unwrap_or_else(|_| DEFAULT_RANGE_URL.to_string());
+            Some(Arc::new(PwnedPasswords::new(url)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_hashes_with_sha1() {
+        let (prefix, suffix) = PwnedPasswords::split("password");
+        assert_eq!(format!("{}{}", prefix, suffix), "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8");
+    }
+
+    #[test]
+    fn test_contains_ignores_padding_and_other_suffixes() {
+        let body = "0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n1E4C9B93F3F0682250B6CF8331B7EE68FD8:0\r\n";
+
+        assert!(PwnedPasswords::contains(body, "0018a45c4d1def81644b54ab7f969b88d65"));
+        assert!(!PwnedPasswords::contains(body, "1E4C9B93F3F0682250B6CF8331B7EE68FD8"));
+        assert!(!PwnedPasswords::contains(body, "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF"));
+    }
+}
@@ -0,0 +1,224 @@
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, RedisError};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+use crate::config::ConfigError;
+use crate::models::{CreateUserRequest, UpdateUserRequest, UserResponse};
+use crate::pagination::{UserPage, UserQuery};
+use crate::passwords::Credentials;
+use crate::repository::{UpdateOutcome, UserRepository};
+
+/// Key of the counter bumped by every write, which is part of the key of each cached
+/// page so that a write makes every page cached before it unreachable at once.
+const GENERATION_KEY: &str = "users:generation";
+
+/// A Redis server, connected on first use and reconnecting on its own afterwards.
+pub struct LazyRedis {
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+}
+
+impl LazyRedis {
+    /// Creates a handle on the server at `url`, such as `redis://cache:6379`.
+    pub fn new(url: &str) -> Result<Self, RedisError> {
+        Ok(LazyRedis { client: redis::Client::open(url)?, connection: OnceCell::new() })
+    }
+
+    /// Returns a connection to the server.
+    pub async fn connection(&self) -> Result<ConnectionManager, RedisError> {
+        self.connection.get_or_try_init(|| self.client.get_connection_manager()).await.cloned()
+    }
+}
+
+/// Returns the cache key of the user with `id`.
+fn user_key(id: &str) -> String {
+    format!("users:id:{}", id)
+}
+
+/// Returns the cache key of the page selected by `query` among the users as of
+/// `generation`.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::cache::list_key;
+/// use safe_user::pagination::UserQuery;
+///
+/// let first = UserQuery { page: Some(1), ..UserQuery::default() };
+/// let second = UserQuery { page: Some(2), ..UserQuery::default() };
+/// assert_eq!(list_key(&first, 7), list_key(&first.clone(), 7));
+/// assert_ne!(list_key(&first, 7), list_key(&second, 7));
+/// assert_ne!(list_key(&first, 7), list_key(&first, 8));
+/// ```
+pub fn list_key(query: &UserQuery, generation: i64) -> String {
+    let query = serde_json::to_string(query).unwrap_or_default();
+    format!("users:list:{}:{}", generation, hex::encode(Sha256::digest(query.as_bytes())))
+}
+
+/// [`UserRepository`] that keeps the users read by id and the pages of users listed
+/// through `inner` in Redis for a few seconds, to take read-heavy traffic off the
+/// database.
+///
+/// Creating, changing, deleting or restoring a user through the repository drops
+/// their cached copy and every cached page. Changes made outside it, such as an
+/// account expiring or a role being granted, show once the entries expire. When
+/// Redis cannot be reached, reads go to `inner` and the error is logged.
+pub struct CachedUsers {
+    inner: Arc<dyn UserRepository>,
+    redis: LazyRedis,
+    ttl_secs: u64,
+}
+
+impl CachedUsers {
+    /// Caches the reads of `inner` in `redis` for `ttl_secs` seconds.
+    pub fn new(inner: Arc<dyn UserRepository>, redis: LazyRedis, ttl_secs: u64) -> Self {
+        CachedUsers { inner, redis, ttl_secs }
+    }
+
+    /// Wraps `inner` in a cache when `USER_CACHE_TTL_SECS` is a positive number of
+    /// seconds, on the Redis server at `REDIS_URL`, and returns `inner` as is otherwise.
+    pub fn from_env(inner: Arc<dyn UserRepository>) -> Result<Arc<dyn UserRepository>, ConfigError> {
+        let ttl_secs = match env::var("USER_CACHE_TTL_SECS").ok().filter(|ttl| !ttl.trim().is_empty()) {
+            Some(ttl) => ttl.trim().parse::<u64>().map_err(|_| format!("Invalid USER_CACHE_TTL_SECS `{}`", ttl))?,
+            None => 0,
+        };
+        if ttl_secs == 0 {
+            return Ok(inner);
+        }
+        let url = env::var("REDIS_URL").map_err(|_| "REDIS_URL must be set for USER_CACHE_TTL_SECS")?;
+        Ok(Arc::new(CachedUsers::new(inner, LazyRedis::new(&url)?, ttl_secs)))
+    }
+
+    async fn read<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let cached: Result<Option<String>, RedisError> = async { self.redis.connection().await?.get(key).await }.await;
+        match cached {
+            Ok(cached) => cached.and_then(|cached| serde_json::from_str(&cached).ok()),
+            Err(e) => {
+                eprintln!("Error reading the user cache: {:?}", e);
+                None
+            }
+        }
+    }
+
+    async fn write<T: Serialize>(&self, key: &str, value: &T) {
+        let value = match serde_json::to_string(value) {
+            Ok(value) => value,
+            Err(_) => return,
+        };
+        let result: Result<(), RedisError> = async { self.redis.connection().await?.set_ex(key, value, self.ttl_secs).await }.await;
+        if let Err(e) = result {
+            eprintln!("Error writing the user cache: {:?}", e);
+        }
+    }
+
+    async fn generation(&self) -> Option<i64> {
+        let generation: Result<Option<i64>, RedisError> = async { self.redis.connection().await?.get(GENERATION_KEY).await }.await;
+        match generation {
+            Ok(generation) => Some(generation.unwrap_or(0)),
+            Err(e) => {
+                eprintln!("Error reading the user cache: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Drops the cached copy of the user with `id`, if any, and every cached page.
+    async fn invalidate(&self, id: Option<&str>) {
+        let result: Result<(), RedisError> = async {
+            let mut connection = self.redis.connection().await?;
+            if let Some(id) = id {
+                connection.del::<_, ()>(user_key(id)).await?;
+            }
+            connection.incr::<_, _, ()>(GENERATION_KEY, 1).await
+        }
+        .await;
+        if let Err(e) = result {
+            eprintln!("Error invalidating the user cache: {:?}", e);
+        }
+    }
+
+    /// Invalidates the cache for `id` unless `written` is an error, and passes it on.
+    async fn invalidate_after<T>(&self, written: Result<T, sqlx::Error>, id: Option<&str>) -> Result<T, sqlx::Error> {
+        if written.is_ok() {
+            self.invalidate(id).await;
+        }
+        written
+    }
+}
+
+#[async_trait]
+impl UserRepository for CachedUsers {
+    async fn create(&self, user: &CreateUserRequest, password_hash: Option<&str>, status: &str, tenant_id: Option<&str>, actor: Option<&str>) -> Result<String, sqlx::Error> {
+        let created = self.inner.create(user, password_hash, status, tenant_id, actor).await;
+        self.invalidate_after(created, None).await
+    }
+
+    async fn create_many(&self, users: &[CreateUserRequest], status: &str, tenant_id: Option<&str>, actor: Option<&str>) -> Result<Vec<Option<String>>, sqlx::Error> {
+        let created = self.inner.create_many(users, status, tenant_id, actor).await;
+        self.invalidate_after(created, None).await
+    }
+
+    async fn create_linked(&self, user: &CreateUserRequest, status: &str, tenant_id: Option<&str>, provider: &str, subject: &str) -> Result<String, sqlx::Error> {
+        let created = self.inner.create_linked(user, status, tenant_id, provider, subject).await;
+        self.invalidate_after(created, None).await
+    }
+
+    async fn list(&self, query: &UserQuery) -> Result<UserPage, sqlx::Error> {
+        let key = self.generation().await.map(|generation| list_key(query, generation));
+        if let Some(key) = &key {
+            if let Some(page) = self.read(key).await {
+                return Ok(page);
+            }
+        }
+        let page = self.inner.list(query).await?;
+        if let Some(key) = &key {
+            self.write(key, &page).await;
+        }
+        Ok(page)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<UserResponse>, sqlx::Error> {
+        let key = user_key(id);
+        if let Some(user) = self.read(&key).await {
+            return Ok(Some(user));
+        }
+        let user = self.inner.get(id).await?;
+        if let Some(user) = &user {
+            self.write(&key, user).await;
+        }
+        Ok(user)
+    }
+
+    async fn count_in_tenant(&self, tenant_id: &str) -> Result<i64, sqlx::Error> {
+        self.inner.count_in_tenant(tenant_id).await
+    }
+
+    async fn credentials_by_email(&self, email: &str) -> Result<Option<Credentials>, sqlx::Error> {
+        self.inner.credentials_by_email(email).await
+    }
+
+    async fn update(&self, id: &str, user: &CreateUserRequest, actor: Option<&str>) -> Result<UpdateOutcome, sqlx::Error> {
+        let updated = self.inner.update(id, user, actor).await;
+        self.invalidate_after(updated, Some(id)).await
+    }
+
+    async fn patch(&self, id: &str, changes: &UpdateUserRequest, actor: Option<&str>) -> Result<UpdateOutcome, sqlx::Error> {
+        let patched = self.inner.patch(id, changes, actor).await;
+        self.invalidate_after(patched, Some(id)).await
+    }
+
+    async fn delete(&self, id: &str, actor: Option<&str>) -> Result<bool, sqlx::Error> {
+        let deleted = self.inner.delete(id, actor).await;
+        self.invalidate_after(deleted, Some(id)).await
+    }
+
+    async fn restore(&self, id: &str, actor: Option<&str>) -> Result<bool, sqlx::Error> {
+        let restored = self.inner.restore(id, actor).await;
+        self.invalidate_after(restored, Some(id)).await
+    }
+}
@@ -0,0 +1,191 @@
+use actix_web::body::{to_bytes, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, ResponseError};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+use crate::config::ConfigError;
+use crate::error::AppError;
+
+/// Convention used for the field names of JSON responses.
+///
+/// The API is written in `snake_case`. With [`FieldCase::Camel`] the
+/// [`negotiate_case`] middleware renames every object key of JSON responses to
+/// `camelCase` before they are sent.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::casing::FieldCase;
+///
+/// assert_eq!("camelCase".parse::<FieldCase>(), Ok(FieldCase::Camel));
+/// assert_eq!(FieldCase::Camel.rename("last_name"), "lastName");
+/// assert_eq!(FieldCase::Snake.rename("last_name"), "last_name");
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FieldCase {
+    #[default]
+    Snake,
+    Camel,
+}
+
+impl FieldCase {
+    /// Reads the default convention from `JSON_CASE`, `snake` when unset.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        match env::var("JSON_CASE") {
+            Ok(case) => Ok(case.parse()?),
+            Err(_) => Ok(FieldCase::Snake),
+        }
+    }
+
+    /// Returns `key`, written in `snake_case`, in this convention.
+    ///
+    /// Leading underscores are kept, so keys such as `_id` are left alone.
+    pub fn rename(self, key: &str) -> String {
+        match self {
+            FieldCase::Snake => key.to_string(),
+            FieldCase::Camel => {
+                let body = key.trim_start_matches('_');
+                let mut renamed = key[..key.len() - body.len()].to_string();
+                let mut upper = false;
+                for c in body.chars() {
+                    match c {
+                        '_' => upper = true,
+                        c if upper => {
+                            renamed.extend(c.to_uppercase());
+                            upper = false;
+                        }
+                        c => renamed.push(c),
+                    }
+                }
+                renamed
+            }
+        }
+    }
+
+    /// Renames every object key in `value`, at any depth.
+    pub fn rename_keys(self, value: Value) -> Value {
+        match value {
+            Value::Object(fields) => Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(key, value)| (self.rename(&key), self.rename_keys(value)))
+                    .collect::<Map<_, _>>(),
+            ),
+            Value::Array(items) => Value::Array(items.into_iter().map(|item| self.rename_keys(item)).collect()),
+            value => value,
+        }
+    }
+}
+
+impl FromStr for FieldCase {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "snake" | "snake_case" => Ok(FieldCase::Snake),
+            "camel" | "camelcase" => Ok(FieldCase::Camel),
+            _ => Err(format!("`{}` is not a field case, expected `snake` or `camel`", value)),
+        }
+    }
+}
+
+/// Returns the convention asked for with `?case=`, falling back to the server default.
+fn requested_case(req: &ServiceRequest) -> Result<FieldCase, AppError> {
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).map(web::Query::into_inner).unwrap_or_default();
+    match query.get("case") {
+        Some(case) => case.parse().map_err(AppError::validation),
+        None => Ok(req.app_data::<web::Data<FieldCase>>().map(|case| *case.get_ref()).unwrap_or_default()),
+    }
+}
+
+/// Middleware function emitting JSON responses in the field-name convention the client
+/// asks for with `?case=camel` or `?case=snake`.
+///
+/// Requests without `case` get the [`FieldCase`] registered as app data, or
+/// `snake_case` if there is none. Only responses with a JSON content type are
+/// rewritten; request bodies are always read in `snake_case`. An unknown `case` is
+/// answered with `400 Bad Request`.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{middleware::from_fn, web, App};
+/// use safe_user::casing::{negotiate_case, FieldCase};
+///
+/// let app = App::new()
+///     .app_data(web::Data::new(FieldCase::Camel))
+///     .wrap(from_fn(negotiate_case));
+/// ```
+pub async fn negotiate_case(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<BoxBody>, Error> {
+    let case = match requested_case(&req) {
+        Ok(case) => case,
+        Err(e) => return Ok(req.into_response(e.error_response())),
+    };
+
+    let response = next.call(req).await?;
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if case == FieldCase::Snake || !is_json {
+        return Ok(response.map_into_boxed_body());
+    }
+
+    let (req, response) = response.into_parts();
+    let (response, body) = response.into_parts();
+    let bytes = to_bytes(body).await.map_err(|e| {
+        let e: Box<dyn std::error::Error> = e.into();
+        actix_web::error::ErrorInternalServerError(e.to_string())
+    })?;
+    let body = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(value) => case.rename_keys(value).to_string().into_bytes(),
+        Err(_) => bytes.to_vec(),
+    };
+    Ok(ServiceResponse::new(req, response.set_body(body)).map_into_boxed_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware::from_fn, App, HttpResponse};
+    use serde_json::json;
+
+    async fn user() -> HttpResponse {
+        HttpResponse::Ok().json(json!({ "last_name": "Doe", "roles": [{ "role_id": 1 }], "_id": 7 }))
+    }
+
+    #[test]
+    fn test_rename_to_camel_case() {
+        assert_eq!(FieldCase::Camel.rename("expires_at"), "expiresAt");
+        assert_eq!(FieldCase::Camel.rename("_id"), "_id");
+        assert_eq!(FieldCase::Camel.rename("id"), "id");
+        assert!("kebab".parse::<FieldCase>().is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_responses_follow_requested_case() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(FieldCase::Camel))
+                .wrap(from_fn(negotiate_case))
+                .route("/user", web::get().to(user))
+        ).await;
+
+        let req = actix_web::test::TestRequest::get().uri("/user").to_request();
+        let body: Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!({ "lastName": "Doe", "roles": [{ "roleId": 1 }], "_id": 7 }));
+
+        let req = actix_web::test::TestRequest::get().uri("/user?case=snake").to_request();
+        let body: Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["last_name"], "Doe");
+
+        let req = actix_web::test::TestRequest::get().uri("/user?case=kebab").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+}
@@ -0,0 +1,615 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use async_trait::async_trait;
+use chrono::Utc;
+use futures_util::{future, stream, StreamExt};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use utoipa::ToSchema;
+use crate::auth::derived_key;
+use crate::config::ConfigError;
+use crate::device::VERIFICATION_PATH;
+use crate::error::AppError;
+use crate::routes::unversioned;
+
+/// Header carrying the client's answer to the challenge: the captcha response token,
+/// or a solved proof-of-work as `<challenge>:<solution>`.
+pub const CHALLENGE_HEADER: &str = "X-Challenge-Response";
+
+/// Fields the answer is read from when a form is posted without [`CHALLENGE_HEADER`]:
+/// the one of the pages served here, and the ones the hCaptcha and Turnstile widgets
+/// fill in.
+pub const CHALLENGE_FORM_FIELDS: &[&str] = &["challenge_response", "h-captcha-response", "cf-turnstile-response"];
+
+/// Endpoint of the hCaptcha verification API.
+pub const HCAPTCHA_VERIFY_URL: &str = "https://api.hcaptcha.com/siteverify";
+
+/// Endpoint of the Cloudflare Turnstile verification API.
+pub const TURNSTILE_VERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+/// Leading zero bits a proof-of-work must have when `CHALLENGE_POW_DIFFICULTY` is not set.
+pub const DEFAULT_POW_DIFFICULTY: u32 = 20;
+
+/// Seconds a proof-of-work challenge can be solved in.
+pub const POW_TTL_SECS: i64 = 300;
+
+/// Purpose the proof-of-work signing key is derived for.
+const POW_PURPOSE: &str = "pow_challenge";
+
+/// Time allowed for a verification request, so a slow provider does not stall logins.
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Error returned when a provider cannot verify a response.
+pub type ChallengeError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A public endpoint that can be put behind a challenge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChallengeAction {
+    /// `/register`, `/create_user` and `/oauth/signup`.
+    Registration,
+    /// `/login`, `/mfa/verify` and the device approval page, `/device`.
+    Login,
+    /// `/forgot_password`. `/reset_password` is already guarded by its token.
+    PasswordReset,
+}
+
+impl ChallengeAction {
+    /// Every action, all of which are challenged when `CHALLENGE_ACTIONS` is not set.
+    pub const ALL: [ChallengeAction; 3] = [ChallengeAction::Registration, ChallengeAction::Login, ChallengeAction::PasswordReset];
+
+    /// Returns the name of the action in `CHALLENGE_ACTIONS`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChallengeAction::Registration => "registration",
+            ChallengeAction::Login => "login",
+            ChallengeAction::PasswordReset => "password_reset",
+        }
+    }
+
+    /// Returns the paths of the action.
+    pub fn paths(&self) -> &'static [&'static str] {
+        match self {
+            ChallengeAction::Registration => &["/register", "/create_user", "/oauth/signup"],
+            ChallengeAction::Login => &["/login", "/mfa/verify", VERIFICATION_PATH],
+            ChallengeAction::PasswordReset => &["/forgot_password"],
+        }
+    }
+
+    /// Returns the action served by `path`, if any.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::challenge::ChallengeAction;
+    ///
+    /// assert_eq!(ChallengeAction::for_path("/login"), Some(ChallengeAction::Login));
+    /// assert_eq!(ChallengeAction::for_path("/reset_password"), None);
+    /// ```
+    pub fn for_path(path: &str) -> Option<ChallengeAction> {
+        ChallengeAction::ALL.into_iter().find(|action| action.paths().contains(&path))
+    }
+}
+
+/// What a client needs to present the next challenge, as returned by `GET /challenge`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ChallengeWidget {
+    /// `hcaptcha`, `turnstile` or `pow`.
+    pub provider: String,
+    /// The site key to render the captcha widget with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site_key: Option<String>,
+    /// The proof-of-work challenge to solve.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub challenge: Option<String>,
+    /// Leading zero bits the SHA-256 of `<challenge>:<solution>` must have.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub difficulty: Option<u32>,
+    /// Seconds left to solve `challenge`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<i64>,
+}
+
+/// A way of telling people from scripts before serving an abusable endpoint.
+#[async_trait]
+pub trait ChallengeProvider: Send + Sync {
+    /// Returns what the client needs to present the next challenge.
+    fn widget(&self) -> ChallengeWidget;
+
+    /// Returns `true` if `response` answers a challenge, given the client's address.
+    async fn verify(&self, response: &str, remote_ip: Option<IpAddr>) -> Result<bool, ChallengeError>;
+}
+
+/// A captcha checked through a `siteverify` API, which hCaptcha and Turnstile share:
+/// the secret and response token are posted as a form, and the answer's `success`
+/// tells whether the token is valid.
+pub struct SiteVerify {
+    provider: &'static str,
+    url: String,
+    secret: String,
+    site_key: Option<String>,
+    client: reqwest::Client,
+}
+
+/// The answer of a `siteverify` API; the other fields are not needed.
+#[derive(Debug, Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+impl SiteVerify {
+    /// Creates an hCaptcha verifier.
+    pub fn hcaptcha(secret: String, site_key: Option<String>) -> Self {
+        SiteVerify::new("hcaptcha", HCAPTCHA_VERIFY_URL.to_string(), secret, site_key)
+    }
+
+    /// Creates a Cloudflare Turnstile verifier.
+    pub fn turnstile(secret: String, site_key: Option<String>) -> Self {
+        SiteVerify::new("turnstile", TURNSTILE_VERIFY_URL.to_string(), secret, site_key)
+    }
+
+    /// Creates a verifier named `provider` posting to `url`.
+    pub fn new(provider: &'static str, url: String, secret: String, site_key: Option<String>) -> Self {
+        SiteVerify { provider, url, secret, site_key, client: reqwest::Client::new() }
+    }
+}
+
+#[async_trait]
+impl ChallengeProvider for SiteVerify {
+    fn widget(&self) -> ChallengeWidget {
+        ChallengeWidget {
+            provider: self.provider.to_string(),
+            site_key: self.site_key.clone(),
+            challenge: None,
+            difficulty: None,
+            expires_in: None,
+        }
+    }
+
+    async fn verify(&self, response: &str, remote_ip: Option<IpAddr>) -> Result<bool, ChallengeError> {
+        let mut form = vec![("secret", self.secret.clone()), ("response", response.to_string())];
+        if let Some(ip) = remote_ip {
+            form.push(("remoteip", ip.to_string()));
+        }
+        let answer: SiteVerifyResponse = self.client
+            .post(&self.url)
+            .form(&form)
+            .timeout(VERIFY_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(answer.success)
+    }
+}
+
+/// A proof-of-work challenge that needs no third party: clients find a `solution`
+/// such that the SHA-256 of `<challenge>:<solution>` starts with `difficulty` zero
+/// bits, which takes a browser a moment but makes bulk requests expensive.
+///
+/// Challenges are signed rather than stored, and each solved one is accepted once.
+/// The spent challenges are kept in memory until they expire, so with several
+/// instances a solution could be replayed once per instance.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::challenge::ProofOfWork;
+///
+/// let pow = ProofOfWork::new(b"key".to_vec(), 8);
+/// let challenge = pow.issue(1_800_000_000);
+/// let solution = ProofOfWork::solve(&challenge, 8);
+/// assert!(pow.check(&format!("{}:{}", challenge, solution), 1_800_000_000));
+/// assert!(!pow.check(&format!("{}:{}", challenge, solution), 1_800_000_000));
+/// ```
+pub struct ProofOfWork {
+    key: Vec<u8>,
+    difficulty: u32,
+    spent: Mutex<HashMap<String, i64>>,
+}
+
+impl ProofOfWork {
+    /// Creates a provider signing challenges with `key`.
+    pub fn new(key: Vec<u8>, difficulty: u32) -> Self {
+        ProofOfWork { key, difficulty, spent: Mutex::new(HashMap::new()) }
+    }
+
+    fn signature(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// Returns a new challenge, as `<expires>.<nonce>.<signature>`, expiring
+    /// [`POW_TTL_SECS`] after `now` (a Unix timestamp).
+    pub fn issue(&self, now: i64) -> String {
+        let nonce = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+        let payload = format!("{}.{}", now + POW_TTL_SECS, nonce);
+        let signature = hex::encode(self.signature(&payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Returns the number of leading zero bits of `hash`.
+    pub fn leading_zero_bits(hash: &[u8]) -> u32 {
+        let mut bits = 0;
+        for byte in hash {
+            bits += byte.leading_zeros();
+            if *byte != 0 {
+                break;
+            }
+        }
+        bits
+    }
+
+    /// Finds a solution of `challenge`, as a client would.
+    pub fn solve(challenge: &str, difficulty: u32) -> String {
+        (0u64..)
+            .map(|counter| counter.to_string())
+            .find(|solution| ProofOfWork::leading_zero_bits(&Sha256::digest(format!("{}:{}", challenge, solution))) >= difficulty)
+            .unwrap_or_default()
+    }
+
+    /// Returns `true` if `response` is a solution of a challenge issued by this
+    /// provider that has not expired at `now` and not been accepted before.
+    pub fn check(&self, response: &str, now: i64) -> bool {
+        let (challenge, solution) = match response.rsplit_once(':') {
+            Some(response) => response,
+            None => return false,
+        };
+        let (payload, signature) = match challenge.rsplit_once('.') {
+            Some(challenge) => challenge,
+            None => return false,
+        };
+        let expires = match payload.split_once('.').and_then(|(expires, _)| expires.parse::<i64>().ok()) {
+            Some(expires) => expires,
+            None => return false,
+        };
+        let signed = hex::decode(signature).is_ok_and(|signature| self.signature(payload).verify_slice(&signature).is_ok());
+        if !signed || expires <= now {
+            return false;
+        }
+        if ProofOfWork::leading_zero_bits(&Sha256::digest(format!("{}:{}", challenge, solution))) < self.difficulty {
+            return false;
+        }
+
+        let mut spent = self.spent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        spent.retain(|_, expires| *expires > now);
+        spent.insert(challenge.to_string(), expires).is_none()
+    }
+}
+
+#[async_trait]
+impl ChallengeProvider for ProofOfWork {
+    fn widget(&self) -> ChallengeWidget {
+        ChallengeWidget {
+            provider: "pow".to_string(),
+            site_key: None,
+            challenge: Some(self.issue(Utc::now().timestamp())),
+            difficulty: Some(self.difficulty),
+            expires_in: Some(POW_TTL_SECS),
+        }
+    }
+
+    async fn verify(&self, response: &str, _remote_ip: Option<IpAddr>) -> Result<bool, ChallengeError> {
+        Ok(self.check(response, Utc::now().timestamp()))
+    }
+}
+
+/// The challenge provider and the actions it guards, registered as app data when
+/// `CHALLENGE_PROVIDER` is set.
+#[derive(Clone)]
+pub struct Challenges {
+    provider: Arc<dyn ChallengeProvider>,
+    actions: BTreeSet<ChallengeAction>,
+}
+
+impl Challenges {
+    /// Guards `actions` with `provider`.
+    pub fn new(provider: Arc<dyn ChallengeProvider>, actions: impl IntoIterator<Item = ChallengeAction>) -> Self {
+        Challenges { provider, actions: actions.into_iter().collect() }
+    }
+
+    /// Reads the provider from the environment.
+    pub fn from_env() -> Result<Option<Self>, ConfigError> {
+        Challenges::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Reads the provider from the variables returned by `var`, or returns `None` when
+    /// `CHALLENGE_PROVIDER` is unset or `off`:
+    ///
+    /// * `hcaptcha`, `turnstile` - [`SiteVerify`], with the secret `CHALLENGE_SECRET`
+    ///   and the optional `CHALLENGE_SITE_KEY` handed to clients.
+    /// * `pow` - [`ProofOfWork`], at `CHALLENGE_POW_DIFFICULTY` bits (default
+    ///   [`DEFAULT_POW_DIFFICULTY`]).
+    ///
+    /// `CHALLENGE_ACTIONS` is a comma-separated list of the actions to guard, by
+    /// default all of them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::challenge::{ChallengeAction, Challenges};
+    ///
+    /// let challenges = Challenges::from_vars(|name| match name {
+    ///     "CHALLENGE_PROVIDER" => Some("turnstile".to_string()),
+    ///     "CHALLENGE_SECRET" => Some("0x4AAA".to_string()),
+    ///     "CHALLENGE_ACTIONS" => Some("registration, password_reset".to_string()),
+    ///     _ => None,
+    /// })
+    /// .unwrap()
+    /// .unwrap();
+    /// assert!(challenges.guards(ChallengeAction::Registration));
+    /// assert!(!challenges.guards(ChallengeAction::Login));
+    /// assert!(Challenges::from_vars(|name| (name == "CHALLENGE_PROVIDER").then(|| "hcaptcha".to_string())).is_err());
+    /// ```
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Option<Self>, ConfigError> {
+        let var = |name: &str| var(name).map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+        let required = |name: &str| var(name).ok_or_else(|| format!("{} must be set for CHALLENGE_PROVIDER", name));
+
+        let provider: Arc<dyn ChallengeProvider> = match var("CHALLENGE_PROVIDER").map(|provider| provider.to_lowercase()).as_deref() {
+            None | Some("off") => return Ok(None),
+            Some("hcaptcha") => Arc::new(SiteVerify::hcaptcha(required("CHALLENGE_SECRET")?, var("CHALLENGE_SITE_KEY"))),
+            Some("turnstile") => Arc::new(SiteVerify::turnstile(required("CHALLENGE_SECRET")?, var("CHALLENGE_SITE_KEY"))),
+            Some("pow") => {
+                let difficulty = match var("CHALLENGE_POW_DIFFICULTY") {
+                    Some(difficulty) => difficulty.parse::<u32>().ok().filter(|bits| (1..=64).contains(bits))
+                        .ok_or_else(|| format!("Invalid CHALLENGE_POW_DIFFICULTY `{}`", difficulty))?,
+                    None => DEFAULT_POW_DIFFICULTY,
+                };
+                Arc::new(ProofOfWork::new(derived_key(POW_PURPOSE), difficulty))
+            }
+            Some(other) => return Err(format!("Unknown CHALLENGE_PROVIDER `{}`", other).into()),
+        };
+
+        let actions = match var("CHALLENGE_ACTIONS") {
+            Some(names) => names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    ChallengeAction::ALL.into_iter().find(|action| action.name() == name)
+                        .ok_or_else(|| ConfigError::from(format!("Unknown challenge action `{}`", name)))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => ChallengeAction::ALL.to_vec(),
+        };
+        Ok(Some(Challenges::new(provider, actions)))
+    }
+
+    /// Returns `true` if `action` needs a challenge.
+    pub fn guards(&self, action: ChallengeAction) -> bool {
+        self.actions.contains(&action)
+    }
+}
+
+/// Middleware function that makes `POST` requests to the guarded actions carry a
+/// valid [`CHALLENGE_HEADER`], answering `403 Forbidden` with `challenge_required`
+/// when it is missing and `challenge_failed` when the provider rejects it. Forms,
+/// which cannot set headers, may send the answer in one of the
+/// [`CHALLENGE_FORM_FIELDS`] instead.
+///
+/// It wraps each public resource inside its rate limit, so rejected attempts still
+/// count against it. When the provider cannot be reached, the request goes through
+/// rather than taking sign-in down with the provider. The middleware is a no-op
+/// when no [`Challenges`] have been registered as app data.
+pub async fn require_challenge(mut req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let challenges = req.app_data::<web::Data<Challenges>>().filter(|challenges| {
+        req.method() == Method::POST && ChallengeAction::for_path(unversioned(req.path())).is_some_and(|action| challenges.guards(action))
+    });
+    let challenges = match challenges {
+        Some(challenges) => challenges.clone(),
+        None => return next.call(req).await.map(ServiceResponse::map_into_left_body),
+    };
+
+    let mut response = req.headers().get(CHALLENGE_HEADER).and_then(|value| value.to_str().ok()).map(str::trim).filter(|value| !value.is_empty()).map(str::to_string);
+    if response.is_none() && req.content_type() == "application/x-www-form-urlencoded" {
+        response = form_response(&mut req).await?;
+    }
+    let error = match response {
+        None => Some(AppError::forbidden("challenge_required", format!("Solve the challenge from /challenge and send it in {}.", CHALLENGE_HEADER))),
+        Some(response) => match challenges.provider.verify(&response, req.peer_addr().map(|addr| addr.ip())).await {
+            Ok(true) => None,
+            Ok(false) => Some(AppError::forbidden("challenge_failed", "The challenge response is invalid or expired, try again.")),
+            Err(e) => {
+                eprintln!("Error verifying challenge response: {:?}", e);
+                None
+            }
+        },
+    };
+
+    if let Some(error) = error {
+        return Ok(req.into_response(error.error_response()).map_into_right_body());
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+/// Reads the answer from the [`CHALLENGE_FORM_FIELDS`] of a form post, and puts the
+/// body back for the handler.
+async fn form_response(req: &mut ServiceRequest) -> Result<Option<String>, Error> {
+    let body = req.extract::<web::Bytes>().await?;
+    let fields: Vec<(String, String)> = serde_urlencoded::from_bytes(&body).unwrap_or_default();
+    req.set_payload(Payload::from(stream::once(future::ready(Ok::<_, PayloadError>(body))).boxed_local()));
+    Ok(fields
+        .into_iter()
+        .find(|(name, value)| CHALLENGE_FORM_FIELDS.contains(&name.as_str()) && !value.trim().is_empty())
+        .map(|(_, value)| value.trim().to_string()))
+}
+
+/// Returns the HTML that presents the challenge of `action` inside a form of a page
+/// served here, or nothing when the action is not challenged.
+///
+/// hCaptcha and Turnstile get their widget. A proof-of-work is solved by a script
+/// when the form is submitted, which needs a browser serving the page over HTTPS
+/// (or from `localhost`) for `crypto.subtle`.
+pub fn form_widget(req: &HttpRequest, action: ChallengeAction) -> String {
+    let widget = match req.app_data::<web::Data<Challenges>>().filter(|challenges| challenges.guards(action)) {
+        Some(challenges) => challenges.provider.widget(),
+        None => return String::new(),
+    };
+    let attribute = |value: &str| value.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;");
+    let site_key = attribute(widget.site_key.as_deref().unwrap_or(""));
+    match widget.provider.as_str() {
+        "hcaptcha" => format!(r#"<script src="https://js.hcaptcha.com/1/api.js" async defer></script><div class="h-captcha" data-sitekey="{}"></div>"#, site_key),
+        "turnstile" => format!(r#"<script src="https://challenges.cloudflare.com/turnstile/v0/api.js" async defer></script><div class="cf-turnstile" data-sitekey="{}"></div>"#, site_key),
+        _ => match (widget.challenge, widget.difficulty) {
+            (Some(challenge), Some(difficulty)) => format!(
+                r#"<input type="hidden" name="challenge_response" data-challenge="{}" data-difficulty="{}">{}"#,
+                attribute(&challenge),
+                difficulty,
+                POW_SCRIPT
+            ),
+            _ => String::new(),
+        },
+    }
+}
+
+/// Solves the proof-of-work of the form's `challenge_response` field before the form
+/// is sent, as [`ProofOfWork::solve`] does.
+const POW_SCRIPT: &str = r#"<script>
+(function () {
+    var input = document.querySelector('input[name="challenge_response"]');
+    function leadingZeroBits(hash) {
+        for (var i = 0, bits = 0; i < hash.length; i++, bits += 8) {
+            if (hash[i] !== 0) return bits + Math.clz32(hash[i]) - 24;
+        }
+        return bits;
+    }
+    input.form.addEventListener('submit', async function (event) {
+        if (input.value) return;
+        event.preventDefault();
+        var challenge = input.dataset.challenge, difficulty = Number(input.dataset.difficulty);
+        for (var counter = 0; ; counter++) {
+            var hash = await crypto.subtle.digest('SHA-256', new TextEncoder().encode(challenge + ':' + counter));
+            if (leadingZeroBits(new Uint8Array(hash)) >= difficulty) break;
+        }
+        input.value = challenge + ':' + counter;
+        input.form.requestSubmit(event.submitter);
+    });
+})();
+</script>"#;
+
+/// Describes the challenge clients must solve before registering, logging in or
+/// asking for a password reset.
+///
+/// # Arguments
+///
+/// * `challenges` - The configured provider, if any.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - The [`ChallengeWidget`] of the provider, with a
+///   fresh challenge for proof-of-work, or `404 Not Found` when none is configured.
+#[utoipa::path(
+    get,
+    path = "/challenge",
+    tag = "auth",
+    responses(
+        (status = 200, description = "What the client needs to present the challenge", body = ChallengeWidget),
+        (status = 404, description = "No challenge provider is configured (`route_not_found`)", body = ErrorBody),
+    )
+)]
+pub async fn get_challenge(challenges: Option<web::Data<Challenges>>) -> Result<HttpResponse, AppError> {
+    let challenges = challenges.ok_or(AppError::NotFound("route"))?;
+    Ok(HttpResponse::Ok()
+        .insert_header(("Cache-Control", "no-store"))
+        .json(challenges.provider.widget()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::{http::StatusCode, test, App, Responder};
+
+    async fn ok() -> impl Responder {
+        HttpResponse::Ok().json("ok")
+    }
+
+    /// Checks that forged, tampered and expired proofs of work are refused.
+    #[actix_web::test]
+    async fn test_proof_of_work_rejects_bad_solutions() {
+        let pow = ProofOfWork::new(b"key".to_vec(), 12);
+        let challenge = pow.issue(1_000);
+        let solution = ProofOfWork::solve(&challenge, 12);
+
+        let other = ProofOfWork::new(b"other key".to_vec(), 12);
+        assert!(!other.check(&format!("{}:{}", challenge, solution), 1_000));
+        let tampered = challenge.replacen("1300", "9300", 1);
+        assert!(!pow.check(&format!("{}:{}", tampered, ProofOfWork::solve(&tampered, 12)), 1_000));
+        assert!(!pow.check(&format!("{}:{}", challenge, solution), 1_000 + POW_TTL_SECS));
+        assert!(!pow.check(&challenge, 1_000));
+        assert!(pow.check(&format!("{}:{}", challenge, solution), 1_000));
+    }
+
+    /// Checks that only the guarded actions need the header.
+    #[actix_web::test]
+    async fn test_guarded_actions_need_a_solved_challenge() {
+        let pow = Arc::new(ProofOfWork::new(b"key".to_vec(), 4));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Challenges::new(pow.clone(), [ChallengeAction::Login])))
+                .wrap(from_fn(require_challenge))
+                .route("/login", web::post().to(ok))
+                .route("/register", web::post().to(ok))
+        ).await;
+
+        let req = test::TestRequest::post().uri("/register").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::post().uri("/login").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::post().uri("/login").insert_header((CHALLENGE_HEADER, "nonsense:1")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let challenge = pow.issue(Utc::now().timestamp());
+        let solved = format!("{}:{}", challenge, ProofOfWork::solve(&challenge, 4));
+        let req = test::TestRequest::post().uri("/login").insert_header((CHALLENGE_HEADER, solved)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    /// Checks that forms can send the answer in a field, and that the handler still
+    /// reads the whole form.
+    #[actix_web::test]
+    async fn test_forms_answer_in_a_field() {
+        #[derive(Deserialize)]
+        struct DeviceForm {
+            user_code: String,
+        }
+
+        async fn echo(form: web::Form<DeviceForm>) -> impl Responder {
+            HttpResponse::Ok().body(form.into_inner().user_code)
+        }
+
+        let pow = Arc::new(ProofOfWork::new(b"key".to_vec(), 4));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Challenges::new(pow.clone(), [ChallengeAction::Login])))
+                .wrap(from_fn(require_challenge))
+                .route(VERIFICATION_PATH, web::post().to(echo))
+        ).await;
+
+        let req = test::TestRequest::post().uri(VERIFICATION_PATH).set_form([("user_code", "WDJB-MJHT")]).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let challenge = pow.issue(Utc::now().timestamp());
+        let solved = format!("{}:{}", challenge, ProofOfWork::solve(&challenge, 4));
+        let req = test::TestRequest::post()
+            .uri(VERIFICATION_PATH)
+            .set_form([("user_code", "WDJB-MJHT"), ("challenge_response", solved.as_str())])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, "WDJB-MJHT");
+        assert_eq!(ChallengeAction::for_path("/mfa/verify"), Some(ChallengeAction::Login));
+    }
+}
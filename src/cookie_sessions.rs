@@ -0,0 +1,390 @@
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::dev::ServiceRequest;
+use actix_web::{web, Error, HttpMessage, HttpRequest};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use sqlx::{Mssql, Pool};
+use std::env;
+use std::sync::Arc;
+use uuid::Uuid;
+use crate::auth::{jwt_validator, Claims};
+#[cfg(feature = "redis")]
+use crate::cache::LazyRedis;
+use crate::config::{flag, ConfigError};
+use crate::error::AppError;
+use crate::handlers::access_claims;
+use crate::sessions;
+use crate::tenancy::TenantKey;
+use crate::timestamp::Timestamp;
+
+/// Name of the session cookie when `SESSION_COOKIE_NAME` is not set.
+pub const DEFAULT_COOKIE_NAME: &str = "safe_user_session";
+
+/// Minutes a cookie session lasts when `SESSION_TTL_MINUTES` is not set.
+pub const DEFAULT_SESSION_TTL_MINUTES: i64 = 12 * 60;
+
+/// Error returned when a session store cannot be reached.
+pub type SessionStoreError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Where the server side of cookie sessions is kept. Sessions are looked up by the
+/// SHA-256 of the cookie, so the store never holds a usable cookie value.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Stores the `claims` of a session under `key`, expiring at `claims.exp`.
+    async fn insert(&self, key: &str, claims: &Claims) -> Result<(), SessionStoreError>;
+
+    /// Returns the claims of the session stored under `key`, unless it has expired.
+    async fn get(&self, key: &str) -> Result<Option<Claims>, SessionStoreError>;
+
+    /// Removes the session stored under `key`, if any.
+    async fn remove(&self, key: &str) -> Result<(), SessionStoreError>;
+}
+
+/// Keeps sessions in the `[web_sessions]` table.
+pub struct MssqlSessionStore {
+    pool: Pool<Mssql>,
+}
+
+impl MssqlSessionStore {
+    pub fn new(pool: Pool<Mssql>) -> Self {
+        MssqlSessionStore { pool }
+    }
+}
+
+#[async_trait]
+impl SessionStore for MssqlSessionStore {
+    async fn insert(&self, key: &str, claims: &Claims) -> Result<(), SessionStoreError> {
+        let expires_at = Timestamp(expiry(claims));
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!("DELETE FROM [web_sessions] WHERE ExpiresAt < SYSUTCDATETIME()")
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!(
+            "INSERT INTO [web_sessions] (Id, UserId, Claims, ExpiresAt) VALUES (@p1, @p2, @p3, @p4)",
+            key,
+            &claims.sub,
+            serde_json::to_string(claims)?,
+            expires_at
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Claims>, SessionStoreError> {
+        let claims = sqlx::query_scalar!(
+            r#"SELECT Claims AS "claims!: String" FROM [web_sessions] WHERE Id = @p1 AND ExpiresAt > SYSUTCDATETIME()"#,
+            key
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(claims.map(|claims| serde_json::from_str(&claims)).transpose()?)
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), SessionStoreError> {
+        sqlx::query!("DELETE FROM [web_sessions] WHERE Id = @p1", key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Keeps sessions in Redis, as `session:<key>` strings that Redis expires on its own.
+#[cfg(feature = "redis")]
+pub struct RedisSessionStore {
+    redis: LazyRedis,
+}
+
+#[cfg(feature = "redis")]
+impl RedisSessionStore {
+    /// Creates a store on the server at `url`, connected on first use.
+    pub fn new(url: &str) -> Result<Self, redis::RedisError> {
+        Ok(RedisSessionStore { redis: LazyRedis::new(url)? })
+    }
+}
+
+#[cfg(feature = "redis")]
+#[async_trait]
+impl SessionStore for RedisSessionStore {
+    async fn insert(&self, key: &str, claims: &Claims) -> Result<(), SessionStoreError> {
+        let ttl = (expiry(claims) - Utc::now()).num_seconds().max(1) as u64;
+        redis::AsyncCommands::set_ex::<_, _, ()>(&mut self.redis.connection().await?, format!("session:{}", key), serde_json::to_string(claims)?, ttl).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Claims>, SessionStoreError> {
+        let claims: Option<String> = redis::AsyncCommands::get(&mut self.redis.connection().await?, format!("session:{}", key)).await?;
+        Ok(claims.map(|claims| serde_json::from_str(&claims)).transpose()?)
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), SessionStoreError> {
+        redis::AsyncCommands::del::<_, ()>(&mut self.redis.connection().await?, format!("session:{}", key)).await?;
+        Ok(())
+    }
+}
+
+/// Returns when a session with `claims` ends.
+fn expiry(claims: &Claims) -> DateTime<Utc> {
+    Utc.timestamp_opt(claims.exp as i64, 0).single().unwrap_or_else(Utc::now)
+}
+
+/// Returns the key a session cookie is stored under.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::cookie_sessions::session_key;
+///
+/// assert_eq!(session_key("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+/// ```
+pub fn session_key(cookie: &str) -> String {
+    hex::encode(Sha256::digest(cookie.as_bytes()))
+}
+
+/// The cookie-session mode for browser frontends, registered as app data when
+/// `SESSION_COOKIES` is set: `/login` then also sets an `HttpOnly` cookie that
+/// [`session_or_bearer`] accepts in place of a bearer token.
+#[derive(Clone)]
+pub struct CookieSessions {
+    store: Arc<dyn SessionStore>,
+    name: String,
+    ttl: Duration,
+    same_site: SameSite,
+    secure: bool,
+}
+
+impl CookieSessions {
+    /// Creates cookie sessions kept in `store`, with the default name and lifetime, as
+    /// `Secure` and `SameSite=Strict` cookies.
+    pub fn new(store: Arc<dyn SessionStore>) -> Self {
+        CookieSessions {
+            store,
+            name: DEFAULT_COOKIE_NAME.to_string(),
+            ttl: Duration::minutes(DEFAULT_SESSION_TTL_MINUTES),
+            same_site: SameSite::Strict,
+            secure: true,
+        }
+    }
+
+    /// Reads the settings from the environment, keeping sessions with `db` in `pool`.
+    pub fn from_env(pool: &Pool<Mssql>) -> Result<Option<Self>, ConfigError> {
+        CookieSessions::from_vars(|name| env::var(name).ok(), pool)
+    }
+
+    /// Reads the settings from the variables returned by `var`, or returns `None` when
+    /// `SESSION_COOKIES` is unset or `off`:
+    ///
+    /// * `db` - [`MssqlSessionStore`], in `pool`.
+    /// * `redis` - `RedisSessionStore`, on the server at `REDIS_URL`. Needs the `redis`
+    ///   feature.
+    ///
+    /// `SESSION_COOKIE_NAME` (default [`DEFAULT_COOKIE_NAME`]) names the cookie,
+    /// `SESSION_TTL_MINUTES` (default [`DEFAULT_SESSION_TTL_MINUTES`]) sets how long a
+    /// session lasts, `SESSION_COOKIE_SAMESITE` is `strict` (default) or `lax`, and
+    /// `SESSION_COOKIE_INSECURE=true` drops the `Secure` attribute for local development
+    /// over plain HTTP.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>, pool: &Pool<Mssql>) -> Result<Option<Self>, ConfigError> {
+        let var = |name: &str| var(name).map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+
+        let store: Arc<dyn SessionStore> = match var("SESSION_COOKIES").map(|store| store.to_lowercase()).as_deref() {
+            None | Some("off") => return Ok(None),
+            Some("db") => Arc::new(MssqlSessionStore::new(pool.clone())),
+            #[cfg(feature = "redis")]
+            Some("redis") => {
+                let url = var("REDIS_URL").ok_or("REDIS_URL must be set for SESSION_COOKIES=redis")?;
+                Arc::new(RedisSessionStore::new(&url)?)
+            }
+            #[cfg(not(feature = "redis"))]
+            Some("redis") => return Err("SESSION_COOKIES=redis needs the server built with the `redis` feature".into()),
+            Some(other) => return Err(format!("Unknown SESSION_COOKIES `{}`", other).into()),
+        };
+
+        let mut sessions = CookieSessions::new(store);
+        if let Some(name) = var("SESSION_COOKIE_NAME") {
+            sessions.name = name;
+        }
+        if let Some(minutes) = var("SESSION_TTL_MINUTES") {
+            let minutes = minutes.parse::<i64>().ok().filter(|minutes| *minutes > 0)
+                .ok_or_else(|| format!("Invalid SESSION_TTL_MINUTES `{}`", minutes))?;
+            sessions.ttl = Duration::minutes(minutes);
+        }
+        sessions.same_site = match var("SESSION_COOKIE_SAMESITE").map(|same_site| same_site.to_lowercase()).as_deref() {
+            None | Some("strict") => SameSite::Strict,
+            Some("lax") => SameSite::Lax,
+            Some(other) => return Err(format!("SESSION_COOKIE_SAMESITE must be `strict` or `lax`, got `{}`", other).into()),
+        };
+        sessions.secure = !flag(|name| var(name), "SESSION_COOKIE_INSECURE")?;
+        Ok(Some(sessions))
+    }
+
+    /// Returns the name of the session cookie.
+    pub fn cookie_name(&self) -> &str {
+        &self.name
+    }
+
+    fn cookie(&self, value: String, max_age: time::Duration) -> Cookie<'static> {
+        Cookie::build(self.name.clone(), value)
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(self.same_site)
+            .max_age(max_age)
+            .finish()
+    }
+
+    /// Starts a session for `sub` and returns its cookie. The session carries the same
+    /// claims as an access token, with the tenant's issuer in multi-tenant mode, and
+    /// its own `sid` and `jti`.
+    pub async fn open(&self, pool: &Pool<Mssql>, sub: &str, tenant_key: Option<&TenantKey>) -> Result<Cookie<'static>, AppError> {
+        let mut claims = access_claims(pool, sub, &Uuid::new_v4().to_string()).await?;
+        claims.exp = (Utc::now() + self.ttl).timestamp() as usize;
+        claims.iss = tenant_key.map(|key| key.issuer.clone());
+
+        let value = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+        self.store.insert(&session_key(&value), &claims).await.map_err(|e| AppError::Internal(e.to_string()))?;
+        Ok(self.cookie(value, time::Duration::seconds(self.ttl.num_seconds())))
+    }
+
+    /// Returns the claims of the session whose cookie is `value`, unless it expired.
+    pub async fn resolve(&self, value: &str) -> Result<Option<Claims>, SessionStoreError> {
+        let claims = self.store.get(&session_key(value)).await?;
+        Ok(claims.filter(|claims| expiry(claims) > Utc::now()))
+    }
+
+    /// Ends the session whose cookie `req` carries, if any, and returns the cookie
+    /// that removes it from the browser.
+    pub async fn close(&self, req: &HttpRequest) -> Result<Option<Cookie<'static>>, AppError> {
+        let cookie = match req.cookie(&self.name) {
+            Some(cookie) => cookie,
+            None => return Ok(None),
+        };
+        self.store.remove(&session_key(cookie.value())).await.map_err(|e| AppError::Internal(e.to_string()))?;
+        Ok(Some(self.cookie(String::new(), time::Duration::ZERO)))
+    }
+}
+
+/// Authentication validator accepting either a bearer token, checked by
+/// [`jwt_validator`], or the cookie of a current session when [`CookieSessions`] are
+/// configured.
+///
+/// Session claims go through the same revocation checks as tokens, so revoking a
+/// user's sessions or deactivating them also ends their cookie sessions. A request
+/// with both is judged by its bearer token.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{web, App, HttpResponse};
+/// use actix_web_httpauth::middleware::HttpAuthentication;
+/// use safe_user::cookie_sessions::session_or_bearer;
+///
+/// let app = App::new().service(
+///     web::resource("/me")
+///         .wrap(HttpAuthentication::with_fn(session_or_bearer))
+///         .route(web::get().to(HttpResponse::Ok)),
+/// );
+/// ```
+pub async fn session_or_bearer(req: ServiceRequest, credentials: Option<BearerAuth>) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    if let Some(credentials) = credentials {
+        return jwt_validator(req, credentials).await;
+    }
+
+    let missing = || AppError::auth("invalid_token", "Missing or invalid token.").into();
+    let cookie_sessions = req.app_data::<web::Data<CookieSessions>>().cloned();
+    let cookie = cookie_sessions.as_ref().and_then(|sessions| req.cookie(sessions.cookie_name()));
+    let (sessions, cookie) = match (cookie_sessions, cookie) {
+        (Some(sessions), Some(cookie)) => (sessions, cookie),
+        _ => return Err((missing(), req)),
+    };
+
+    let claims = match sessions.resolve(cookie.value()).await {
+        Ok(Some(claims)) => claims,
+        Ok(None) => return Err((missing(), req)),
+        Err(e) => {
+            eprintln!("Error reading cookie session: {:?}", e);
+            return Err((missing(), req));
+        }
+    };
+    if let Some(pool) = req.app_data::<web::Data<Pool<Mssql>>>() {
+        match sessions::is_current(pool.get_ref(), &claims).await {
+            Ok(true) => {}
+            Ok(false) => return Err((AppError::auth("token_revoked", "Session has been revoked.").into(), req)),
+            Err(e) => {
+                eprintln!("Error checking session: {:?}", e);
+                return Err((missing(), req));
+            }
+        }
+    }
+
+    req.extensions_mut().insert(claims);
+    Ok(req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App, HttpResponse, Responder};
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use crate::extractors::AuthenticatedUser;
+
+    #[derive(Default)]
+    struct MemoryStore(Mutex<HashMap<String, Claims>>);
+
+    #[async_trait]
+    impl SessionStore for MemoryStore {
+        async fn insert(&self, key: &str, claims: &Claims) -> Result<(), SessionStoreError> {
+            self.0.lock().unwrap().insert(key.to_string(), claims.clone());
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Claims>, SessionStoreError> {
+            Ok(self.0.lock().unwrap().get(key).cloned())
+        }
+
+        async fn remove(&self, key: &str) -> Result<(), SessionStoreError> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    async fn whoami(user: AuthenticatedUser) -> impl Responder {
+        HttpResponse::Ok().body(user.sub.clone())
+    }
+
+    /// Checks that a stored session authenticates its cookie until it expires.
+    #[actix_web::test]
+    async fn test_session_cookie_authenticates() {
+        let store = Arc::new(MemoryStore::default());
+        store.insert(&session_key("alive"), &Claims::new("tester", 0, &[])).await.unwrap();
+        let expired = Claims { exp: (Utc::now() - Duration::minutes(1)).timestamp() as usize, ..Claims::new("tester", 0, &[]) };
+        store.insert(&session_key("expired"), &expired).await.unwrap();
+
+        let sessions = CookieSessions::new(store);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(sessions))
+                .service(web::resource("/me").wrap(HttpAuthentication::with_fn(session_or_bearer)).route(web::get().to(whoami)))
+        ).await;
+
+        let req = test::TestRequest::get().uri("/me").cookie(Cookie::new(DEFAULT_COOKIE_NAME, "alive")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, "tester");
+
+        for req in [
+            test::TestRequest::get().uri("/me").cookie(Cookie::new(DEFAULT_COOKIE_NAME, "expired")),
+            test::TestRequest::get().uri("/me").cookie(Cookie::new(DEFAULT_COOKIE_NAME, "forged")),
+            test::TestRequest::get().uri("/me"),
+        ] {
+            assert_eq!(test::call_service(&app, req.to_request()).await.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{Error, HttpResponse};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use crate::config::ConfigError;
+
+/// Methods allowed cross-origin when `CORS_ALLOWED_METHODS` is not set.
+pub const DEFAULT_ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE";
+
+/// Request headers allowed cross-origin when `CORS_ALLOWED_HEADERS` is not set.
+pub const DEFAULT_ALLOWED_HEADERS: &str = "Authorization, Content-Type, X-Tenant-Id, X-Challenge-Response";
+
+/// Response headers readable by browser scripts when `CORS_EXPOSED_HEADERS` is not set.
+pub const DEFAULT_EXPOSED_HEADERS: &str = "Retry-After";
+
+/// Seconds browsers may cache a preflight answer when `CORS_MAX_AGE` is not set.
+pub const DEFAULT_MAX_AGE: u32 = 3600;
+
+/// How strictly cross-origin requests are checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorsMode {
+    /// Only the configured origins, methods and headers are allowed.
+    Strict,
+    /// Every origin is allowed with whatever method and headers it asks for. Meant for
+    /// local development only.
+    Permissive,
+}
+
+/// Which browser origins may call the API, read from the environment:
+///
+/// * `CORS_MODE` - `strict` (default) or `permissive`.
+/// * `CORS_ALLOWED_ORIGINS` - Comma-separated origins such as `https://app.example.com`.
+///   No origin is allowed when unset, so strict mode keeps the API same-origin only.
+/// * `CORS_ALLOWED_METHODS` (default [`DEFAULT_ALLOWED_METHODS`]), `CORS_ALLOWED_HEADERS`
+///   (default [`DEFAULT_ALLOWED_HEADERS`]) and `CORS_EXPOSED_HEADERS` (default
+///   [`DEFAULT_EXPOSED_HEADERS`]) - Comma-separated lists.
+/// * `CORS_MAX_AGE` (default [`DEFAULT_MAX_AGE`]) - Seconds a preflight answer may be cached.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::cors::{CorsConfig, CorsMode};
+///
+/// let config = CorsConfig::from_vars(|name| match name {
+///     "CORS_ALLOWED_ORIGINS" => Some("https://app.example.com, https://admin.example.com/".to_string()),
+///     _ => None,
+/// })
+/// .unwrap();
+///
+/// assert_eq!(config.mode, CorsMode::Strict);
+/// assert!(config.allows_origin("https://admin.example.com"));
+/// assert!(!config.allows_origin("https://evil.example.com"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorsConfig {
+    pub mode: CorsMode,
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: String,
+    pub allowed_headers: String,
+    pub exposed_headers: String,
+    pub max_age: u32,
+}
+
+impl CorsConfig {
+    /// Reads the configuration from the variables returned by `var`.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mode = match var("CORS_MODE").map(|mode| mode.trim().to_lowercase()).as_deref() {
+            None | Some("strict") => CorsMode::Strict,
+            Some("permissive") => CorsMode::Permissive,
+            Some(mode) => return Err(format!("CORS_MODE must be `strict` or `permissive`, got `{}`", mode).into()),
+        };
+
+        let allowed_origins: Vec<String> = var("CORS_ALLOWED_ORIGINS")
+            .map(|origins| list(&origins).map(|origin| origin.trim_end_matches('/').to_lowercase()).collect())
+            .unwrap_or_default();
+        if mode == CorsMode::Strict && allowed_origins.iter().any(|origin| origin == "*") {
+            return Err("CORS_ALLOWED_ORIGINS cannot contain `*` in strict mode, list the origins or set CORS_MODE=permissive".into());
+        }
+        if let Some(origin) = allowed_origins.iter().find(|origin| *origin != "*" && !origin.contains("://")) {
+            return Err(format!("CORS_ALLOWED_ORIGINS must hold origins such as `https://app.example.com`, got `{}`", origin).into());
+        }
+
+        let header_list = |name: &str, default: &str| -> Result<String, ConfigError> {
+            let value = var(name).unwrap_or_else(|| default.to_string());
+            let items: Vec<&str> = list(&value).collect();
+            if let Some(item) = items.iter().find(|item| HeaderName::from_bytes(item.as_bytes()).is_err()) {
+                return Err(format!("{} must be a comma-separated list of names, got `{}`", name, item).into());
+            }
+            Ok(items.join(", "))
+        };
+
+        let max_age = match var("CORS_MAX_AGE") {
+            Some(max_age) => max_age.trim().parse().map_err(|_| format!("CORS_MAX_AGE must be a number of seconds, got `{}`", max_age))?,
+            None => DEFAULT_MAX_AGE,
+        };
+
+        Ok(CorsConfig {
+            mode,
+            allowed_origins,
+            allowed_methods: header_list("CORS_ALLOWED_METHODS", DEFAULT_ALLOWED_METHODS)?.to_uppercase(),
+            allowed_headers: header_list("CORS_ALLOWED_HEADERS", DEFAULT_ALLOWED_HEADERS)?,
+            exposed_headers: header_list("CORS_EXPOSED_HEADERS", DEFAULT_EXPOSED_HEADERS)?,
+            max_age,
+        })
+    }
+
+    /// Returns `true` if requests from `origin` may be read by the browser.
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.mode == CorsMode::Permissive || self.allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+
+    /// Returns `true` if a preflight may announce `method`.
+    fn allows_method(&self, method: &str) -> bool {
+        self.mode == CorsMode::Permissive || list(&self.allowed_methods).any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+}
+
+/// Splits a comma-separated list into its non-empty, trimmed items.
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+fn set(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+/// Middleware applying a [`CorsConfig`]: it answers preflight requests from allowed
+/// origins and adds the CORS headers to their other responses.
+///
+/// Requests from origins that are not allowed are passed through without CORS
+/// headers, so the browser refuses to hand the response to the calling page.
+/// Register it outside the authentication middleware, so preflights (which carry no
+/// token) and `401` answers are handled too.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::App;
+/// use safe_user::cors::{Cors, CorsConfig};
+///
+/// let config = CorsConfig::from_vars(|name| match name {
+///     "CORS_ALLOWED_ORIGINS" => Some("https://app.example.com".to_string()),
+///     _ => None,
+/// })
+/// .unwrap();
+/// let app = App::new().wrap(Cors::new(config));
+/// ```
+#[derive(Clone)]
+pub struct Cors {
+    config: Arc<CorsConfig>,
+}
+
+impl Cors {
+    /// Creates the middleware for `config`.
+    pub fn new(config: CorsConfig) -> Self {
+        Cors { config: Arc::new(config) }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Cors
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = CorsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CorsMiddleware {
+            service: Rc::new(service),
+            config: Arc::clone(&self.config),
+        }))
+    }
+}
+
+/// The service produced by [`Cors`].
+pub struct CorsMiddleware<S> {
+    service: Rc<S>,
+    config: Arc<CorsConfig>,
+}
+
+impl<S, B> Service<ServiceRequest> for CorsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let config = Arc::clone(&self.config);
+
+        Box::pin(async move {
+            let origin = req
+                .headers()
+                .get(header::ORIGIN)
+                .and_then(|origin| origin.to_str().ok())
+                .filter(|origin| config.allows_origin(origin))
+                .map(str::to_string);
+            let origin = match origin {
+                Some(origin) => origin,
+                None => return service.call(req).await.map(ServiceResponse::map_into_left_body),
+            };
+
+            let requested_method = req
+                .headers()
+                .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+                .and_then(|method| method.to_str().ok())
+                .map(str::to_string);
+            if req.method() == Method::OPTIONS {
+                if let Some(method) = requested_method.filter(|method| config.allows_method(method)) {
+                    let mut response = HttpResponse::NoContent().finish();
+                    let headers = response.headers_mut();
+                    set(headers, header::ACCESS_CONTROL_ALLOW_ORIGIN, &origin);
+                    set(headers, header::VARY, "Origin");
+                    set(headers, header::ACCESS_CONTROL_MAX_AGE, &config.max_age.to_string());
+                    match config.mode {
+                        CorsMode::Strict => {
+                            set(headers, header::ACCESS_CONTROL_ALLOW_METHODS, &config.allowed_methods);
+                            set(headers, header::ACCESS_CONTROL_ALLOW_HEADERS, &config.allowed_headers);
+                        }
+                        CorsMode::Permissive => {
+                            let requested_headers = req
+                                .headers()
+                                .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+                                .and_then(|headers| headers.to_str().ok())
+                                .unwrap_or("")
+                                .to_string();
+                            set(headers, header::ACCESS_CONTROL_ALLOW_METHODS, &method);
+                            set(headers, header::ACCESS_CONTROL_ALLOW_HEADERS, &requested_headers);
+                        }
+                    }
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            }
+
+            let mut response = service.call(req).await?;
+            let headers = response.headers_mut();
+            set(headers, header::ACCESS_CONTROL_ALLOW_ORIGIN, &origin);
+            set(headers, header::ACCESS_CONTROL_EXPOSE_HEADERS, &config.exposed_headers);
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, web, App};
+    use std::collections::HashMap;
+
+    fn config(vars: &[(&str, &str)]) -> Result<CorsConfig, ConfigError> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        CorsConfig::from_vars(|name| vars.get(name).cloned())
+    }
+
+    fn preflight(origin: &str) -> actix_web::test::TestRequest {
+        actix_web::test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/users")
+            .insert_header((header::ORIGIN, origin))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "PUT"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization, x-trace-id"))
+    }
+
+    #[test]
+    fn test_rejects_invalid_configuration() {
+        assert!(config(&[("CORS_ALLOWED_ORIGINS", "*")]).is_err());
+        assert!(config(&[("CORS_ALLOWED_ORIGINS", "app.example.com")]).is_err());
+        assert!(config(&[("CORS_MODE", "open")]).is_err());
+        assert!(config(&[("CORS_MAX_AGE", "an hour")]).is_err());
+        assert!(config(&[("CORS_MODE", "permissive"), ("CORS_ALLOWED_ORIGINS", "*")]).is_ok());
+        assert!(config(&[]).unwrap().allowed_origins.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_strict_mode_answers_allowed_origins_only() {
+        let config = config(&[("CORS_ALLOWED_ORIGINS", "https://app.example.com")]).unwrap();
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(Cors::new(config))
+                .route("/users", web::get().to(HttpResponse::Ok))
+        ).await;
+
+        let resp = actix_web::test::call_service(&app, preflight("https://app.example.com").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.example.com");
+        assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap(), DEFAULT_ALLOWED_HEADERS);
+
+        let resp = actix_web::test::call_service(&app, preflight("https://evil.example.com").to_request()).await;
+        assert!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+        let req = actix_web::test::TestRequest::get().uri("/users").insert_header((header::ORIGIN, "https://app.example.com")).to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(header::ACCESS_CONTROL_EXPOSE_HEADERS).unwrap(), "Retry-After");
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "Origin");
+    }
+
+    #[actix_web::test]
+    async fn test_permissive_mode_reflects_the_request() {
+        let app = actix_web::test::init_service(
+            App::new().wrap(Cors::new(config(&[("CORS_MODE", "permissive")]).unwrap()))
+        ).await;
+
+        let resp = actix_web::test::call_service(&app, preflight("http://localhost:3000").to_request()).await;
+        assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "http://localhost:3000");
+        assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_METHODS).unwrap(), "PUT");
+        assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap(), "authorization, x-trace-id");
+    }
+}
@@ -0,0 +1,208 @@
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{FromRow, Mssql, Pool, Transaction};
+use crate::approvals::{ACTIVE, DISABLED};
+use crate::audit;
+use crate::config::ConfigError;
+use crate::outbox;
+
+/// What happens to the resources a user owns when their account stops being usable,
+/// because an administrator disabled it or its `expires_at` passed.
+///
+/// Read from `DEACTIVATION_CASCADE`, a comma-separated list of:
+///
+/// * `revoke_sessions` - Invalidates the user's access tokens and revokes their refresh tokens.
+/// * `remove_roles` - Removes the user's role assignments, so a reactivated account starts without privileges.
+///
+/// `none` keeps everything. When unset only `revoke_sessions` applies. Access tokens
+/// of deactivated users are rejected either way, since only [`ACTIVE`] users pass
+/// [`is_current`](crate::sessions::is_current); revoking them also ends sessions that
+/// are reactivated later. Deleting a user always removes their roles and refresh tokens.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::deactivation::CascadePolicy;
+///
+/// let policy = CascadePolicy::from_vars(|name| match name {
+///     "DEACTIVATION_CASCADE" => Some("revoke_sessions, remove_roles".to_string()),
+///     _ => None,
+/// })
+/// .unwrap();
+///
+/// assert!(policy.revoke_sessions && policy.remove_roles);
+/// assert_eq!(CascadePolicy::from_vars(|_| None).unwrap(), CascadePolicy::default());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CascadePolicy {
+    pub revoke_sessions: bool,
+    pub remove_roles: bool,
+}
+
+impl Default for CascadePolicy {
+    fn default() -> Self {
+        CascadePolicy {
+            revoke_sessions: true,
+            remove_roles: false,
+        }
+    }
+}
+
+impl CascadePolicy {
+    /// Reads the policy from the variables returned by `var`.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let steps = match var("DEACTIVATION_CASCADE") {
+            Some(steps) => steps,
+            None => return Ok(CascadePolicy::default()),
+        };
+
+        let mut policy = CascadePolicy {
+            revoke_sessions: false,
+            remove_roles: false,
+        };
+        for step in steps.split(',').map(|step| step.trim().to_lowercase()).filter(|step| !step.is_empty()) {
+            match step.as_str() {
+                "revoke_sessions" => policy.revoke_sessions = true,
+                "remove_roles" => policy.remove_roles = true,
+                "none" => {}
+                _ => return Err(format!("DEACTIVATION_CASCADE has an unknown step `{}`, expected `revoke_sessions`, `remove_roles` or `none`", step).into()),
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Returns the names of the steps this policy applies, as recorded in the audit log.
+    pub fn steps(&self) -> Vec<&'static str> {
+        let mut steps = Vec::new();
+        if self.revoke_sessions {
+            steps.push("revoke_sessions");
+        }
+        if self.remove_roles {
+            steps.push("remove_roles");
+        }
+        steps
+    }
+
+    /// Applies the policy to the resources of `user_id` inside the caller's transaction,
+    /// so they only change if the deactivation itself commits, and returns what changed.
+    pub async fn apply(&self, tx: &mut Transaction<'_, Mssql>, user_id: &str) -> Result<CascadeEffect, sqlx::Error> {
+        let mut effect = CascadeEffect::default();
+        if self.revoke_sessions {
+            sqlx::query!("UPDATE [users] SET TokenVersion = TokenVersion + 1 WHERE id = @p1", user_id)
+                .execute(&mut *tx)
+                .await?;
+            effect.refresh_tokens_revoked = sqlx::query!(
+                "UPDATE [refresh_tokens] SET RevokedAt = SYSUTCDATETIME() WHERE UserId = @p1 AND RevokedAt IS NULL",
+                user_id
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        if self.remove_roles {
+            effect.roles_removed = sqlx::query!("DELETE FROM [user_roles] WHERE UserId = @p1", user_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+        Ok(effect)
+    }
+}
+
+/// What a [`CascadePolicy`] changed for one user.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CascadeEffect {
+    pub refresh_tokens_revoked: u64,
+    pub roles_removed: u64,
+}
+
+/// The contact details of a user returned by [`disable_user`].
+#[derive(Debug, FromRow)]
+struct DisabledUser {
+    email: String,
+    name: String,
+}
+
+/// Disables an active account and applies `policy` to its resources.
+///
+/// The status change, the cascade, the `user.disabled` event and the audit entry are
+/// written in one transaction, so either all of them happen or none does. With
+/// `dry_run` the transaction is rolled back, so only its effect is reported.
+///
+/// # Arguments
+///
+/// * `pool` - The database connection pool.
+/// * `policy` - What happens to the user's sessions and roles.
+/// * `user_id` - The user to disable.
+/// * `actor_id` - The administrator disabling the account.
+/// * `dry_run` - Whether to roll the change back.
+///
+/// # Returns
+///
+/// * `Result<Option<CascadeEffect>, sqlx::Error>` - What the cascade changed, or `Ok(None)`
+///   if no active user has that id.
+pub async fn disable_user(pool: &Pool<Mssql>, policy: &CascadePolicy, user_id: &str, actor_id: Option<&str>, dry_run: bool) -> Result<Option<CascadeEffect>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let disabled = sqlx::query_as!(
+        DisabledUser,
+        r#"
+        UPDATE [users]
+        SET Status = @p2, UpdatedAt = SYSUTCDATETIME()
+        OUTPUT
+            INSERTED.Email AS "email!",
+            INSERTED.Name  AS "name!"
+        WHERE id = @p1 AND Status = @p3
+        "#,
+        user_id,
+        DISABLED,
+        ACTIVE
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+
+    let user = match disabled {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    let effect = policy.apply(&mut tx, user_id).await?;
+
+    let payload = json!({ "id": user_id, "email": user.email, "name": user.name });
+    outbox::enqueue(&mut tx, outbox::USER_DISABLED, user_id, &payload).await?;
+    let details = json!({ "cascade": policy.steps() });
+    audit::record(&mut tx, user_id, audit::ACCOUNT_DISABLED, actor_id, Some(&details)).await?;
+
+    if dry_run {
+        tx.rollback().await?;
+    } else {
+        tx.commit().await?;
+    }
+    Ok(Some(effect))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(steps: &str) -> Result<CascadePolicy, ConfigError> {
+        CascadePolicy::from_vars(|name| match name {
+            "DEACTIVATION_CASCADE" => Some(steps.to_string()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_policy_parses_steps() {
+        assert_eq!(policy("none").unwrap().steps(), Vec::<&str>::new());
+        assert_eq!(policy("remove_roles").unwrap().steps(), vec!["remove_roles"]);
+        assert_eq!(policy("Remove_Roles,revoke_sessions").unwrap().steps(), vec!["revoke_sessions", "remove_roles"]);
+        assert!(policy("pause_webhooks").is_err());
+    }
+
+    #[test]
+    fn test_cascade_effect_reports_counts() {
+        let effect = CascadeEffect { refresh_tokens_revoked: 2, ..CascadeEffect::default() };
+        assert_eq!(serde_json::to_value(effect).unwrap(), json!({ "refresh_tokens_revoked": 2, "roles_removed": 0 }));
+    }
+}
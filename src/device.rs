@@ -0,0 +1,521 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use rand::{Rng, RngCore};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, Mssql, Pool};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use crate::audit;
+use crate::challenge::{self, ChallengeAction};
+use crate::db::is_unique_violation;
+use crate::error::AppError;
+use crate::handlers::{authenticate_with_lockout, forget_failures, start_session};
+use crate::mfa;
+use crate::oidc::{self, Issuer};
+use crate::repository::UserRepository;
+use crate::tenancy::TenantKeys;
+
+/// `grant_type` of the token requests polling for a device authorization (RFC 8628).
+pub const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Path of the page where users enter the code shown by their device.
+pub const VERIFICATION_PATH: &str = "/device";
+
+/// Seconds a device code stays valid.
+pub const DEVICE_CODE_TTL_SECONDS: i64 = 600;
+
+/// Seconds a device must wait between two polls; each poll made sooner adds five.
+pub const DEFAULT_POLL_INTERVAL_SECONDS: i32 = 5;
+
+/// Letters of user codes: consonants only, so that no word can be spelled and
+/// no letter is mistaken for a digit.
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+/// Returns a new user code such as `WDJB-MJHT`.
+pub fn generate_user_code() -> String {
+    let mut rng = rand::thread_rng();
+    let letters: String = (0..8).map(|_| USER_CODE_ALPHABET[rng.gen_range(0..USER_CODE_ALPHABET.len())] as char).collect();
+    format!("{}-{}", &letters[..4], &letters[4..])
+}
+
+/// Returns a user code as typed by a person in its canonical form, or `None` if it
+/// cannot be one.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::device::normalize_user_code;
+///
+/// assert_eq!(normalize_user_code(" wdjb mjht ").as_deref(), Some("WDJB-MJHT"));
+/// assert_eq!(normalize_user_code("WDJB-MJHT").as_deref(), Some("WDJB-MJHT"));
+/// assert_eq!(normalize_user_code("WDJB-MJH"), None);
+/// assert_eq!(normalize_user_code("AEIO-U123"), None);
+/// ```
+pub fn normalize_user_code(input: &str) -> Option<String> {
+    let letters: String = input.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_uppercase()).collect();
+    if letters.len() != 8 || !letters.bytes().all(|c| USER_CODE_ALPHABET.contains(&c)) {
+        return None;
+    }
+    Some(format!("{}-{}", &letters[..4], &letters[4..]))
+}
+
+fn hash_device_code(device_code: &str) -> String {
+    format!("{:x}", Sha256::digest(device_code.as_bytes()))
+}
+
+/// Form body of `POST /oauth/device/code`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeviceCodeRequest {
+    pub client_id: String,
+    /// Accepted for compatibility; tokens always carry the user's roles.
+    #[serde(default)]
+    pub scope: Option<String>,
+}
+
+/// Response of `POST /oauth/device/code`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DeviceCodeResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: String,
+    pub expires_in: i64,
+    pub interval: i32,
+}
+
+/// Form body of `POST /oauth/token`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub device_code: Option<String>,
+    pub client_id: Option<String>,
+}
+
+/// What a device learns when it polls for its authorization.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PollOutcome {
+    /// The user has not entered the code yet.
+    Pending,
+    /// The device polled before its interval elapsed, which is now five seconds longer.
+    SlowDown,
+    /// The code expired before the user approved it.
+    Expired,
+    /// The user refused the device.
+    Denied,
+    /// The user with this id approved the device. The code cannot be redeemed again.
+    Approved(String),
+    /// The code is unknown, belongs to another client or was already redeemed.
+    Invalid,
+}
+
+/// The state of an authorization read by [`poll`].
+#[derive(Debug, FromRow)]
+struct PolledAuthorization {
+    status: String,
+    client_id: String,
+    user_id: Option<String>,
+    expired: bool,
+    too_fast: bool,
+}
+
+/// Stores a new pending authorization for `client_id`, returning its device code
+/// and user code. Only a digest of the device code is stored.
+pub async fn create_authorization(pool: &Pool<Mssql>, client_id: &str) -> Result<(String, String), sqlx::Error> {
+    sqlx::query!("DELETE FROM [device_authorizations] WHERE ExpiresAt < DATEADD(DAY, -1, SYSUTCDATETIME())")
+        .execute(pool)
+        .await?;
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let device_code = hex::encode(bytes);
+
+    // The user code only has to be unique among pending authorizations, which a
+    // filtered index enforces; a clash is retried with a new code.
+    let mut attempts = 0;
+    loop {
+        let user_code = generate_user_code();
+        let inserted = sqlx::query!(
+            r#"
+            INSERT INTO [device_authorizations] (DeviceCodeHash, UserCode, ClientId, PollInterval, ExpiresAt)
+            VALUES (@p1, @p2, @p3, @p4, DATEADD(SECOND, @p5, SYSUTCDATETIME()))
+            "#,
+            hash_device_code(&device_code),
+            &user_code,
+            client_id,
+            DEFAULT_POLL_INTERVAL_SECONDS,
+            DEVICE_CODE_TTL_SECONDS
+        )
+        .execute(pool)
+        .await;
+        match inserted {
+            Ok(_) => return Ok((device_code, user_code)),
+            Err(e) if is_unique_violation(&e) && attempts < 3 => attempts += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Approves, with the id of the approving user, or denies, with `None`, the pending
+/// authorization showing `user_code`.
+///
+/// # Returns
+///
+/// * `Result<Option<String>, sqlx::Error>` - The client id of the device, or `None` if no
+///   pending authorization that has not expired shows that code.
+pub async fn decide(pool: &Pool<Mssql>, user_code: &str, user_id: Option<&str>) -> Result<Option<String>, sqlx::Error> {
+    let status = if user_id.is_some() { "approved" } else { "denied" };
+    sqlx::query_scalar!(
+        r#"
+        UPDATE [device_authorizations]
+        SET Status = @p2, UserId = @p3
+        OUTPUT INSERTED.ClientId AS "client_id!: String"
+        WHERE UserCode = @p1 AND Status = 'pending' AND ExpiresAt > SYSUTCDATETIME()
+        "#,
+        user_code,
+        status,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Returns `true` if a pending authorization that has not expired shows `user_code`.
+pub async fn is_pending(pool: &Pool<Mssql>, user_code: &str) -> Result<bool, sqlx::Error> {
+    let pending = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!: i32" FROM [device_authorizations] WHERE UserCode = @p1 AND Status = 'pending' AND ExpiresAt > SYSUTCDATETIME()"#,
+        user_code
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(pending > 0)
+}
+
+/// Records a poll by `client_id` for the authorization of `device_code`.
+pub async fn poll(pool: &Pool<Mssql>, device_code: &str, client_id: Option<&str>) -> Result<PollOutcome, sqlx::Error> {
+    let hash = hash_device_code(device_code);
+    let mut tx = pool.begin().await?;
+
+    let row = sqlx::query_as!(
+        PolledAuthorization,
+        r#"
+        SELECT
+            Status                           AS "status!: String",
+            ClientId                         AS "client_id!: String",
+            CAST(UserId AS VARCHAR(36))      AS "user_id?: String",
+            CAST(CASE WHEN ExpiresAt <= SYSUTCDATETIME() THEN 1 ELSE 0 END AS BIT) AS "expired!: bool",
+            CAST(CASE WHEN LastPolledAt > DATEADD(SECOND, -PollInterval, SYSUTCDATETIME()) THEN 1 ELSE 0 END AS BIT) AS "too_fast!: bool"
+        FROM [device_authorizations] WITH (UPDLOCK)
+        WHERE DeviceCodeHash = @p1
+        "#,
+        &hash
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+
+    let row = match row {
+        Some(row) if client_id.is_none_or(|client_id| client_id == row.client_id) => row,
+        _ => return Ok(PollOutcome::Invalid),
+    };
+    let outcome = match (row.status.as_str(), row.user_id) {
+        _ if row.expired => PollOutcome::Expired,
+        ("denied", _) => PollOutcome::Denied,
+        ("approved", Some(user_id)) => {
+            sqlx::query!("UPDATE [device_authorizations] SET Status = 'used' WHERE DeviceCodeHash = @p1", &hash)
+                .execute(&mut tx)
+                .await?;
+            PollOutcome::Approved(user_id.to_lowercase())
+        }
+        ("pending", _) => {
+            sqlx::query!(
+                r#"
+                UPDATE [device_authorizations]
+                SET LastPolledAt = SYSUTCDATETIME(), PollInterval = PollInterval + @p2
+                WHERE DeviceCodeHash = @p1
+                "#,
+                &hash,
+                if row.too_fast { 5 } else { 0 }
+            )
+            .execute(&mut tx)
+            .await?;
+            if row.too_fast { PollOutcome::SlowDown } else { PollOutcome::Pending }
+        }
+        _ => PollOutcome::Invalid,
+    };
+
+    tx.commit().await?;
+    Ok(outcome)
+}
+
+/// An error response in the format of RFC 6749, section 5.2.
+fn oauth_error(status: StatusCode, error: &str, description: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .insert_header(("Cache-Control", "no-store"))
+        .json(json!({ "error": error, "error_description": description }))
+}
+
+/// Starts a device authorization: the device shows `user_code` and `verification_uri`
+/// to its user, then polls `/oauth/token` with `device_code`.
+///
+/// # Arguments
+///
+/// * `req` - The request, whose scheme and host give the verification URI when `issuer` is not set.
+/// * `pool` - A connection pool to the database.
+/// * `issuer` - The public base URL configured with `OIDC_ISSUER`, if any.
+/// * `form` - The `client_id` of the device.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - The codes, or an `unsupported_grant_type` OAuth error in
+///   multi-tenant mode, where devices cannot name their tenant.
+#[utoipa::path(
+    post,
+    path = "/oauth/device/code",
+    tag = "auth",
+    request_body(content = DeviceCodeRequest, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "The device and user codes", body = DeviceCodeResponse),
+        (status = 400, description = "An OAuth error: invalid_request or unsupported_grant_type", body = Object),
+    )
+)]
+pub async fn request_device_code(req: HttpRequest, pool: web::Data<Pool<Mssql>>, issuer: Option<web::Data<Issuer>>, form: web::Form<DeviceCodeRequest>) -> Result<HttpResponse, AppError> {
+    if req.app_data::<web::Data<TenantKeys>>().is_some() {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "unsupported_grant_type", "The device flow is not available in multi-tenant mode."));
+    }
+    if form.client_id.trim().is_empty() || form.client_id.len() > 100 {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_request", "client_id must be 1 to 100 characters."));
+    }
+
+    let (device_code, user_code) = create_authorization(pool.get_ref(), &form.client_id).await?;
+    let verification_uri = format!("{}{}", oidc::issuer_for(&req, issuer.as_ref().map(|issuer| issuer.get_ref())), VERIFICATION_PATH);
+    Ok(HttpResponse::Ok().insert_header(("Cache-Control", "no-store")).json(DeviceCodeResponse {
+        verification_uri_complete: format!("{}?user_code={}", verification_uri, user_code),
+        verification_uri,
+        device_code,
+        user_code,
+        expires_in: DEVICE_CODE_TTL_SECONDS,
+        interval: DEFAULT_POLL_INTERVAL_SECONDS,
+    }))
+}
+
+/// The OAuth token endpoint, which devices poll with their device code.
+///
+/// Until the user decides, polls get `authorization_pending`, or `slow_down` when
+/// made too often. Once approved, the first poll gets an access token and a refresh
+/// token bound to the device, exactly as from `/login`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - The tokens, or an RFC 6749 error such as
+///   `authorization_pending`, `slow_down`, `expired_token`, `access_denied`, `invalid_grant`
+///   or `unsupported_grant_type`.
+#[utoipa::path(
+    post,
+    path = "/oauth/token",
+    tag = "auth",
+    request_body(content = TokenRequest, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "The access and refresh tokens", body = TokenResponse),
+        (status = 400, description = "An OAuth error such as authorization_pending or slow_down", body = Object),
+    )
+)]
+pub async fn oauth_token(req: HttpRequest, pool: web::Data<Pool<Mssql>>, form: web::Form<TokenRequest>) -> Result<HttpResponse, AppError> {
+    if form.grant_type != DEVICE_CODE_GRANT || req.app_data::<web::Data<TenantKeys>>().is_some() {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "unsupported_grant_type", "Only the device_code grant is supported here."));
+    }
+    let device_code = match &form.device_code {
+        Some(device_code) => device_code,
+        None => return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_request", "device_code is required.")),
+    };
+
+    let user_id = match poll(pool.get_ref(), device_code, form.client_id.as_deref()).await? {
+        PollOutcome::Approved(user_id) => user_id,
+        PollOutcome::Pending => return Ok(oauth_error(StatusCode::BAD_REQUEST, "authorization_pending", "The user has not entered the code yet.")),
+        PollOutcome::SlowDown => return Ok(oauth_error(StatusCode::BAD_REQUEST, "slow_down", "Polling too often; wait five more seconds between polls.")),
+        PollOutcome::Expired => return Ok(oauth_error(StatusCode::BAD_REQUEST, "expired_token", "The device code has expired.")),
+        PollOutcome::Denied => return Ok(oauth_error(StatusCode::BAD_REQUEST, "access_denied", "The user denied the request.")),
+        PollOutcome::Invalid => return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "Unknown or already used device code.")),
+    };
+
+    let details = json!({ "method": "device_code", "client_id": form.client_id });
+    audit::record(pool.get_ref(), &user_id, audit::LOGIN, Some(&user_id), Some(&details)).await?;
+    let tokens = start_session(&req, pool.get_ref(), &user_id, None).await?;
+    Ok(HttpResponse::Ok().insert_header(("Cache-Control", "no-store")).json(tokens))
+}
+
+/// Query of `GET /device`, prefilled from `verification_uri_complete`.
+#[derive(Debug, Deserialize)]
+pub struct DevicePageQuery {
+    pub user_code: Option<String>,
+}
+
+/// Form posted by the `/device` page.
+#[derive(Debug, Deserialize)]
+pub struct DeviceVerification {
+    pub user_code: String,
+    pub email: String,
+    pub password: SecretString,
+    /// `approve` or `deny`.
+    pub action: String,
+}
+
+/// Serves the page where users enter the code shown by their device and sign in to
+/// approve or deny it.
+pub async fn device_page(req: HttpRequest, query: web::Query<DevicePageQuery>) -> HttpResponse {
+    render_page(&req, StatusCode::OK, query.user_code.as_deref().unwrap_or(""), None)
+}
+
+/// Approves or denies a device after checking the user's email and password as
+/// `/login` does, lockout included. The code is checked first, so the page cannot be
+/// used to try passwords without one.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - A page telling the outcome: `200 OK`, `401 Unauthorized`
+///   for refused credentials, `423 Locked` for a locked-out email or client, or `400 Bad
+///   Request` for a malformed, unknown or expired code.
+pub async fn verify_device(req: HttpRequest, pool: web::Data<Pool<Mssql>>, users: web::Data<Arc<dyn UserRepository>>, form: web::Form<DeviceVerification>) -> Result<HttpResponse, AppError> {
+    const UNKNOWN_CODE: &str = "That code is unknown or has expired. Request a new one on your device.";
+    let form = form.into_inner();
+    let user_code = match normalize_user_code(&form.user_code) {
+        Some(user_code) => user_code,
+        None => return Ok(render_page(&req, StatusCode::BAD_REQUEST, &form.user_code, Some("That code is not valid. Check it on your device."))),
+    };
+    if !is_pending(pool.get_ref(), &user_code).await? {
+        return Ok(render_page(&req, StatusCode::BAD_REQUEST, &user_code, Some(UNKNOWN_CODE)));
+    }
+
+    let user_id = match authenticate_with_lockout(&req, users.get_ref().as_ref(), pool.get_ref(), &form.email, form.password.expose_secret()).await {
+        Ok(user) => user.id,
+        Err(e @ (AppError::Auth { .. } | AppError::PasswordExpired)) => return Ok(render_page(&req, StatusCode::UNAUTHORIZED, &user_code, Some(&e.body().message))),
+        Err(e @ AppError::LockedOut(_)) => return Ok(render_page(&req, StatusCode::LOCKED, &user_code, Some(&e.body().message))),
+        Err(e) => return Err(e),
+    };
+    // The page has no step for a second factor, so it must not become a way around it.
+    if mfa::is_enabled(pool.get_ref(), &user_id).await? {
+        return Ok(render_page(&req, StatusCode::UNAUTHORIZED, &user_code, Some("Accounts with two-factor authentication cannot approve devices.")));
+    }
+    forget_failures(&req, pool.get_ref(), &form.email).await?;
+
+    let approve = form.action == "approve";
+    let client_id = match decide(pool.get_ref(), &user_code, approve.then_some(user_id.as_str())).await? {
+        Some(client_id) => client_id,
+        None => return Ok(render_page(&req, StatusCode::BAD_REQUEST, &user_code, Some(UNKNOWN_CODE))),
+    };
+
+    if approve {
+        audit::record(pool.get_ref(), &user_id, audit::DEVICE_AUTHORIZED, Some(&user_id), Some(&json!({ "client_id": client_id }))).await?;
+    }
+    let message = if approve {
+        "Your device is signed in. You can return to it now."
+    } else {
+        "The device was denied. It will not be signed in."
+    };
+    Ok(HttpResponse::Ok().content_type("text/html; charset=utf-8").body(RESULT_HTML.replace("{message}", message)))
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+fn render_page(req: &HttpRequest, status: StatusCode, user_code: &str, error: Option<&str>) -> HttpResponse {
+    let error = error.map(|error| format!("<p class=\"error\">{}</p>", escape_html(error))).unwrap_or_default();
+    // The code comes from the query string, so it goes in last: placeholders it
+    // spells out must not be filled in.
+    HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .insert_header(("Cache-Control", "no-store"))
+        .body(
+            DEVICE_HTML
+                .replace("{challenge}", &challenge::form_widget(req, ChallengeAction::Login))
+                .replace("{error}", &error)
+                .replace("{user_code}", &escape_html(user_code)),
+        )
+}
+
+const DEVICE_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="utf-8">
+    <title>Sign in a device</title>
+</head>
+<body>
+    <h1>Sign in a device</h1>
+    {error}
+    <form method="post" action="/device">
+        <p><label>Code shown on your device <input name="user_code" value="{user_code}" autocomplete="off" required></label></p>
+        <p><label>Email <input name="email" type="email" autocomplete="username" required></label></p>
+        <p><label>Password <input name="password" type="password" autocomplete="current-password" required></label></p>
+        {challenge}
+        <p>
+            <button name="action" value="approve">Approve</button>
+            <button name="action" value="deny">Deny</button>
+        </p>
+    </form>
+</body>
+</html>
+"#;
+
+const RESULT_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="utf-8">
+    <title>Sign in a device</title>
+</head>
+<body>
+    <h1>Sign in a device</h1>
+    <p>{message}</p>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_user_codes_are_canonical() {
+        for _ in 0..100 {
+            let code = generate_user_code();
+            assert_eq!(normalize_user_code(&code.to_lowercase()), Some(code));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_device_page_escapes_the_code() {
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let resp = device_page(req, web::Query(DevicePageQuery { user_code: Some("\"><script>".to_string()) })).await;
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("value=\"&quot;&gt;&lt;script&gt;\""));
+        assert!(!body.contains("<script>"));
+    }
+
+    #[actix_web::test]
+    async fn test_device_page_presents_the_login_challenge() {
+        let pow = challenge::ProofOfWork::new(b"key".to_vec(), 4);
+        let req = actix_web::test::TestRequest::default()
+            .app_data(web::Data::new(challenge::Challenges::new(Arc::new(pow), [ChallengeAction::Login])))
+            .to_http_request();
+        let resp = device_page(req, web::Query(DevicePageQuery { user_code: None })).await;
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<input type=\"hidden\" name=\"challenge_response\" data-challenge=\""));
+        assert!(body.contains("data-difficulty=\"4\""));
+    }
+
+    #[actix_web::test]
+    async fn test_device_page_does_not_expand_placeholders_in_the_code() {
+        let pow = challenge::ProofOfWork::new(b"key".to_vec(), 4);
+        let req = actix_web::test::TestRequest::default()
+            .app_data(web::Data::new(challenge::Challenges::new(Arc::new(pow), [ChallengeAction::Login])))
+            .to_http_request();
+        let resp = device_page(req, web::Query(DevicePageQuery { user_code: Some("{challenge}".to_string()) })).await;
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("value=\"{challenge}\""));
+        assert_eq!(body.matches("name=\"challenge_response\"").count(), 1);
+    }
+}
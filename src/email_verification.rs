@@ -0,0 +1,173 @@
+use actix_web::{web, HttpResponse};
+use chrono::{Duration, Utc};
+use jsonwebtoken::errors::Error as JwtError;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{Mssql, Pool};
+use std::env;
+use utoipa::IntoParams;
+use crate::audit;
+use crate::auth::derived_key;
+use crate::error::AppError;
+
+/// How long a verification link stays valid, in hours.
+pub const VERIFICATION_TOKEN_TTL_HOURS: i64 = 48;
+
+/// The `purpose` claim of verification tokens, also used to derive their signing key.
+const PURPOSE: &str = "email_verification";
+
+/// Returns `true` if `EMAIL_VERIFICATION_REQUIRED` is enabled, in which case users
+/// cannot log in before verifying their email address.
+pub fn verification_required() -> bool {
+    env::var("EMAIL_VERIFICATION_REQUIRED")
+        .map(|value| matches!(value.to_lowercase().as_str(), "true" | "1" | "yes"))
+        .unwrap_or(false)
+}
+
+/// The claims of an email verification token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerificationClaims {
+    /// The id of the user.
+    pub sub: String,
+    /// The address being verified; the token stops working if the user's email changes.
+    pub email: String,
+    pub exp: usize,
+    purpose: String,
+}
+
+/// Signs a token proving that whoever holds it received mail at `email`.
+///
+/// Tokens are HS256 JWTs signed with a key derived from the token derivation secret, so
+/// they cannot be used as access tokens and stop working if the secret changes.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::email_verification::{issue_token, verify_token};
+///
+/// safe_user::auth::set_token_derivation_secret(secrecy::SecretString::new("0123456789abcdef0123456789abcdef".to_string()));
+/// let token = issue_token("813b6b04-dfbb-4eed-b820-2372216a2367", "ana@example.com").unwrap();
+/// assert_eq!(verify_token(&token).unwrap().email, "ana@example.com");
+/// ```
+pub fn issue_token(user_id: &str, email: &str) -> Result<String, JwtError> {
+    let claims = VerificationClaims {
+        sub: user_id.to_string(),
+        email: email.to_string(),
+        exp: (Utc::now() + Duration::hours(VERIFICATION_TOKEN_TTL_HOURS)).timestamp() as usize,
+        purpose: PURPOSE.to_string(),
+    };
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(&derived_key(PURPOSE)))
+}
+
+/// Checks the signature and expiration of a token issued by [`issue_token`].
+pub fn verify_token(token: &str) -> Result<VerificationClaims, JwtError> {
+    let claims = decode::<VerificationClaims>(token, &DecodingKey::from_secret(&derived_key(PURPOSE)), &Validation::default())?.claims;
+    if claims.purpose != PURPOSE {
+        return Err(jsonwebtoken::errors::ErrorKind::InvalidToken.into());
+    }
+    Ok(claims)
+}
+
+/// Returns the digest of a verification token stored in `[users].[EmailVerificationHash]`,
+/// which only lets the latest token issued to a user be used, and only once.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Marks the email of the token's user as verified.
+///
+/// # Returns
+///
+/// * `Result<bool, sqlx::Error>` - `Ok(false)` if the user does not exist, their email
+///   changed since the token was issued, or the token was already used.
+pub async fn mark_verified(pool: &Pool<Mssql>, claims: &VerificationClaims, token: &str) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let result = sqlx::query!(
+        r#"
+        UPDATE [users]
+        SET EmailVerified = 1, EmailVerificationHash = NULL, UpdatedAt = SYSUTCDATETIME()
+        WHERE id = @p1 AND Email = @p2 AND EmailVerificationHash = @p3
+        "#,
+        &claims.sub,
+        &claims.email,
+        hash_token(token)
+    )
+    .execute(&mut tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    audit::record(&mut tx, &claims.sub, audit::EMAIL_VERIFIED, Some(&claims.sub), None).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Query parameters of `GET /verify_email`.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct VerifyEmailQuery {
+    /// The token from the verification link.
+    pub token: String,
+}
+
+/// Verifies a user's email address with the token sent to them after registering.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `query` - The verification token.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `200 OK`, or `400 Bad Request` with the
+///   `invalid_verification_token` code if the token is malformed, expired or already used.
+#[utoipa::path(
+    get,
+    path = "/verify_email",
+    tag = "auth",
+    params(VerifyEmailQuery),
+    responses(
+        (status = 200, description = "The email address is verified", body = Object),
+        (status = 400, description = "The token is invalid, expired or was already used", body = ErrorBody),
+    )
+)]
+pub async fn verify_email(pool: web::Data<Pool<Mssql>>, query: web::Query<VerifyEmailQuery>) -> Result<HttpResponse, AppError> {
+    let invalid = || AppError::Validation {
+        code: "invalid_verification_token",
+        message: "The verification link is invalid or has expired.".to_string(),
+        details: None,
+    };
+
+    let claims = verify_token(&query.token).map_err(|_| invalid())?;
+    if !mark_verified(pool.get_ref(), &claims, &query.token).await? {
+        return Err(invalid());
+    }
+    Ok(HttpResponse::Ok().json(json!({ "message": "Email address verified." })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_roundtrip() {
+        let token = issue_token("user", "ana@example.com").unwrap();
+        let claims = verify_token(&token).unwrap();
+        assert_eq!(claims.sub, "user");
+        assert_eq!(claims.email, "ana@example.com");
+        assert_eq!(hash_token(&token).len(), 64);
+    }
+
+    #[test]
+    fn test_access_tokens_are_not_verification_tokens() {
+        let access_token = crate::auth::generate_jwt("user").unwrap();
+        assert!(verify_token(&access_token).is_err());
+
+        let token = issue_token("user", "ana@example.com").unwrap();
+        assert!(crate::auth::validate_jwt(&token).is_err());
+    }
+}
@@ -0,0 +1,444 @@
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+use utoipa::ToSchema;
+use validator::ValidationErrors;
+use crate::db::{is_unique_violation, unique_violation_field};
+use crate::tokens::TokenError;
+use crate::trace::TraceId;
+
+/// Body of every error response.
+///
+/// `code` is stable and meant for programs, `message` is meant for people and
+/// `details` optionally carries structured context such as the offending field.
+/// `trace_id` is the id of the request, to quote when reporting the error. Every
+/// code is listed in [`ERROR_CODES`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    #[schema(value_type = Option<Object>)]
+    pub details: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+}
+
+/// Error returned by handlers, turned into a JSON [`ErrorBody`] with the matching status.
+///
+/// Database and internal errors are logged and answered with a generic message, so
+/// driver and library errors never reach the client.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{HttpResponse, ResponseError};
+/// use safe_user::error::AppError;
+///
+/// async fn get_widget() -> Result<HttpResponse, AppError> {
+///     Err(AppError::NotFound("widget"))
+/// }
+///
+/// assert_eq!(AppError::NotFound("widget").status_code(), 404);
+/// assert_eq!(AppError::NotFound("widget").body().code, "widget_not_found");
+/// ```
+#[derive(Debug)]
+pub enum AppError {
+    /// The request is malformed or breaks a rule (`400 Bad Request`).
+    Validation {
+        code: &'static str,
+        message: String,
+        details: Option<Value>,
+    },
+    /// Some fields of the payload break their rules (`422 Unprocessable Entity`).
+    InvalidFields(ValidationErrors),
+    /// The named resource, such as `"user"` or `"role"`, does not exist (`404 Not Found`).
+    NotFound(&'static str),
+    /// The request clashes with existing data (`409 Conflict`); `field` names the
+    /// field of the request holding the clashing value, when there is one.
+    Conflict {
+        code: &'static str,
+        message: String,
+        field: Option<&'static str>,
+    },
+    /// The caller could not be authenticated (`401 Unauthorized`).
+    Auth {
+        code: &'static str,
+        message: String,
+    },
+    /// The caller is authenticated but may not do this (`403 Forbidden`).
+    Forbidden {
+        code: &'static str,
+        message: String,
+        details: Option<Value>,
+    },
+    /// The password was right but is older than `PASSWORD_MAX_AGE_DAYS`, so it must be
+    /// reset before the next login (`401 Unauthorized`).
+    PasswordExpired,
+    /// Creating the users would take the tenant past its cap in `TENANT_USER_QUOTAS`
+    /// (`403 Forbidden`).
+    QuotaExceeded { tenant_id: String, limit: i64 },
+    /// The caller sent too many requests and may retry after the given number of
+    /// seconds (`429 Too Many Requests`).
+    RateLimited(u64),
+    /// Too many logins failed for the email or client, which may retry after the given
+    /// number of seconds (`423 Locked`).
+    LockedOut(u64),
+    /// The named [`Feature`](crate::features::Feature) was switched off by an operator
+    /// (`503 Service Unavailable`).
+    FeatureDisabled(&'static str),
+    /// Writes are rejected while [`ReadOnlyMode`](crate::read_only::ReadOnlyMode) is on
+    /// (`503 Service Unavailable`).
+    ReadOnly,
+    /// Low-priority reads are shed while the [`LoadShedder`](crate::load_shedding::LoadShedder)
+    /// is overloaded (`503 Service Unavailable`).
+    Overloaded,
+    /// A database query failed (`500 Internal Server Error`).
+    Database(sqlx::Error),
+    /// Anything else that went wrong on the server (`500 Internal Server Error`).
+    Internal(String),
+}
+
+impl AppError {
+    /// Returns a [`Validation`](AppError::Validation) error with the generic `validation_error` code.
+    pub fn validation(message: impl Into<String>) -> Self {
+        AppError::Validation {
+            code: "validation_error",
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    /// Returns a [`Conflict`](AppError::Conflict) with no field.
+    pub fn conflict(code: &'static str, message: impl Into<String>) -> Self {
+        AppError::Conflict { code, message: message.into(), field: None }
+    }
+
+    /// Returns an [`Auth`](AppError::Auth) error.
+    pub fn auth(code: &'static str, message: impl Into<String>) -> Self {
+        AppError::Auth { code, message: message.into() }
+    }
+
+    /// Returns a [`Forbidden`](AppError::Forbidden) error with no details.
+    pub fn forbidden(code: &'static str, message: impl Into<String>) -> Self {
+        AppError::Forbidden { code, message: message.into(), details: None }
+    }
+
+    /// Returns a [`Conflict`](AppError::Conflict) with `code` and `message`, naming the
+    /// field of the violated constraint, if `error` is a unique constraint violation,
+    /// and a [`Database`](AppError::Database) error otherwise.
+    pub fn conflict_if_unique(error: sqlx::Error, code: &'static str, message: &str) -> Self {
+        if is_unique_violation(&error) {
+            AppError::Conflict { code, message: message.to_string(), field: unique_violation_field(&error) }
+        } else {
+            AppError::Database(error)
+        }
+    }
+
+    /// Returns the body sent to the client.
+    pub fn body(&self) -> ErrorBody {
+        let (code, message, details) = match self {
+            AppError::Validation { code, message, details } => (code.to_string(), message.clone(), details.clone()),
+            AppError::InvalidFields(errors) => (
+                "invalid_fields".to_string(),
+                "Some fields are invalid.".to_string(),
+                Some(field_messages(errors)),
+            ),
+            AppError::NotFound(resource) => (format!("{}_not_found", resource), format!("{} not found.", capitalize(resource)), None),
+            AppError::Conflict { code, message, field } => (
+                code.to_string(),
+                message.clone(),
+                field.map(|field| serde_json::json!({ "field": field })),
+            ),
+            AppError::Auth { code, message } => (code.to_string(), message.clone(), None),
+            AppError::Forbidden { code, message, details } => (code.to_string(), message.clone(), details.clone()),
+            AppError::PasswordExpired => (
+                "password_expired".to_string(),
+                "The password has expired. A link to choose a new one has been sent by email.".to_string(),
+                None,
+            ),
+            AppError::QuotaExceeded { tenant_id, limit } => (
+                "quota_exceeded".to_string(),
+                format!("The tenant has reached its limit of {} users.", limit),
+                Some(serde_json::json!({ "tenant_id": tenant_id, "limit": limit })),
+            ),
+            AppError::RateLimited(retry_after) => (
+                "rate_limited".to_string(),
+                "Too many requests, try again later.".to_string(),
+                Some(serde_json::json!({ "retry_after": retry_after })),
+            ),
+            AppError::LockedOut(retry_after) => (
+                "too_many_failed_logins".to_string(),
+                "Too many failed logins, try again later.".to_string(),
+                Some(serde_json::json!({ "retry_after": retry_after })),
+            ),
+            AppError::FeatureDisabled(feature) => (
+                "feature_disabled".to_string(),
+                "This feature is disabled.".to_string(),
+                Some(serde_json::json!({ "feature": feature })),
+            ),
+            AppError::ReadOnly => ("read_only".to_string(), "Service is in read-only mode, try again later.".to_string(), None),
+            AppError::Overloaded => ("overloaded".to_string(), "Service is under heavy load, try again shortly.".to_string(), None),
+            AppError::Database(_) => ("database_error".to_string(), "A database error occurred.".to_string(), None),
+            AppError::Internal(_) => ("internal_error".to_string(), "An internal error occurred.".to_string(), None),
+        };
+        ErrorBody { code, message, details, trace_id: TraceId::current().map(|id| id.0) }
+    }
+}
+
+/// Maps each invalid field to the messages of the rules it breaks.
+pub(crate) fn field_messages(errors: &ValidationErrors) -> Value {
+    let fields: Map<String, Value> = errors
+        .field_errors()
+        .into_iter()
+        .map(|(field, errors)| {
+            let messages = errors
+                .iter()
+                .map(|error| Value::from(error.message.as_deref().unwrap_or(&error.code).to_string()))
+                .collect();
+            (field.to_string(), Value::Array(messages))
+        })
+        .collect();
+    Value::Object(fields)
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Database(e) => write!(f, "Database error: {}", e),
+            AppError::Internal(message) => write!(f, "Internal error: {}", message),
+            _ => write!(f, "{}", self.body().message),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Validation { .. } => StatusCode::BAD_REQUEST,
+            AppError::InvalidFields(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict { .. } => StatusCode::CONFLICT,
+            AppError::Auth { .. } | AppError::PasswordExpired => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden { .. } | AppError::QuotaExceeded { .. } => StatusCode::FORBIDDEN,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::LockedOut(_) => StatusCode::LOCKED,
+            AppError::FeatureDisabled(_) | AppError::ReadOnly | AppError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Database(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // A disabled feature, read-only mode or shed load is a decision, not a failure worth logging.
+        if self.status_code().is_server_error() && !matches!(self, AppError::FeatureDisabled(_) | AppError::ReadOnly | AppError::Overloaded) {
+            match TraceId::current() {
+                Some(TraceId(trace_id)) => eprintln!("Error handling request {}: {:?}", trace_id, self),
+                None => eprintln!("Error handling request: {:?}", self),
+            }
+        }
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            AppError::RateLimited(retry_after) | AppError::LockedOut(retry_after) => {
+                response.insert_header((RETRY_AFTER, retry_after.to_string()));
+            }
+            AppError::ReadOnly => {
+                response.insert_header((RETRY_AFTER, "120"));
+            }
+            AppError::Overloaded => {
+                response.insert_header((RETRY_AFTER, "5"));
+            }
+            _ => {}
+        }
+        response.json(self.body())
+    }
+}
+
+/// An error code that can appear in an [`ErrorBody`], with the status it comes with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ErrorCode {
+    pub code: &'static str,
+    pub status: u16,
+    pub description: &'static str,
+}
+
+const fn code(code: &'static str, status: u16, description: &'static str) -> ErrorCode {
+    ErrorCode { code, status, description }
+}
+
+/// Every code returned in an [`ErrorBody`], served at
+/// [`ERROR_CODES_PATH`](crate::openapi::ERROR_CODES_PATH) so clients can branch on
+/// codes instead of parsing messages. Codes are never renamed or reused.
+pub const ERROR_CODES: &[ErrorCode] = &[
+    code("validation_error", 400, "The request breaks a rule; the message says which."),
+    code("invalid_json_body", 400, "The body is not valid JSON or does not match the expected shape."),
+    code("invalid_path_parameter", 400, "A path parameter has the wrong format."),
+    code("invalid_query_parameter", 400, "A query parameter is unknown or has the wrong format."),
+    code("invalid_policy", 400, "An access policy expression cannot be parsed."),
+    code("invalid_reset_token", 400, "The password reset token is unknown, used or expired."),
+    code("invalid_verification_token", 400, "The email verification token is unknown, used or expired."),
+    code("password_too_short", 400, "The new password is shorter than the minimum length."),
+    code("password_breached", 400, "The new password appears in known data breaches."),
+    code("oauth_email_mismatch", 400, "The email of a provider sign-up differs from the one the provider verified."),
+    code("invalid_enrollment_code", 400, "The code does not match the pending MFA enrollment, or none is pending."),
+    code("invalid_fields", 422, "Some fields break their rules; `details` maps each field to its messages."),
+    code("invalid_credentials", 401, "The email or password is wrong."),
+    code("account_expired", 401, "The account has passed its expiry date."),
+    code("account_locked", 401, "An administrator has locked the account."),
+    code("account_pending_approval", 401, "The account is waiting for an administrator's approval."),
+    code("email_not_verified", 401, "The email address has not been verified yet."),
+    code("password_expired", 401, "The password has expired; a reset link has been sent by email."),
+    code("invalid_refresh_token", 401, "The refresh token is unknown, revoked or expired."),
+    code("invalid_token", 401, "The bearer token is missing, malformed, expired or badly signed."),
+    code("token_revoked", 401, "The bearer token belongs to a session that has been revoked."),
+    code("tenant_mismatch", 401, "The token was issued for another tenant."),
+    code("invalid_mfa_token", 401, "The MFA challenge of the login is invalid or expired; log in again."),
+    code("invalid_mfa_code", 401, "The TOTP or recovery code is wrong or already used."),
+    code("invalid_oauth_state", 401, "The provider's callback does not match a sign-in started by this browser, or it expired."),
+    code("oauth_failed", 401, "The identity provider refused or failed the sign-in."),
+    code("oauth_email_unverified", 401, "The identity provider has not verified the user's email address."),
+    code("invalid_signup_token", 401, "The sign-up token of a provider sign-in is invalid or expired."),
+    code("quota_exceeded", 403, "The tenant has reached its user quota."),
+    code("missing_permission", 403, "The caller lacks the permission in `details.permission`."),
+    code("missing_role", 403, "The caller lacks the role in `details.role`."),
+    code("access_denied", 403, "The caller does not satisfy the access policy of the route."),
+    code("action_not_allowed", 403, "The access policies do not allow the caller this action."),
+    code("invalid_signature", 403, "The signed URL is tampered with or expired."),
+    code("challenge_required", 403, "The endpoint needs a solved challenge in `X-Challenge-Response`."),
+    code("challenge_failed", 403, "The challenge provider rejected the `X-Challenge-Response`."),
+    code("user_not_found", 404, "The user does not exist."),
+    code("active_user_not_found", 404, "No enabled user has that id."),
+    code("deleted_user_not_found", 404, "No deleted user has that id."),
+    code("pending_user_not_found", 404, "No user awaiting approval has that id."),
+    code("role_not_found", 404, "The role does not exist."),
+    code("rate_limit_not_found", 404, "The role has no rate limit."),
+    code("route_not_found", 404, "No access policy is set on that route."),
+    code("provider_not_found", 404, "No identity provider with that name is configured."),
+    code("mfa_enrollment_not_found", 404, "The user has not enrolled in two-factor authentication."),
+    code("saved_search_not_found", 404, "The saved search does not exist or belongs to someone else."),
+    code("email_taken", 409, "Another user has that email."),
+    code("user_id_taken", 409, "Another user has that user_id."),
+    code("role_name_taken", 409, "Another role has that name."),
+    code("saved_search_name_taken", 409, "The caller already has a saved search with that name."),
+    code("mfa_already_enabled", 409, "The caller already has two-factor authentication enabled."),
+    code("replication_conflict", 409, "Another region changed the user at the same time and its change won; read the user again."),
+    code("oauth_account_unverified", 409, "An account has the provider's email but has not verified it, so it is not linked."),
+    code("too_many_failed_logins", 423, "Too many logins failed for the email or client; retry after `details.retry_after` seconds."),
+    code("rate_limited", 429, "Too many requests; retry after `details.retry_after` seconds."),
+    code("feature_disabled", 503, "An operator switched off the feature in `details.feature`."),
+    code("read_only", 503, "The service is in read-only mode and rejects writes."),
+    code("overloaded", 503, "The service is under heavy load and sheds low-priority reads."),
+    code("database_error", 500, "A database query failed."),
+    code("internal_error", 500, "Something else went wrong on the server."),
+];
+
+impl From<sqlx::Error> for AppError {
+    fn from(error: sqlx::Error) -> Self {
+        AppError::Database(error)
+    }
+}
+
+impl From<TokenError> for AppError {
+    fn from(error: TokenError) -> Self {
+        AppError::Internal(error.to_string())
+    }
+}
+
+impl From<argon2::password_hash::Error> for AppError {
+    fn from(error: argon2::password_hash::Error) -> Self {
+        AppError::Internal(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App};
+
+    async fn missing() -> Result<HttpResponse, AppError> {
+        Err(AppError::NotFound("user"))
+    }
+
+    async fn broken() -> Result<HttpResponse, AppError> {
+        Err(sqlx::Error::RowNotFound)?
+    }
+
+    #[test]
+    fn test_status_codes() {
+        assert_eq!(AppError::validation("bad").status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(AppError::conflict("email_taken", "taken").status_code(), StatusCode::CONFLICT);
+        assert_eq!(AppError::auth("invalid_token", "who?").status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(AppError::forbidden("missing_role", "no").status_code(), StatusCode::FORBIDDEN);
+        assert_eq!(AppError::conflict_if_unique(sqlx::Error::RowNotFound, "email_taken", "taken").status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_conflicts_name_their_field() {
+        let body = AppError::Conflict { code: "user_id_taken", message: "taken".to_string(), field: Some("user_id") }.body();
+        assert_eq!(body.code, "user_id_taken");
+        assert_eq!(body.details, Some(serde_json::json!({ "field": "user_id" })));
+        assert_eq!(AppError::conflict("email_taken", "taken").body().details, None);
+    }
+
+    #[test]
+    fn test_error_codes_are_catalogued_once() {
+        let errors = [
+            AppError::validation("bad"),
+            AppError::InvalidFields(ValidationErrors::new()),
+            AppError::NotFound("user"),
+            AppError::PasswordExpired,
+            AppError::QuotaExceeded { tenant_id: "acme".to_string(), limit: 1 },
+            AppError::RateLimited(1),
+            AppError::LockedOut(900),
+            AppError::FeatureDisabled("import"),
+            AppError::ReadOnly,
+            AppError::Overloaded,
+            AppError::Database(sqlx::Error::RowNotFound),
+            AppError::Internal("boom".to_string()),
+        ];
+        for error in &errors {
+            let code = error.body().code;
+            let entry = ERROR_CODES.iter().find(|entry| entry.code == code).unwrap_or_else(|| panic!("{} is not catalogued", code));
+            assert_eq!(entry.status, error.status_code().as_u16(), "{}", code);
+        }
+
+        let mut codes: Vec<&str> = ERROR_CODES.iter().map(|entry| entry.code).collect();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), ERROR_CODES.len());
+    }
+
+    #[actix_web::test]
+    async fn test_error_responses_are_json() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .route("/missing", web::get().to(missing))
+                .route("/broken", web::get().to(broken))
+        ).await;
+
+        let req = actix_web::test::TestRequest::get().uri("/missing").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: ErrorBody = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body.code, "user_not_found");
+        assert_eq!(body.message, "User not found.");
+
+        let req = actix_web::test::TestRequest::get().uri("/broken").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body: ErrorBody = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body.code, "database_error");
+        assert!(!body.message.contains("RowNotFound"));
+    }
+}
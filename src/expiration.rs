@@ -0,0 +1,171 @@
+use serde_json::json;
+use sqlx::{FromRow, Mssql, Pool};
+use std::env;
+use std::time::Duration;
+use crate::approvals::{ACTIVE, EXPIRED};
+use crate::audit;
+use crate::deactivation::CascadePolicy;
+use crate::leader::JobLease;
+use crate::outbox;
+use crate::timestamp::Timestamp;
+
+/// Days before expiry at which users are reminded, when `ACCOUNT_EXPIRY_REMINDER_DAYS` is not set.
+pub const DEFAULT_REMINDER_DAYS: i32 = 7;
+
+/// Returns how many days before expiry users get a `user.expiring` reminder, as set
+/// by the `ACCOUNT_EXPIRY_REMINDER_DAYS` environment variable.
+pub fn reminder_days() -> i32 {
+    env::var("ACCOUNT_EXPIRY_REMINDER_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(DEFAULT_REMINDER_DAYS)
+}
+
+/// A user returned by the expiration queries, with what the notifier needs.
+#[derive(Debug, FromRow)]
+struct ExpiringUser {
+    id: String,
+    email: String,
+    name: String,
+    expires_at: Timestamp,
+}
+
+/// Disables the active accounts whose `ExpiresAt` has passed.
+///
+/// Their status becomes [`EXPIRED`], `policy` is applied to their resources and a
+/// `user.expired` event is recorded for each, all in one transaction.
+///
+/// # Returns
+///
+/// * `Result<usize, sqlx::Error>` - The number of accounts disabled.
+pub async fn expire_accounts(pool: &Pool<Mssql>, policy: &CascadePolicy) -> Result<usize, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let expired = sqlx::query_as!(
+        ExpiringUser,
+        r#"
+        UPDATE [users]
+        SET Status = @p1
+        OUTPUT
+            CAST(INSERTED.id AS VARCHAR(36))                 AS "id!",
+            INSERTED.Email                                   AS "email!",
+            INSERTED.Name                                    AS "name!",
+            CONVERT(VARCHAR(33), INSERTED.ExpiresAt, 126)    AS "expires_at!: Timestamp"
+        WHERE Status = @p2 AND ExpiresAt <= SYSUTCDATETIME()
+        "#,
+        EXPIRED,
+        ACTIVE
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    for user in &expired {
+        policy.apply(&mut tx, &user.id).await?;
+
+        let payload = json!({ "id": user.id, "email": user.email, "name": user.name, "expires_at": user.expires_at });
+        outbox::enqueue(&mut tx, outbox::USER_EXPIRED, &user.id, &payload).await?;
+        audit::record(&mut tx, &user.id, audit::ACCOUNT_EXPIRED, None, None).await?;
+    }
+
+    tx.commit().await?;
+    Ok(expired.len())
+}
+
+/// Records a `user.expiring` event for each active account expiring within `days`
+/// that has not been reminded yet.
+///
+/// Changing a user's `expires_at` clears the reminder, so they are reminded again
+/// before the new date.
+///
+/// # Returns
+///
+/// * `Result<usize, sqlx::Error>` - The number of reminders recorded.
+pub async fn send_expiry_reminders(pool: &Pool<Mssql>, days: i32) -> Result<usize, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let expiring = sqlx::query_as!(
+        ExpiringUser,
+        r#"
+        UPDATE [users]
+        SET ExpiryReminderSentAt = SYSUTCDATETIME()
+        OUTPUT
+            CAST(INSERTED.id AS VARCHAR(36))                 AS "id!",
+            INSERTED.Email                                   AS "email!",
+            INSERTED.Name                                    AS "name!",
+            CONVERT(VARCHAR(33), INSERTED.ExpiresAt, 126)    AS "expires_at!: Timestamp"
+        WHERE Status = @p1
+            AND ExpiryReminderSentAt IS NULL
+            AND ExpiresAt > SYSUTCDATETIME()
+            AND ExpiresAt <= DATEADD(DAY, @p2, SYSUTCDATETIME())
+        "#,
+        ACTIVE,
+        days
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    for user in &expiring {
+        let payload = json!({ "id": user.id, "email": user.email, "name": user.name, "expires_at": user.expires_at });
+        outbox::enqueue(&mut tx, outbox::USER_EXPIRING, &user.id, &payload).await?;
+    }
+
+    tx.commit().await?;
+    Ok(expiring.len())
+}
+
+/// Spawns the background task that sends expiry reminders and disables expired
+/// accounts every `interval`, applying `policy` to their resources, on the replica
+/// holding the `expiration` [`JobLease`].
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use safe_user::config::AppConfig;
+/// use safe_user::db::DbPool;
+/// use safe_user::expiration::spawn_expiration_task;
+///
+/// #[actix_web::main]
+/// async fn main() {
+///     let config = AppConfig::load().unwrap();
+///     let pool = DbPool::connect(&config.database_url).await.unwrap().pool;
+///     spawn_expiration_task(pool, config.deactivation, Duration::from_secs(60));
+/// }
+/// ```
+pub fn spawn_expiration_task(pool: Pool<Mssql>, policy: CascadePolicy, interval: Duration) {
+    let days = reminder_days();
+    let lease = JobLease::new(pool.clone(), "expiration", interval);
+
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if !lease.acquire().await {
+                continue;
+            }
+            if let Err(e) = send_expiry_reminders(&pool, days).await {
+                eprintln!("Error sending expiry reminders: {:?}", e);
+            }
+            if let Err(e) = expire_accounts(&pool, &policy).await {
+                eprintln!("Error expiring accounts: {:?}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reminder_days_follows_env() {
+        env::set_var("ACCOUNT_EXPIRY_REMINDER_DAYS", "3");
+        assert_eq!(reminder_days(), 3);
+
+        env::set_var("ACCOUNT_EXPIRY_REMINDER_DAYS", "soon");
+        assert_eq!(reminder_days(), DEFAULT_REMINDER_DAYS);
+
+        env::remove_var("ACCOUNT_EXPIRY_REMINDER_DAYS");
+        assert_eq!(reminder_days(), DEFAULT_REMINDER_DAYS);
+    }
+}
@@ -0,0 +1,264 @@
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpResponse};
+use futures_util::{stream, Stream, TryStreamExt};
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::{Mssql, Pool};
+use tokio::sync::mpsc;
+use utoipa::{IntoParams, ToSchema};
+use crate::birthdate::Birthdate;
+use crate::error::AppError;
+use crate::models::{Email, Phone, User, UserId, UserResponse};
+use crate::timestamp::Timestamp;
+use crate::visibility::Viewer;
+
+/// Columns of a CSV export, in order. They are the fields of [`UserResponse`] except
+/// `deleted_at`, since deleted users are not exported.
+pub const CSV_COLUMNS: [&str; 16] = [
+    "id", "user_id", "name", "last_name", "email", "age", "phone", "address", "birthdate",
+    "place_birth", "org_unit", "expires_at", "created_at", "updated_at", "last_seen_at", "plan",
+];
+
+/// Encoded rows buffered between the database and a slow client.
+const BUFFERED_ROWS: usize = 64;
+
+/// Format of `GET /protected/users/export`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// Comma-separated values with a header line of [`CSV_COLUMNS`].
+    Csv,
+    /// One JSON user per line.
+    #[default]
+    Ndjson,
+    /// A single JSON array of users.
+    Json,
+}
+
+/// Query parameters of `GET /protected/users/export`.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    /// `csv`, `ndjson` (default) or `json`.
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+impl ExportFormat {
+    /// Returns what precedes the rows: the CSV header line, the opening bracket of
+    /// a JSON array, or nothing.
+    pub fn header(&self) -> Vec<u8> {
+        match self {
+            ExportFormat::Ndjson => Vec::new(),
+            ExportFormat::Json => b"[".to_vec(),
+            ExportFormat::Csv => csv_record(CSV_COLUMNS.iter().map(|column| column.to_string())),
+        }
+    }
+
+    /// Returns what follows the rows: the closing bracket of a JSON array, or nothing.
+    pub fn footer(&self) -> Vec<u8> {
+        match self {
+            ExportFormat::Json => b"]".to_vec(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Encodes one user, as already presented to the viewer; `first` tells whether
+    /// it is the first row, which in a JSON array has no separator.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::export::ExportFormat;
+    /// use serde_json::json;
+    ///
+    /// let user = json!({ "id": "1", "name": "Ana, Maria", "age": 30, "phone": null });
+    /// assert_eq!(ExportFormat::Csv.encode(&user, true), b"1,,\"Ana, Maria\",,,30,,,,,,,,,,\n");
+    /// assert_eq!(ExportFormat::Ndjson.encode(&user, true).last(), Some(&b'\n'));
+    /// assert_eq!(ExportFormat::Json.encode(&json!({ "id": "2" }), false), br#",{"id":"2"}"#);
+    /// ```
+    pub fn encode(&self, user: &Value, first: bool) -> Vec<u8> {
+        match self {
+            ExportFormat::Json => {
+                let mut element = if first { Vec::new() } else { b",".to_vec() };
+                element.extend(user.to_string().into_bytes());
+                element
+            }
+            ExportFormat::Ndjson => {
+                let mut line = user.to_string().into_bytes();
+                line.push(b'\n');
+                line
+            }
+            ExportFormat::Csv => csv_record(CSV_COLUMNS.iter().map(|column| match &user[*column] {
+                Value::Null => String::new(),
+                Value::String(text) => text.clone(),
+                other => other.to_string(),
+            })),
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Json => "application/json",
+        }
+    }
+
+    fn file_name(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "users.csv",
+            ExportFormat::Ndjson => "users.ndjson",
+            ExportFormat::Json => "users.json",
+        }
+    }
+}
+
+fn csv_record(cells: impl IntoIterator<Item = String>) -> Vec<u8> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    // Writing to a Vec cannot fail.
+    let _ = writer.write_record(cells);
+    writer.into_inner().unwrap_or_default()
+}
+
+/// Streams every user that is not deleted, oldest first, encoded in `format` and
+/// presented to `viewer`.
+///
+/// The rows are read with a `fetch` cursor by a background task and handed over
+/// through a bounded channel, so at most a few dozen rows are held in memory and a
+/// client that goes away stops the query. A database error ends the stream with
+/// that error, which cuts the response short instead of ending it cleanly.
+pub fn stream_users(pool: &Pool<Mssql>, viewer: Viewer, format: ExportFormat) -> impl Stream<Item = Result<Bytes, AppError>> {
+    let (tx, rx) = mpsc::channel::<Result<Bytes, AppError>>(BUFFERED_ROWS);
+    let pool = pool.clone();
+
+    actix_web::rt::spawn(async move {
+        let mut rows = sqlx::query_as!(
+            User,
+            r#"
+            SELECT
+                CAST(id AS VARCHAR(36))         AS "id!",
+                UserId                          AS "user_id!: UserId",
+                Name                            AS "name!",
+                LastName                        AS "last_name!",
+                Email                           AS "email!: Email",
+                Age                             AS "age?",
+                Phone                           AS "phone?: Phone",
+                Address                         AS "address?",
+                CONVERT(VARCHAR, BirthDate, 23) AS "birthdate!: Birthdate",
+                PlaceBirth                      AS "place_birth?",
+                OrgUnit                         AS "org_unit?",
+                CONVERT(VARCHAR(33), ExpiresAt, 126) AS "expires_at?: Timestamp",
+                CONVERT(VARCHAR(33), CreatedAt, 126) AS "created_at?: Timestamp",
+                CONVERT(VARCHAR(33), UpdatedAt, 126) AS "updated_at?: Timestamp",
+                CONVERT(VARCHAR(33), LastSeenAt, 126) AS "last_seen_at?: Timestamp",
+                CONVERT(VARCHAR(33), DeletedAt, 126) AS "deleted_at?: Timestamp",
+                [Plan]                          AS "plan?"
+            FROM [users]
+            WHERE DeletedAt IS NULL
+            ORDER BY CreatedAt, id
+            "#
+        )
+        .fetch(&pool);
+
+        let header = format.header();
+        if !header.is_empty() && tx.send(Ok(Bytes::from(header))).await.is_err() {
+            return;
+        }
+        let mut first = true;
+        loop {
+            let item = match rows.try_next().await {
+                Ok(Some(user)) => Ok(Bytes::from(format.encode(&viewer.present(&user.into()), std::mem::replace(&mut first, false)))),
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Error streaming users: {:?}", e);
+                    Err(AppError::Database(e))
+                }
+            };
+            let failed = item.is_err();
+            // A failed send means the client went away; stop reading the table.
+            if tx.send(item).await.is_err() || failed {
+                return;
+            }
+        }
+        let footer = format.footer();
+        if !footer.is_empty() {
+            let _ = tx.send(Ok(Bytes::from(footer))).await;
+        }
+    });
+
+    stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|item| (item, rx)) })
+}
+
+/// Exports every user that is not deleted, oldest first, as CSV, NDJSON or a JSON array.
+///
+/// Rows are streamed by [`stream_users`] as they are read from the database, so the
+/// table is never held in memory. Sensitive fields the caller may not see are left
+/// out, or empty in CSV. A database error after the first rows cuts the response
+/// short, so a truncated export cannot pass for a complete one.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `query` - The `format` of the export.
+/// * `viewer` - The caller, used to hide sensitive fields they may not see.
+///
+/// # Returns
+///
+/// * `HttpResponse` - `200 OK` with the users as an attachment.
+#[utoipa::path(
+    get,
+    path = "/protected/users/export",
+    tag = "users",
+    params(ExportQuery),
+    responses(
+        (status = 200, description = "Every user, as CSV, NDJSON or JSON", content_type = "application/x-ndjson", body = String),
+        (status = 400, description = "Unknown format", body = ErrorBody),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn export_users(pool: web::Data<Pool<Mssql>>, query: web::Query<ExportQuery>, viewer: Viewer) -> HttpResponse {
+    let format = query.format;
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format.file_name().to_string())],
+        })
+        .streaming(stream_users(pool.get_ref(), viewer, format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_csv_rows_line_up_with_the_header() {
+        let user = json!({ "id": "1", "name": "Ana", "email": "ana@example.com", "age": 30, "last_seen_at": "2024-01-01T00:00:00Z" });
+        let mut output = ExportFormat::Csv.header();
+        output.extend(ExportFormat::Csv.encode(&user, true));
+
+        let mut reader = csv::Reader::from_reader(output.as_slice());
+        assert_eq!(reader.headers().unwrap().len(), CSV_COLUMNS.len());
+        let row = reader.records().next().unwrap().unwrap();
+        assert_eq!(&row[4], "ana@example.com");
+        assert_eq!(&row[5], "30");
+        assert_eq!(&row[6], "");
+        assert_eq!(&row[14], "2024-01-01T00:00:00Z");
+        assert!(ExportFormat::Ndjson.header().is_empty());
+    }
+
+    #[test]
+    fn test_json_rows_form_an_array() {
+        let format = ExportFormat::Json;
+        let mut output = format.header();
+        output.extend(format.encode(&json!({ "id": "1" }), true));
+        output.extend(format.encode(&json!({ "id": "2" }), false));
+        output.extend(format.footer());
+
+        let users: Vec<Value> = serde_json::from_slice(&output).unwrap();
+        assert_eq!(users, [json!({ "id": "1" }), json!({ "id": "2" })]);
+    }
+}
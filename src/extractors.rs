@@ -0,0 +1,232 @@
+use actix_web::dev::Payload;
+use actix_web::{error, web, FromRequest, HttpMessage, HttpRequest, ResponseError};
+use serde::Deserialize;
+use std::fmt;
+use std::future::{ready, Ready};
+use std::ops::Deref;
+use uuid::Uuid;
+use crate::auth::Claims;
+use crate::error::AppError;
+
+/// A user id taken from a `/users/{id}` path segment.
+///
+/// Extracting `web::Path<UserId>` fails before the handler runs when the segment is
+/// not a valid UUID, so handlers (and the database) only ever see well-formed ids.
+/// Register [`path_config`] on the app to turn that failure into a structured `400`.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{web, HttpResponse, Responder};
+/// use safe_user::extractors::UserId;
+///
+/// async fn get_user(id: web::Path<UserId>) -> impl Responder {
+///     HttpResponse::Ok().json(id.to_string())
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct UserId(Uuid);
+
+impl UserId {
+    /// Returns the id as a [`Uuid`].
+    pub fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for UserId {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Uuid::parse_str(&value)
+            .map(UserId)
+            .map_err(|_| format!("`{}` is not a valid user id, expected a UUID", value))
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.hyphenated().fmt(f)
+    }
+}
+
+/// The caller of a route behind [`jwt_validator`](crate::auth::jwt_validator), built
+/// from the [`Claims`] the validator stored in the request extensions.
+///
+/// Extraction fails with `401 Unauthorized` when the request carries no claims, for
+/// instance on a route that is not wrapped by the validator. Use
+/// `Option<AuthenticatedUser>` for routes that also serve anonymous callers.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{HttpResponse, Responder};
+/// use safe_user::extractors::AuthenticatedUser;
+///
+/// async fn whoami(user: AuthenticatedUser) -> impl Responder {
+///     HttpResponse::Ok().json(user.id())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser(Claims);
+
+impl AuthenticatedUser {
+    /// Returns the id of the caller (the `sub` claim).
+    pub fn id(&self) -> &str {
+        &self.0.sub
+    }
+
+    /// Returns the claims of the caller's token.
+    pub fn claims(&self) -> &Claims {
+        &self.0
+    }
+}
+
+impl Deref for AuthenticatedUser {
+    type Target = Claims;
+
+    fn deref(&self) -> &Claims {
+        &self.0
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(match req.extensions().get::<Claims>() {
+            Some(claims) => Ok(AuthenticatedUser(claims.clone())),
+            None => Err(AppError::auth("invalid_token", "Missing or invalid token.")),
+        })
+    }
+}
+
+/// Path extractor configuration returning `400 Bad Request` with a JSON body
+/// (`{ "code": "invalid_path_parameter", "message": ..., "details": null }`) for
+/// malformed path segments, instead of actix's default empty `404`.
+pub fn path_config() -> web::PathConfig {
+    web::PathConfig::default().error_handler(|err, _req| {
+        let response = AppError::Validation {
+            code: "invalid_path_parameter",
+            message: err.to_string(),
+            details: None,
+        }
+        .error_response();
+        error::InternalError::from_response(err, response).into()
+    })
+}
+
+/// Query string extractor configuration answering malformed query parameters with
+/// an `invalid_query_parameter` JSON error body.
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err, _req| {
+        let response = AppError::Validation {
+            code: "invalid_query_parameter",
+            message: err.to_string(),
+            details: None,
+        }
+        .error_response();
+        error::InternalError::from_response(err, response).into()
+    })
+}
+
+/// JSON body extractor configuration answering malformed or mistyped payloads with
+/// an `invalid_json_body` JSON error body.
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err, _req| {
+        let response = AppError::Validation {
+            code: "invalid_json_body",
+            message: err.to_string(),
+            details: None,
+        }
+        .error_response();
+        error::InternalError::from_response(err, response).into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App, HttpResponse, Responder};
+
+    async fn echo(id: web::Path<UserId>) -> impl Responder {
+        HttpResponse::Ok().json(id.to_string())
+    }
+
+    #[actix_web::test]
+    async fn test_authenticated_user_reads_claims() {
+        async fn whoami(user: AuthenticatedUser) -> impl Responder {
+            HttpResponse::Ok().json(user.id())
+        }
+
+        let app = test::init_service(App::new().route("/whoami", web::get().to(whoami))).await;
+
+        let req = test::TestRequest::get().uri("/whoami").to_request();
+        req.extensions_mut().insert(Claims::new("alice", 0, &[]));
+        let body: String = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, "alice");
+
+        let req = test::TestRequest::get().uri("/whoami").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_valid_user_id() {
+        let app = test::init_service(
+            App::new()
+                .app_data(path_config())
+                .route("/users/{id}", web::get().to(echo))
+        ).await;
+
+        let req = test::TestRequest::get().uri("/users/813B6B04-DFBB-4EED-B820-2372216A2367").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body: String = test::read_body_json(resp).await;
+        assert_eq!(body, "813b6b04-dfbb-4eed-b820-2372216a2367");
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_user_id_returns_structured_400() {
+        let app = test::init_service(
+            App::new()
+                .app_data(path_config())
+                .route("/users/{id}", web::get().to(echo))
+        ).await;
+
+        let req = test::TestRequest::get().uri("/users/not-a-uuid").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "invalid_path_parameter");
+        assert!(body["message"].as_str().unwrap().contains("not-a-uuid"));
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_json_body_returns_structured_400() {
+        async fn accept(_body: web::Json<serde_json::Value>) -> impl Responder {
+            HttpResponse::Ok().finish()
+        }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(json_config())
+                .route("/users", web::post().to(accept))
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .insert_header(("content-type", "application/json"))
+            .set_payload("{not json")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "invalid_json_body");
+    }
+}
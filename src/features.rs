@@ -0,0 +1,328 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse, Responder, ResponseError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use sqlx::{Mssql, Pool};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use crate::config::ConfigError;
+use crate::error::AppError;
+use crate::routes::unversioned;
+
+/// A group of routes that operators can switch off at runtime, for instance to stop
+/// abuse of public sign-up without redeploying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// Public sign-up: `/register`, `/create_user` and `/oauth/signup`.
+    Registration,
+    /// `/forgot_password` and `/reset_password`.
+    PasswordReset,
+    /// The device authorization grant: `/oauth/device/code`, `/oauth/token` and `/device`.
+    DeviceFlow,
+    /// Bulk user import: `/protected/users/import`.
+    Import,
+    /// Bulk user reads: `/protected/users/export`, `/protected/users/stream` and the
+    /// shared export, `/shared/users/export`.
+    Export,
+}
+
+impl Feature {
+    /// Every feature, in the order they are listed by the admin endpoint.
+    pub const ALL: [Feature; 5] = [Feature::Registration, Feature::PasswordReset, Feature::DeviceFlow, Feature::Import, Feature::Export];
+
+    /// Returns the name of the feature in `DISABLED_FEATURES` and the admin endpoint.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Registration => "registration",
+            Feature::PasswordReset => "password_reset",
+            Feature::DeviceFlow => "device_flow",
+            Feature::Import => "import",
+            Feature::Export => "export",
+        }
+    }
+
+    /// Returns the paths served by the feature.
+    pub fn paths(&self) -> &'static [&'static str] {
+        match self {
+            Feature::Registration => &["/register", "/create_user", "/oauth/signup"],
+            Feature::PasswordReset => &["/forgot_password", "/reset_password"],
+            Feature::DeviceFlow => &["/oauth/device/code", "/oauth/token", "/device"],
+            Feature::Import => &["/protected/users/import"],
+            Feature::Export => &["/protected/users/export", "/protected/users/stream", "/shared/users/export"],
+        }
+    }
+
+    /// Returns the feature called `name`, ignoring case.
+    pub fn from_name(name: &str) -> Option<Feature> {
+        Feature::ALL.into_iter().find(|feature| feature.name() == name.to_lowercase())
+    }
+
+    /// Returns the feature serving `path`, if any.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::features::Feature;
+    ///
+    /// assert_eq!(Feature::for_path("/register"), Some(Feature::Registration));
+    /// assert_eq!(Feature::for_path("/protected/users/import"), Some(Feature::Import));
+    /// assert_eq!(Feature::for_path("/login"), None);
+    /// ```
+    pub fn for_path(path: &str) -> Option<Feature> {
+        Feature::ALL.into_iter().find(|feature| feature.paths().contains(&path))
+    }
+
+    /// Returns `true` if the feature is reachable without a token. Disabled public
+    /// features answer `404 Not Found`, so they look absent to whoever was abusing
+    /// them; the others answer `503 Service Unavailable`.
+    fn is_public(&self) -> bool {
+        self.paths().iter().all(|path| !path.starts_with("/protected/"))
+    }
+
+    /// Reads `DISABLED_FEATURES`, a comma-separated list of feature names.
+    pub fn disabled_from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Vec<Feature>, ConfigError> {
+        let names = var("DISABLED_FEATURES").unwrap_or_default();
+        names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                Feature::from_name(name).ok_or_else(|| {
+                    let known: Vec<&str> = Feature::ALL.iter().map(Feature::name).collect();
+                    format!("DISABLED_FEATURES contains unknown feature `{}`, expected one of {}", name, known.join(", ")).into()
+                })
+            })
+            .collect()
+    }
+}
+
+/// Runtime switches of the [`Feature`]s, shared between all workers.
+///
+/// Changes made through the admin endpoint are stored in `[feature_toggles]`, where they
+/// override `DISABLED_FEATURES`. They take effect immediately on the instance that
+/// received the request, and on the others once [`spawn_feature_reloader`] has read them.
+#[derive(Clone, Debug, Default)]
+pub struct FeatureToggles {
+    defaults: Arc<BTreeSet<Feature>>,
+    disabled: Arc<RwLock<BTreeSet<Feature>>>,
+}
+
+impl FeatureToggles {
+    /// Creates toggles with `disabled` switched off and every other feature on, until
+    /// stored changes are loaded.
+    pub fn new(disabled: impl IntoIterator<Item = Feature>) -> Self {
+        let defaults: BTreeSet<Feature> = disabled.into_iter().collect();
+        FeatureToggles {
+            disabled: Arc::new(RwLock::new(defaults.clone())),
+            defaults: Arc::new(defaults),
+        }
+    }
+
+    /// Replaces the state of every feature with the startup defaults overridden by
+    /// `overrides`.
+    pub fn apply(&self, overrides: impl IntoIterator<Item = (Feature, bool)>) {
+        let mut disabled = (*self.defaults).clone();
+        for (feature, enabled) in overrides {
+            if enabled {
+                disabled.remove(&feature);
+            } else {
+                disabled.insert(feature);
+            }
+        }
+        *self.disabled.write().unwrap_or_else(|e| e.into_inner()) = disabled;
+    }
+
+    /// Applies the changes stored in `[feature_toggles]`. Rows naming features this
+    /// version does not know are ignored.
+    pub async fn reload(&self, pool: &Pool<Mssql>) -> Result<(), sqlx::Error> {
+        let rows = sqlx::query!(r#"SELECT Feature AS "feature!", Enabled AS "enabled!: bool" FROM [feature_toggles]"#)
+            .fetch_all(pool)
+            .await?;
+        self.apply(rows.into_iter().filter_map(|row| Some((Feature::from_name(&row.feature)?, row.enabled))));
+        Ok(())
+    }
+
+    /// Returns `true` if `feature` is switched on.
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        !self.disabled.read().unwrap_or_else(|e| e.into_inner()).contains(&feature)
+    }
+
+    /// Switches `feature` on or off on this instance only; see [`store`] to change it
+    /// on every one.
+    pub fn set(&self, feature: Feature, enabled: bool) {
+        let mut disabled = self.disabled.write().unwrap_or_else(|e| e.into_inner());
+        if enabled {
+            disabled.remove(&feature);
+        } else {
+            disabled.insert(feature);
+        }
+    }
+
+    /// Returns whether each feature is switched on.
+    pub fn states(&self) -> BTreeMap<Feature, bool> {
+        Feature::ALL.into_iter().map(|feature| (feature, self.is_enabled(feature))).collect()
+    }
+}
+
+/// Stores `changes` in `[feature_toggles]`, all or none, for every instance to apply.
+pub async fn store(pool: &Pool<Mssql>, changes: &BTreeMap<Feature, bool>) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for (feature, enabled) in changes {
+        sqlx::query!(
+            r#"
+            MERGE [feature_toggles] WITH (HOLDLOCK) AS target
+            USING (SELECT @p1 AS Feature, @p2 AS Enabled) AS source
+            ON target.Feature = source.Feature
+            WHEN MATCHED THEN UPDATE SET Enabled = source.Enabled, UpdatedAt = SYSUTCDATETIME()
+            WHEN NOT MATCHED THEN INSERT (Feature, Enabled) VALUES (source.Feature, source.Enabled);
+            "#,
+            feature.name(),
+            enabled
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+/// Spawns a task applying the changes stored in `[feature_toggles]` to `toggles` every
+/// `interval`, so switches changed on another instance take effect on this one too. A
+/// failed read keeps the current state.
+pub fn spawn_feature_reloader(pool: Pool<Mssql>, toggles: FeatureToggles, interval: Duration) {
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = toggles.reload(&pool).await {
+                eprintln!("Error reloading feature toggles: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Middleware function that rejects requests to the routes of disabled features.
+///
+/// Public features answer `404 Not Found` and the others `503 Service Unavailable`
+/// with the `feature_disabled` code. The middleware is a no-op when no
+/// `FeatureToggles` has been registered as app data.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{middleware::from_fn, web, App};
+/// use safe_user::features::{reject_disabled_features, Feature, FeatureToggles};
+///
+/// let app = App::new()
+///     .app_data(web::Data::new(FeatureToggles::new([Feature::Import])))
+///     .wrap(from_fn(reject_disabled_features));
+/// ```
+pub async fn reject_disabled_features(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let disabled = Feature::for_path(unversioned(req.path())).filter(|feature| {
+        req.app_data::<web::Data<FeatureToggles>>()
+            .is_some_and(|toggles| !toggles.is_enabled(*feature))
+    });
+
+    if let Some(feature) = disabled {
+        let error = if feature.is_public() {
+            AppError::NotFound("route")
+        } else {
+            AppError::FeatureDisabled(feature.name())
+        };
+        return Ok(req.into_response(error.error_response()).map_into_right_body());
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+/// Returns whether each feature is switched on, as `{"registration": true, ...}`.
+pub async fn get_features(toggles: web::Data<FeatureToggles>) -> impl Responder {
+    HttpResponse::Ok().json(toggles.states())
+}
+
+/// Switches features on or off on every instance.
+///
+/// # Arguments
+///
+/// * `pool` - The database the changes are stored in.
+/// * `toggles` - The feature switches of this instance.
+/// * `changes` - A JSON object mapping the names of the features to change to `true` or
+///   `false`; features left out keep their state, and unknown names get `400 Bad Request`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - A JSON response with the state of every feature.
+pub async fn set_features(pool: web::Data<Pool<Mssql>>, toggles: web::Data<FeatureToggles>, changes: web::Json<BTreeMap<Feature, bool>>) -> Result<HttpResponse, AppError> {
+    let changes = changes.into_inner();
+    store(pool.get_ref(), &changes).await?;
+    for (feature, enabled) in changes {
+        toggles.set(feature, enabled);
+        eprintln!("Feature {} {}", feature.name(), if enabled { "enabled" } else { "disabled" });
+    }
+
+    Ok(HttpResponse::Ok().json(toggles.states()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    async fn ok() -> impl Responder {
+        HttpResponse::Ok().json("ok")
+    }
+
+    #[actix_web::test]
+    async fn test_disabled_features_are_rejected_until_enabled() {
+        let toggles = FeatureToggles::new([Feature::Registration, Feature::Import]);
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(toggles.clone()))
+                .wrap(from_fn(reject_disabled_features))
+                .route("/register", web::post().to(ok))
+                .route("/api/v1/register", web::post().to(ok))
+                .route("/protected/users/import", web::post().to(ok))
+                .route("/protected/users/export", web::get().to(ok))
+                .route("/protected/admin/features", web::get().to(get_features))
+        ).await;
+
+        let resp = call_service(&app, TestRequest::post().uri("/register").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = call_service(&app, TestRequest::post().uri("/api/v1/register").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = call_service(&app, TestRequest::post().uri("/protected/users/import").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let resp = call_service(&app, TestRequest::get().uri("/protected/users/export").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        toggles.set(Feature::Registration, true);
+        let req = TestRequest::get().uri("/protected/admin/features").to_request();
+        let states: BTreeMap<Feature, bool> = call_and_read_body_json(&app, req).await;
+        assert!(states[&Feature::Registration] && !states[&Feature::Import]);
+        let resp = call_service(&app, TestRequest::post().uri("/register").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_stored_changes_override_the_defaults() {
+        let toggles = FeatureToggles::new([Feature::Import]);
+        toggles.apply([(Feature::Import, true), (Feature::Export, false)]);
+        assert!(toggles.is_enabled(Feature::Import) && !toggles.is_enabled(Feature::Export));
+
+        toggles.apply([]);
+        assert!(!toggles.is_enabled(Feature::Import) && toggles.is_enabled(Feature::Export), "Without stored changes the defaults apply again");
+    }
+
+    #[test]
+    fn test_disabled_features_are_read_from_the_environment() {
+        let disabled = Feature::disabled_from_vars(|_| Some("Registration, device_flow".to_string())).unwrap();
+        assert_eq!(disabled, [Feature::Registration, Feature::DeviceFlow]);
+        assert!(Feature::disabled_from_vars(|_| None).unwrap().is_empty());
+        assert!(Feature::disabled_from_vars(|_| Some("signup".to_string())).is_err());
+    }
+}
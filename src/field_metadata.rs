@@ -0,0 +1,269 @@
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::{IntoParams, ToSchema};
+use crate::approvals::{ACTIVE, DISABLED, EXPIRED, PENDING_APPROVAL, REJECTED};
+
+/// Path of the localized metadata of the user fields.
+pub const FIELD_METADATA_PATH: &str = "/metadata/users/fields";
+
+/// A language the field metadata is translated to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Es,
+}
+
+impl Locale {
+    /// Returns the supported locale of a language tag such as `es-MX`, if any.
+    pub fn from_tag(tag: &str) -> Option<Locale> {
+        let language = tag.trim().split(['-', '_']).next().unwrap_or_default();
+        match language.to_ascii_lowercase().as_str() {
+            "en" => Some(Locale::En),
+            "es" => Some(Locale::Es),
+            _ => None,
+        }
+    }
+
+    /// Returns the supported locale the client prefers in an `Accept-Language` header,
+    /// honouring `q` weights; ties go to the one listed first.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::field_metadata::Locale;
+    ///
+    /// assert_eq!(Locale::negotiate("fr-FR, es-ES;q=0.8, en;q=0.5"), Some(Locale::Es));
+    /// assert_eq!(Locale::negotiate("es;q=0.2, en-GB"), Some(Locale::En));
+    /// assert_eq!(Locale::negotiate("de, *;q=0.1"), None);
+    /// ```
+    pub fn negotiate(accept_language: &str) -> Option<Locale> {
+        let mut best: Option<(Locale, f32)> = None;
+        for range in accept_language.split(',') {
+            let mut parts = range.split(';');
+            let locale = match parts.next().and_then(Locale::from_tag) {
+                Some(locale) => locale,
+                None => continue,
+            };
+            let weight = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if weight > best.map_or(0.0, |(_, best)| best) {
+                best = Some((locale, weight));
+            }
+        }
+        best.map(|(locale, _)| locale)
+    }
+
+    /// Returns the language tag of the locale, as sent in `Content-Language`.
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Es => "es",
+        }
+    }
+}
+
+/// Query parameters of `GET /metadata/users/fields`.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MetadataQuery {
+    /// `en` or `es`; overrides `Accept-Language`.
+    pub locale: Option<Locale>,
+}
+
+/// How a field is rendered and checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    Text,
+    Email,
+    Phone,
+    Integer,
+    Date,
+    DateTime,
+}
+
+/// A field of a user, with its label and validation messages in one locale.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FieldMetadata {
+    /// The name of the field in request and response bodies.
+    pub name: &'static str,
+    pub label: &'static str,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    pub required: bool,
+    /// Messages by validation rule, for checking forms before they are sent. The English
+    /// ones match the messages of `422 Unprocessable Entity` responses.
+    pub messages: BTreeMap<&'static str, &'static str>,
+}
+
+/// Body of `GET /metadata/users/fields`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct UserFieldsMetadata {
+    pub locale: Locale,
+    pub fields: Vec<FieldMetadata>,
+    /// Display names of the values of enumerated fields, such as the account `status`.
+    pub enums: BTreeMap<&'static str, BTreeMap<&'static str, &'static str>>,
+}
+
+/// A translated text: English, then Spanish.
+type Text = [&'static str; 2];
+
+struct Field {
+    name: &'static str,
+    label: Text,
+    field_type: FieldType,
+    required: bool,
+    messages: &'static [(&'static str, Text)],
+}
+
+const REQUIRED: (&str, Text) = ("required", ["is required", "es obligatorio"]);
+const LENGTH_50: (&str, Text) = ("length", ["must be between 1 and 50 characters", "debe tener entre 1 y 50 caracteres"]);
+
+/// The fields of [`CreateUserRequest`](crate::models::CreateUserRequest), in form order.
+const FIELDS: [Field; 11] = [
+    Field { name: "user_id", label: ["User ID", "ID de usuario"], field_type: FieldType::Text, required: true, messages: &[REQUIRED, LENGTH_50] },
+    Field { name: "name", label: ["First name", "Nombre"], field_type: FieldType::Text, required: true, messages: &[REQUIRED, LENGTH_50] },
+    Field { name: "last_name", label: ["Last name", "Apellidos"], field_type: FieldType::Text, required: true, messages: &[REQUIRED, LENGTH_50] },
+    Field {
+        name: "email",
+        label: ["Email", "Correo electrónico"],
+        field_type: FieldType::Email,
+        required: true,
+        messages: &[
+            REQUIRED,
+            ("email", ["must be a valid email address of at most 100 characters", "debe ser un correo electrónico válido de 100 caracteres como máximo"]),
+        ],
+    },
+    Field {
+        name: "age",
+        label: ["Age", "Edad"],
+        field_type: FieldType::Integer,
+        required: false,
+        messages: &[("range", ["must be between 0 and 150", "debe estar entre 0 y 150"])],
+    },
+    Field {
+        name: "phone",
+        label: ["Phone", "Teléfono"],
+        field_type: FieldType::Phone,
+        required: false,
+        messages: &[("phone", ["must have 7 to 15 digits", "debe tener entre 7 y 15 dígitos"])],
+    },
+    Field { name: "address", label: ["Address", "Dirección"], field_type: FieldType::Text, required: false, messages: &[] },
+    Field {
+        name: "birthdate",
+        label: ["Birthdate", "Fecha de nacimiento"],
+        field_type: FieldType::Date,
+        required: true,
+        messages: &[REQUIRED, ("birthdate", ["must not be in the future", "no puede ser una fecha futura"])],
+    },
+    Field { name: "place_birth", label: ["Place of birth", "Lugar de nacimiento"], field_type: FieldType::Text, required: false, messages: &[] },
+    Field { name: "org_unit", label: ["Organizational unit", "Unidad organizativa"], field_type: FieldType::Text, required: false, messages: &[] },
+    Field { name: "expires_at", label: ["Account expires at", "La cuenta caduca el"], field_type: FieldType::DateTime, required: false, messages: &[] },
+];
+
+/// Display names of the account statuses.
+const STATUSES: [(&str, Text); 5] = [
+    (ACTIVE, ["Active", "Activa"]),
+    (PENDING_APPROVAL, ["Pending approval", "Pendiente de aprobación"]),
+    (REJECTED, ["Rejected", "Rechazada"]),
+    (EXPIRED, ["Expired", "Caducada"]),
+    (DISABLED, ["Disabled", "Deshabilitada"]),
+];
+
+/// Returns the metadata of the user fields translated to `locale`.
+pub fn user_fields_metadata(locale: Locale) -> UserFieldsMetadata {
+    let text = |text: &Text| text[locale as usize];
+    let fields = FIELDS
+        .iter()
+        .map(|field| FieldMetadata {
+            name: field.name,
+            label: text(&field.label),
+            field_type: field.field_type,
+            required: field.required,
+            messages: field.messages.iter().map(|(code, message)| (*code, text(message))).collect(),
+        })
+        .collect();
+    let statuses = STATUSES.iter().map(|(status, name)| (*status, text(name))).collect();
+    UserFieldsMetadata { locale, fields, enums: BTreeMap::from([("status", statuses)]) }
+}
+
+/// Returns the locale asked for by `locale`, else by the `Accept-Language` header of
+/// `req`, else English.
+pub fn request_locale(req: &HttpRequest, locale: Option<Locale>) -> Locale {
+    locale
+        .or_else(|| {
+            req.headers()
+                .get(header::ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(Locale::negotiate)
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the labels, validation messages and enum display names of the user
+/// fields, so every frontend renders the same forms without its own dictionaries.
+///
+/// # Arguments
+///
+/// * `req` - The request, whose `Accept-Language` header picks the locale.
+/// * `query` - The `locale`, which takes precedence over the header.
+///
+/// # Returns
+///
+/// * `HttpResponse` - A [`UserFieldsMetadata`] as JSON, in English when no supported
+///   locale is asked for, with the locale in `Content-Language`.
+#[utoipa::path(
+    get,
+    path = "/metadata/users/fields",
+    tag = "users",
+    params(MetadataQuery),
+    responses(
+        (status = 200, description = "The user fields, translated", body = UserFieldsMetadata),
+        (status = 400, description = "Unknown locale", body = ErrorBody),
+    )
+)]
+pub async fn user_fields(req: HttpRequest, query: web::Query<MetadataQuery>) -> HttpResponse {
+    let locale = request_locale(&req, query.locale);
+
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_LANGUAGE, HeaderValue::from_static(locale.tag())))
+        .insert_header((header::VARY, HeaderValue::from_static("Accept-Language")))
+        .json(user_fields_metadata(locale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_metadata_is_translated() {
+        let metadata = serde_json::to_value(user_fields_metadata(Locale::Es)).unwrap();
+        assert_eq!(metadata["locale"], "es");
+        assert_eq!(metadata["fields"][1], json!({
+            "name": "name",
+            "label": "Nombre",
+            "type": "text",
+            "required": true,
+            "messages": { "length": "debe tener entre 1 y 50 caracteres", "required": "es obligatorio" },
+        }));
+        assert_eq!(metadata["enums"]["status"]["pending_approval"], "Pendiente de aprobación");
+        assert_eq!(user_fields_metadata(Locale::En).fields[3].label, "Email");
+    }
+
+    #[test]
+    fn test_every_field_is_translated() {
+        for field in &FIELDS {
+            assert!(field.label.iter().all(|label| !label.is_empty()), "{}", field.name);
+            assert!(field.messages.iter().all(|(_, message)| message.iter().all(|text| !text.is_empty())), "{}", field.name);
+        }
+        assert_eq!(Locale::from_tag("ES_mx"), Some(Locale::Es));
+        assert_eq!(Locale::negotiate("es;q=0"), None);
+    }
+}
@@ -0,0 +1,218 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Mssql, Pool};
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use utoipa::ToSchema;
+use crate::audit;
+use crate::models::{CreateUserRequest, UpdateUserRequest, UserResponse};
+use crate::outbox;
+use crate::pagination::{UserPage, UserQuery};
+use crate::passwords::Credentials;
+use crate::repository::{UpdateOutcome, UserRepository};
+use crate::trace::TraceId;
+
+/// Payload of `PUT /admin/users/{id}/honeytoken`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct HoneytokenFlag {
+    pub honeytoken: bool,
+}
+
+/// Flags or unflags a user as a honeytoken on behalf of `actor`, returning `Ok(false)`
+/// if the user does not exist. The change is recorded in the user's timeline.
+pub async fn set_honeytoken(pool: &Pool<Mssql>, user_id: &str, honeytoken: bool, actor: Option<&str>) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let result = sqlx::query!(
+        "UPDATE [users] SET IsHoneytoken = @p2, UpdatedAt = SYSUTCDATETIME() WHERE id = @p1 AND DeletedAt IS NULL",
+        user_id,
+        honeytoken
+    )
+    .execute(&mut tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    audit::record(&mut tx, user_id, audit::HONEYTOKEN_CHANGED, actor, Some(&json!({ "honeytoken": honeytoken }))).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// The ids of the users flagged as honeytokens, kept in memory so that checking an
+/// access costs no query and works on users served from the cache. Shared between
+/// all workers; [`spawn_honeytoken_reloader`] keeps it up to date.
+#[derive(Clone, Debug, Default)]
+pub struct Honeytokens {
+    ids: Arc<RwLock<HashSet<String>>>,
+}
+
+impl Honeytokens {
+    /// Returns `true` if the user is flagged as a honeytoken.
+    pub fn contains(&self, user_id: &str) -> bool {
+        self.ids.read().unwrap_or_else(|e| e.into_inner()).contains(&user_id.to_lowercase())
+    }
+
+    /// Replaces the flagged ids with `ids`.
+    pub fn set(&self, ids: impl IntoIterator<Item = String>) {
+        *self.ids.write().unwrap_or_else(|e| e.into_inner()) = ids.into_iter().map(|id| id.to_lowercase()).collect();
+    }
+
+    /// Reads the flagged ids from `[users]`.
+    pub async fn reload(&self, pool: &Pool<Mssql>) -> Result<(), sqlx::Error> {
+        let ids = sqlx::query_scalar!(r#"SELECT CAST(id AS VARCHAR(36)) AS "id!" FROM [users] WHERE IsHoneytoken = 1"#)
+            .fetch_all(pool)
+            .await?;
+        self.set(ids);
+        Ok(())
+    }
+}
+
+/// Spawns a task reading the honeytoken flags into `honeytokens` every `interval`, so
+/// flags changed through the admin endpoint take effect on every instance. A failed
+/// read keeps the ids read before.
+pub fn spawn_honeytoken_reloader(pool: Pool<Mssql>, honeytokens: Honeytokens, interval: Duration) {
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = honeytokens.reload(&pool).await {
+                eprintln!("Error reloading honeytokens: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Raises the alert for an `access`, such as `"authentication"` or `"read"`, to the
+/// honeytoken `user_id`: a `security.honeytoken_tripped` entry in its timeline, which
+/// reaches the SIEM export, and an outbox event of the same type, which reaches the
+/// webhook and the `SECURITY_ALERT_EMAIL` inbox within seconds.
+pub async fn trip(pool: &Pool<Mssql>, user_id: &str, access: &str) -> Result<(), sqlx::Error> {
+    let trace_id = TraceId::current().map(|id| id.0);
+    let details = json!({ "access": access, "trace_id": trace_id });
+    eprintln!("Honeytoken {} tripped by {} (request {})", user_id, access, trace_id.as_deref().unwrap_or("-"));
+
+    let mut tx = pool.begin().await?;
+    audit::record(&mut tx, user_id, audit::HONEYTOKEN_TRIPPED, None, Some(&details)).await?;
+    outbox::enqueue(&mut tx, outbox::HONEYTOKEN_TRIPPED, user_id, &json!({ "user_id": user_id, "access": access, "trace_id": trace_id })).await?;
+    tx.commit().await
+}
+
+/// [`UserRepository`] that raises the [`trip`] alert whenever a honeytoken is
+/// authenticated as, read, changed, deleted or restored by id through `inner`.
+///
+/// Lists, searches and exports that merely include a honeytoken among other users
+/// do not trip it, so the decoys can sit in the directory as bait. Failing to raise
+/// the alert is logged and never fails the request, which must look the same as for
+/// any other account.
+pub struct HoneytokenWatch {
+    inner: Arc<dyn UserRepository>,
+    pool: Pool<Mssql>,
+    honeytokens: Honeytokens,
+}
+
+impl HoneytokenWatch {
+    /// Watches the users of `inner` flagged in `honeytokens`, raising alerts in `pool`.
+    pub fn new(inner: Arc<dyn UserRepository>, pool: Pool<Mssql>, honeytokens: Honeytokens) -> Self {
+        HoneytokenWatch { inner, pool, honeytokens }
+    }
+
+    async fn watch(&self, user_id: &str, access: &str) {
+        if !self.honeytokens.contains(user_id) {
+            return;
+        }
+        if let Err(e) = trip(&self.pool, user_id, access).await {
+            eprintln!("Error raising honeytoken {}: {:?}", user_id, e);
+        }
+    }
+
+    async fn watch_if(&self, touched: Result<bool, sqlx::Error>, user_id: &str, access: &str) -> Result<bool, sqlx::Error> {
+        if let Ok(true) = touched {
+            self.watch(user_id, access).await;
+        }
+        touched
+    }
+}
+
+#[async_trait]
+impl UserRepository for HoneytokenWatch {
+    async fn create(&self, user: &CreateUserRequest, password_hash: Option<&str>, status: &str, tenant_id: Option<&str>, actor: Option<&str>) -> Result<String, sqlx::Error> {
+        self.inner.create(user, password_hash, status, tenant_id, actor).await
+    }
+
+    async fn create_many(&self, users: &[CreateUserRequest], status: &str, tenant_id: Option<&str>, actor: Option<&str>) -> Result<Vec<Option<String>>, sqlx::Error> {
+        self.inner.create_many(users, status, tenant_id, actor).await
+    }
+
+    async fn create_linked(&self, user: &CreateUserRequest, status: &str, tenant_id: Option<&str>, provider: &str, subject: &str) -> Result<String, sqlx::Error> {
+        self.inner.create_linked(user, status, tenant_id, provider, subject).await
+    }
+
+    async fn list(&self, query: &UserQuery) -> Result<UserPage, sqlx::Error> {
+        self.inner.list(query).await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<UserResponse>, sqlx::Error> {
+        let user = self.inner.get(id).await?;
+        if user.is_some() {
+            self.watch(id, "read").await;
+        }
+        Ok(user)
+    }
+
+    async fn count_in_tenant(&self, tenant_id: &str) -> Result<i64, sqlx::Error> {
+        self.inner.count_in_tenant(tenant_id).await
+    }
+
+    async fn credentials_by_email(&self, email: &str) -> Result<Option<Credentials>, sqlx::Error> {
+        let credentials = self.inner.credentials_by_email(email).await?;
+        if let Some(credentials) = &credentials {
+            self.watch(&credentials.id, "authentication").await;
+        }
+        Ok(credentials)
+    }
+
+    async fn update(&self, id: &str, user: &CreateUserRequest, actor: Option<&str>) -> Result<UpdateOutcome, sqlx::Error> {
+        let updated = self.inner.update(id, user, actor).await?;
+        if updated != UpdateOutcome::NotFound {
+            self.watch(id, "update").await;
+        }
+        Ok(updated)
+    }
+
+    async fn patch(&self, id: &str, changes: &UpdateUserRequest, actor: Option<&str>) -> Result<UpdateOutcome, sqlx::Error> {
+        let patched = self.inner.patch(id, changes, actor).await?;
+        if patched != UpdateOutcome::NotFound {
+            self.watch(id, "update").await;
+        }
+        Ok(patched)
+    }
+
+    async fn delete(&self, id: &str, actor: Option<&str>) -> Result<bool, sqlx::Error> {
+        let deleted = self.inner.delete(id, actor).await;
+        self.watch_if(deleted, id, "delete").await
+    }
+
+    async fn restore(&self, id: &str, actor: Option<&str>) -> Result<bool, sqlx::Error> {
+        let restored = self.inner.restore(id, actor).await;
+        self.watch_if(restored, id, "restore").await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_honeytokens_match_ids_in_any_case() {
+        let honeytokens = Honeytokens::default();
+        honeytokens.set(["6F9619FF-8B86-D011-B42D-00C04FC964FF".to_string()]);
+        assert!(honeytokens.contains("6f9619ff-8b86-d011-b42d-00c04fc964ff"));
+        assert!(!honeytokens.contains("00000000-0000-0000-0000-000000000000"));
+
+        honeytokens.set([]);
+        assert!(!honeytokens.contains("6f9619ff-8b86-d011-b42d-00c04fc964ff"), "Unflagged users stop matching");
+    }
+}
@@ -0,0 +1,137 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use rand::RngCore;
+use sqlx::{Mssql, Pool};
+use std::env;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Error returned by [`id_generator_from_env`] for an unknown `ID_STRATEGY`.
+pub type IdStrategyError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Source of the primary keys given to new users.
+///
+/// Every strategy produces a value that fits the `UNIQUEIDENTIFIER` id column,
+/// formatted like a UUID.
+#[async_trait]
+pub trait IdGenerator: Send + Sync {
+    async fn next_id(&self) -> Result<String, sqlx::Error>;
+}
+
+/// Random UUIDv4 ids, the default.
+pub struct UuidV4;
+
+#[async_trait]
+impl IdGenerator for UuidV4 {
+    async fn next_id(&self) -> Result<String, sqlx::Error> {
+        Ok(Uuid::new_v4().to_string())
+    }
+}
+
+/// Time-ordered UUIDv7 ids, which sort by creation time as text and in most databases.
+///
+/// SQL Server compares `UNIQUEIDENTIFIER` values starting from their last six bytes,
+/// so inside its indexes these ids are not ordered; use [`SequentialId`] for that.
+pub struct UuidV7;
+
+#[async_trait]
+impl IdGenerator for UuidV7 {
+    async fn next_id(&self) -> Result<String, sqlx::Error> {
+        Ok(Uuid::now_v7().to_string())
+    }
+}
+
+/// ULIDs: a 48-bit millisecond timestamp followed by 80 random bits, formatted as a
+/// UUID to fit the id column. The same index ordering caveat as [`UuidV7`] applies.
+pub struct Ulid;
+
+impl Ulid {
+    /// Returns the ULID for `millis` since the Unix epoch with the given random bits,
+    /// of which only the low 80 are used.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::ids::Ulid;
+    ///
+    /// let id = Ulid::from_parts(1_700_000_000_000, 0x42);
+    /// assert_eq!(id.to_string(), "018bcfe5-6800-0000-0000-000000000042");
+    /// ```
+    pub fn from_parts(millis: u64, random: u128) -> Uuid {
+        let timestamp = (millis as u128 & ((1 << 48) - 1)) << 80;
+        Uuid::from_u128(timestamp | (random & ((1 << 80) - 1)))
+    }
+}
+
+#[async_trait]
+impl IdGenerator for Ulid {
+    async fn next_id(&self) -> Result<String, sqlx::Error> {
+        let mut random = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut random);
+        let millis = Utc::now().timestamp_millis().max(0) as u64;
+        Ok(Ulid::from_parts(millis, u128::from_be_bytes(random)).to_string())
+    }
+}
+
+/// Ids taken from SQL Server's `NEWSEQUENTIALID()`, which increase in the server's
+/// own `UNIQUEIDENTIFIER` order and so keep inserts at the end of the clustered index.
+///
+/// Each id costs a round trip, and the sequence may start lower after the server restarts.
+pub struct SequentialId {
+    pool: Pool<Mssql>,
+}
+
+impl SequentialId {
+    /// Creates a generator drawing ids from the database behind `pool`.
+    pub fn new(pool: Pool<Mssql>) -> Self {
+        SequentialId { pool }
+    }
+}
+
+#[async_trait]
+impl IdGenerator for SequentialId {
+    async fn next_id(&self) -> Result<String, sqlx::Error> {
+        // NEWSEQUENTIALID() is only allowed as a column default.
+        sqlx::query_scalar!(
+            r#"
+            DECLARE @ids TABLE (id UNIQUEIDENTIFIER NOT NULL DEFAULT NEWSEQUENTIALID());
+            INSERT INTO @ids OUTPUT CAST(INSERTED.id AS VARCHAR(36)) AS "id!: String" DEFAULT VALUES;
+            "#
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map(|id| id.to_lowercase())
+    }
+}
+
+/// Builds the generator selected by `ID_STRATEGY`.
+///
+/// * `uuid4` (default) - [`UuidV4`].
+/// * `uuid7` - [`UuidV7`].
+/// * `ulid` - [`Ulid`].
+/// * `sequential` - [`SequentialId`], drawing from `pool`.
+pub fn id_generator_from_env(pool: &Pool<Mssql>) -> Result<Arc<dyn IdGenerator>, IdStrategyError> {
+    match env::var("ID_STRATEGY").unwrap_or_else(|_| "uuid4".into()).trim().to_lowercase().as_str() {
+        "" | "uuid4" => Ok(Arc::new(UuidV4)),
+        "uuid7" => Ok(Arc::new(UuidV7)),
+        "ulid" => Ok(Arc::new(Ulid)),
+        "sequential" => Ok(Arc::new(SequentialId::new(pool.clone()))),
+        other => Err(format!("Unknown ID_STRATEGY `{}`", other).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_time_ordered_ids_sort_by_creation() {
+        let first = Ulid::from_parts(1_000, u128::MAX);
+        let second = Ulid::from_parts(1_001, 0);
+        assert!(first.to_string() < second.to_string());
+
+        let v7 = UuidV7.next_id().await.unwrap();
+        assert_eq!(Uuid::parse_str(&v7).unwrap().get_version_num(), 7);
+        assert!(Uuid::parse_str(&Ulid.next_id().await.unwrap()).is_ok());
+    }
+}
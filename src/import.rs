@@ -0,0 +1,177 @@
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use validator::Validate;
+use crate::approvals::ACTIVE;
+use crate::audit;
+use crate::error::{field_messages, AppError};
+use crate::extractors::AuthenticatedUser;
+use crate::models::CreateUserRequest;
+use crate::quotas::{self, request_tenant};
+use crate::repository::UserRepository;
+
+/// Most rows accepted by one `POST /protected/users/import`.
+pub const MAX_IMPORT_ROWS: usize = 1000;
+
+/// Outcome of one imported row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    /// The user was created.
+    Created,
+    /// The email or `user_id` was already registered, by an existing user or an earlier row.
+    Skipped,
+    /// The row could not be read or breaks a rule; see `errors`.
+    Invalid,
+}
+
+/// Result of one row of an import. `row` counts from 1, not counting the CSV header.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImportRow {
+    pub row: usize,
+    pub status: ImportStatus,
+    /// The id of the created user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// The messages of each rule the row breaks, by field, as in a `422` response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub errors: Option<Value>,
+}
+
+/// Report of `POST /protected/users/import`, with one entry per row in input order.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ImportReport {
+    pub created: usize,
+    pub skipped: usize,
+    pub invalid: usize,
+    pub rows: Vec<ImportRow>,
+}
+
+/// Reads the rows of a CSV file with a header line naming the [`CreateUserRequest`] fields.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::import::parse_csv;
+///
+/// let rows = parse_csv(b"user_id,name,last_name,email,birthdate\n7,Ana,Diaz,ana@example.com,1990-01-01\n").unwrap();
+/// assert_eq!(rows.len(), 1);
+/// assert_eq!(rows[0].as_ref().unwrap().email, "ana@example.com");
+/// ```
+pub fn parse_csv(body: &[u8]) -> Result<Vec<Result<CreateUserRequest, String>>, AppError> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(body);
+    reader.headers().map_err(|e| AppError::validation(format!("The CSV header cannot be read: {}", e)))?;
+    Ok(reader.deserialize().map(|row| row.map_err(|e: csv::Error| e.to_string())).collect())
+}
+
+/// Reads the rows of a JSON array of [`CreateUserRequest`] objects. A malformed row only fails
+/// that row; a body that is not an array fails the whole import.
+pub fn parse_json(body: &[u8]) -> Result<Vec<Result<CreateUserRequest, String>>, AppError> {
+    let rows: Vec<Value> = serde_json::from_slice(body).map_err(|e| AppError::validation(format!("The body must be a JSON array of users: {}", e)))?;
+    Ok(rows.into_iter().map(|row| serde_json::from_value(row).map_err(|e| e.to_string())).collect())
+}
+
+/// Imports users from a CSV file (`text/csv`) or a JSON array (`application/json`).
+///
+/// Imported users are active and have no password, like users created with
+/// `POST /admin/users`. Every row is validated first; the valid ones are then
+/// inserted in a single transaction, skipping emails and `user_id`s that are
+/// already registered.
+///
+/// # Arguments
+///
+/// * `users` - The user repository.
+/// * `request` - The request, whose `Content-Type` selects the format and whose
+///   `X-Tenant-Id` header names the tenant of the users in multi-tenant mode.
+/// * `body` - Up to [`MAX_IMPORT_ROWS`] rows.
+/// * `caller` - The authenticated caller, recorded as the actor of each creation.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `200 OK` with an [`ImportReport`], `400 Bad Request`
+///   if the body cannot be read, has too many rows or has an unsupported content type, or
+///   `403 Forbidden` if the valid rows would take the tenant past its user cap.
+#[utoipa::path(
+    post,
+    path = "/protected/users/import",
+    tag = "users",
+    request_body(content = Vec<CreateUserRequest>, description = "A JSON array of users, or a CSV file with the same fields", content_type = "application/json"),
+    responses(
+        (status = 200, description = "The outcome of each row", body = ImportReport),
+        (status = 400, description = "The body cannot be read or has too many rows", body = ErrorBody),
+        (status = 403, description = "The valid rows would take the tenant past its user cap (`quota_exceeded`)", body = ErrorBody),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn import_users(users: web::Data<Arc<dyn UserRepository>>, request: HttpRequest, body: web::Bytes, caller: Option<AuthenticatedUser>) -> Result<HttpResponse, AppError> {
+    let content_type = request.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("");
+    let parsed = match content_type.split(';').next().unwrap_or("").trim().to_lowercase().as_str() {
+        "text/csv" => parse_csv(&body)?,
+        "application/json" => parse_json(&body)?,
+        _ => return Err(AppError::validation("The body must be text/csv or application/json.")),
+    };
+    if parsed.len() > MAX_IMPORT_ROWS {
+        return Err(AppError::validation(format!("At most {} rows can be imported at once.", MAX_IMPORT_ROWS)));
+    }
+
+    let mut rows = Vec::with_capacity(parsed.len());
+    let mut valid = Vec::new();
+    for (index, user) in parsed.into_iter().enumerate() {
+        let mut row = ImportRow { row: index + 1, status: ImportStatus::Invalid, id: None, email: None, errors: None };
+        match user {
+            Err(message) => row.errors = Some(json!({ "row": [message] })),
+            Ok(user) => {
+                row.email = Some(user.email.to_string());
+                match user.validate() {
+                    Err(errors) => row.errors = Some(field_messages(&errors)),
+                    Ok(()) => valid.push((rows.len(), user)),
+                }
+            }
+        }
+        rows.push(row);
+    }
+
+    let (positions, new_users): (Vec<usize>, Vec<CreateUserRequest>) = valid.into_iter().unzip();
+    let tenant_id = request_tenant(&request)?;
+    quotas::enforce(users.get_ref().as_ref(), tenant_id.as_deref(), new_users.len()).await?;
+    let ids = users.create_many(&new_users, ACTIVE, tenant_id.as_deref(), audit::actor(&caller).as_deref()).await?;
+    for (position, id) in positions.into_iter().zip(ids) {
+        let row = &mut rows[position];
+        row.status = if id.is_some() { ImportStatus::Created } else { ImportStatus::Skipped };
+        row.id = id;
+    }
+
+    let count = |status| rows.iter().filter(|row| row.status == status).count();
+    let report = ImportReport {
+        created: count(ImportStatus::Created),
+        skipped: count(ImportStatus::Skipped),
+        invalid: count(ImportStatus::Invalid),
+        rows,
+    };
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_errors_only_fail_their_rows() {
+        let csv = b"user_id,name,last_name,email,age,birthdate\n1,Ana,Diaz,ana@example.com,thirty,1990-01-01\n2,Bob,Ruiz,bob@example.com,,1991-02-03\n";
+        let rows = parse_csv(csv).unwrap();
+        assert!(rows[0].is_err());
+        assert_eq!(rows[1].as_ref().unwrap().age, None);
+
+        let rows = parse_json(br#"[{"name": 5}, {"user_id": "3", "name": "Eva", "last_name": "Gil", "email": "eva@example.com", "birthdate": "1993-04-05"}]"#).unwrap();
+        assert!(rows[0].is_err());
+        assert_eq!(rows[1].as_ref().unwrap().name, "Eva");
+
+        assert!(parse_json(br#"{"users": []}"#).is_err());
+    }
+}
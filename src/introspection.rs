@@ -0,0 +1,135 @@
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Mssql, Pool};
+use utoipa::ToSchema;
+use crate::auth::Claims;
+use crate::error::AppError;
+use crate::extractors::{AuthenticatedUser, UserId};
+use crate::rbac;
+use crate::timestamp::Timestamp;
+
+/// The login session an access token belongs to, as tracked by its refresh tokens.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SessionInfo {
+    /// The `sid` claim of the token.
+    pub id: String,
+    /// The `User-Agent` of the client that logged in.
+    pub device: Option<String>,
+    /// When the user logged in.
+    #[schema(value_type = String, format = DateTime)]
+    pub started_at: Timestamp,
+    /// When the session's latest refresh token expires.
+    #[schema(value_type = String, format = DateTime)]
+    pub expires_at: Timestamp,
+    /// `false` once the session was logged out, revoked or has expired; the access
+    /// token keeps working until its own expiration unless revoked too.
+    pub active: bool,
+}
+
+/// Body of `GET /me/token`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TokenIntrospection {
+    /// The decoded claims of the access token the request was made with.
+    pub claims: Claims,
+    /// Seconds until the access token expires, `0` once it has.
+    pub expires_in: i64,
+    /// The permissions granted to the caller through their roles.
+    pub scopes: Vec<String>,
+    /// The session of the token, if it has a `sid` claim for a session that still exists.
+    pub session: Option<SessionInfo>,
+}
+
+#[derive(Debug, FromRow)]
+struct SessionRow {
+    device: Option<String>,
+    started_at: Timestamp,
+    expires_at: Timestamp,
+    active: i32,
+}
+
+/// Returns the seconds left before `exp` (a Unix timestamp), never less than `0`.
+fn remaining_lifetime(exp: usize, now: i64) -> i64 {
+    (exp as i64 - now).max(0)
+}
+
+/// Returns the session `session_id` of `user_id`, or `None` if it has no refresh token left.
+pub async fn session(pool: &Pool<Mssql>, user_id: &str, session_id: &str) -> Result<Option<SessionInfo>, sqlx::Error> {
+    let row = sqlx::query_as!(
+        SessionRow,
+        r#"
+        SELECT
+            MAX(UserAgent)                            AS "device?",
+            CONVERT(VARCHAR(33), MIN(CreatedAt), 126) AS "started_at!: Timestamp",
+            CONVERT(VARCHAR(33), MAX(ExpiresAt), 126) AS "expires_at!: Timestamp",
+            MAX(CASE WHEN RevokedAt IS NULL AND ExpiresAt > SYSUTCDATETIME() THEN 1 ELSE 0 END) AS "active!: i32"
+        FROM [refresh_tokens]
+        WHERE FamilyId = @p1 AND UserId = @p2
+        GROUP BY FamilyId
+        "#,
+        session_id,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| SessionInfo {
+        id: session_id.to_string(),
+        device: row.device,
+        started_at: row.started_at,
+        expires_at: row.expires_at,
+        active: row.active != 0,
+    }))
+}
+
+/// Describes the access token the request was made with, so clients can schedule
+/// their refresh without decoding it.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `user` - The caller and the claims of their token.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - A [`TokenIntrospection`] with the claims, the
+///   remaining lifetime, the caller's scopes and the session of the token.
+#[utoipa::path(
+    get,
+    path = "/me/token",
+    tag = "auth",
+    responses(
+        (status = 200, description = "The claims, lifetime, scopes and session of the token", body = TokenIntrospection),
+        (status = 401, description = "Missing, invalid or revoked token"),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn token_info(pool: web::Data<Pool<Mssql>>, user: AuthenticatedUser) -> Result<HttpResponse, AppError> {
+    let scopes = match UserId::try_from(user.sub.clone()) {
+        Ok(user_id) => rbac::permissions_for_user(pool.get_ref(), &user_id.to_string()).await?,
+        Err(_) => Vec::new(),
+    };
+    let session = match &user.sid {
+        Some(sid) => session(pool.get_ref(), user.id(), sid).await?,
+        None => None,
+    };
+
+    Ok(HttpResponse::Ok().json(TokenIntrospection {
+        expires_in: remaining_lifetime(user.exp, Utc::now().timestamp()),
+        claims: user.claims().clone(),
+        scopes,
+        session,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remaining_lifetime_is_never_negative() {
+        assert_eq!(remaining_lifetime(1_700_000_600, 1_700_000_000), 600);
+        assert_eq!(remaining_lifetime(1_700_000_000, 1_700_000_000), 0);
+        assert_eq!(remaining_lifetime(1_699_999_000, 1_700_000_000), 0);
+    }
+}
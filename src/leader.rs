@@ -0,0 +1,117 @@
+use sqlx::{Mssql, Pool};
+use std::env;
+use std::sync::OnceLock;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Shortest time a replica holds a lease without renewing it.
+const MIN_LEASE_TTL: Duration = Duration::from_secs(30);
+
+/// Leases renewed every tick outlive this many ticks, so a slow tick does not hand
+/// the job to another replica.
+const TICKS_PER_LEASE: u32 = 3;
+
+static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+
+/// Returns the name this replica holds leases under: `INSTANCE_ID` if set, so a
+/// restarted replica takes its leases straight back, and otherwise `HOSTNAME`
+/// followed by a random suffix.
+pub fn instance_id() -> &'static str {
+    INSTANCE_ID.get_or_init(|| instance_id_from_vars(|name| env::var(name).ok()))
+}
+
+fn instance_id_from_vars(var: impl Fn(&str) -> Option<String>) -> String {
+    let configured = var("INSTANCE_ID").map(|id| id.trim().to_string()).filter(|id| !id.is_empty());
+    configured.unwrap_or_else(|| {
+        let host = var("HOSTNAME").unwrap_or_else(|| "safe_user".to_string());
+        format!("{}:{}", host, Uuid::new_v4())
+    })
+}
+
+/// Returns how long a lease on a job run every `interval` lasts.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use safe_user::leader::lease_ttl;
+///
+/// assert_eq!(lease_ttl(Duration::from_secs(60)), Duration::from_secs(180));
+/// assert_eq!(lease_ttl(Duration::from_secs(5)), Duration::from_secs(30));
+/// ```
+pub fn lease_ttl(interval: Duration) -> Duration {
+    (interval * TICKS_PER_LEASE).max(MIN_LEASE_TTL)
+}
+
+/// A lease in `[job_leases]` electing the one replica that runs a background job.
+///
+/// Each replica tries to [`acquire`](JobLease::acquire) the lease before every run:
+/// the holder renews it, and the others only get it once it has gone unrenewed for
+/// [`lease_ttl`], for example because the holder stopped. A run that outlasts the
+/// lease may overlap with the next holder's, so jobs keep being safe to repeat.
+#[derive(Debug, Clone)]
+pub struct JobLease {
+    pool: Pool<Mssql>,
+    name: &'static str,
+    ttl: Duration,
+}
+
+impl JobLease {
+    /// Creates the lease of the job `name`, run every `interval`.
+    pub fn new(pool: Pool<Mssql>, name: &'static str, interval: Duration) -> Self {
+        JobLease { pool, name, ttl: lease_ttl(interval) }
+    }
+
+    /// Takes or renews the lease for this replica, returning `true` if it now holds it.
+    ///
+    /// The lease is taken with a single `MERGE` under `HOLDLOCK`, so two replicas
+    /// racing for a free lease cannot both win. Database errors are logged and count
+    /// as not holding it, leaving the job to whichever replica can reach the database.
+    pub async fn acquire(&self) -> bool {
+        match try_acquire(&self.pool, self.name, instance_id(), self.ttl).await {
+            Ok(held) => held,
+            Err(e) => {
+                eprintln!("Error acquiring the {} job lease: {:?}", self.name, e);
+                false
+            }
+        }
+    }
+}
+
+/// Gives the lease `name` to `holder` for `ttl` if it is free, expired or already
+/// held by `holder`, returning whether `holder` holds it.
+pub async fn try_acquire(pool: &Pool<Mssql>, name: &str, holder: &str, ttl: Duration) -> Result<bool, sqlx::Error> {
+    let ttl_secs = ttl.as_secs() as i64;
+    let result = sqlx::query!(
+        r#"
+        MERGE [job_leases] WITH (HOLDLOCK) AS target
+        USING (SELECT @p1 AS Name) AS source
+        ON target.Name = source.Name
+        WHEN MATCHED AND (target.Holder = @p2 OR target.ExpiresAt < SYSUTCDATETIME()) THEN
+            UPDATE SET Holder = @p2, ExpiresAt = DATEADD(SECOND, @p3, SYSUTCDATETIME())
+        WHEN NOT MATCHED THEN
+            INSERT (Name, Holder, ExpiresAt) VALUES (@p1, @p2, DATEADD(SECOND, @p3, SYSUTCDATETIME()));
+        "#,
+        name,
+        holder,
+        ttl_secs
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_id_is_configurable_or_unique() {
+        assert_eq!(instance_id_from_vars(|name| (name == "INSTANCE_ID").then(|| " api-0 ".to_string())), "api-0");
+
+        let host = |name: &str| (name == "HOSTNAME").then(|| "api-7d9f".to_string());
+        let (first, second) = (instance_id_from_vars(host), instance_id_from_vars(host));
+        assert!(first.starts_with("api-7d9f:"));
+        assert_ne!(first, second);
+    }
+}
@@ -1,4 +1,72 @@
+pub mod admin;
+pub mod approvals;
+pub mod audit;
+pub mod audit_chain;
 pub mod auth;
+pub mod backup;
+pub mod birthdate;
+pub mod breach;
+#[cfg(feature = "redis")]
+pub mod cache;
+pub mod casing;
+pub mod challenge;
+pub mod config;
+pub mod cookie_sessions;
+pub mod cors;
 pub mod db;
+pub mod deactivation;
+pub mod device;
+pub mod email_verification;
+pub mod error;
+pub mod expiration;
+pub mod export;
+pub mod extractors;
+pub mod features;
+pub mod field_metadata;
+pub mod mailer;
+pub mod metrics;
+pub mod mfa;
+pub mod migrations;
 pub mod handlers;
-pub mod models;
\ No newline at end of file
+pub mod health;
+pub mod honeytokens;
+pub mod ids;
+pub mod import;
+pub mod leader;
+pub mod load_shedding;
+pub mod lockout;
+pub mod locks;
+pub mod introspection;
+pub mod models;
+pub mod oauth;
+pub mod oidc;
+pub mod openapi;
+pub mod org_chart;
+pub mod outbox;
+pub mod pagination;
+pub mod password_reset;
+pub mod passwords;
+pub mod plans;
+pub mod policy;
+pub mod presence;
+pub mod profile_gaps;
+pub mod quotas;
+pub mod rate_limit;
+pub mod rbac;
+pub mod read_only;
+pub mod replication;
+pub mod repository;
+pub mod route_policy;
+pub mod routes;
+pub mod saved_searches;
+pub mod sessions;
+pub mod siem;
+pub mod signed_urls;
+pub mod suggest;
+pub mod telemetry;
+pub mod tenancy;
+pub mod timestamp;
+pub mod tokens;
+pub mod trace;
+pub mod validation;
+pub mod visibility;
\ No newline at end of file
@@ -0,0 +1,219 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, ResponseError};
+use sqlx::{Mssql, Pool};
+use std::env;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::device::VERIFICATION_PATH;
+use crate::error::AppError;
+use crate::metrics::METRICS_PATH;
+use crate::oidc::DISCOVERY_PATH;
+use crate::routes::unversioned;
+use crate::tokens::JWKS_PATH;
+
+/// Reads that are never shed: probes, metrics, the keys and documents other services
+/// validate tokens with, and the reads that are part of signing in, along with every
+/// path under `/oauth/`.
+pub const SHED_EXEMPT_PATHS: &[&str] = &[
+    "/health/live",
+    "/health/ready",
+    METRICS_PATH,
+    JWKS_PATH,
+    DISCOVERY_PATH,
+    VERIFICATION_PATH,
+    "/verify_email",
+    "/me/token",
+    "/challenge",
+];
+
+/// How often the time waited for a database connection is measured.
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shared state of the load-shedding middleware: the requests being served and the
+/// latest time waited for a connection of the pool.
+///
+/// Once either goes past its limit, reads outside [`SHED_EXEMPT_PATHS`] are answered
+/// with `503 Service Unavailable` so logins, token refreshes and writes keep the
+/// workers and connections.
+#[derive(Debug, Clone)]
+pub struct LoadShedder {
+    max_in_flight: Option<usize>,
+    max_pool_wait: Option<Duration>,
+    in_flight: Arc<AtomicUsize>,
+    pool_wait_ms: Arc<AtomicU64>,
+}
+
+/// Counts a request as in flight until dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl LoadShedder {
+    /// Creates a shedder rejecting reads once more than `max_in_flight` requests are
+    /// being served or a connection took longer than `max_pool_wait` to get; `None`
+    /// disables that limit.
+    pub fn new(max_in_flight: Option<usize>, max_pool_wait: Option<Duration>) -> Self {
+        LoadShedder {
+            max_in_flight,
+            max_pool_wait,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            pool_wait_ms: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Reads the limits from the environment.
+    pub fn from_env() -> Option<Self> {
+        LoadShedder::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Reads the limits from the variables returned by `var`: `SHED_MAX_IN_FLIGHT`
+    /// requests and `SHED_MAX_POOL_WAIT_MS` milliseconds. Returns `None`, disabling
+    /// load shedding, when neither is a positive number.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::load_shedding::LoadShedder;
+    ///
+    /// assert!(LoadShedder::from_vars(|name| (name == "SHED_MAX_IN_FLIGHT").then(|| "200".to_string())).is_some());
+    /// assert!(LoadShedder::from_vars(|name| (name == "SHED_MAX_POOL_WAIT_MS").then(|| "0".to_string())).is_none());
+    /// ```
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let limit = |name| var(name).and_then(|value| value.trim().parse::<u64>().ok()).filter(|limit| *limit > 0);
+        let max_in_flight = limit("SHED_MAX_IN_FLIGHT").map(|limit| limit as usize);
+        let max_pool_wait = limit("SHED_MAX_POOL_WAIT_MS").map(Duration::from_millis);
+        (max_in_flight.is_some() || max_pool_wait.is_some()).then(|| LoadShedder::new(max_in_flight, max_pool_wait))
+    }
+
+    /// Returns the number of requests being served.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Records how long the latest connection took to get from the pool.
+    pub fn record_pool_wait(&self, wait: Duration) {
+        self.pool_wait_ms.store(wait.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if either limit is exceeded.
+    pub fn is_overloaded(&self) -> bool {
+        let busy = self.max_in_flight.is_some_and(|max| self.in_flight() > max);
+        let slow_pool = self.max_pool_wait.is_some_and(|max| self.pool_wait_ms.load(Ordering::SeqCst) > max.as_millis() as u64);
+        busy || slow_pool
+    }
+
+    fn enter(&self) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.in_flight.clone())
+    }
+}
+
+/// Returns `true` for the reads that are shed first under load.
+fn is_low_priority(method: &Method, path: &str) -> bool {
+    let path = unversioned(path);
+    matches!(*method, Method::GET | Method::HEAD) && !SHED_EXEMPT_PATHS.contains(&path) && !path.starts_with("/oauth/")
+}
+
+/// Middleware function that counts the requests in flight and answers low-priority
+/// reads with `503 Service Unavailable` while the [`LoadShedder`] is overloaded.
+///
+/// The middleware is a no-op when no `LoadShedder` has been registered as app data.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{middleware::from_fn, web, App};
+/// use safe_user::load_shedding::{shed_reads_under_load, LoadShedder};
+///
+/// let app = App::new()
+///     .app_data(web::Data::new(LoadShedder::new(Some(200), None)))
+///     .wrap(from_fn(shed_reads_under_load));
+/// ```
+pub async fn shed_reads_under_load(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let shedder = match req.app_data::<web::Data<LoadShedder>>() {
+        Some(shedder) => shedder.clone(),
+        None => return next.call(req).await.map(ServiceResponse::map_into_left_body),
+    };
+    let _in_flight = shedder.enter();
+
+    if shedder.is_overloaded() && is_low_priority(req.method(), req.path()) {
+        let response = AppError::Overloaded.error_response();
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+/// Spawns a task measuring every second how long a connection of `pool` takes to get,
+/// for the pool wait limit of `shedder`. A failed attempt counts as having waited
+/// until it failed.
+pub fn spawn_pool_probe(pool: Pool<Mssql>, shedder: LoadShedder) {
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(PROBE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let started = Instant::now();
+            let connection = pool.acquire().await;
+            shedder.record_pool_wait(started.elapsed());
+            drop(connection);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::{http::StatusCode, test, App, HttpResponse, Responder};
+
+    async fn ok() -> impl Responder {
+        HttpResponse::Ok().json("ok")
+    }
+
+    /// Checks that only low-priority reads are shed, and only while overloaded.
+    #[actix_web::test]
+    async fn test_overload_sheds_reads_only() {
+        let shedder = LoadShedder::new(None, Some(Duration::from_millis(500)));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(shedder.clone()))
+                .wrap(from_fn(shed_reads_under_load))
+                .route("/users", web::get().to(ok))
+                .route("/create_user", web::post().to(ok))
+                .route("/me/token", web::get().to(ok))
+        ).await;
+
+        let req = test::TestRequest::get().uri("/users").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        shedder.record_pool_wait(Duration::from_secs(2));
+        let req = test::TestRequest::get().uri("/users").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(resp.headers().contains_key("retry-after"));
+
+        for req in [test::TestRequest::post().uri("/create_user"), test::TestRequest::get().uri("/me/token")] {
+            assert_eq!(test::call_service(&app, req.to_request()).await.status(), StatusCode::OK);
+        }
+        assert_eq!(shedder.in_flight(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_in_flight_limit() {
+        let shedder = LoadShedder::new(Some(1), None);
+        let first = shedder.enter();
+        assert!(!shedder.is_overloaded());
+        let second = shedder.enter();
+        assert!(shedder.is_overloaded());
+        drop((first, second));
+        assert!(!shedder.is_overloaded());
+    }
+}
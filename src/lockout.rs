@@ -0,0 +1,172 @@
+use serde_json::json;
+use sqlx::{Mssql, Pool};
+use std::env;
+use std::net::IpAddr;
+use crate::audit;
+use crate::error::AppError;
+use crate::repository::UserRepository;
+
+/// Longest email stored in `[login_failures]`, the length of `[users].Email`.
+const MAX_EMAIL_LENGTH: usize = 100;
+
+/// When repeated failed logins lock out an email or a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    /// Failed logins for one email within the window that lock it out, from
+    /// `LOCKOUT_MAX_FAILURES` (default 5); `0` disables the per-email lockout.
+    pub max_failures: u32,
+    /// Failed logins from one IP within the window that lock it out, from
+    /// `LOCKOUT_MAX_IP_FAILURES` (default 50); `0` disables the per-IP lockout.
+    pub max_ip_failures: u32,
+    /// Seconds failures are counted for, and so the longest a lockout lasts, from
+    /// `LOCKOUT_WINDOW_MINUTES` (default 15).
+    pub window_secs: u64,
+}
+
+impl LockoutPolicy {
+    /// Reads the policy from the environment.
+    pub fn from_env() -> Self {
+        LockoutPolicy::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Reads the policy from the variables returned by `var`. Values that are not
+    /// numbers are ignored, and a window of `0` minutes falls back to the default.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::lockout::LockoutPolicy;
+    ///
+    /// let policy = LockoutPolicy::from_vars(|name| match name {
+    ///     "LOCKOUT_MAX_FAILURES" => Some("3".to_string()),
+    ///     "LOCKOUT_MAX_IP_FAILURES" => Some("0".to_string()),
+    ///     _ => None,
+    /// });
+    /// assert_eq!(policy, LockoutPolicy { max_failures: 3, max_ip_failures: 0, window_secs: 900 });
+    /// ```
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let number = |name: &str| var(name).and_then(|value| value.trim().parse::<u32>().ok());
+        LockoutPolicy {
+            max_failures: number("LOCKOUT_MAX_FAILURES").unwrap_or(5),
+            max_ip_failures: number("LOCKOUT_MAX_IP_FAILURES").unwrap_or(50),
+            window_secs: number("LOCKOUT_WINDOW_MINUTES").filter(|minutes| *minutes > 0).unwrap_or(15) as u64 * 60,
+        }
+    }
+
+    /// Returns `true` if `failures` within the window lock out an email, or a client
+    /// when `per_ip` is set.
+    pub fn is_exceeded(&self, failures: i32, per_ip: bool) -> bool {
+        let max = if per_ip { self.max_ip_failures } else { self.max_failures };
+        max > 0 && failures >= max as i32
+    }
+
+    fn locked_out(&self) -> AppError {
+        AppError::LockedOut(self.window_secs)
+    }
+}
+
+/// Refuses a login for `email` from `ip` with [`LockedOut`](AppError::LockedOut) if
+/// either has failed too often within the window, before the password is checked.
+///
+/// Lockouts apply to emails whether or not an account has them, so a `423` does not
+/// reveal which emails are registered.
+pub async fn check(pool: &Pool<Mssql>, policy: &LockoutPolicy, email: &str, ip: Option<IpAddr>) -> Result<(), AppError> {
+    let window = policy.window_secs as i64;
+    if policy.max_failures > 0 {
+        let email_failures = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!: i32" FROM [login_failures] WHERE Email = @p1 AND FailedAt > DATEADD(SECOND, -@p2, SYSUTCDATETIME())"#,
+            stored_email(email),
+            window
+        )
+        .fetch_one(pool)
+        .await?;
+        if policy.is_exceeded(email_failures, false) {
+            return Err(policy.locked_out());
+        }
+    }
+
+    if let Some(ip) = ip.filter(|_| policy.max_ip_failures > 0) {
+        let ip_failures = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!: i32" FROM [login_failures] WHERE ClientIp = @p1 AND FailedAt > DATEADD(SECOND, -@p2, SYSUTCDATETIME())"#,
+            ip.to_string(),
+            window
+        )
+        .fetch_one(pool)
+        .await?;
+        if policy.is_exceeded(ip_failures, true) {
+            return Err(policy.locked_out());
+        }
+    }
+    Ok(())
+}
+
+/// Records a failed login for `email` from `ip` and forgets failures older than the
+/// window.
+///
+/// # Returns
+///
+/// * `Result<(), AppError>` - [`LockedOut`](AppError::LockedOut) if this failure locks
+///   the email out, in which case a `security.account_locked_out` entry is added to the
+///   timeline of the account with that email, if there is one.
+pub async fn record_failure(pool: &Pool<Mssql>, users: &dyn UserRepository, policy: &LockoutPolicy, email: &str, ip: Option<IpAddr>) -> Result<(), AppError> {
+    let window = policy.window_secs as i64;
+    let email = stored_email(email);
+    let ip = ip.map(|ip| ip.to_string());
+    sqlx::query!(
+        r#"
+        DELETE FROM [login_failures] WHERE FailedAt <= DATEADD(SECOND, -@p3, SYSUTCDATETIME());
+        INSERT INTO [login_failures] (Email, ClientIp) VALUES (@p1, @p2);
+        "#,
+        &email,
+        &ip,
+        window
+    )
+    .execute(pool)
+    .await?;
+    let failures = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!: i32" FROM [login_failures] WHERE Email = @p1 AND FailedAt > DATEADD(SECOND, -@p2, SYSUTCDATETIME())"#,
+        &email,
+        window
+    )
+    .fetch_one(pool)
+    .await?;
+    if !policy.is_exceeded(failures, false) {
+        return Ok(());
+    }
+
+    if let Some(stored) = users.credentials_by_email(&email).await? {
+        let details = json!({ "failures": failures, "ip": ip });
+        audit::record(pool, &stored.id, audit::ACCOUNT_LOCKED_OUT, None, Some(&details)).await?;
+    }
+    Err(policy.locked_out())
+}
+
+/// Forgets the failed logins for `email`, after it logs in successfully.
+pub async fn clear(pool: &Pool<Mssql>, email: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM [login_failures] WHERE Email = @p1", stored_email(email))
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+fn stored_email(email: &str) -> String {
+    email.trim().chars().take(MAX_EMAIL_LENGTH).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockout_thresholds() {
+        let policy = LockoutPolicy::from_vars(|name| (name == "LOCKOUT_WINDOW_MINUTES").then(|| "0".to_string()));
+        assert_eq!(policy, LockoutPolicy { max_failures: 5, max_ip_failures: 50, window_secs: 900 });
+        assert!(!policy.is_exceeded(4, false));
+        assert!(policy.is_exceeded(5, false));
+        assert!(!policy.is_exceeded(5, true));
+
+        let disabled = LockoutPolicy { max_failures: 0, ..policy };
+        assert!(!disabled.is_exceeded(1000, false));
+        assert_eq!(stored_email(&format!(" {} ", "a".repeat(150))).len(), MAX_EMAIL_LENGTH);
+    }
+}
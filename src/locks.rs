@@ -0,0 +1,46 @@
+use sqlx::{Mssql, Transaction};
+use crate::sessions;
+
+/// Locks a user's account as part of `tx`: logins are refused with `401` and every
+/// session ends, until the account is unlocked. Unlike disabling, locking keeps the
+/// account's roles and status, so unlocking restores it exactly.
+///
+/// Locking an account that is already locked keeps its original `LockedAt`.
+///
+/// # Returns
+///
+/// * `Result<bool, sqlx::Error>` - `Ok(false)` if the user does not exist.
+pub async fn lock_user(tx: &mut Transaction<'_, Mssql>, user_id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE [users] SET LockedAt = COALESCE(LockedAt, SYSUTCDATETIME()), UpdatedAt = SYSUTCDATETIME() WHERE id = @p1 AND DeletedAt IS NULL",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+    sessions::revoke_sessions(tx, user_id).await
+}
+
+/// Unlocks a user's account as part of `tx`, returning `Ok(false)` if the user does
+/// not exist. Failed logins for the account's email are forgotten too, ending a
+/// [lockout](crate::lockout). Sessions ended by the lock stay ended.
+pub async fn unlock_user(tx: &mut Transaction<'_, Mssql>, user_id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE [users] SET LockedAt = NULL, UpdatedAt = SYSUTCDATETIME() WHERE id = @p1 AND DeletedAt IS NULL",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+    sqlx::query!(
+        "DELETE f FROM [login_failures] f JOIN [users] u ON u.Email = f.Email WHERE u.id = @p1",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(true)
+}
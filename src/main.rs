@@ -1,32 +1,235 @@
-use actix_web::{web, App, HttpServer};
-use actix_web_httpauth::middleware::HttpAuthentication;
-use safe_user::db::DbPool;
-use safe_user::handlers::{create_user, create_jwt_for_user, get_all_users, protected_route};
-use safe_user::auth::jwt_validator;
+use actix_web::{middleware::from_fn, web, App, HttpServer};
+use safe_user::audit_chain::{spawn_sealer, verify_chain};
+use safe_user::backup::{self, BackupKey, FileStorage};
+use safe_user::db::{DbPool, RetryPolicy};
+use safe_user::health;
+use safe_user::extractors::{json_config, path_config, query_config};
+use safe_user::features::{reject_disabled_features, spawn_feature_reloader, FeatureToggles};
+use safe_user::auth::{set_jwt_secret, set_token_derivation_secret};
+use safe_user::breach::breach_check_from_env;
+use safe_user::challenge::Challenges;
+use safe_user::cookie_sessions::CookieSessions;
+use safe_user::casing::{negotiate_case, FieldCase};
+use safe_user::config::AppConfig;
+use safe_user::cors::Cors;
+use safe_user::expiration::spawn_expiration_task;
+use safe_user::ids::id_generator_from_env;
+use safe_user::honeytokens::{spawn_honeytoken_reloader, HoneytokenWatch, Honeytokens};
+use safe_user::load_shedding::{shed_reads_under_load, spawn_pool_probe, LoadShedder};
+use safe_user::lockout::LockoutPolicy;
+use safe_user::mfa::{self, MfaAttempts};
+use safe_user::metrics::{serve_metrics, track_metrics, Metrics, METRICS_PATH};
+use safe_user::presence::Presence;
+use safe_user::openapi::{error_codes, openapi_json, swagger_ui, ERROR_CODES_PATH, OPENAPI_PATH, SWAGGER_UI_PATH};
+use safe_user::mailer::MailSink;
+use safe_user::oauth::OAuthProviders;
+use safe_user::oidc::{serve_discovery, Issuer, DISCOVERY_PATH};
+use safe_user::outbox::{sink_from_env, spawn_dispatcher};
+use safe_user::policy::PolicyStore;
+use safe_user::profile_gaps::ProfileRequirements;
+use safe_user::rate_limit::RateLimiter;
+use safe_user::replication::Replication;
+use safe_user::routes::{configure_v1, API_V1};
+use safe_user::siem;
+use safe_user::signed_urls::configure_shared;
+use safe_user::suggest::SuggestionCache;
+use safe_user::telemetry::spawn_reporter;
+use safe_user::trace::assign_trace_id;
+use safe_user::tenancy::{multi_tenant_enabled, TenantKeys};
+use safe_user::tokens::{provider_from_env, serve_jwks, JWKS_PATH};
+use safe_user::repository::{MssqlUserRepository, UserRepository};
+use safe_user::read_only::{reject_writes_when_read_only, ReadOnlyMode};
 use dotenv::dotenv;
+use secrecy::ExposeSecret;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
+    let config = match AppConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid server configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(secret) = config.jwt_secret.clone() {
+        set_jwt_secret(secret);
+    }
+    set_token_derivation_secret(config.token_derivation_secret.clone());
+    mfa::set_encryption_key(config.mfa_encryption_key.expose_secret()).expect("Invalid MFA_ENCRYPTION_KEY.");
 
-    let db_pool = DbPool::new().await.expect("No se pudo crear la conexión a la base de datos.");
-    let pool_data = web::Data::new(db_pool.pool);
+    let db_pool = DbPool::with_options(&config.database_url, config.pool).await.expect("No se pudo crear la conexión a la base de datos.");
+
+    // `--migrate` applies pending migrations and exits without starting the server.
+    let migrate_only = std::env::args().any(|arg| arg == "--migrate");
+    if migrate_only || config.migrate_on_startup {
+        db_pool.migrate().await.expect("Could not apply database migrations.");
+    }
+    if migrate_only {
+        return Ok(());
+    }
+
+    // `--verify-audit` checks the audit log hash chain, prints the report and exits.
+    if std::env::args().any(|arg| arg == "--verify-audit") {
+        let report = verify_chain(&db_pool.pool).await.expect("Could not read the audit log.");
+        println!("{}", serde_json::to_string_pretty(&report).expect("Could not serialize the report."));
+        std::process::exit(if report.valid { 0 } else { 1 });
+    }
+
+    // `backup <name>` and `restore <name>` copy the database to or from `BACKUP_DIR`,
+    // print the report and exit.
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(command @ ("backup" | "restore")) = args.get(1).map(String::as_str) {
+        let name = args.get(2).expect("Usage: safe_user backup|restore <name>");
+        let key = BackupKey::from_env().expect("Invalid backup configuration.");
+        let storage = FileStorage::from_env();
+        let report = if command == "backup" {
+            backup::backup(&db_pool.pool, &storage, &key, name).await
+        } else {
+            backup::restore(&db_pool.pool, &storage, &key, name).await
+        };
+        match report {
+            Ok(report) => println!("{}", serde_json::to_string_pretty(&report).expect("Could not serialize the report.")),
+            Err(e) => {
+                eprintln!("The {} failed: {}", command, e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    let mail_sink = MailSink::from_env(sink_from_env()).expect("Invalid mail configuration.");
+    spawn_dispatcher(db_pool.pool.clone(), Arc::new(mail_sink), Duration::from_secs(5));
+    spawn_expiration_task(db_pool.pool.clone(), config.deactivation, Duration::from_secs(60));
+    spawn_sealer(db_pool.pool.clone(), Duration::from_secs(5));
+    if let Some(sink) = siem::sink_from_env().expect("Invalid SIEM export configuration.") {
+        siem::spawn_exporter(db_pool.pool.clone(), sink, Duration::from_secs(5));
+    }
 
-    HttpServer::new(move || {
-        let auth = HttpAuthentication::bearer(jwt_validator);
+    let tenant_keys = if multi_tenant_enabled() {
+        let keys = TenantKeys::load(&db_pool.pool).await.expect("Could not load tenant keys.");
+        Some(web::Data::new(keys))
+    } else {
+        None
+    };
 
-        App::new()
+    let ids = id_generator_from_env(&db_pool.pool).expect("Invalid ID_STRATEGY.");
+    let users: Arc<dyn UserRepository> = Arc::new(MssqlUserRepository::new(db_pool.pool.clone()).with_id_generator(ids).with_retry_policy(RetryPolicy::from_env()).with_replication(Replication::from_env()));
+    #[cfg(feature = "redis")]
+    let users = safe_user::cache::CachedUsers::from_env(users).expect("Invalid user cache configuration.");
+    let honeytokens = Honeytokens::default();
+    honeytokens.reload(&db_pool.pool).await.expect("Could not load honeytokens.");
+    spawn_honeytoken_reloader(db_pool.pool.clone(), honeytokens.clone(), Duration::from_secs(5));
+    let users: Arc<dyn UserRepository> = Arc::new(HoneytokenWatch::new(users, db_pool.pool.clone(), honeytokens));
+    let users = web::Data::new(users);
+    let pool_data = web::Data::new(db_pool.pool);
+    let pool_config = web::Data::new(config.pool);
+    let read_only = web::Data::new(ReadOnlyMode::new(config.read_only));
+    let features = FeatureToggles::new(config.disabled_features.clone());
+    features.reload(&pool_data).await.expect("Could not load feature toggles.");
+    spawn_feature_reloader(pool_data.get_ref().clone(), features.clone(), Duration::from_secs(5));
+    let features = web::Data::new(features);
+    let deactivation = web::Data::new(config.deactivation);
+    let tokens = web::Data::new(provider_from_env().expect("Invalid token configuration."));
+    let policy = web::Data::new(PolicyStore::from_env().expect("Invalid access policy."));
+    let rate_limiter = web::Data::new(RateLimiter::load(&pool_data).await.expect("Could not load rate limits."));
+    let suggestions = web::Data::new(SuggestionCache::from_env());
+    let metrics = web::Data::new(Metrics::new());
+    let presence = web::Data::new(Presence::from_env());
+    let lockout = web::Data::new(LockoutPolicy::from_env());
+    let mfa_attempts = web::Data::new(MfaAttempts::new());
+    let profile_requirements = web::Data::new(ProfileRequirements::from_env());
+    if let Some(telemetry) = config.telemetry.clone() {
+        spawn_reporter(metrics.clone(), telemetry);
+    }
+    let breach_check = breach_check_from_env().map(web::Data::new);
+    let cookie_sessions = CookieSessions::from_env(pool_data.get_ref()).expect("Invalid session cookie configuration.").map(web::Data::new);
+    let challenges = Challenges::from_env().expect("Invalid challenge configuration.").map(web::Data::new);
+    let shedder = LoadShedder::from_env();
+    if let Some(shedder) = &shedder {
+        spawn_pool_probe(pool_data.get_ref().clone(), shedder.clone());
+    }
+    let shedder = shedder.map(web::Data::new);
+    let issuer = Issuer::from_env().map(web::Data::new);
+    let oauth_providers = OAuthProviders::from_env().map(web::Data::new);
+    let cors = Cors::new(config.cors.clone());
+    let field_case = web::Data::new(FieldCase::from_env().expect("Invalid JSON_CASE."));
+
+    let mut server = HttpServer::new(move || {
+        let mut app = App::new()
             .app_data(pool_data.clone())
-            .route("/create_user", web::post().to(create_user))
-            .route("/get_jwt", web::post().to(create_jwt_for_user))
-            .service(
-                web::scope("/protected")
-                    .wrap(auth)
-                    .route("/users", web::get().to(get_all_users))
-                    .route("/route", web::get().to(protected_route))
-            )
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+            .app_data(pool_config.clone())
+            .app_data(users.clone())
+            .app_data(tokens.clone())
+            .app_data(read_only.clone())
+            .app_data(features.clone())
+            .app_data(deactivation.clone())
+            .app_data(policy.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(suggestions.clone())
+            .app_data(metrics.clone())
+            .app_data(presence.clone())
+            .app_data(lockout.clone())
+            .app_data(mfa_attempts.clone())
+            .app_data(profile_requirements.clone())
+            .app_data(field_case.clone())
+            .app_data(path_config())
+            .app_data(query_config())
+            .app_data(json_config());
+        if let Some(keys) = &tenant_keys {
+            app = app.app_data(keys.clone());
+        }
+        if let Some(check) = &breach_check {
+            app = app.app_data(check.clone());
+        }
+        if let Some(challenges) = &challenges {
+            app = app.app_data(challenges.clone());
+        }
+        if let Some(sessions) = &cookie_sessions {
+            app = app.app_data(sessions.clone());
+        }
+        if let Some(shedder) = &shedder {
+            app = app.app_data(shedder.clone());
+        }
+        if let Some(issuer) = &issuer {
+            app = app.app_data(issuer.clone());
+        }
+        if let Some(providers) = &oauth_providers {
+            app = app.app_data(providers.clone());
+        }
+
+        app
+            .wrap(from_fn(negotiate_case))
+            .wrap(from_fn(reject_writes_when_read_only))
+            .wrap(from_fn(reject_disabled_features))
+            .wrap(from_fn(shed_reads_under_load))
+            .wrap(cors.clone())
+            .wrap(from_fn(track_metrics))
+            .wrap(from_fn(assign_trace_id))
+            .route("/health/live", web::get().to(health::live))
+            .route("/health/ready", web::get().to(health::ready))
+            .route(METRICS_PATH, web::get().to(serve_metrics))
+            .route(JWKS_PATH, web::get().to(serve_jwks))
+            .route(DISCOVERY_PATH, web::get().to(serve_discovery))
+            .route(OPENAPI_PATH, web::get().to(openapi_json))
+            .route(ERROR_CODES_PATH, web::get().to(error_codes))
+            .route(SWAGGER_UI_PATH, web::get().to(swagger_ui))
+            .configure(configure_shared)
+            .service(web::scope(API_V1).configure(configure_v1))
+            // Unversioned aliases of v1, for clients written before `/api/v1`.
+            .configure(configure_v1)
+    });
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+
+    let server = match &config.tls {
+        Some(tls) => {
+            let tls_config = tls.server_config().expect("Could not load the TLS certificate and key.");
+            server.bind_rustls_0_23(config.address(), tls_config)?
+        }
+        None => server.bind(config.address())?,
+    };
+    server.run().await
 }
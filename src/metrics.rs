@@ -0,0 +1,226 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use prometheus::core::Collector;
+use sqlx::{Mssql, Pool};
+use std::collections::BTreeMap;
+use std::time::Instant;
+use crate::db::{PoolConfig, PoolStats, MAX_CONNECTIONS};
+
+/// Path the metrics are served at.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Route label of requests that matched no route, so unknown paths do not create new series.
+const UNMATCHED: &str = "unmatched";
+
+/// JWT validation failure reason: the token could not be decoded or verified.
+pub const JWT_INVALID: &str = "invalid";
+
+/// JWT validation failure reason: the token was revoked or its user is no longer active.
+pub const JWT_REVOKED: &str = "revoked";
+
+/// JWT validation failure reason: the session state could not be read from the database.
+pub const JWT_ERROR: &str = "error";
+
+/// The Prometheus metrics of the service.
+///
+/// Register it as app data together with the [`track_metrics`] middleware, and serve
+/// it with [`serve_metrics`]. Series are labelled with the route pattern (such as
+/// `/protected/users/{id}`) rather than the raw path, to keep their number bounded.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::metrics::Metrics;
+///
+/// let metrics = Metrics::new();
+/// metrics.observe_request("GET", "/login", 200, 0.012);
+///
+/// assert!(metrics.render().contains("http_requests_total"));
+/// ```
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    jwt_failures: IntCounterVec,
+    pool_size: IntGauge,
+    pool_idle: IntGauge,
+    pool_max: IntGauge,
+}
+
+impl Metrics {
+    /// Creates and registers every metric, starting at zero.
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled, by method, route and status."),
+            &["method", "route", "status"],
+        )
+        .expect("valid metric");
+        let latency = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time taken to handle HTTP requests, by method and route."),
+            &["method", "route"],
+        )
+        .expect("valid metric");
+        let jwt_failures = IntCounterVec::new(
+            Opts::new("jwt_validation_failures_total", "Bearer tokens rejected by the JWT validator, by reason."),
+            &["reason"],
+        )
+        .expect("valid metric");
+        let pool_size = IntGauge::new("db_pool_connections", "Open database connections, idle or in use.").expect("valid metric");
+        let pool_idle = IntGauge::new("db_pool_idle_connections", "Open database connections not in use.").expect("valid metric");
+        let pool_max = IntGauge::new("db_pool_max_connections", "Largest number of database connections the pool opens.").expect("valid metric");
+
+        for collector in [
+            Box::new(requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(latency.clone()),
+            Box::new(jwt_failures.clone()),
+            Box::new(pool_size.clone()),
+            Box::new(pool_idle.clone()),
+            Box::new(pool_max.clone()),
+        ] {
+            registry.register(collector).expect("metric names are unique");
+        }
+
+        Metrics { registry, requests, latency, jwt_failures, pool_size, pool_idle, pool_max }
+    }
+
+    /// Counts a handled request and records how long it took.
+    pub fn observe_request(&self, method: &str, route: &str, status: u16, seconds: f64) {
+        self.requests.with_label_values(&[method, route, &status.to_string()]).inc();
+        self.latency.with_label_values(&[method, route]).observe(seconds);
+    }
+
+    /// Returns the number of requests handled so far by `"METHOD route"`, across all statuses.
+    pub fn request_counts(&self) -> BTreeMap<String, u64> {
+        let mut counts = BTreeMap::new();
+        for family in self.requests.collect() {
+            for metric in family.get_metric() {
+                let label = |name: &str| metric.get_label().iter().find(|pair| pair.get_name() == name).map(|pair| pair.get_value().to_string()).unwrap_or_default();
+                *counts.entry(format!("{} {}", label("method"), label("route"))).or_insert(0) += metric.get_counter().get_value() as u64;
+            }
+        }
+        counts
+    }
+
+    /// Counts a token rejected by the JWT validator, e.g. with [`JWT_INVALID`].
+    pub fn observe_jwt_failure(&self, reason: &str) {
+        self.jwt_failures.with_label_values(&[reason]).inc();
+    }
+
+    /// Updates the connection pool gauges.
+    pub fn observe_pool(&self, stats: &PoolStats) {
+        self.pool_size.set(stats.size as i64);
+        self.pool_idle.set(stats.idle as i64);
+        self.pool_max.set(stats.max_connections as i64);
+    }
+
+    /// Returns every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            eprintln!("Error encoding metrics: {:?}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+/// Middleware function counting every request and timing it by route.
+///
+/// Wrap it around the whole app so requests rejected by inner middleware (such as the
+/// JWT validator) are counted too. Apps without [`Metrics`] registered as app data are
+/// passed through.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{middleware::from_fn, web, App};
+/// use safe_user::metrics::{serve_metrics, track_metrics, Metrics, METRICS_PATH};
+///
+/// let app = App::new()
+///     .app_data(web::Data::new(Metrics::new()))
+///     .wrap(from_fn(track_metrics))
+///     .route(METRICS_PATH, web::get().to(serve_metrics));
+/// ```
+pub async fn track_metrics(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let metrics = match req.app_data::<web::Data<Metrics>>() {
+        Some(metrics) => metrics.clone(),
+        None => return next.call(req).await,
+    };
+
+    let method = req.method().to_string();
+    let route = req.match_pattern().unwrap_or_else(|| UNMATCHED.to_string());
+    let started = Instant::now();
+
+    let result = next.call(req).await;
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(error) => error.as_response_error().status_code(),
+    };
+    metrics.observe_request(&method, &route, status.as_u16(), started.elapsed().as_secs_f64());
+    result
+}
+
+/// Serves the metrics for Prometheus to scrape.
+///
+/// # Arguments
+///
+/// * `metrics` - The metrics of the service.
+/// * `pool` - A connection pool to the database, whose gauges are read at scrape time.
+/// * `config` - The configuration of the pool, if registered, for its `max_connections`.
+///
+/// # Returns
+///
+/// * `HttpResponse` - The metrics in the Prometheus text format.
+pub async fn serve_metrics(metrics: web::Data<Metrics>, pool: web::Data<Pool<Mssql>>, config: Option<web::Data<PoolConfig>>) -> HttpResponse {
+    metrics.observe_pool(&PoolStats::of(pool.get_ref(), config.map_or(MAX_CONNECTIONS, |config| config.max_connections)));
+    HttpResponse::Ok()
+        .content_type(TextEncoder::new().format_type())
+        .body(metrics.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware::from_fn, App};
+
+    #[actix_web::test]
+    async fn test_requests_are_counted_by_route_pattern() {
+        let metrics = web::Data::new(Metrics::new());
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(metrics.clone())
+                .wrap(from_fn(track_metrics))
+                .route("/users/{id}", web::get().to(HttpResponse::Ok))
+        ).await;
+
+        for uri in ["/users/1", "/users/2", "/missing"] {
+            actix_web::test::call_service(&app, actix_web::test::TestRequest::get().uri(uri).to_request()).await;
+        }
+
+        let text = metrics.render();
+        assert!(text.contains(r#"http_requests_total{method="GET",route="/users/{id}",status="200"} 2"#));
+        assert!(text.contains(r#"http_requests_total{method="GET",route="unmatched",status="404"} 1"#));
+        assert!(text.contains("http_request_duration_seconds_bucket"));
+    }
+
+    #[test]
+    fn test_jwt_failures_and_pool_gauges() {
+        let metrics = Metrics::new();
+        metrics.observe_jwt_failure(JWT_REVOKED);
+        metrics.observe_pool(&PoolStats { size: 3, idle: 1, max_connections: 5 });
+
+        let text = metrics.render();
+        assert!(text.contains(r#"jwt_validation_failures_total{reason="revoked"} 1"#));
+        assert!(text.contains("db_pool_idle_connections 1"));
+    }
+}
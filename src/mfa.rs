@@ -0,0 +1,639 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use jsonwebtoken::errors::{Error as JwtError, ErrorKind};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sqlx::{Mssql, Pool, Transaction};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex, OnceLock};
+use utoipa::ToSchema;
+use uuid::Uuid;
+use crate::audit;
+use crate::auth::derived_key;
+use crate::config::ConfigError;
+use crate::cookie_sessions::CookieSessions;
+use crate::email_verification::hash_token;
+use crate::error::AppError;
+use crate::extractors::AuthenticatedUser;
+use crate::handlers::{forget_failures, lockout_of, login_tenant_key, start_session};
+use crate::lockout;
+use crate::repository::UserRepository;
+
+/// The `purpose` claim of MFA challenge tokens, also used to derive their signing key.
+const CHALLENGE_PURPOSE: &str = "mfa_challenge";
+
+/// Seconds an MFA challenge token stays valid after the password was accepted.
+pub const CHALLENGE_TTL_SECS: i64 = 300;
+
+/// Wrong codes after which a challenge is refused, so that the login must start over.
+pub const MAX_CODE_FAILURES: u32 = 3;
+
+/// Seconds each TOTP code is valid for, as assumed by authenticator apps.
+const STEP_SECS: u64 = 30;
+
+/// Steps before and after the current one whose codes are still accepted, to
+/// tolerate clock drift between the server and the user's device.
+const ALLOWED_DRIFT_STEPS: u64 = 1;
+
+/// Bytes of a TOTP secret, the 160 bits RFC 4226 recommends.
+const SECRET_LENGTH: usize = 20;
+
+/// Recovery codes issued when enrollment is confirmed.
+const RECOVERY_CODE_COUNT: usize = 10;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+const NONCE_LENGTH: usize = 12;
+
+/// Body of `POST /protected/mfa/enroll`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MfaEnrollment {
+    /// The TOTP secret, base32-encoded, for typing into an authenticator app.
+    pub secret: String,
+    /// The `otpauth://` URI carrying the secret, to render as a QR code.
+    pub otpauth_uri: String,
+}
+
+/// Payload of `POST /protected/mfa/confirm`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MfaCode {
+    /// A 6-digit code from the authenticator app.
+    pub code: String,
+}
+
+/// Body of `POST /protected/mfa/confirm`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RecoveryCodes {
+    /// One-time codes accepted instead of a TOTP code, shown only once.
+    pub recovery_codes: Vec<String>,
+}
+
+/// Body of the `202 Accepted` answered by `/login` for users with MFA enabled.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MfaChallenge {
+    /// Token to send with a code to `/mfa/verify`.
+    pub mfa_token: String,
+    /// Lifetime of `mfa_token`, in seconds.
+    pub expires_in: i64,
+}
+
+/// Payload of `POST /mfa/verify`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MfaVerifyRequest {
+    pub mfa_token: String,
+    /// A 6-digit TOTP code, or one of the recovery codes.
+    pub code: String,
+}
+
+/// The claims of an MFA challenge token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChallengeClaims {
+    /// The id of the user whose password was accepted.
+    pub sub: String,
+    pub exp: usize,
+    /// Random id of the challenge, under which [`MfaAttempts`] counts its wrong codes.
+    pub jti: String,
+    purpose: String,
+}
+
+/// Signs a challenge token for `user_id`, valid for [`CHALLENGE_TTL_SECS`].
+pub fn issue_challenge(user_id: &str) -> Result<String, JwtError> {
+    let claims = ChallengeClaims {
+        sub: user_id.to_string(),
+        exp: (Utc::now() + Duration::seconds(CHALLENGE_TTL_SECS)).timestamp() as usize,
+        jti: Uuid::new_v4().to_string(),
+        purpose: CHALLENGE_PURPOSE.to_string(),
+    };
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(&derived_key(CHALLENGE_PURPOSE)))
+}
+
+/// Checks the signature and expiration of a token issued by [`issue_challenge`].
+pub fn verify_challenge(token: &str) -> Result<ChallengeClaims, JwtError> {
+    let claims = decode::<ChallengeClaims>(token, &DecodingKey::from_secret(&derived_key(CHALLENGE_PURPOSE)), &Validation::default())?.claims;
+    if claims.purpose != CHALLENGE_PURPOSE {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+    Ok(claims)
+}
+
+/// The wrong codes given for each unexpired challenge, which refuse a challenge once
+/// it has had [`MAX_CODE_FAILURES`] of them.
+///
+/// The counts are kept in memory, so with several replicas a challenge gets that many
+/// tries on each of them; every wrong code also counts toward the lockout of the
+/// user's email, which is shared.
+#[derive(Clone, Default)]
+pub struct MfaAttempts {
+    failures: Arc<Mutex<HashMap<String, (u32, usize)>>>,
+}
+
+impl MfaAttempts {
+    /// Creates an empty count.
+    pub fn new() -> Self {
+        MfaAttempts::default()
+    }
+
+    /// Returns `true` if `challenge` has had too many wrong codes to be used again.
+    pub fn is_burned(&self, challenge: &ChallengeClaims) -> bool {
+        self.failures
+            .lock()
+            .unwrap()
+            .get(&challenge.jti)
+            .is_some_and(|(failures, _)| *failures >= MAX_CODE_FAILURES)
+    }
+
+    /// Counts a wrong code for `challenge`, and forgets the challenges that have expired.
+    pub fn record_failure(&self, challenge: &ChallengeClaims) {
+        let now = Utc::now().timestamp() as usize;
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, (_, exp)| *exp > now);
+        failures.entry(challenge.jti.clone()).or_insert((0, challenge.exp)).0 += 1;
+    }
+}
+
+/// Encodes `bytes` in unpadded base32, the encoding of secrets in `otpauth://` URIs.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::mfa::base32;
+///
+/// assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+/// ```
+pub fn base32(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            encoded.push(BASE32_ALPHABET[((buffer >> (bits - 5)) & 31) as usize] as char);
+            bits -= 5;
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    encoded
+}
+
+/// Returns the 6-digit TOTP code of `secret` for the `step`-th 30-second period
+/// since the epoch, as defined by RFC 6238 with HMAC-SHA1.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::mfa::totp;
+///
+/// // The test vectors of RFC 6238, cut to 6 digits.
+/// assert_eq!(totp(b"12345678901234567890", 59 / 30), 287082);
+/// assert_eq!(totp(b"12345678901234567890", 1111111109 / 30), 81804);
+/// ```
+pub fn totp(secret: &[u8], step: u64) -> u32 {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+    truncated % 1_000_000
+}
+
+/// Returns the step whose code `code` is at `now` (seconds since the epoch), within
+/// [`ALLOWED_DRIFT_STEPS`], or `None` if it matches none.
+pub fn matching_step(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != 6 || !code.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = now / STEP_SECS;
+    (current.saturating_sub(ALLOWED_DRIFT_STEPS)..=current + ALLOWED_DRIFT_STEPS).find(|step| totp(secret, *step) == code)
+}
+
+/// Returns the `otpauth://` URI provisioning `secret` for `account` in authenticator
+/// apps, under the issuer from `MFA_ISSUER` (default `safe_user`).
+pub fn provisioning_uri(account: &str, secret: &[u8]) -> String {
+    let issuer = env::var("MFA_ISSUER").ok().filter(|issuer| !issuer.trim().is_empty()).unwrap_or_else(|| "safe_user".to_string());
+    let query = serde_urlencoded::to_string([
+        ("secret", base32(secret).as_str()),
+        ("issuer", issuer.as_str()),
+        ("algorithm", "SHA1"),
+        ("digits", "6"),
+        ("period", "30"),
+    ])
+    .expect("string pairs always encode");
+    format!("otpauth://totp/{}:{}?{}", percent_encode(&issuer), percent_encode(account), query)
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// The cipher of the stored TOTP secrets, set once at startup from
+/// [`crate::config::AppConfig`].
+static SECRET_CIPHER: OnceLock<Aes256Gcm> = OnceLock::new();
+
+/// Reads a hex-encoded 32-byte `MFA_ENCRYPTION_KEY`.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::mfa::encryption_key;
+///
+/// assert!(encryption_key(&"ab".repeat(32)).is_ok());
+/// assert!(encryption_key("ab").is_err());
+/// assert!(encryption_key("not hex").is_err());
+/// ```
+pub fn encryption_key(hex: &str) -> Result<Aes256Gcm, ConfigError> {
+    let key = hex::decode(hex.trim()).map_err(|_| "MFA_ENCRYPTION_KEY must be hex-encoded")?;
+    Aes256Gcm::new_from_slice(&key).map_err(|_| "MFA_ENCRYPTION_KEY must be 32 bytes".into())
+}
+
+/// Sets the hex-encoded 32-byte key the TOTP secrets are encrypted with.
+///
+/// The key is its own, rather than derived from the token secrets, so that those can
+/// rotate without every enrolled user losing their second factor. Only the first
+/// call has an effect.
+pub fn set_encryption_key(hex: &str) -> Result<(), ConfigError> {
+    let _ = SECRET_CIPHER.set(encryption_key(hex)?);
+    Ok(())
+}
+
+/// Returns the cipher set by [`set_encryption_key`].
+///
+/// # Panics
+///
+/// Panics when [`set_encryption_key`] has not been called.
+fn cipher() -> &'static Aes256Gcm {
+    SECRET_CIPHER.get().expect("the MFA encryption key is set at startup")
+}
+
+/// Encrypts the secret of `user_id` for storage, bound to the user so it cannot be
+/// copied to another account.
+fn seal_secret(user_id: &str, secret: &[u8]) -> Result<String, AppError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher()
+        .encrypt(&nonce, Payload { msg: secret, aad: user_id.as_bytes() })
+        .map_err(|_| AppError::Internal("Failed to encrypt the MFA secret.".to_string()))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(STANDARD.encode(sealed))
+}
+
+/// Decrypts a secret sealed by [`seal_secret`], or returns `None` if it was sealed
+/// for another user or under another `MFA_ENCRYPTION_KEY`.
+fn open_secret(user_id: &str, sealed: &str) -> Option<Vec<u8>> {
+    let sealed = STANDARD.decode(sealed).ok()?;
+    if sealed.len() < NONCE_LENGTH {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    cipher().decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: user_id.as_bytes() }).ok()
+}
+
+/// Returns a fresh recovery code such as `k7d2m-q9xa4`.
+fn recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    let mut code: String = (0..10).map(|_| BASE32_ALPHABET[rng.gen_range(0..32)].to_ascii_lowercase() as char).collect();
+    code.insert(5, '-');
+    code
+}
+
+/// Returns the form of a recovery code that is hashed, ignoring case, spaces and dashes.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_lowercase()).collect()
+}
+
+/// Returns `true` if the user has confirmed their MFA enrollment, so logins need a code.
+pub async fn is_enabled(pool: &Pool<Mssql>, user_id: &str) -> Result<bool, sqlx::Error> {
+    let enabled = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!: i32" FROM [mfa_secrets] WHERE UserId = @p1 AND ConfirmedAt IS NOT NULL"#, user_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(enabled > 0)
+}
+
+/// Turns off two-factor authentication for `user_id` as part of `tx`, deleting their
+/// secret, confirmed or not, and their recovery codes, so they log in with their
+/// password alone until they enroll again.
+///
+/// # Returns
+///
+/// * `Result<bool, sqlx::Error>` - `Ok(false)` if the user had not enrolled.
+pub async fn reset(tx: &mut Transaction<'_, Mssql>, user_id: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query!("DELETE FROM [mfa_recovery_codes] WHERE UserId = @p1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    let result = sqlx::query!("DELETE FROM [mfa_secrets] WHERE UserId = @p1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Stores a new, unconfirmed secret for `user_id`, replacing any earlier unconfirmed one.
+///
+/// # Returns
+///
+/// * `Result<bool, sqlx::Error>` - `Ok(false)` if the user already has MFA enabled.
+async fn store_secret(pool: &Pool<Mssql>, user_id: &str, sealed: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        MERGE [mfa_secrets] WITH (HOLDLOCK) AS target
+        USING (SELECT @p1 AS UserId) AS source
+        ON target.UserId = source.UserId
+        WHEN MATCHED AND target.ConfirmedAt IS NULL THEN
+            UPDATE SET Secret = @p2, LastUsedStep = NULL, CreatedAt = SYSUTCDATETIME()
+        WHEN NOT MATCHED THEN
+            INSERT (UserId, Secret) VALUES (@p1, @p2);
+        "#,
+        user_id,
+        sealed
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Returns the decrypted secret of `user_id`, confirmed or not.
+async fn load_secret(pool: &Pool<Mssql>, user_id: &str, confirmed: bool) -> Result<Option<Vec<u8>>, sqlx::Error> {
+    let sealed = sqlx::query_scalar!(
+        r#"SELECT Secret AS "secret!: String" FROM [mfa_secrets] WHERE UserId = @p1 AND CASE WHEN ConfirmedAt IS NULL THEN 0 ELSE 1 END = @p2"#,
+        user_id,
+        confirmed
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(sealed.and_then(|sealed| open_secret(user_id, &sealed)))
+}
+
+/// Records that the code of `step` was used, returning `false` if it or a later one
+/// already was, so a code cannot be replayed.
+async fn consume_step(pool: &Pool<Mssql>, user_id: &str, step: u64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE [mfa_secrets] SET LastUsedStep = @p2 WHERE UserId = @p1 AND (LastUsedStep IS NULL OR LastUsedStep < @p2)",
+        user_id,
+        step as i64
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Checks a login code of `user_id`: a TOTP code of their confirmed secret, or an
+/// unused recovery code, which is used up.
+pub async fn check_code(pool: &Pool<Mssql>, user_id: &str, code: &str) -> Result<bool, sqlx::Error> {
+    if let Some(secret) = load_secret(pool, user_id, true).await? {
+        if let Some(step) = matching_step(&secret, code, Utc::now().timestamp() as u64) {
+            return consume_step(pool, user_id, step).await;
+        }
+    }
+    let result = sqlx::query!(
+        "UPDATE [mfa_recovery_codes] SET UsedAt = SYSUTCDATETIME() WHERE UserId = @p1 AND CodeHash = @p2 AND UsedAt IS NULL",
+        user_id,
+        hash_token(&normalize_recovery_code(code))
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Enables MFA for `user_id` if `code` matches their unconfirmed secret, replacing
+/// their recovery codes with fresh ones.
+///
+/// # Returns
+///
+/// * `Result<Option<Vec<String>>, AppError>` - The recovery codes, or `None` if there
+///   is no pending enrollment or the code is wrong.
+async fn confirm_enrollment(pool: &Pool<Mssql>, user_id: &str, code: &str) -> Result<Option<Vec<String>>, AppError> {
+    let secret = match load_secret(pool, user_id, false).await? {
+        Some(secret) => secret,
+        None => return Ok(None),
+    };
+    let step = match matching_step(&secret, code, Utc::now().timestamp() as u64) {
+        Some(step) => step,
+        None => return Ok(None),
+    };
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| recovery_code()).collect();
+    let mut tx = pool.begin().await?;
+    sqlx::query!("DELETE FROM [mfa_recovery_codes] WHERE UserId = @p1", user_id)
+        .execute(&mut tx)
+        .await?;
+    for code in &codes {
+        sqlx::query!(
+            "INSERT INTO [mfa_recovery_codes] (UserId, CodeHash) VALUES (@p1, @p2)",
+            user_id,
+            hash_token(&normalize_recovery_code(code))
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    let result = sqlx::query!(
+        "UPDATE [mfa_secrets] SET ConfirmedAt = SYSUTCDATETIME(), LastUsedStep = @p2 WHERE UserId = @p1 AND ConfirmedAt IS NULL",
+        user_id,
+        step as i64
+    )
+    .execute(&mut tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(None);
+    }
+    audit::record(&mut tx, user_id, audit::MFA_ENABLED, Some(user_id), None).await?;
+    tx.commit().await?;
+    Ok(Some(codes))
+}
+
+/// Starts enrolling the caller in two-factor authentication with a new TOTP secret.
+///
+/// The secret is stored encrypted and only guards logins once a code from it is sent
+/// to `/protected/mfa/confirm`, so a mistyped secret never locks the user out.
+/// Enrolling again before confirming replaces the secret.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - A [`MfaEnrollment`] as JSON, or `409 Conflict`
+///   (`mfa_already_enabled`) if MFA is already enabled.
+#[utoipa::path(
+    post,
+    path = "/protected/mfa/enroll",
+    tag = "auth",
+    responses(
+        (status = 200, description = "The secret to add to an authenticator app", body = MfaEnrollment),
+        (status = 409, description = "MFA is already enabled (`mfa_already_enabled`)", body = ErrorBody),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn enroll(pool: web::Data<Pool<Mssql>>, users: web::Data<Arc<dyn UserRepository>>, caller: AuthenticatedUser) -> Result<HttpResponse, AppError> {
+    let user = users.get(caller.id()).await?.ok_or(AppError::NotFound("user"))?;
+
+    let mut secret = [0u8; SECRET_LENGTH];
+    rand::thread_rng().fill_bytes(&mut secret);
+    if !store_secret(pool.get_ref(), caller.id(), &seal_secret(caller.id(), &secret)?).await? {
+        return Err(AppError::conflict("mfa_already_enabled", "Two-factor authentication is already enabled."));
+    }
+
+    Ok(HttpResponse::Ok().json(MfaEnrollment {
+        secret: base32(&secret),
+        otpauth_uri: provisioning_uri(user.email.as_ref(), &secret),
+    }))
+}
+
+/// Confirms the caller's enrollment with a code from their authenticator app, which
+/// enables two-factor authentication, and returns their recovery codes.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - [`RecoveryCodes`] as JSON, or `400 Bad Request`
+///   (`invalid_enrollment_code`) if the code is wrong or there is no pending enrollment.
+#[utoipa::path(
+    post,
+    path = "/protected/mfa/confirm",
+    tag = "auth",
+    request_body = MfaCode,
+    responses(
+        (status = 200, description = "MFA is enabled; the recovery codes are shown only once", body = RecoveryCodes),
+        (status = 400, description = "Wrong code or no pending enrollment (`invalid_enrollment_code`)", body = ErrorBody),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn confirm(pool: web::Data<Pool<Mssql>>, caller: AuthenticatedUser, body: web::Json<MfaCode>) -> Result<HttpResponse, AppError> {
+    match confirm_enrollment(pool.get_ref(), caller.id(), &body.code).await? {
+        Some(recovery_codes) => Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes })),
+        None => Err(AppError::Validation {
+            code: "invalid_enrollment_code",
+            message: "The code is wrong, or no enrollment is pending.".to_string(),
+            details: None,
+        }),
+    }
+}
+
+/// Completes a login that `/login` answered with an [`MfaChallenge`], issuing the
+/// tokens once a TOTP or recovery code is given.
+///
+/// A challenge is refused after [`MAX_CODE_FAILURES`] wrong codes, and every wrong code
+/// counts toward the lockout of the user's email as a failed login does.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - The tokens as `/login` returns them, `401
+///   Unauthorized` if the challenge is invalid, expired or refused (`invalid_mfa_token`)
+///   or the code is wrong (`invalid_mfa_code`), or `423 Locked` while the email or
+///   client is locked out.
+#[utoipa::path(
+    post,
+    path = "/mfa/verify",
+    tag = "auth",
+    request_body = MfaVerifyRequest,
+    responses(
+        (status = 200, description = "The access and refresh tokens", body = TokenResponse),
+        (status = 401, description = "Invalid challenge or code", body = ErrorBody),
+        (status = 423, description = "Too many failed logins for the email or client (`too_many_failed_logins`)", body = ErrorBody),
+    )
+)]
+pub async fn verify(req: HttpRequest, pool: web::Data<Pool<Mssql>>, users: web::Data<Arc<dyn UserRepository>>, attempts: web::Data<MfaAttempts>, body: web::Json<MfaVerifyRequest>) -> Result<HttpResponse, AppError> {
+    let tenant_key = login_tenant_key(&req)?;
+    let expired = || AppError::auth("invalid_mfa_token", "The login has expired, log in again.");
+    let claims = verify_challenge(&body.mfa_token)
+        .ok()
+        .filter(|claims| !attempts.is_burned(claims))
+        .ok_or_else(expired)?;
+    let email = login_email(pool.get_ref(), &claims.sub).await?.ok_or_else(expired)?;
+
+    let lockout = lockout_of(&req);
+    if let Some((policy, ip)) = lockout {
+        lockout::check(pool.get_ref(), policy, &email, ip).await?;
+    }
+    if !check_code(pool.get_ref(), &claims.sub, &body.code).await? {
+        attempts.record_failure(&claims);
+        if let Some((policy, ip)) = lockout {
+            lockout::record_failure(pool.get_ref(), users.get_ref().as_ref(), policy, &email, ip).await?;
+        }
+        return Err(AppError::auth("invalid_mfa_code", "The code is wrong or has already been used."));
+    }
+    forget_failures(&req, pool.get_ref(), &email).await?;
+
+    audit::record(pool.get_ref(), &claims.sub, audit::LOGIN, Some(&claims.sub), None).await?;
+    let tokens = start_session(&req, pool.get_ref(), &claims.sub, tenant_key.as_ref()).await?;
+    let mut response = HttpResponse::Ok();
+    if let Some(cookie_sessions) = req.app_data::<web::Data<CookieSessions>>() {
+        response.cookie(cookie_sessions.open(pool.get_ref(), &claims.sub, tenant_key.as_ref()).await?);
+    }
+    Ok(response.json(tokens))
+}
+
+/// Returns the email of the user with `id`, under which their failed logins are counted.
+async fn login_email(pool: &Pool<Mssql>, id: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!(r#"SELECT Email AS "email!: String" FROM [users] WHERE id = @p1"#, id)
+        .fetch_optional(pool)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenges_are_refused_after_too_many_wrong_codes() {
+        let attempts = MfaAttempts::new();
+        let challenge = verify_challenge(&issue_challenge("user").unwrap()).unwrap();
+        let other = verify_challenge(&issue_challenge("user").unwrap()).unwrap();
+        assert_ne!(challenge.jti, other.jti);
+
+        for _ in 1..MAX_CODE_FAILURES {
+            attempts.record_failure(&challenge);
+            assert!(!attempts.is_burned(&challenge));
+        }
+        attempts.record_failure(&challenge);
+        assert!(attempts.is_burned(&challenge), "The challenge must be refused after {} wrong codes", MAX_CODE_FAILURES);
+        assert!(!attempts.is_burned(&other), "Other challenges are unaffected");
+
+        let expired = ChallengeClaims { jti: "expired".to_string(), exp: 1, ..challenge.clone() };
+        attempts.record_failure(&expired);
+        attempts.record_failure(&other);
+        assert!(!attempts.is_burned(&expired));
+        assert_eq!(attempts.failures.lock().unwrap().len(), 2, "Expired challenges are forgotten");
+    }
+
+    #[test]
+    fn test_codes_match_within_drift() {
+        let secret = b"12345678901234567890";
+        let now = 1_111_111_109;
+        assert_eq!(matching_step(secret, "081804", now), Some(now / 30));
+        assert_eq!(matching_step(secret, &format!("{:06}", totp(secret, now / 30 + 1)), now), Some(now / 30 + 1));
+        assert_eq!(matching_step(secret, &format!("{:06}", totp(secret, now / 30 + 2)), now), None);
+        assert_eq!(matching_step(secret, "81804", now), None);
+    }
+
+    #[test]
+    fn test_secrets_are_sealed_per_user() {
+        set_encryption_key(&"00".repeat(32)).unwrap();
+        let sealed = seal_secret("ana", b"secret").unwrap();
+        assert_eq!(open_secret("ana", &sealed).unwrap(), b"secret");
+        assert!(open_secret("bob", &sealed).is_none());
+
+        let code = recovery_code();
+        assert_eq!(code.len(), 11);
+        assert_eq!(normalize_recovery_code(&code.to_uppercase()), code.replace('-', ""));
+    }
+
+    #[test]
+    fn test_challenges_are_not_access_tokens() {
+        let token = issue_challenge("user").unwrap();
+        assert_eq!(verify_challenge(&token).unwrap().sub, "user");
+        assert!(crate::auth::validate_jwt(&token).is_err());
+        assert!(verify_challenge(&crate::password_reset::issue_token("user").unwrap()).is_err());
+        assert!(provisioning_uri("ana+1@example.com", b"foobar").contains("totp/safe_user:ana%2B1@example.com?secret=MZXW6YTBOI&issuer=safe_user"));
+    }
+}
@@ -0,0 +1,294 @@
+use sha2::{Digest, Sha256};
+use sqlx::{Executor, Mssql, Pool};
+use crate::error::AppError;
+
+/// A schema change embedded in the binary from the `migrations` directory.
+#[derive(Debug)]
+pub struct Migration {
+    /// Position of the migration; migrations are applied in increasing order.
+    pub version: i64,
+    pub name: &'static str,
+    /// T-SQL batches separated by `GO` lines, as in `sqlcmd` scripts.
+    pub sql: &'static str,
+}
+
+/// Every migration, oldest first. New files in `migrations/` must be added here.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        sql: include_str!("../migrations/0001_initial_schema.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "account_expiration",
+        sql: include_str!("../migrations/0002_account_expiration.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "rate_limits",
+        sql: include_str!("../migrations/0003_rate_limits.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "audit_log",
+        sql: include_str!("../migrations/0004_audit_log.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "revoked_tokens",
+        sql: include_str!("../migrations/0005_revoked_tokens.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "user_suggest_indexes",
+        sql: include_str!("../migrations/0006_user_suggest_indexes.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "export_cursors",
+        sql: include_str!("../migrations/0007_export_cursors.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "audit_chain",
+        sql: include_str!("../migrations/0008_audit_chain.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "user_timestamps",
+        sql: include_str!("../migrations/0009_user_timestamps.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "user_managers",
+        sql: include_str!("../migrations/0010_user_managers.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "refresh_token_families",
+        sql: include_str!("../migrations/0011_refresh_token_families.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "refresh_token_user_agent",
+        sql: include_str!("../migrations/0012_refresh_token_user_agent.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "email_verification",
+        sql: include_str!("../migrations/0013_email_verification.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "password_reset",
+        sql: include_str!("../migrations/0014_password_reset.sql"),
+    },
+    Migration {
+        version: 15,
+        name: "saved_searches",
+        sql: include_str!("../migrations/0015_saved_searches.sql"),
+    },
+    Migration {
+        version: 16,
+        name: "last_seen",
+        sql: include_str!("../migrations/0016_last_seen.sql"),
+    },
+    Migration {
+        version: 17,
+        name: "soft_delete",
+        sql: include_str!("../migrations/0017_soft_delete.sql"),
+    },
+    Migration {
+        version: 18,
+        name: "device_authorizations",
+        sql: include_str!("../migrations/0018_device_authorizations.sql"),
+    },
+    Migration {
+        version: 19,
+        name: "unique_user_ids",
+        sql: include_str!("../migrations/0019_unique_user_ids.sql"),
+    },
+    Migration {
+        version: 20,
+        name: "password_changed_at",
+        sql: include_str!("../migrations/0020_password_changed_at.sql"),
+    },
+    Migration {
+        version: 21,
+        name: "user_tenants",
+        sql: include_str!("../migrations/0021_user_tenants.sql"),
+    },
+    Migration {
+        version: 22,
+        name: "plans",
+        sql: include_str!("../migrations/0022_plans.sql"),
+    },
+    Migration {
+        version: 23,
+        name: "job_leases",
+        sql: include_str!("../migrations/0023_job_leases.sql"),
+    },
+    Migration {
+        version: 24,
+        name: "account_locks",
+        sql: include_str!("../migrations/0024_account_locks.sql"),
+    },
+    Migration {
+        version: 25,
+        name: "login_failures",
+        sql: include_str!("../migrations/0025_login_failures.sql"),
+    },
+    Migration {
+        version: 26,
+        name: "honeytokens",
+        sql: include_str!("../migrations/0026_honeytokens.sql"),
+    },
+    Migration {
+        version: 27,
+        name: "mfa",
+        sql: include_str!("../migrations/0027_mfa.sql"),
+    },
+    Migration {
+        version: 28,
+        name: "replication",
+        sql: include_str!("../migrations/0028_replication.sql"),
+    },
+    Migration {
+        version: 29,
+        name: "oauth_identities",
+        sql: include_str!("../migrations/0029_oauth_identities.sql"),
+    },
+    Migration {
+        version: 30,
+        name: "web_sessions",
+        sql: include_str!("../migrations/0030_web_sessions.sql"),
+    },
+    Migration {
+        version: 31,
+        name: "feature_toggles",
+        sql: include_str!("../migrations/0031_feature_toggles.sql"),
+    },
+];
+
+impl Migration {
+    /// Returns the SHA-256 of the script, recorded when the migration is applied so
+    /// later edits to an applied file are detected.
+    pub fn checksum(&self) -> String {
+        hex::encode(Sha256::digest(self.sql.as_bytes()))
+    }
+
+    /// Returns the batches of the script, split on lines consisting of `GO`.
+    pub fn batches(&self) -> Vec<&'static str> {
+        let mut batches = Vec::new();
+        let mut start = 0;
+        let mut offset = 0;
+
+        for line in self.sql.split_inclusive('\n') {
+            if line.trim().eq_ignore_ascii_case("go") {
+                batches.push(&self.sql[start..offset]);
+                start = offset + line.len();
+            }
+            offset += line.len();
+        }
+        batches.push(&self.sql[start..]);
+
+        batches.into_iter().filter(|batch| !is_blank(batch)).collect()
+    }
+}
+
+/// Returns `true` for batches with nothing but whitespace and `--` comments.
+fn is_blank(batch: &str) -> bool {
+    batch.lines().all(|line| {
+        let line = line.trim();
+        line.is_empty() || line.starts_with("--")
+    })
+}
+
+/// Applies the migrations that have not been applied yet, each in its own transaction.
+///
+/// Applied migrations are recorded in the `[_migrations]` table, created on first run.
+/// The queries are checked at run time rather than with the compile-time macros,
+/// since the tables they touch may not exist yet.
+///
+/// # Returns
+///
+/// * `Result<Vec<i64>, AppError>` - The versions applied by this call, or an error if a
+///   migration fails or an already applied script was modified.
+pub async fn run(pool: &Pool<Mssql>) -> Result<Vec<i64>, AppError> {
+    pool.execute(
+        r#"
+        IF OBJECT_ID('[dbo].[_migrations]', 'U') IS NULL
+        CREATE TABLE [dbo].[_migrations](
+            [Version] BIGINT NOT NULL,
+            [Name] NVARCHAR(200) NOT NULL,
+            [Checksum] NVARCHAR(64) NOT NULL,
+            [AppliedAt] DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME(),
+
+            CONSTRAINT [PK_migrations] PRIMARY KEY CLUSTERED ([Version] ASC)
+        )
+        "#,
+    )
+    .await?;
+
+    let recorded: Vec<(i64, String)> = sqlx::query_as("SELECT Version, Checksum FROM [_migrations]")
+        .fetch_all(pool)
+        .await?;
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS {
+        let checksum = migration.checksum();
+
+        if let Some((_, recorded)) = recorded.iter().find(|(version, _)| *version == migration.version) {
+            if *recorded != checksum {
+                return Err(AppError::Internal(format!(
+                    "Migration {} ({}) was modified after being applied.",
+                    migration.version, migration.name
+                )));
+            }
+            continue;
+        }
+
+        let mut tx = pool.begin().await?;
+        for batch in migration.batches() {
+            tx.execute(batch).await?;
+        }
+        sqlx::query("INSERT INTO [_migrations] (Version, Name, Checksum) VALUES (@p1, @p2, @p3)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(&checksum)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        eprintln!("Applied migration {} ({})", migration.version, migration.name);
+        applied.push(migration.version);
+    }
+
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        assert!(MIGRATIONS.windows(2).all(|pair| pair[0].version < pair[1].version));
+        assert!(MIGRATIONS.iter().all(|migration| !migration.batches().is_empty()));
+    }
+
+    #[test]
+    fn test_batches_split_on_go_lines() {
+        let migration = Migration {
+            version: 1,
+            name: "test",
+            sql: "-- header\nCREATE TABLE a (x INT);\nGO\n\nCREATE TABLE b (y INT);\ngo\n-- done\n",
+        };
+
+        let batches = migration.batches();
+        assert_eq!(batches.len(), 2);
+        assert!(batches[0].contains("TABLE a"));
+        assert!(batches[1].trim() == "CREATE TABLE b (y INT);");
+    }
+}
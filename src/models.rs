@@ -1,27 +1,412 @@
 use serde::{Serialize, Deserialize};
-use sqlx::FromRow;
+use sqlx::encode::IsNull;
+use sqlx::error::BoxDynError;
+use sqlx::mssql::{MssqlTypeInfo, MssqlValueRef};
+use sqlx::{Decode, Encode, FromRow, Mssql, Type};
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use utoipa::ToSchema;
+use validator::{Validate, ValidateEmail};
+use crate::birthdate::Birthdate;
+use crate::timestamp::Timestamp;
+use crate::validation::validate_phone;
 
-/// Represents a user in the system.
-#[derive(Debug, Serialize, FromRow, Deserialize)]
-pub struct User {
+/// A row of the `[users]` table, as read by the repository layer.
+///
+/// It never leaves the crate: payloads are received as [`CreateUserRequest`] or
+/// [`UpdateUserRequest`], and users are returned as [`UserResponse`]. It is serialized
+/// only to diff two versions of a user for the `user.updated` event and the audit log.
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub(crate) struct User {
+    pub id: String,
+    pub user_id: UserId,
+    pub name: String,
+    pub last_name: String,
+    pub email: Email,
+    pub age: Option<i32>,
+    pub phone: Option<Phone>,
+    pub address: Option<String>,
+    pub birthdate: Birthdate,
+    pub place_birth: Option<String>,
+    pub org_unit: Option<String>,
+    pub expires_at: Option<Timestamp>,
+    pub created_at: Option<Timestamp>,
+    pub updated_at: Option<Timestamp>,
+    pub last_seen_at: Option<Timestamp>,
+    pub deleted_at: Option<Timestamp>,
+    pub plan: Option<String>,
+}
+
+/// The fields of a user as sent to create one, or to replace every field of one with
+/// `PUT /protected/users/{id}`.
+///
+/// Payloads are checked with [`Validate`] when received through
+/// [`ValidJson`](crate::validation::ValidJson). The fields set by the server, such as
+/// `id` and `created_at`, are not part of it, so they cannot be sent.
+#[derive(Debug, Clone, Serialize, Deserialize, Validate, ToSchema)]
+pub struct CreateUserRequest {
+    /// The id of the user, as a [`UserId`].
+    #[schema(value_type = String)]
+    pub user_id: UserId,
+    /// The first name of the user.
+    #[validate(length(min = 1, max = 50, message = "must be between 1 and 50 characters"))]
+    pub name: String,
+    /// The last name of the user.
+    #[validate(length(min = 1, max = 50, message = "must be between 1 and 50 characters"))]
+    pub last_name: String,
+    /// The email address of the user, as an [`Email`].
+    #[schema(value_type = String)]
+    pub email: Email,
+    /// The age of the user.
+    #[validate(range(min = 0, max = 150, message = "must be between 0 and 150"))]
+    pub age: Option<i32>,
+    /// The phone number of the user, as a [`Phone`].
+    #[schema(value_type = Option<String>)]
+    pub phone: Option<Phone>,
+    /// The address of the user.
+    pub address: Option<String>,
+    /// The birthdate of the user, as a [`Birthdate`].
+    #[validate(custom(function = "crate::validation::validate_birthdate", message = "must not be in the future"))]
+    #[schema(value_type = String, format = Date)]
+    pub birthdate: Birthdate,
+    /// The place of birth of the user.
+    pub place_birth: Option<String>,
+    /// The organizational unit the user belongs to, used by access policies.
+    #[serde(default)]
+    pub org_unit: Option<String>,
+    /// When the account expires, as a [`Timestamp`]; `None` for accounts that never expire.
+    #[serde(default)]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub expires_at: Option<Timestamp>,
+}
+
+/// A partial update of a user, as sent to `PATCH /protected/users/{id}`.
+///
+/// Fields left out of the payload keep their current value; the others follow the
+/// same rules as [`CreateUserRequest`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Validate, ToSchema)]
+pub struct UpdateUserRequest {
+    #[schema(value_type = Option<String>)]
+    pub user_id: Option<UserId>,
+    #[validate(length(min = 1, max = 50, message = "must be between 1 and 50 characters"))]
+    pub name: Option<String>,
+    #[validate(length(min = 1, max = 50, message = "must be between 1 and 50 characters"))]
+    pub last_name: Option<String>,
+    #[schema(value_type = Option<String>)]
+    pub email: Option<Email>,
+    #[validate(range(min = 0, max = 150, message = "must be between 0 and 150"))]
+    pub age: Option<i32>,
+    #[schema(value_type = Option<String>)]
+    pub phone: Option<Phone>,
+    pub address: Option<String>,
+    #[validate(custom(function = "crate::validation::validate_birthdate", message = "must not be in the future"))]
+    #[schema(value_type = Option<String>, format = Date)]
+    pub birthdate: Option<Birthdate>,
+    pub place_birth: Option<String>,
+    pub org_unit: Option<String>,
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub expires_at: Option<Timestamp>,
+}
+
+/// A user as returned by the API, before [`Viewer::present`](crate::visibility::Viewer::present)
+/// removes the fields the caller may not see.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct UserResponse {
     /// The unique identifier of the user.
-    pub id:  Option<String>,
-    /// The id of the user.
-    pub user_id: String,
+    pub id: String,
+    /// The id of the user, as a [`UserId`].
+    #[schema(value_type = String)]
+    pub user_id: UserId,
     /// The first name of the user.
     pub name: String,
     /// The last name of the user.
     pub last_name: String,
-    /// The email address of the user.
-    pub email: String,
+    /// The email address of the user, as an [`Email`].
+    #[schema(value_type = String)]
+    pub email: Email,
     /// The age of the user.
-    pub age:  Option<i32>,
-    /// The phone number of the user.
-    pub phone:  Option<String>,
+    pub age: Option<i32>,
+    /// The phone number of the user, as a [`Phone`].
+    #[schema(value_type = Option<String>)]
+    pub phone: Option<Phone>,
     /// The address of the user.
     pub address: Option<String>,
-    /// The birthdate of the user.
-    pub birthdate: String,
+    /// The birthdate of the user, as a [`Birthdate`].
+    #[schema(value_type = String, format = Date)]
+    pub birthdate: Birthdate,
     /// The place of birth of the user.
     pub place_birth: Option<String>,
-}
\ No newline at end of file
+    /// The organizational unit the user belongs to, used by access policies.
+    pub org_unit: Option<String>,
+    /// When the account expires; `None` for accounts that never expire.
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub expires_at: Option<Timestamp>,
+    /// When the user was created.
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub created_at: Option<Timestamp>,
+    /// When the user was last changed.
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub updated_at: Option<Timestamp>,
+    /// When the user last made an authenticated request, to within
+    /// `LAST_SEEN_INTERVAL_SECONDS`; `None` if never.
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub last_seen_at: Option<Timestamp>,
+    /// When the user was deleted; only present on deleted users, which are listed with
+    /// `include_deleted=true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub deleted_at: Option<Timestamp>,
+    /// The billing plan of the user, set with `PUT /admin/users/{id}/plan`; `None` if
+    /// they inherit the plan of their tenant.
+    #[serde(default)]
+    pub plan: Option<String>,
+}
+
+impl UserResponse {
+    /// Returns the user created from `request` with the id `id`, before the server
+    /// has set any timestamp.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::models::{CreateUserRequest, UserResponse};
+    ///
+    /// let request: CreateUserRequest = serde_json::from_value(serde_json::json!({
+    ///     "user_id": "7", "name": "Ana", "last_name": "Diaz",
+    ///     "email": "ana@example.com", "birthdate": "1990-01-01",
+    /// })).unwrap();
+    /// let user = UserResponse::new("1".to_string(), request);
+    /// assert_eq!(user.email, "ana@example.com");
+    /// assert!(user.created_at.is_none());
+    /// ```
+    pub fn new(id: String, request: CreateUserRequest) -> Self {
+        UserResponse {
+            id,
+            user_id: request.user_id,
+            name: request.name,
+            last_name: request.last_name,
+            email: request.email,
+            age: request.age,
+            phone: request.phone,
+            address: request.address,
+            birthdate: request.birthdate,
+            place_birth: request.place_birth,
+            org_unit: request.org_unit,
+            expires_at: request.expires_at,
+            created_at: None,
+            updated_at: None,
+            last_seen_at: None,
+            deleted_at: None,
+            plan: None,
+        }
+    }
+}
+
+impl From<User> for UserResponse {
+    fn from(user: User) -> Self {
+        UserResponse {
+            id: user.id,
+            user_id: user.user_id,
+            name: user.name,
+            last_name: user.last_name,
+            email: user.email,
+            age: user.age,
+            phone: user.phone,
+            address: user.address,
+            birthdate: user.birthdate,
+            place_birth: user.place_birth,
+            org_unit: user.org_unit,
+            expires_at: user.expires_at,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+            last_seen_at: user.last_seen_at,
+            deleted_at: user.deleted_at,
+            plan: user.plan,
+        }
+    }
+}
+
+/// Implements what [`Email`], [`Phone`] and [`UserId`] share: reading as `&str`,
+/// serde through `String` (checked by their `TryFrom<String>`), and reading and
+/// binding as SQL Server strings.
+///
+/// Values read from the database are not checked again, so rows stored before a
+/// rule was tightened can still be loaded.
+macro_rules! string_newtype {
+    ($name:ident) => {
+        impl $name {
+            /// Returns the value as a string slice.
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = String;
+
+            fn from_str(value: &str) -> Result<Self, Self::Err> {
+                $name::try_from(value.to_string())
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl PartialEq<String> for $name {
+            fn eq(&self, other: &String) -> bool {
+                &self.0 == other
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                self.0 == *other
+            }
+        }
+
+        impl Type<Mssql> for $name {
+            fn type_info() -> MssqlTypeInfo {
+                <String as Type<Mssql>>::type_info()
+            }
+
+            fn compatible(ty: &MssqlTypeInfo) -> bool {
+                <String as Type<Mssql>>::compatible(ty)
+            }
+        }
+
+        impl Encode<'_, Mssql> for $name {
+            fn produces(&self) -> Option<MssqlTypeInfo> {
+                <String as Encode<Mssql>>::produces(&self.0)
+            }
+
+            fn encode_by_ref(&self, buf: &mut Vec<u8>) -> IsNull {
+                <String as Encode<Mssql>>::encode_by_ref(&self.0, buf)
+            }
+        }
+
+        impl<'r> Decode<'r, Mssql> for $name {
+            fn decode(value: MssqlValueRef<'r>) -> Result<Self, BoxDynError> {
+                Ok($name(<String as Decode<Mssql>>::decode(value)?))
+            }
+        }
+    };
+}
+
+/// A well-formed email address of at most 100 characters.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::models::Email;
+///
+/// let email = Email::try_from("ana@example.com".to_string()).unwrap();
+/// assert_eq!(email, "ana@example.com");
+/// assert!(Email::try_from("not-an-email".to_string()).is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Email(String);
+
+impl TryFrom<String> for Email {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.chars().count() > 100 {
+            return Err("email must be at most 100 characters".to_string());
+        }
+        if !value.validate_email() {
+            return Err(format!("`{}` is not a valid email address", value));
+        }
+        Ok(Email(value))
+    }
+}
+
+string_newtype!(Email);
+
+/// A phone number of 7 to 15 digits, optionally starting with `+` and grouped with
+/// spaces, dashes or parentheses, as checked by
+/// [`validate_phone`](crate::validation::validate_phone).
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::models::Phone;
+///
+/// assert!("+34 (600) 123-456".parse::<Phone>().is_ok());
+/// assert!("call me".parse::<Phone>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Phone(String);
+
+impl TryFrom<String> for Phone {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match validate_phone(&value) {
+            Ok(()) => Ok(Phone(value)),
+            Err(_) => Err(format!("`{}` is not a phone number of 7 to 15 digits", value)),
+        }
+    }
+}
+
+string_newtype!(Phone);
+
+/// The `user_id` of a [`User`], a business identifier of 1 to 50 characters. Not to be
+/// confused with [`extractors::UserId`](crate::extractors::UserId), the UUID of a user
+/// in `/users/{id}` paths.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::models::UserId;
+///
+/// assert_eq!("891009".parse::<UserId>().unwrap().as_str(), "891009");
+/// assert!("".parse::<UserId>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct UserId(String);
+
+impl TryFrom<String> for UserId {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.chars().count() {
+            1..=50 => Ok(UserId(value)),
+            _ => Err("user_id must be between 1 and 50 characters".to_string()),
+        }
+    }
+}
+
+string_newtype!(UserId);
@@ -0,0 +1,650 @@
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::http::header::LOCATION;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use jsonwebtoken::errors::{Error as JwtError, ErrorKind};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{Mssql, Pool, Transaction};
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+use validator::{Validate, ValidationErrors};
+use crate::approvals::{registration_status, ACTIVE};
+use crate::audit;
+use crate::auth::derived_key;
+use crate::cookie_sessions::CookieSessions;
+use crate::error::AppError;
+use crate::handlers::{check_can_log_in, login_tenant_key, start_session, user_conflict, REGISTRATION_ACCEPTED};
+use crate::mfa::{self, MfaChallenge};
+use crate::models::{CreateUserRequest, Email};
+use crate::quotas::{self, request_tenant};
+use crate::repository::{UnitOfWork, UserRepository};
+use crate::validation::ValidJson;
+
+/// The `purpose` claim of the `state` of authorization requests.
+const STATE_PURPOSE: &str = "oauth_state";
+
+/// The `purpose` claim of the tokens that finish a sign-up through `/oauth/signup`.
+const SIGNUP_PURPOSE: &str = "oauth_signup";
+
+/// Seconds the provider's sign-in page may take before the callback is refused.
+const STATE_TTL_SECS: i64 = 600;
+
+/// Seconds a new user has to finish signing up after the callback.
+pub const SIGNUP_TTL_SECS: i64 = 1800;
+
+/// Cookie binding an authorization request to the browser that started it, so a
+/// callback URL cannot be replayed in someone else's browser.
+const STATE_COOKIE: &str = "oauth_state";
+
+/// Time allowed for each request to a provider.
+const HTTP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// An identity provider users can log in with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProviderKind {
+    Google,
+    Github,
+}
+
+impl ProviderKind {
+    const ALL: [ProviderKind; 2] = [ProviderKind::Google, ProviderKind::Github];
+
+    /// Returns the name of the provider in paths and in `OAUTH_<NAME>_...` variables.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProviderKind::Google => "google",
+            ProviderKind::Github => "github",
+        }
+    }
+
+    fn authorize_endpoint(&self) -> &'static str {
+        match self {
+            ProviderKind::Google => "https://accounts.google.com/o/oauth2/v2/auth",
+            ProviderKind::Github => "https://github.com/login/oauth/authorize",
+        }
+    }
+
+    fn token_endpoint(&self) -> &'static str {
+        match self {
+            ProviderKind::Google => "https://oauth2.googleapis.com/token",
+            ProviderKind::Github => "https://github.com/login/oauth/access_token",
+        }
+    }
+
+    fn scope(&self) -> &'static str {
+        match self {
+            ProviderKind::Google => "openid email profile",
+            ProviderKind::Github => "read:user user:email",
+        }
+    }
+}
+
+/// An application registered with a provider.
+#[derive(Debug, Clone)]
+pub struct OAuthProvider {
+    pub kind: ProviderKind,
+    pub client_id: String,
+    pub client_secret: SecretString,
+    /// The URL of `/oauth/{provider}/callback` as registered with the provider.
+    pub redirect_uri: String,
+}
+
+impl OAuthProvider {
+    /// Returns the URL of the provider's sign-in page for a request with `state`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::oauth::OAuthProviders;
+    ///
+    /// let providers = OAuthProviders::from_vars(|name| match name {
+    ///     "OAUTH_GITHUB_CLIENT_ID" => Some("abc".to_string()),
+    ///     "OAUTH_GITHUB_CLIENT_SECRET" => Some("s3cret".to_string()),
+    ///     "OAUTH_GITHUB_REDIRECT_URI" => Some("https://users.example.com/oauth/github/callback".to_string()),
+    ///     _ => None,
+    /// })
+    /// .unwrap();
+    /// assert!(providers.get("google").is_none());
+    /// assert_eq!(
+    ///     providers.get("github").unwrap().authorize_url("xyz"),
+    ///     "https://github.com/login/oauth/authorize?response_type=code&client_id=abc\
+    ///      &redirect_uri=https%3A%2F%2Fusers.example.com%2Foauth%2Fgithub%2Fcallback&scope=read%3Auser+user%3Aemail&state=xyz",
+    /// );
+    /// ```
+    pub fn authorize_url(&self, state: &str) -> String {
+        let query = serde_urlencoded::to_string([
+            ("response_type", "code"),
+            ("client_id", &self.client_id),
+            ("redirect_uri", &self.redirect_uri),
+            ("scope", self.kind.scope()),
+            ("state", state),
+        ])
+        .unwrap_or_default();
+        format!("{}?{}", self.kind.authorize_endpoint(), query)
+    }
+}
+
+/// The providers configured with `OAUTH_GOOGLE_...` and `OAUTH_GITHUB_...` variables,
+/// registered as app data when there is at least one.
+#[derive(Debug, Clone)]
+pub struct OAuthProviders {
+    providers: BTreeMap<&'static str, OAuthProvider>,
+    client: reqwest::Client,
+}
+
+impl OAuthProviders {
+    /// Reads the providers from the environment.
+    pub fn from_env() -> Option<Self> {
+        OAuthProviders::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Reads the providers from the variables returned by `var`. A provider is enabled
+    /// by its `OAUTH_<NAME>_CLIENT_ID`, `OAUTH_<NAME>_CLIENT_SECRET` and
+    /// `OAUTH_<NAME>_REDIRECT_URI`; returns `None` if none has all three.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let mut providers = BTreeMap::new();
+        for kind in ProviderKind::ALL {
+            let setting = |key: &str| {
+                var(&format!("OAUTH_{}_{}", kind.name().to_ascii_uppercase(), key))
+                    .map(|value| value.trim().to_string())
+                    .filter(|value| !value.is_empty())
+            };
+            if let (Some(client_id), Some(client_secret), Some(redirect_uri)) = (setting("CLIENT_ID"), setting("CLIENT_SECRET"), setting("REDIRECT_URI")) {
+                providers.insert(kind.name(), OAuthProvider { kind, client_id, client_secret: SecretString::new(client_secret), redirect_uri });
+            }
+        }
+        if providers.is_empty() {
+            return None;
+        }
+        let client = reqwest::Client::builder().timeout(HTTP_TIMEOUT).build().unwrap_or_default();
+        Some(OAuthProviders { providers, client })
+    }
+
+    /// Returns the provider named `name`, if configured.
+    pub fn get(&self, name: &str) -> Option<&OAuthProvider> {
+        self.providers.get(name)
+    }
+}
+
+/// A user as described by a provider.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExternalIdentity {
+    /// The provider's stable id of the user, never reused.
+    pub subject: String,
+    pub email: Option<String>,
+    /// `true` if the provider checked that the user owns `email`.
+    pub email_verified: bool,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+}
+
+/// Reads the identity of a Google user from the OpenID Connect userinfo response.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::oauth::google_identity;
+///
+/// let identity = google_identity(&serde_json::json!({
+///     "sub": "1090", "email": "ada@example.com", "email_verified": true, "given_name": "Ada", "family_name": "Lovelace",
+/// }))
+/// .unwrap();
+/// assert_eq!(identity.subject, "1090");
+/// assert!(identity.email_verified);
+/// ```
+pub fn google_identity(userinfo: &Value) -> Option<ExternalIdentity> {
+    let text = |key: &str| userinfo[key].as_str().map(String::from);
+    Some(ExternalIdentity {
+        subject: text("sub")?,
+        email: text("email"),
+        email_verified: userinfo["email_verified"].as_bool().unwrap_or(false),
+        first_name: text("given_name"),
+        last_name: text("family_name"),
+    })
+}
+
+/// Reads the identity of a GitHub user from `/user` and `/user/emails`, taking their
+/// primary email and splitting their display name at the first space.
+pub fn github_identity(user: &Value, emails: &Value) -> Option<ExternalIdentity> {
+    let subject = user["id"].as_i64()?.to_string();
+    let primary = emails.as_array().and_then(|emails| emails.iter().find(|email| email["primary"].as_bool() == Some(true)));
+    let (first_name, last_name) = match user["name"].as_str().map(str::trim).filter(|name| !name.is_empty()) {
+        Some(name) => match name.split_once(' ') {
+            Some((first, last)) => (Some(first.to_string()), Some(last.trim().to_string())),
+            None => (Some(name.to_string()), None),
+        },
+        None => (None, None),
+    };
+    Some(ExternalIdentity {
+        subject,
+        email: primary.and_then(|email| email["email"].as_str()).map(String::from),
+        email_verified: primary.and_then(|email| email["verified"].as_bool()).unwrap_or(false),
+        first_name,
+        last_name,
+    })
+}
+
+fn provider_failed() -> AppError {
+    AppError::auth("oauth_failed", "Signing in with the provider failed, try again.")
+}
+
+/// Exchanges an authorization `code` for an access token and asks the provider who
+/// the user is.
+async fn fetch_identity(client: &reqwest::Client, provider: &OAuthProvider, code: &str) -> Result<ExternalIdentity, AppError> {
+    let log = |e: reqwest::Error| {
+        eprintln!("Error signing in with {}: {:?}", provider.kind.name(), e);
+        provider_failed()
+    };
+    let tokens: Value = client
+        .post(provider.kind.token_endpoint())
+        .header(reqwest::header::ACCEPT, "application/json")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("client_id", &provider.client_id),
+            ("client_secret", provider.client_secret.expose_secret()),
+            ("redirect_uri", &provider.redirect_uri),
+        ])
+        .send()
+        .await
+        .map_err(log)?
+        .json()
+        .await
+        .map_err(log)?;
+    let access_token = tokens["access_token"].as_str().ok_or_else(provider_failed)?;
+
+    let get = |url: &'static str| {
+        client
+            .get(url)
+            .bearer_auth(access_token)
+            .header(reqwest::header::USER_AGENT, "safe_user")
+            .header(reqwest::header::ACCEPT, "application/json")
+            .send()
+    };
+    let identity = match provider.kind {
+        ProviderKind::Google => {
+            let userinfo: Value = get("https://openidconnect.googleapis.com/v1/userinfo").await.map_err(log)?.json().await.map_err(log)?;
+            google_identity(&userinfo)
+        }
+        ProviderKind::Github => {
+            let user: Value = get("https://api.github.com/user").await.map_err(log)?.json().await.map_err(log)?;
+            let emails: Value = get("https://api.github.com/user/emails").await.map_err(log)?.json().await.map_err(log)?;
+            github_identity(&user, &emails)
+        }
+    };
+    identity.ok_or_else(provider_failed)
+}
+
+/// The claims of the `state` of an authorization request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct StateClaims {
+    provider: String,
+    /// Random value also kept in the [`STATE_COOKIE`] of the browser.
+    nonce: String,
+    exp: usize,
+    purpose: String,
+}
+
+fn issue_state(provider: &str, nonce: &str) -> Result<String, JwtError> {
+    let claims = StateClaims {
+        provider: provider.to_string(),
+        nonce: nonce.to_string(),
+        exp: (Utc::now() + Duration::seconds(STATE_TTL_SECS)).timestamp() as usize,
+        purpose: STATE_PURPOSE.to_string(),
+    };
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(&derived_key(STATE_PURPOSE)))
+}
+
+/// Returns `true` if `state` was issued for `provider` to the browser holding `nonce`.
+fn verify_state(state: &str, provider: &str, nonce: Option<&str>) -> bool {
+    match decode::<StateClaims>(state, &DecodingKey::from_secret(&derived_key(STATE_PURPOSE)), &Validation::default()) {
+        Ok(data) => data.claims.purpose == STATE_PURPOSE && data.claims.provider == provider && nonce == Some(data.claims.nonce.as_str()),
+        Err(_) => false,
+    }
+}
+
+/// The claims of a sign-up token: who the provider says the new user is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SignupClaims {
+    provider: String,
+    sub: String,
+    email: String,
+    exp: usize,
+    purpose: String,
+}
+
+fn issue_signup(provider: &str, subject: &str, email: &str) -> Result<String, JwtError> {
+    let claims = SignupClaims {
+        provider: provider.to_string(),
+        sub: subject.to_string(),
+        email: email.to_string(),
+        exp: (Utc::now() + Duration::seconds(SIGNUP_TTL_SECS)).timestamp() as usize,
+        purpose: SIGNUP_PURPOSE.to_string(),
+    };
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(&derived_key(SIGNUP_PURPOSE)))
+}
+
+fn verify_signup(token: &str) -> Result<SignupClaims, JwtError> {
+    let claims = decode::<SignupClaims>(token, &DecodingKey::from_secret(&derived_key(SIGNUP_PURPOSE)), &Validation::default())?.claims;
+    if claims.purpose != SIGNUP_PURPOSE {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+    Ok(claims)
+}
+
+/// Returns the user linked to the identity `subject` of `provider`, if any.
+pub async fn linked_user(pool: &Pool<Mssql>, provider: &str, subject: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT i.UserId AS "user_id!: String"
+        FROM [oauth_identities] i
+        JOIN [users] u ON CAST(u.id AS NVARCHAR(36)) = i.UserId
+        WHERE i.Provider = @p1 AND i.Subject = @p2 AND u.DeletedAt IS NULL
+        "#,
+        provider,
+        subject
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Links the identity `subject` of `provider` to `user_id`, whose email the provider
+/// has verified, and records it in the user's timeline, as part of `tx`.
+pub async fn link(tx: &mut Transaction<'_, Mssql>, provider: &str, subject: &str, user_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!("INSERT INTO [oauth_identities] (Provider, Subject, UserId) VALUES (@p1, @p2, @p3)", provider, subject, user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "UPDATE [users] SET EmailVerified = 1, EmailVerificationHash = NULL WHERE id = @p1 AND EmailVerified = 0",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    audit::record(&mut *tx, user_id, audit::OAUTH_LINKED, Some(user_id), Some(&json!({ "provider": provider }))).await
+}
+
+/// Body of the `202 Accepted` answered by the callback when no account matches the
+/// provider's identity: the token to finish signing up with, and what the provider
+/// said about the user to prefill the form.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OAuthSignup {
+    /// Token to send to `/oauth/signup`, valid for [`SIGNUP_TTL_SECS`].
+    pub signup_token: String,
+    pub expires_in: i64,
+    pub email: String,
+    pub name: Option<String>,
+    pub last_name: Option<String>,
+}
+
+/// Query parameters the provider redirects to `/oauth/{provider}/callback` with.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    /// Set instead of `code` when the user declined or the provider refused.
+    pub error: Option<String>,
+}
+
+/// Payload of `POST /oauth/signup`: the fields of a new user, whose `email` must be
+/// the one the provider verified, and the sign-up token.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct OAuthSignupRequest {
+    pub signup_token: String,
+    #[serde(flatten)]
+    pub user: CreateUserRequest,
+}
+
+impl Validate for OAuthSignupRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        self.user.validate()
+    }
+}
+
+fn configured<'a>(req: &'a HttpRequest, name: &str) -> Result<(&'a OAuthProviders, &'a OAuthProvider), AppError> {
+    let providers = req.app_data::<web::Data<OAuthProviders>>().map(|providers| providers.get_ref()).ok_or(AppError::NotFound("provider"))?;
+    let provider = providers.get(name).ok_or(AppError::NotFound("provider"))?;
+    Ok((providers, provider))
+}
+
+/// Logs in `user_id` as `/login` does once the password is accepted: refusing
+/// accounts that may not log in, sending users with MFA to `/mfa/verify` and setting
+/// the session cookie when [`CookieSessions`] are configured.
+async fn log_in(req: &HttpRequest, pool: &Pool<Mssql>, users: &dyn UserRepository, user_id: &str, provider: &str) -> Result<HttpResponse, AppError> {
+    let tenant_key = login_tenant_key(req)?;
+    let user = users.get(user_id).await?.ok_or_else(provider_failed)?;
+    let stored = users.credentials_by_email(user.email.as_ref()).await?.ok_or_else(provider_failed)?;
+    check_can_log_in(&stored)?;
+    if mfa::is_enabled(pool, user_id).await? {
+        let mfa_token = mfa::issue_challenge(user_id).map_err(|e| AppError::Internal(e.to_string()))?;
+        return Ok(HttpResponse::Accepted().json(MfaChallenge { mfa_token, expires_in: mfa::CHALLENGE_TTL_SECS }));
+    }
+
+    audit::record(pool, user_id, audit::LOGIN, Some(user_id), Some(&json!({ "provider": provider }))).await?;
+    let tokens = start_session(req, pool, user_id, tenant_key.as_ref()).await?;
+    let mut response = HttpResponse::Ok();
+    if let Some(cookie_sessions) = req.app_data::<web::Data<CookieSessions>>() {
+        response.cookie(cookie_sessions.open(pool, user_id, tenant_key.as_ref()).await?);
+    }
+    Ok(response.json(tokens))
+}
+
+/// Starts a login with a provider by redirecting to its sign-in page.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `302 Found` to the provider, with the cookie
+///   the callback checks, or `404 Not Found` if the provider is not configured.
+#[utoipa::path(
+    get,
+    path = "/oauth/{provider}/start",
+    tag = "auth",
+    params(("provider" = String, Path, description = "`google` or `github`")),
+    responses(
+        (status = 302, description = "Redirect to the provider's sign-in page"),
+        (status = 404, description = "The provider is not configured (`provider_not_found`)", body = ErrorBody),
+    )
+)]
+pub async fn start(req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let (_, provider) = configured(&req, &path)?;
+    let nonce: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+    let state = issue_state(provider.kind.name(), &nonce).map_err(|e| AppError::Internal(e.to_string()))?;
+    let cookie = Cookie::build(STATE_COOKIE, nonce)
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(STATE_TTL_SECS))
+        .finish();
+
+    Ok(HttpResponse::Found().insert_header((LOCATION, provider.authorize_url(&state))).cookie(cookie).finish())
+}
+
+/// Completes a login with a provider, which redirects here with an authorization
+/// code. The user linked to the provider's identity is logged in; failing that,
+/// the account with the same email, if the provider verified it and the account may
+/// log in, is linked and logged in. Anyone else gets a token to finish signing up with.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - The tokens as `/login` returns them, `202
+///   Accepted` with an [`MfaChallenge`] for users with MFA or with an [`OAuthSignup`]
+///   for new users, or `401 Unauthorized` if the request did not start here
+///   (`invalid_oauth_state`), the provider failed (`oauth_failed`) or did not verify
+///   the email (`oauth_email_unverified`), or the account may not log in, as for `/login`.
+#[utoipa::path(
+    get,
+    path = "/oauth/{provider}/callback",
+    tag = "auth",
+    params(("provider" = String, Path, description = "`google` or `github`"), CallbackQuery),
+    responses(
+        (status = 200, description = "The access and refresh tokens", body = TokenResponse),
+        (status = 202, description = "A new user must finish signing up, or an MFA code is needed", body = OAuthSignup),
+        (status = 401, description = "Invalid state, failed sign-in or unverified email", body = ErrorBody),
+        (status = 404, description = "The provider is not configured (`provider_not_found`)", body = ErrorBody),
+        (status = 409, description = "An account has the email but has not verified it (`oauth_account_unverified`)", body = ErrorBody),
+    )
+)]
+pub async fn callback(
+    req: HttpRequest,
+    pool: web::Data<Pool<Mssql>>,
+    users: web::Data<Arc<dyn UserRepository>>,
+    path: web::Path<String>,
+    query: web::Query<CallbackQuery>,
+) -> Result<HttpResponse, AppError> {
+    let (providers, provider) = configured(&req, &path)?;
+    let name = provider.kind.name();
+    let nonce = req.cookie(STATE_COOKIE);
+    if !query.state.as_deref().is_some_and(|state| verify_state(state, name, nonce.as_ref().map(|cookie| cookie.value()))) {
+        return Err(AppError::auth("invalid_oauth_state", "The sign-in did not start here or has expired, start again."));
+    }
+    let code = match (&query.code, &query.error) {
+        (Some(code), None) => code,
+        _ => return Err(provider_failed()),
+    };
+    let identity = fetch_identity(&providers.client, provider, code).await?;
+
+    let mut response = match linked_user(pool.get_ref(), name, &identity.subject).await? {
+        Some(user_id) => log_in(&req, pool.get_ref(), users.get_ref().as_ref(), &user_id, name).await?,
+        None => {
+            let email = match (&identity.email, identity.email_verified) {
+                (Some(email), true) => email,
+                _ => return Err(AppError::auth("oauth_email_unverified", "The provider has not verified your email address.")),
+            };
+            match users.credentials_by_email(email).await? {
+                // Whoever registered the address without verifying it may not own it,
+                // and linking would let them keep a password on the owner's account.
+                Some(stored) if !stored.email_verified => {
+                    return Err(AppError::conflict(
+                        "oauth_account_unverified",
+                        "An account with this email has not verified it yet. Verify the email first, then sign in with this provider again.",
+                    ));
+                }
+                Some(stored) => {
+                    // An account that may not log in is not linked either, so a refused
+                    // sign-in leaves no link behind.
+                    check_can_log_in(&stored)?;
+                    let mut work = UnitOfWork::begin(pool.get_ref()).await?;
+                    link(&mut work, name, &identity.subject, &stored.id).await?;
+                    work.commit().await?;
+                    log_in(&req, pool.get_ref(), users.get_ref().as_ref(), &stored.id, name).await?
+                }
+                None => {
+                    let signup_token = issue_signup(name, &identity.subject, email).map_err(|e| AppError::Internal(e.to_string()))?;
+                    HttpResponse::Accepted().json(OAuthSignup {
+                        signup_token,
+                        expires_in: SIGNUP_TTL_SECS,
+                        email: email.clone(),
+                        name: identity.first_name,
+                        last_name: identity.last_name,
+                    })
+                }
+            }
+        }
+    };
+    if let Some(mut cookie) = nonce {
+        cookie.set_path("/");
+        response.add_removal_cookie(&cookie).map_err(|e| AppError::Internal(e.to_string()))?;
+    }
+    Ok(response)
+}
+
+/// Creates the account of a user the callback did not know, with the fields the
+/// provider does not share, links it to their identity and logs them in. The email
+/// counts as verified, since the provider verified it.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `201 Created` with the tokens and, with
+///   [`CookieSessions`], the session cookie, `202 Accepted` when `REGISTRATION_APPROVAL`
+///   holds the account for review, `401 Unauthorized` for an invalid or expired token
+///   (`invalid_signup_token`), `409 Conflict` if the email or `user_id` is taken, or
+///   `422 Unprocessable Entity` for invalid fields or an email other than the provider's.
+#[utoipa::path(
+    post,
+    path = "/oauth/signup",
+    tag = "auth",
+    request_body = OAuthSignupRequest,
+    params(
+        ("X-Tenant-Id" = Option<String>, Header, description = "The tenant to join, required in multi-tenant mode"),
+    ),
+    responses(
+        (status = 201, description = "The account was created; the access and refresh tokens", body = TokenResponse),
+        (status = 202, description = "The account awaits approval", body = String),
+        (status = 401, description = "Invalid or expired sign-up token", body = ErrorBody),
+        (status = 409, description = "The email or user id is taken", body = ErrorBody),
+        (status = 422, description = "Some fields are invalid", body = ErrorBody),
+    )
+)]
+pub async fn signup(req: HttpRequest, pool: web::Data<Pool<Mssql>>, users: web::Data<Arc<dyn UserRepository>>, request: ValidJson<OAuthSignupRequest>) -> Result<HttpResponse, AppError> {
+    let request = request.into_inner();
+    let claims = verify_signup(&request.signup_token).map_err(|_| AppError::auth("invalid_signup_token", "The sign-up has expired, sign in with the provider again."))?;
+    if Email::try_from(claims.email.clone()).ok() != Some(request.user.email.clone()) {
+        return Err(AppError::Validation {
+            code: "oauth_email_mismatch",
+            message: "The email must be the one verified by the provider.".to_string(),
+            details: Some(json!({ "field": "email", "expected": claims.email })),
+        });
+    }
+    let tenant_id = request_tenant(&req)?;
+    quotas::enforce(users.get_ref().as_ref(), tenant_id.as_deref(), 1).await?;
+
+    let status = registration_status();
+    let user_id = users
+        .create_linked(&request.user, status, tenant_id.as_deref(), &claims.provider, &claims.sub)
+        .await
+        .map_err(user_conflict)?;
+    if status != ACTIVE {
+        return Ok(HttpResponse::Accepted().json(REGISTRATION_ACCEPTED));
+    }
+
+    let tenant_key = login_tenant_key(&req)?;
+    audit::record(pool.get_ref(), &user_id, audit::LOGIN, Some(&user_id), Some(&json!({ "provider": claims.provider }))).await?;
+    let tokens = start_session(&req, pool.get_ref(), &user_id, tenant_key.as_ref()).await?;
+    let mut response = HttpResponse::Created();
+    if let Some(cookie_sessions) = req.app_data::<web::Data<CookieSessions>>() {
+        response.cookie(cookie_sessions.open(pool.get_ref(), &user_id, tenant_key.as_ref()).await?);
+    }
+    Ok(response.json(tokens))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_is_bound_to_provider_and_browser() {
+        let state = issue_state("google", "n0nce").unwrap();
+        assert!(verify_state(&state, "google", Some("n0nce")));
+        assert!(!verify_state(&state, "github", Some("n0nce")));
+        assert!(!verify_state(&state, "google", Some("other")));
+        assert!(!verify_state(&state, "google", None));
+
+        let signup = issue_signup("google", "1090", "ada@example.com").unwrap();
+        assert_eq!(verify_signup(&signup).unwrap().email, "ada@example.com");
+        assert!(verify_signup(&state).is_err());
+    }
+
+    #[test]
+    fn test_github_identity_uses_the_primary_email() {
+        let user = json!({ "id": 583231, "login": "octocat", "name": "The Octocat" });
+        let emails = json!([
+            { "email": "old@example.com", "primary": false, "verified": true },
+            { "email": "octocat@example.com", "primary": true, "verified": false },
+        ]);
+        let identity = github_identity(&user, &emails).unwrap();
+        assert_eq!(identity.subject, "583231");
+        assert_eq!(identity.email.as_deref(), Some("octocat@example.com"));
+        assert!(!identity.email_verified);
+        assert_eq!((identity.first_name.as_deref(), identity.last_name.as_deref()), (Some("The"), Some("Octocat")));
+
+        assert!(github_identity(&json!({ "login": "ghost" }), &json!([])).is_none());
+    }
+}
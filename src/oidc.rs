@@ -0,0 +1,159 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use jsonwebtoken::jwk::{JwkSet, KeyAlgorithm};
+use serde::Serialize;
+use std::env;
+use std::sync::Arc;
+use crate::device::DEVICE_CODE_GRANT;
+use crate::tokens::{TokenProvider, JWKS_PATH};
+
+/// Path the OpenID Connect discovery document is served at.
+pub const DISCOVERY_PATH: &str = "/.well-known/openid-configuration";
+
+/// The public base URL of the service, used as the `issuer` of the discovery document.
+///
+/// Set it with `OIDC_ISSUER` behind proxies or load balancers; without it the issuer
+/// is derived from the scheme and host of each request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issuer(pub String);
+
+impl Issuer {
+    /// Reads `OIDC_ISSUER`, `None` if it is unset or empty.
+    pub fn from_env() -> Option<Self> {
+        env::var("OIDC_ISSUER")
+            .ok()
+            .map(|issuer| issuer.trim_end_matches('/').to_string())
+            .filter(|issuer| !issuer.is_empty())
+            .map(Issuer)
+    }
+}
+
+/// Returns the public base URL of the service: `issuer` if configured, otherwise
+/// the scheme and host of `req`.
+pub fn issuer_for(req: &HttpRequest, issuer: Option<&Issuer>) -> String {
+    match issuer {
+        Some(issuer) => issuer.0.clone(),
+        None => {
+            let info = req.connection_info();
+            format!("{}://{}", info.scheme(), info.host())
+        }
+    }
+}
+
+/// OpenID Provider metadata, as defined by OpenID Connect Discovery 1.0.
+///
+/// Only what the service implements is advertised. The token endpoint is
+/// `/oauth/token`, which takes the form-encoded requests of the device authorization
+/// grant, the only grant served there; `/login` and `/refresh_token` take JSON rather
+/// than RFC 6749 forms, so they are not advertised. There is no authorization
+/// endpoint, so browser-based redirect flows are not available.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveryDocument {
+    pub issuer: String,
+    pub jwks_uri: String,
+    pub token_endpoint: String,
+    pub revocation_endpoint: String,
+    pub device_authorization_endpoint: String,
+    pub grant_types_supported: Vec<&'static str>,
+    pub response_types_supported: Vec<&'static str>,
+    pub subject_types_supported: Vec<&'static str>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub token_endpoint_auth_methods_supported: Vec<&'static str>,
+    pub claims_supported: Vec<&'static str>,
+}
+
+impl DiscoveryDocument {
+    /// Describes the endpoints under `issuer`, advertising the algorithms of `jwks`.
+    ///
+    /// Without published keys the tokens are HMAC-signed or PASETO, which clients
+    /// cannot verify on their own; `HS256` is advertised for the former.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jsonwebtoken::jwk::JwkSet;
+    /// use safe_user::oidc::DiscoveryDocument;
+    ///
+    /// let document = DiscoveryDocument::new("https://users.example.com/", &JwkSet { keys: Vec::new() });
+    /// assert_eq!(document.issuer, "https://users.example.com");
+    /// assert_eq!(document.jwks_uri, "https://users.example.com/.well-known/jwks.json");
+    /// assert_eq!(document.id_token_signing_alg_values_supported, ["HS256"]);
+    /// ```
+    pub fn new(issuer: &str, jwks: &JwkSet) -> Self {
+        let issuer = issuer.trim_end_matches('/');
+        let mut algorithms: Vec<String> = jwks
+            .keys
+            .iter()
+            .filter_map(|key| key.common.key_algorithm)
+            .map(|algorithm| match algorithm {
+                KeyAlgorithm::RS256 => "RS256".to_string(),
+                KeyAlgorithm::ES256 => "ES256".to_string(),
+                other => format!("{:?}", other),
+            })
+            .collect();
+        algorithms.sort();
+        algorithms.dedup();
+        if algorithms.is_empty() {
+            algorithms.push("HS256".to_string());
+        }
+
+        DiscoveryDocument {
+            issuer: issuer.to_string(),
+            jwks_uri: format!("{}{}", issuer, JWKS_PATH),
+            token_endpoint: format!("{}/oauth/token", issuer),
+            revocation_endpoint: format!("{}/logout", issuer),
+            device_authorization_endpoint: format!("{}/oauth/device/code", issuer),
+            grant_types_supported: vec![DEVICE_CODE_GRANT],
+            response_types_supported: vec!["token"],
+            subject_types_supported: vec!["public"],
+            id_token_signing_alg_values_supported: algorithms,
+            token_endpoint_auth_methods_supported: vec!["none"],
+            claims_supported: vec!["sub", "exp", "iss", "roles", "jti", "sid"],
+        }
+    }
+}
+
+/// Serves the OpenID Connect discovery document, so OIDC client libraries can find
+/// the keys and endpoints of the service.
+///
+/// # Arguments
+///
+/// * `req` - The request, whose scheme and host give the issuer when `issuer` is not set.
+/// * `issuer` - The issuer configured with `OIDC_ISSUER`, if any.
+/// * `tokens` - The token provider, whose published keys give the signing algorithms.
+///
+/// # Returns
+///
+/// * `HttpResponse` - The document, cacheable for five minutes like the key set.
+pub async fn serve_discovery(req: HttpRequest, issuer: Option<web::Data<Issuer>>, tokens: Option<web::Data<Arc<dyn TokenProvider>>>) -> HttpResponse {
+    let issuer = issuer_for(&req, issuer.as_ref().map(|issuer| issuer.get_ref()));
+    let jwks = tokens.and_then(|provider| provider.jwks()).unwrap_or(JwkSet { keys: Vec::new() });
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "public, max-age=300"))
+        .json(DiscoveryDocument::new(&issuer, &jwks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    async fn test_issuer_defaults_to_the_request_host() {
+        let app = init_service(App::new().route(DISCOVERY_PATH, web::get().to(serve_discovery))).await;
+        let req = TestRequest::get().uri(DISCOVERY_PATH).insert_header(("Host", "users.example.com")).to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body["issuer"], "http://users.example.com");
+        assert_eq!(body["token_endpoint"], "http://users.example.com/oauth/token");
+        assert_eq!(body["device_authorization_endpoint"], "http://users.example.com/oauth/device/code");
+        assert_eq!(body["grant_types_supported"], serde_json::json!([DEVICE_CODE_GRANT]));
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Issuer("https://id.example.com".to_string())))
+                .route(DISCOVERY_PATH, web::get().to(serve_discovery))
+        ).await;
+        let body: serde_json::Value = read_body_json(call_service(&app, TestRequest::get().uri(DISCOVERY_PATH).to_request()).await).await;
+        assert_eq!(body["jwks_uri"], "https://id.example.com/.well-known/jwks.json");
+    }
+}
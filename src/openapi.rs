@@ -0,0 +1,260 @@
+use actix_web::{HttpResponse, Responder};
+use serde::Serialize;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+use crate::auth::{Claims, RefreshTokenRequest, TokenResponse};
+use crate::challenge::{self, ChallengeWidget};
+use crate::device::{self, DeviceCodeRequest, DeviceCodeResponse, TokenRequest};
+use crate::email_verification;
+use crate::error::{ErrorBody, ErrorCode, ERROR_CODES};
+use crate::handlers;
+use crate::export::{self, ExportFormat};
+use crate::field_metadata::{self, FieldMetadata, FieldType, Locale, UserFieldsMetadata};
+use crate::health;
+use crate::import::{self, ImportReport, ImportRow, ImportStatus};
+use crate::introspection::{self, SessionInfo, TokenIntrospection};
+use crate::mfa::{self, MfaChallenge, MfaCode, MfaEnrollment, MfaVerifyRequest, RecoveryCodes};
+use crate::oauth::{self, OAuthSignup, OAuthSignupRequest};
+use crate::models::{CreateUserRequest, UpdateUserRequest, UserResponse};
+use crate::password_reset::{self, ForgotPasswordRequest, ResetPasswordRequest};
+use crate::org_chart::{self, GraphEdge, GraphNode, UserGraph};
+use crate::pagination::{PageMeta, SortOrder, UserSort};
+use crate::passwords::{LoginRequest, RegisterRequest};
+use crate::profile_gaps::{self, ProfileGaps};
+use crate::quotas::{self, TenantUsage};
+use crate::replication::{self, ReplicationConflict};
+use crate::saved_searches::{self, NewSavedSearch, SavedSearch, SearchCriteria};
+use crate::signed_urls::{self, SignUrlRequest, SignedUrl};
+use crate::suggest::{self, Suggestion};
+
+/// Path of the generated OpenAPI document.
+pub const OPENAPI_PATH: &str = "/api-docs/openapi.json";
+
+/// Path of the catalogue of the codes returned in error bodies.
+pub const ERROR_CODES_PATH: &str = "/api-docs/errors";
+
+/// Path of the Swagger UI page rendering [`OPENAPI_PATH`].
+pub const SWAGGER_UI_PATH: &str = "/swagger-ui";
+
+/// Body of `GET /protected/users`, as documented in the OpenAPI spec.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UserList {
+    pub data: Vec<UserResponse>,
+    pub meta: PageMeta,
+}
+
+/// The OpenAPI 3 description of the API, built from the annotations on the handlers
+/// in [`handlers`] and on the types they exchange.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::openapi::ApiDoc;
+/// use utoipa::OpenApi;
+///
+/// let spec = ApiDoc::openapi();
+/// assert!(spec.paths.paths.contains_key("/login"));
+/// ```
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "safe_user", description = "User management service with JWT authentication."),
+    paths(
+        handlers::create_user,
+        handlers::register,
+        handlers::login,
+        handlers::refresh_token,
+        handlers::logout,
+        mfa::enroll,
+        mfa::confirm,
+        mfa::verify,
+        oauth::start,
+        oauth::callback,
+        oauth::signup,
+        challenge::get_challenge,
+        device::request_device_code,
+        device::oauth_token,
+        email_verification::verify_email,
+        password_reset::forgot_password,
+        password_reset::reset_password,
+        introspection::token_info,
+        profile_gaps::profile_gaps,
+        handlers::get_all_users,
+        handlers::stream_all_users,
+        handlers::get_user,
+        handlers::update_user,
+        handlers::patch_user,
+        handlers::delete_user,
+        handlers::restore_user,
+        import::import_users,
+        export::export_users,
+        field_metadata::user_fields,
+        quotas::get_tenant_usage,
+        replication::list_conflicts,
+        handlers::protected_route,
+        health::live,
+        health::ready,
+        suggest::suggest,
+        org_chart::get_user_graph,
+        saved_searches::create_search,
+        saved_searches::get_searches,
+        saved_searches::delete_search,
+        saved_searches::get_search_results,
+        signed_urls::create_signed_url,
+    ),
+    components(schemas(
+        CreateUserRequest,
+        UpdateUserRequest,
+        UserResponse,
+        UserList,
+        PageMeta,
+        UserSort,
+        SortOrder,
+        Claims,
+        LoginRequest,
+        RegisterRequest,
+        RefreshTokenRequest,
+        ForgotPasswordRequest,
+        ResetPasswordRequest,
+        TokenResponse,
+        MfaEnrollment,
+        MfaCode,
+        RecoveryCodes,
+        MfaChallenge,
+        MfaVerifyRequest,
+        OAuthSignup,
+        OAuthSignupRequest,
+        ChallengeWidget,
+        DeviceCodeRequest,
+        DeviceCodeResponse,
+        TokenRequest,
+        TokenIntrospection,
+        SessionInfo,
+        ErrorBody,
+        ErrorCode,
+        Suggestion,
+        UserGraph,
+        GraphNode,
+        GraphEdge,
+        SearchCriteria,
+        NewSavedSearch,
+        SavedSearch,
+        SignUrlRequest,
+        SignedUrl,
+        ImportReport,
+        ImportRow,
+        ImportStatus,
+        ExportFormat,
+        TenantUsage,
+        ReplicationConflict,
+        Locale,
+        FieldType,
+        FieldMetadata,
+        ProfileGaps,
+        UserFieldsMetadata,
+    )),
+    modifiers(&BearerAuth),
+    tags(
+        (name = "auth", description = "Registration, login and tokens"),
+        (name = "users", description = "User management"),
+        (name = "health", description = "Liveness and readiness probes"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Registers the `bearer_auth` security scheme used by the protected routes.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer_auth",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).bearer_format("JWT").build()),
+        );
+    }
+}
+
+/// Serves the OpenAPI document.
+///
+/// # Returns
+///
+/// * `HttpResponse` - The [`ApiDoc`] spec as JSON.
+pub async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+/// Serves the [`ERROR_CODES`] catalogue.
+///
+/// # Returns
+///
+/// * `HttpResponse` - Every error code with its status and meaning, as JSON.
+pub async fn error_codes() -> impl Responder {
+    HttpResponse::Ok().json(ERROR_CODES)
+}
+
+/// Serves a Swagger UI page for the OpenAPI document.
+///
+/// The page loads the Swagger UI assets from the jsDelivr CDN, so nothing has to be
+/// bundled into the binary.
+///
+/// # Returns
+///
+/// * `HttpResponse` - An HTML page.
+pub async fn swagger_ui() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(SWAGGER_UI_HTML.replace("{spec}", OPENAPI_PATH))
+}
+
+const SWAGGER_UI_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="utf-8">
+    <title>safe_user API</title>
+    <link rel="stylesheet" href="https://cdn.jsdelivr.net/npm/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+    <div id="swagger-ui"></div>
+    <script src="https://cdn.jsdelivr.net/npm/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>
+        window.ui = SwaggerUIBundle({ url: "{spec}", dom_id: "#swagger-ui" });
+    </script>
+</body>
+</html>
+"##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, web, App};
+
+    #[actix_web::test]
+    async fn test_spec_and_ui_are_served() {
+        let app = test::init_service(
+            App::new()
+                .route(OPENAPI_PATH, web::get().to(openapi_json))
+                .route(SWAGGER_UI_PATH, web::get().to(swagger_ui))
+                .route(ERROR_CODES_PATH, web::get().to(error_codes))
+        ).await;
+
+        let req = test::TestRequest::get().uri(OPENAPI_PATH).to_request();
+        let spec: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+        assert!(spec["paths"]["/protected/users/{id}"]["patch"].is_object());
+        for schema in ["CreateUserRequest", "UserResponse", "Claims", "ErrorBody"] {
+            assert!(spec["components"]["schemas"][schema].is_object(), "missing schema {}", schema);
+        }
+        assert_eq!(spec["components"]["securitySchemes"]["bearer_auth"]["scheme"], "bearer");
+
+        let req = test::TestRequest::get().uri(SWAGGER_UI_PATH).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains(OPENAPI_PATH));
+
+        let req = test::TestRequest::get().uri(ERROR_CODES_PATH).to_request();
+        let codes: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let taken = codes.iter().find(|code| code["code"] == "email_taken").unwrap();
+        assert_eq!(taken["status"], 409);
+    }
+}
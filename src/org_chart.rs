@@ -0,0 +1,290 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Mssql, Pool};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use utoipa::{IntoParams, ToSchema};
+use crate::audit;
+use crate::error::AppError;
+use crate::extractors::UserId;
+
+/// Depth of the graph returned when `depth` is not given.
+pub const DEFAULT_GRAPH_DEPTH: u32 = 2;
+
+/// Largest `depth` accepted by the graph endpoint.
+pub const MAX_GRAPH_DEPTH: u32 = 4;
+
+/// Most users returned in one graph, so a wide organization cannot make a single
+/// request walk the whole table.
+pub const MAX_GRAPH_NODES: usize = 500;
+
+/// Query parameters of the graph endpoint: `?depth=2`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct GraphQuery {
+    /// How many manager or report links to follow from the user, at most [`MAX_GRAPH_DEPTH`].
+    pub depth: Option<u32>,
+}
+
+impl GraphQuery {
+    /// Returns the requested depth, or an error message if it is out of range.
+    pub fn depth(&self) -> Result<u32, String> {
+        match self.depth.unwrap_or(DEFAULT_GRAPH_DEPTH) {
+            depth @ 1..=MAX_GRAPH_DEPTH => Ok(depth),
+            _ => Err(format!("`depth` must be between 1 and {}.", MAX_GRAPH_DEPTH)),
+        }
+    }
+}
+
+/// A user in an org-chart graph.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct GraphNode {
+    pub id: String,
+    pub name: String,
+    pub last_name: String,
+    pub org_unit: Option<String>,
+    /// Number of links between this user and the user the graph was asked for.
+    pub distance: u32,
+}
+
+/// A reporting line: `source` manages `target`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+pub struct GraphEdge {
+    pub source: String,
+    pub target: String,
+}
+
+/// The org-chart neighborhood of a user, as returned by `GET /protected/users/{id}/graph`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UserGraph {
+    /// The user the graph was asked for.
+    pub root: String,
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+    /// `true` if [`MAX_GRAPH_NODES`] was reached before `depth` was.
+    pub truncated: bool,
+}
+
+/// A user and their manager, as read while walking the graph.
+#[derive(Debug, FromRow)]
+struct Neighbor {
+    id: String,
+    name: String,
+    last_name: String,
+    org_unit: Option<String>,
+    manager_id: Option<String>,
+}
+
+/// Payload of `PUT /admin/users/{id}/manager`; `null` removes the manager.
+#[derive(Debug, Deserialize)]
+pub struct ManagerAssignment {
+    pub manager_id: Option<UserId>,
+}
+
+/// Why [`set_manager`] did not change a user's manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManagerChange {
+    Changed,
+    UnknownUser,
+    UnknownManager,
+    /// The manager reports, directly or not, to the user.
+    Cycle,
+}
+
+/// Returns a user with their direct reports and manager, or nothing if the user does not exist.
+async fn neighbors(pool: &Pool<Mssql>, user_id: &str) -> Result<Vec<Neighbor>, sqlx::Error> {
+    sqlx::query_as!(
+        Neighbor,
+        r#"
+        SELECT
+            CAST(id AS VARCHAR(36))        AS "id!",
+            Name                           AS "name!",
+            LastName                       AS "last_name!",
+            OrgUnit                        AS "org_unit?",
+            CAST(ManagerId AS VARCHAR(36)) AS "manager_id?"
+        FROM [users]
+        WHERE (id = @p1
+           OR ManagerId = @p1
+           OR id = (SELECT ManagerId FROM [users] WHERE id = @p1))
+           AND DeletedAt IS NULL
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Returns the users within `depth` manager or report links of `user_id`, and the
+/// reporting lines between them.
+///
+/// The walk is breadth-first, so each node carries its shortest distance to the user,
+/// and stops early once [`MAX_GRAPH_NODES`] users have been found.
+///
+/// # Returns
+///
+/// * `Result<Option<UserGraph>, sqlx::Error>` - `Ok(None)` if the user does not exist.
+pub async fn graph(pool: &Pool<Mssql>, user_id: &str, depth: u32) -> Result<Option<UserGraph>, sqlx::Error> {
+    let mut nodes: BTreeMap<String, GraphNode> = BTreeMap::new();
+    let mut managers: BTreeMap<String, Option<String>> = BTreeMap::new();
+    let mut queue = VecDeque::from([(user_id.to_string(), 0)]);
+    let mut truncated = false;
+
+    while let Some((id, distance)) = queue.pop_front() {
+        for neighbor in neighbors(pool, &id).await? {
+            managers.insert(neighbor.id.clone(), neighbor.manager_id.clone());
+            if nodes.contains_key(&neighbor.id) {
+                continue;
+            }
+            if nodes.len() >= MAX_GRAPH_NODES {
+                truncated = true;
+                continue;
+            }
+
+            let distance = if neighbor.id.eq_ignore_ascii_case(&id) { distance } else { distance + 1 };
+            if distance < depth {
+                queue.push_back((neighbor.id.clone(), distance));
+            }
+            nodes.insert(neighbor.id.clone(), GraphNode {
+                id: neighbor.id,
+                name: neighbor.name,
+                last_name: neighbor.last_name,
+                org_unit: neighbor.org_unit,
+                distance,
+            });
+        }
+        if nodes.is_empty() {
+            return Ok(None);
+        }
+    }
+
+    let root = nodes.values().find(|node| node.distance == 0).map_or_else(|| user_id.to_string(), |node| node.id.clone());
+    Ok(Some(UserGraph {
+        root,
+        edges: edges(&nodes.keys().cloned().collect(), &managers),
+        nodes: nodes.into_values().collect(),
+        truncated,
+    }))
+}
+
+/// Returns the reporting lines whose manager and report are both in `nodes`.
+fn edges(nodes: &BTreeSet<String>, managers: &BTreeMap<String, Option<String>>) -> Vec<GraphEdge> {
+    managers
+        .iter()
+        .filter_map(|(report, manager)| Some((report, manager.as_ref()?)))
+        .filter(|(report, manager)| nodes.contains(*report) && nodes.contains(*manager))
+        .map(|(report, manager)| GraphEdge { source: manager.clone(), target: report.clone() })
+        .collect()
+}
+
+/// Sets or removes the manager of a user.
+///
+/// The manager's own chain of managers is walked first, so a change that would make
+/// someone their own indirect manager is refused.
+pub async fn set_manager(pool: &Pool<Mssql>, user_id: &str, manager_id: Option<&str>, actor_id: Option<&str>) -> Result<ManagerChange, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    if let Some(manager_id) = manager_id {
+        let mut current = Some(manager_id.to_string());
+        let mut first = true;
+        while let Some(id) = current {
+            if id.eq_ignore_ascii_case(user_id) {
+                return Ok(ManagerChange::Cycle);
+            }
+            let row = sqlx::query_scalar!(
+                r#"SELECT CAST(ManagerId AS VARCHAR(36)) AS "manager_id?: String" FROM [users] WITH (UPDLOCK) WHERE id = @p1"#,
+                &id
+            )
+            .fetch_optional(&mut tx)
+            .await?;
+            current = match row {
+                Some(next) => next,
+                None if first => return Ok(ManagerChange::UnknownManager),
+                None => None,
+            };
+            first = false;
+        }
+    }
+
+    let result = sqlx::query!(
+        "UPDATE [users] SET ManagerId = @p2, UpdatedAt = SYSUTCDATETIME() WHERE id = @p1",
+        user_id,
+        manager_id
+    )
+    .execute(&mut tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(ManagerChange::UnknownUser);
+    }
+
+    let details = serde_json::json!({ "manager_id": manager_id });
+    audit::record(&mut tx, user_id, audit::MANAGER_CHANGED, actor_id, Some(&details)).await?;
+
+    tx.commit().await?;
+    Ok(ManagerChange::Changed)
+}
+
+/// Returns the org-chart neighborhood of a user as nodes and edges, for visualization tools.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `path` - The id of the user.
+/// * `query` - How many links to follow (`depth`, default [`DEFAULT_GRAPH_DEPTH`]).
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - A [`UserGraph`], `400 Bad Request` for an invalid
+///   `depth`, or `404 Not Found` if the user does not exist.
+#[utoipa::path(
+    get,
+    path = "/protected/users/{id}/graph",
+    tag = "users",
+    params(("id" = String, Path, description = "The id of the user"), GraphQuery),
+    responses(
+        (status = 200, description = "The users around the user and their reporting lines", body = UserGraph),
+        (status = 400, description = "`depth` is out of range", body = ErrorBody),
+        (status = 401, description = "Missing, invalid or revoked token"),
+        (status = 403, description = "The caller may not read users"),
+        (status = 404, description = "The user does not exist", body = ErrorBody),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn get_user_graph(pool: web::Data<Pool<Mssql>>, path: web::Path<UserId>, query: web::Query<GraphQuery>) -> Result<HttpResponse, AppError> {
+    let depth = query.depth().map_err(AppError::validation)?;
+    match graph(pool.get_ref(), &path.to_string(), depth).await? {
+        Some(graph) => Ok(HttpResponse::Ok().json(graph)),
+        None => Err(AppError::NotFound("user")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_depth_is_bounded() {
+        assert_eq!(GraphQuery::default().depth(), Ok(DEFAULT_GRAPH_DEPTH));
+        assert_eq!(GraphQuery { depth: Some(MAX_GRAPH_DEPTH) }.depth(), Ok(MAX_GRAPH_DEPTH));
+        assert!(GraphQuery { depth: Some(0) }.depth().is_err());
+        assert!(GraphQuery { depth: Some(MAX_GRAPH_DEPTH + 1) }.depth().is_err());
+    }
+
+    #[test]
+    fn test_edges_only_link_returned_nodes() {
+        let nodes: BTreeSet<String> = ["ceo", "cto", "dev"].iter().map(|id| id.to_string()).collect();
+        let managers: BTreeMap<String, Option<String>> = [
+            ("ceo", None),
+            ("cto", Some("ceo")),
+            ("dev", Some("cto")),
+            ("intern", Some("dev")),
+            ("cfo", Some("board")),
+        ]
+        .iter()
+        .map(|(id, manager)| (id.to_string(), manager.map(String::from)))
+        .collect();
+
+        assert_eq!(edges(&nodes, &managers), vec![
+            GraphEdge { source: "ceo".to_string(), target: "cto".to_string() },
+            GraphEdge { source: "cto".to_string(), target: "dev".to_string() },
+        ]);
+    }
+}
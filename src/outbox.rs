@@ -0,0 +1,476 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sqlx::{FromRow, Mssql, Pool, Transaction};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::leader::JobLease;
+use crate::timestamp::Timestamp;
+use crate::visibility::SENSITIVE_FIELDS;
+
+/// Version of the JSON payloads emitted for user events.
+///
+/// Bump it whenever a payload changes shape so consumers can branch on it.
+pub const USER_EVENT_SCHEMA_VERSION: i32 = 2;
+
+/// Event type emitted after a user has been created.
+pub const USER_CREATED: &str = "user.created";
+
+/// Event type emitted after a user has been updated; the payload's `changes` holds
+/// the old and new value of every changed field (see [`field_changes`]).
+pub const USER_UPDATED: &str = "user.updated";
+
+/// Event type emitted after a user has been deleted.
+pub const USER_DELETED: &str = "user.deleted";
+
+/// Event type emitted after a deleted user has been restored.
+pub const USER_RESTORED: &str = "user.restored";
+
+/// Event type emitted after an administrator has approved a pending registration.
+pub const USER_APPROVED: &str = "user.approved";
+
+/// Event type emitted after an administrator has rejected a pending registration.
+pub const USER_REJECTED: &str = "user.rejected";
+
+/// Event type emitted once when an active account is about to expire.
+pub const USER_EXPIRING: &str = "user.expiring";
+
+/// Event type emitted after an account has been disabled because it expired.
+pub const USER_EXPIRED: &str = "user.expired";
+
+/// Event type emitted after an administrator has disabled an account.
+pub const USER_DISABLED: &str = "user.disabled";
+
+/// Event type emitted when a new user must verify their email address; the payload
+/// carries the `verification_token` a notifier sends them as a link to `/verify_email`,
+/// which is wiped from the outbox once the event is dispatched.
+pub const USER_VERIFICATION_REQUESTED: &str = "user.verification_requested";
+
+/// Event type emitted when a user asks to reset their password; the payload carries
+/// the `reset_token` a notifier sends them as a link to set a new password, which is
+/// wiped from the outbox once the event is dispatched.
+pub const USER_PASSWORD_RESET_REQUESTED: &str = "user.password_reset_requested";
+
+/// Event type emitted when a tenant reaches `TENANT_QUOTA_WARNING_PERCENT` of its
+/// user cap; the payload carries its `users` and `limit`.
+pub const TENANT_QUOTA_WARNING: &str = "tenant.quota_warning";
+
+/// Event type emitted after an administrator has set or removed the plan of a tenant;
+/// the payload carries the new `plan` and the `actor_id` who changed it.
+pub const TENANT_PLAN_CHANGED: &str = "tenant.plan_changed";
+
+/// Event type emitted when a honeytoken account is authenticated as or accessed; the
+/// payload carries its `user_id`, the kind of `access` and the `trace_id` of the request.
+pub const HONEYTOKEN_TRIPPED: &str = "security.honeytoken_tripped";
+
+/// Error returned by an [`EventSink`] when an event could not be delivered.
+pub type SinkError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A row of the `[outbox]` table.
+#[derive(Debug, FromRow)]
+pub struct OutboxRecord {
+    pub id: i64,
+    pub event_type: String,
+    pub schema_version: i32,
+    pub aggregate_id: String,
+    pub payload: String,
+    pub created_at: Timestamp,
+}
+
+/// The envelope delivered to sinks for every outbox event.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EventEnvelope {
+    /// Monotonic id of the event, usable by consumers for de-duplication.
+    pub id: i64,
+    pub event_type: String,
+    pub schema_version: i32,
+    /// Id of the entity the event is about.
+    pub aggregate_id: String,
+    pub payload: serde_json::Value,
+    pub created_at: Timestamp,
+}
+
+impl EventEnvelope {
+    /// Returns a copy of the event whose payload lacks the one-time secrets, such as
+    /// a `reset_token`, for sinks that must not see them.
+    pub fn without_secrets(&self) -> EventEnvelope {
+        EventEnvelope {
+            id: self.id,
+            event_type: self.event_type.clone(),
+            schema_version: self.schema_version,
+            aggregate_id: self.aggregate_id.clone(),
+            payload: without_secrets(&self.payload),
+            created_at: self.created_at,
+        }
+    }
+}
+
+impl TryFrom<OutboxRecord> for EventEnvelope {
+    type Error = serde_json::Error;
+
+    fn try_from(record: OutboxRecord) -> Result<Self, Self::Error> {
+        Ok(EventEnvelope {
+            id: record.id,
+            event_type: record.event_type,
+            schema_version: record.schema_version,
+            aggregate_id: record.aggregate_id,
+            payload: serde_json::from_str(&record.payload)?,
+            created_at: record.created_at,
+        })
+    }
+}
+
+/// Destination for outbox events (webhook, message broker, ...).
+///
+/// Delivery is at-least-once: an event is only marked as dispatched after
+/// `deliver` returns `Ok`, so implementations must tolerate duplicates.
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    async fn deliver(&self, event: &EventEnvelope) -> Result<(), SinkError>;
+}
+
+/// Sink that POSTs every event as JSON to a webhook URL.
+pub struct WebhookSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookSink {
+    /// Creates a new `WebhookSink` posting to `url`.
+    pub fn new(url: String) -> Self {
+        WebhookSink {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+}
+
+#[async_trait]
+impl EventSink for WebhookSink {
+    async fn deliver(&self, event: &EventEnvelope) -> Result<(), SinkError> {
+        self.client
+            .post(&self.url)
+            .json(event)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Sink that only prints events, used when no webhook is configured.
+///
+/// Payloads carry personal data and tokens, so only the id, type and aggregate of
+/// each event are printed.
+pub struct LogSink;
+
+#[async_trait]
+impl EventSink for LogSink {
+    async fn deliver(&self, event: &EventEnvelope) -> Result<(), SinkError> {
+        println!("Outbox event {} ({}) for {}", event.id, event.event_type, event.aggregate_id);
+        Ok(())
+    }
+}
+
+/// Builds the sink configured through `OUTBOX_WEBHOOK_URL`, falling back to [`LogSink`].
+pub fn sink_from_env() -> Arc<dyn EventSink> {
+    match env::var("OUTBOX_WEBHOOK_URL") {
+        Ok(url) if !url.is_empty() => Arc::new(WebhookSink::new(url)),
+        _ => Arc::new(LogSink),
+    }
+}
+
+/// Placeholder sent instead of the old and new values of redacted fields.
+pub const REDACTED: &str = "[redacted]";
+
+/// Payload fields holding one-time secrets, which only the mailer gets to see: they
+/// are stripped before an event reaches any other sink and wiped from its row once it
+/// has been dispatched or parked.
+const SECRET_PAYLOAD_FIELDS: &[&str] = &["verification_token", "reset_token"];
+
+/// Returns `payload` without its [`SECRET_PAYLOAD_FIELDS`].
+fn without_secrets(payload: &Value) -> Value {
+    let mut payload = payload.clone();
+    if let Some(fields) = payload.as_object_mut() {
+        for field in SECRET_PAYLOAD_FIELDS {
+            fields.remove(*field);
+        }
+    }
+    payload
+}
+
+/// Fields that are never part of a change diff because the server maintains them.
+const UNTRACKED_FIELDS: &[&str] = &["id", "created_at", "updated_at", "last_seen_at"];
+
+/// Returns the user fields whose values are redacted in event payloads, from the
+/// comma-separated `OUTBOX_REDACTED_FIELDS` (default: the [`SENSITIVE_FIELDS`]).
+///
+/// Set it to an empty string to send every value in clear.
+pub fn redacted_fields() -> Vec<String> {
+    match env::var("OUTBOX_REDACTED_FIELDS") {
+        Ok(fields) => fields
+            .split(',')
+            .map(|field| field.trim().to_string())
+            .filter(|field| !field.is_empty())
+            .collect(),
+        Err(_) => SENSITIVE_FIELDS.iter().map(|field| field.to_string()).collect(),
+    }
+}
+
+/// Diffs two serialized versions of an entity, field by field.
+///
+/// Every field whose value differs maps to `{"old": .., "new": ..}`; the values of
+/// the `redacted` fields are replaced with [`REDACTED`], so consumers still learn
+/// that they changed.
+///
+/// # Examples
+///
+/// ```
+/// use serde_json::json;
+/// use safe_user::outbox::field_changes;
+///
+/// let old = json!({ "email": "ana@example.com", "phone": "5551234567", "name": "Ana" });
+/// let new = json!({ "email": "ana@example.org", "phone": "5557654321", "name": "Ana" });
+///
+/// let changes = field_changes(&old, &new, &["phone".to_string()]);
+/// assert_eq!(changes["email"], json!({ "old": "ana@example.com", "new": "ana@example.org" }));
+/// assert_eq!(changes["phone"]["new"], "[redacted]");
+/// assert!(changes.get("name").is_none());
+/// ```
+pub fn field_changes(old: &Value, new: &Value, redacted: &[String]) -> Map<String, Value> {
+    let empty = Map::new();
+    let old = old.as_object().unwrap_or(&empty);
+    let new = new.as_object().unwrap_or(&empty);
+
+    let mut fields: Vec<&String> = old.keys().chain(new.keys()).collect();
+    fields.sort();
+    fields.dedup();
+
+    fields
+        .into_iter()
+        .filter(|field| !UNTRACKED_FIELDS.contains(&field.as_str()))
+        .filter_map(|field| {
+            let before = old.get(field).unwrap_or(&Value::Null);
+            let after = new.get(field).unwrap_or(&Value::Null);
+            if before == after {
+                return None;
+            }
+            let change = if redacted.iter().any(|name| name == field) {
+                json!({ "old": REDACTED, "new": REDACTED })
+            } else {
+                json!({ "old": before, "new": after })
+            };
+            Some((field.clone(), change))
+        })
+        .collect()
+}
+
+/// Writes an event into the outbox as part of an open transaction.
+///
+/// Because the event shares the caller's transaction, it is persisted if and only
+/// if the surrounding write commits.
+///
+/// # Arguments
+///
+/// * `tx` - The transaction the user mutation is running in.
+/// * `event_type` - The event type, e.g. [`USER_CREATED`].
+/// * `aggregate_id` - The id of the entity the event is about.
+/// * `payload` - The event payload.
+///
+/// # Returns
+///
+/// * `Result<(), sqlx::Error>` - An error if the insert failed.
+pub async fn enqueue(tx: &mut Transaction<'_, Mssql>, event_type: &str, aggregate_id: &str, payload: &serde_json::Value) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO [outbox] (EventType, SchemaVersion, AggregateId, Payload)
+        VALUES (@p1, @p2, @p3, @p4)
+        "#,
+        event_type,
+        USER_EVENT_SCHEMA_VERSION,
+        aggregate_id,
+        payload.to_string()
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Number of failed deliveries after which an event is parked: it stays in the
+/// outbox, undispatched, but is no longer retried, so it cannot hold back the events
+/// after it.
+pub const MAX_DISPATCH_ATTEMPTS: i32 = 10;
+
+/// Delivers up to `batch_size` pending events in insertion order.
+///
+/// Dispatching stops at the first failed delivery so that events are never
+/// emitted out of order; the failed event is retried on the next run. After
+/// [`MAX_DISPATCH_ATTEMPTS`] failures, or at once if its payload is not valid JSON,
+/// the event is parked and the events after it are delivered without it. Secrets in
+/// the payload, such as verification and reset tokens, are wiped from dispatched and parked
+/// events.
+///
+/// # Returns
+///
+/// * `Result<usize, sqlx::Error>` - The number of events delivered, or a database error.
+pub async fn dispatch_pending(pool: &Pool<Mssql>, sink: &dyn EventSink, batch_size: i32) -> Result<usize, sqlx::Error> {
+    let records = sqlx::query_as!(
+        OutboxRecord,
+        r#"
+        SELECT TOP (@p1)
+            id                                AS "id!",
+            EventType                         AS "event_type!",
+            SchemaVersion                     AS "schema_version!",
+            AggregateId                       AS "aggregate_id!",
+            Payload                           AS "payload!",
+            CONVERT(VARCHAR(33), CreatedAt, 126) AS "created_at!: Timestamp"
+        FROM [outbox]
+        WHERE DispatchedAt IS NULL AND Attempts < @p2
+        ORDER BY id
+        "#,
+        batch_size,
+        MAX_DISPATCH_ATTEMPTS
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut delivered = 0;
+    for record in records {
+        let id = record.id;
+        let envelope = match EventEnvelope::try_from(record) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                eprintln!("Parking outbox event {}, its payload is invalid: {:?}", id, e);
+                sqlx::query!("UPDATE [outbox] SET Attempts = @p2 WHERE id = @p1", id, MAX_DISPATCH_ATTEMPTS)
+                    .execute(pool)
+                    .await?;
+                continue;
+            }
+        };
+
+        if let Err(e) = sink.deliver(&envelope).await {
+            eprintln!("Error dispatching outbox event {}: {:?}", id, e);
+            let attempts = sqlx::query_scalar!(
+                r#"
+                UPDATE [outbox]
+                SET Attempts = Attempts + 1,
+                    Payload = CASE WHEN Attempts + 1 >= @p3 THEN @p2 ELSE Payload END
+                OUTPUT INSERTED.Attempts AS "attempts!: i32"
+                WHERE id = @p1
+                "#,
+                id,
+                without_secrets(&envelope.payload).to_string(),
+                MAX_DISPATCH_ATTEMPTS
+            )
+            .fetch_one(pool)
+            .await?;
+            if attempts < MAX_DISPATCH_ATTEMPTS {
+                break;
+            }
+            eprintln!("Parking outbox event {} after {} failed deliveries", id, attempts);
+            continue;
+        }
+
+        sqlx::query!(
+            "UPDATE [outbox] SET DispatchedAt = SYSUTCDATETIME(), Payload = @p2 WHERE id = @p1",
+            id,
+            without_secrets(&envelope.payload).to_string()
+        )
+        .execute(pool)
+        .await?;
+        delivered += 1;
+    }
+
+    Ok(delivered)
+}
+
+/// Spawns the background task that drains the outbox every `interval`, on the replica
+/// holding the `outbox` [`JobLease`].
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use safe_user::config::AppConfig;
+/// use safe_user::db::DbPool;
+/// use safe_user::outbox::{sink_from_env, spawn_dispatcher};
+///
+/// #[actix_web::main]
+/// async fn main() {
+///     let config = AppConfig::load().unwrap();
+///     let pool = DbPool::connect(&config.database_url).await.unwrap().pool;
+///     spawn_dispatcher(pool, sink_from_env(), Duration::from_secs(5));
+/// }
+/// ```
+pub fn spawn_dispatcher(pool: Pool<Mssql>, sink: Arc<dyn EventSink>, interval: Duration) {
+    let lease = JobLease::new(pool.clone(), "outbox", interval);
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if !lease.acquire().await {
+                continue;
+            }
+            if let Err(e) = dispatch_pending(&pool, sink.as_ref(), 100).await {
+                eprintln!("Error reading outbox: {:?}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_from_record() {
+        let record = OutboxRecord {
+            id: 7,
+            event_type: USER_CREATED.to_string(),
+            schema_version: USER_EVENT_SCHEMA_VERSION,
+            aggregate_id: "813B6B04-DFBB-4EED-B820-2372216A2367".to_string(),
+            payload: r#"{"email":"example@example.com"}"#.to_string(),
+            created_at: "2024-01-01T00:00:00Z".parse().unwrap(),
+        };
+
+        let envelope = EventEnvelope::try_from(record).expect("Payload should be valid JSON");
+        assert_eq!(envelope.id, 7);
+        assert_eq!(envelope.payload["email"], "example@example.com");
+    }
+
+    #[test]
+    fn test_envelope_from_record_invalid_payload() {
+        let record = OutboxRecord {
+            id: 1,
+            event_type: USER_CREATED.to_string(),
+            schema_version: USER_EVENT_SCHEMA_VERSION,
+            aggregate_id: "1".to_string(),
+            payload: "not json".to_string(),
+            created_at: "2024-01-01T00:00:00Z".parse().unwrap(),
+        };
+
+        assert!(EventEnvelope::try_from(record).is_err(), "Invalid payloads should be rejected");
+    }
+
+    #[test]
+    fn test_without_secrets_drops_tokens_only() {
+        let payload = json!({ "id": "1", "email": "a@example.com", "verification_token": "secret", "reset_token": "secret" });
+
+        assert_eq!(without_secrets(&payload), json!({ "id": "1", "email": "a@example.com" }));
+    }
+
+    #[test]
+    fn test_field_changes_redacts_values_only() {
+        let old = json!({ "id": "1", "email": "a@example.com", "address": null, "updated_at": "2024-01-01T00:00:00Z" });
+        let new = json!({ "id": "1", "email": "a@example.com", "address": "Main St 1", "updated_at": "2024-01-02T00:00:00Z" });
+
+        let changes = field_changes(&old, &new, &["address".to_string()]);
+        assert_eq!(changes.len(), 1, "Unchanged and server-maintained fields are left out");
+        assert_eq!(changes["address"], json!({ "old": REDACTED, "new": REDACTED }));
+
+        let changes = field_changes(&old, &new, &[]);
+        assert_eq!(changes["address"], json!({ "old": null, "new": "Main St 1" }));
+    }
+}
@@ -0,0 +1,211 @@
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::models::UserResponse;
+use crate::timestamp::Timestamp;
+
+/// Page size used when `per_page` is not given.
+pub const DEFAULT_PER_PAGE: u32 = 20;
+
+/// Largest page size a client may request.
+pub const MAX_PER_PAGE: u32 = 100;
+
+/// Columns `GET /protected/users` can be sorted by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UserSort {
+    #[default]
+    Name,
+    LastName,
+    Email,
+    Age,
+    Birthdate,
+}
+
+impl UserSort {
+    /// Returns the name the SQL queries use for this column.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserSort::Name => "name",
+            UserSort::LastName => "last_name",
+            UserSort::Email => "email",
+            UserSort::Age => "age",
+            UserSort::Birthdate => "birthdate",
+        }
+    }
+}
+
+/// Sort direction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    /// Returns `"asc"` or `"desc"`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+}
+
+/// Query parameters of `GET /protected/users`.
+///
+/// `?page=2&per_page=50&sort=email&order=desc&email=a@b.c&name_contains=jo`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UserQuery {
+    /// 1-based page number.
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+    #[serde(default)]
+    pub sort: UserSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Only users with exactly this email.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// Only users whose name contains this text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name_contains: Option<String>,
+    /// Only users seen at or after this instant, to find dormant accounts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[param(value_type = Option<String>, format = DateTime)]
+    pub active_since: Option<Timestamp>,
+    /// Also list deleted users, with their `deleted_at`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub include_deleted: bool,
+}
+
+impl UserQuery {
+    /// Returns the requested page, starting at 1.
+    pub fn page(&self) -> u32 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    /// Returns the requested page size, clamped to `1..=MAX_PER_PAGE`.
+    pub fn per_page(&self) -> u32 {
+        self.per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE)
+    }
+
+    /// Returns the number of rows to skip.
+    pub fn offset(&self) -> u32 {
+        (self.page() - 1).saturating_mul(self.per_page())
+    }
+
+    /// Returns `name_contains` as a `LIKE` pattern, with wildcards in the input escaped.
+    pub fn name_pattern(&self) -> Option<String> {
+        self.name_contains.as_ref().map(|text| format!("%{}%", escape_like(text)))
+    }
+
+    /// Returns this query pointing at another page.
+    fn with_page(&self, page: u32) -> UserQuery {
+        UserQuery { page: Some(page), per_page: Some(self.per_page()), ..self.clone() }
+    }
+}
+
+/// Escapes the `LIKE` wildcards in `text`, for patterns using `ESCAPE '\'`.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::pagination::escape_like;
+///
+/// assert_eq!(escape_like("50%_off"), "50\\%\\_off");
+/// ```
+pub fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// One page of users and the total number of users matching the filters.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserPage {
+    pub users: Vec<UserResponse>,
+    pub total: i64,
+}
+
+/// Pagination metadata returned next to the page of users.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PageMeta {
+    pub page: u32,
+    pub per_page: u32,
+    pub total: i64,
+    pub total_pages: u32,
+}
+
+impl PageMeta {
+    /// Computes the metadata of `query`'s page, given the total number of matches.
+    pub fn new(query: &UserQuery, total: i64) -> Self {
+        PageMeta::for_page(query.page(), query.per_page(), total)
+    }
+
+    /// Computes the metadata of a page of `per_page` items, given the total number of matches.
+    pub fn for_page(page: u32, per_page: u32, total: i64) -> Self {
+        let total_pages = (total.max(0) as u64).div_ceil(per_page as u64) as u32;
+        PageMeta { page, per_page, total, total_pages }
+    }
+
+    /// Builds an RFC 8288 `Link` header value with the `first`, `prev`, `next` and
+    /// `last` pages of `query` under `path`.
+    pub fn link_header(&self, path: &str, query: &UserQuery) -> String {
+        let last = self.total_pages.max(1);
+        let mut links = vec![(1, "first")];
+        if self.page > 1 {
+            links.push(((self.page - 1).min(last), "prev"));
+        }
+        if self.page < self.total_pages {
+            links.push((self.page + 1, "next"));
+        }
+        links.push((last, "last"));
+
+        links
+            .into_iter()
+            .map(|(page, rel)| {
+                let params = serde_urlencoded::to_string(query.with_page(page)).unwrap_or_default();
+                format!("<{}?{}>; rel=\"{}\"", path, params, rel)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_defaults_and_clamping() {
+        let query = UserQuery::default();
+        assert_eq!((query.page(), query.per_page(), query.offset()), (1, DEFAULT_PER_PAGE, 0));
+
+        let query = UserQuery { page: Some(3), per_page: Some(500), ..UserQuery::default() };
+        assert_eq!((query.page(), query.per_page(), query.offset()), (3, MAX_PER_PAGE, 200));
+
+        let query = UserQuery { name_contains: Some("50%_off".to_string()), ..UserQuery::default() };
+        assert_eq!(query.name_pattern().unwrap(), "%50\\%\\_off%");
+    }
+
+    #[test]
+    fn test_link_header() {
+        let query = UserQuery { page: Some(2), per_page: Some(10), email: Some("a@b.c".to_string()), ..UserQuery::default() };
+        let meta = PageMeta::new(&query, 35);
+        assert_eq!(meta.total_pages, 4);
+
+        let link = meta.link_header("/protected/users", &query);
+        assert!(link.contains("page=1&per_page=10&sort=name&order=asc&email=a%40b.c>; rel=\"first\""));
+        assert!(link.contains("page=1&per_page=10&sort=name&order=asc&email=a%40b.c>; rel=\"prev\""));
+        assert!(link.contains("page=3&per_page=10&sort=name&order=asc&email=a%40b.c>; rel=\"next\""));
+        assert!(link.contains("page=4&per_page=10&sort=name&order=asc&email=a%40b.c>; rel=\"last\""));
+    }
+}
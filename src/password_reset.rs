@@ -0,0 +1,295 @@
+use actix_web::{web, HttpResponse};
+use chrono::{Duration, Utc};
+use jsonwebtoken::errors::{Error as JwtError, ErrorKind};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, Mssql, Pool, Transaction};
+use std::env;
+use std::sync::Arc;
+use utoipa::ToSchema;
+use crate::approvals::ACTIVE;
+use crate::audit;
+use crate::auth::derived_key;
+use crate::breach::BreachCheck;
+use crate::email_verification::hash_token;
+use crate::error::AppError;
+use crate::handlers::check_new_password;
+use crate::outbox;
+use crate::passwords::hash_password;
+
+/// Body of every `/forgot_password` response, whether or not the email is registered.
+pub const RESET_REQUESTED: &str = "If the email is registered, a password reset link has been sent.";
+
+/// The `purpose` claim of reset tokens, also used to derive their signing key.
+const PURPOSE: &str = "password_reset";
+
+/// Returns the reset token lifetime in minutes, from `PASSWORD_RESET_TTL_MINUTES` (default 30).
+fn reset_token_ttl_minutes() -> i64 {
+    env::var("PASSWORD_RESET_TTL_MINUTES")
+        .ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .filter(|minutes| *minutes > 0)
+        .unwrap_or(30)
+}
+
+/// The claims of a password reset token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResetClaims {
+    /// The id of the user.
+    pub sub: String,
+    pub exp: usize,
+    purpose: String,
+}
+
+/// Signs a reset token for `user_id`, valid for `PASSWORD_RESET_TTL_MINUTES`.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::password_reset::{issue_token, verify_token};
+///
+/// safe_user::auth::set_token_derivation_secret(secrecy::SecretString::new("0123456789abcdef0123456789abcdef".to_string()));
+/// let token = issue_token("813b6b04-dfbb-4eed-b820-2372216a2367").unwrap();
+/// assert_eq!(verify_token(&token).unwrap().sub, "813b6b04-dfbb-4eed-b820-2372216a2367");
+/// ```
+pub fn issue_token(user_id: &str) -> Result<String, JwtError> {
+    let claims = ResetClaims {
+        sub: user_id.to_string(),
+        exp: (Utc::now() + Duration::minutes(reset_token_ttl_minutes())).timestamp() as usize,
+        purpose: PURPOSE.to_string(),
+    };
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(&derived_key(PURPOSE)))
+}
+
+/// Checks the signature and expiration of a token issued by [`issue_token`].
+pub fn verify_token(token: &str) -> Result<ResetClaims, JwtError> {
+    let claims = decode::<ResetClaims>(token, &DecodingKey::from_secret(&derived_key(PURPOSE)), &Validation::default())?.claims;
+    if claims.purpose != PURPOSE {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+    Ok(claims)
+}
+
+/// The user a reset link is sent to.
+#[derive(Debug, FromRow)]
+struct ResetRecipient {
+    id: String,
+    name: String,
+}
+
+/// Issues a reset token for the active user with `email` and queues the
+/// `user.password_reset_requested` event carrying it.
+///
+/// Only the digest of the token is stored, replacing any earlier one, so only the
+/// latest link sent works.
+///
+/// # Returns
+///
+/// * `Result<bool, sqlx::Error>` - `Ok(false)` if no active user has that email.
+pub async fn request_reset(pool: &Pool<Mssql>, email: &str) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let recipient = sqlx::query_as!(
+        ResetRecipient,
+        r#"
+        SELECT CAST(id AS VARCHAR(36)) AS "id!", Name AS "name!"
+        FROM [users] WITH (UPDLOCK)
+        WHERE Email = @p1 AND Status = @p2 AND DeletedAt IS NULL
+        "#,
+        email,
+        ACTIVE
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+
+    let recipient = match recipient {
+        Some(recipient) => recipient,
+        None => return Ok(false),
+    };
+
+    let token = issue_token(&recipient.id).map_err(|e| sqlx::Error::Configuration(Box::new(e)))?;
+    sqlx::query!(
+        "UPDATE [users] SET PasswordResetHash = @p2 WHERE id = @p1",
+        &recipient.id,
+        hash_token(&token)
+    )
+    .execute(&mut tx)
+    .await?;
+
+    let payload = json!({ "id": recipient.id, "email": email, "name": recipient.name, "reset_token": token });
+    outbox::enqueue(&mut tx, outbox::USER_PASSWORD_RESET_REQUESTED, &recipient.id, &payload).await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Sets the password of the token's user and ends all of their sessions.
+///
+/// The token is consumed, the token version bumped and every refresh token revoked
+/// in one transaction.
+///
+/// # Returns
+///
+/// * `Result<bool, sqlx::Error>` - `Ok(false)` if the token was already used, replaced
+///   by a newer one, or its user is no longer active.
+pub async fn reset_password_with_token(pool: &Pool<Mssql>, claims: &ResetClaims, token: &str, password_hash: &str) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let result = sqlx::query!(
+        r#"
+        UPDATE [users]
+        SET PasswordHash = @p2,
+            PasswordChangedAt = SYSUTCDATETIME(),
+            PasswordResetHash = NULL,
+            PasswordResetRequired = 0,
+            TokenVersion = TokenVersion + 1,
+            UpdatedAt = SYSUTCDATETIME()
+        WHERE id = @p1 AND PasswordResetHash = @p3 AND Status = @p4
+        "#,
+        &claims.sub,
+        password_hash,
+        hash_token(token),
+        ACTIVE
+    )
+    .execute(&mut tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    sqlx::query!(
+        "UPDATE [refresh_tokens] SET RevokedAt = SYSUTCDATETIME() WHERE UserId = @p1 AND RevokedAt IS NULL",
+        &claims.sub
+    )
+    .execute(&mut tx)
+    .await?;
+    audit::record(&mut tx, &claims.sub, audit::PASSWORD_RESET, Some(&claims.sub), None).await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Requires a user to reset their password before logging in again, as part of `tx`.
+/// Logins with the current password are refused until then, each one sending a
+/// reset link.
+///
+/// # Returns
+///
+/// * `Result<Option<String>, sqlx::Error>` - The email of the user, to send the first reset
+///   link to, or `None` if the user does not exist.
+pub async fn require_reset(tx: &mut Transaction<'_, Mssql>, user_id: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        UPDATE [users]
+        SET PasswordResetRequired = 1, UpdatedAt = SYSUTCDATETIME()
+        OUTPUT INSERTED.Email AS "email!: String"
+        WHERE id = @p1 AND DeletedAt IS NULL
+        "#,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+}
+
+/// Payload of a `/forgot_password` request.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+/// Payload of a `/reset_password` request.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ResetPasswordRequest {
+    /// The token from the reset link.
+    pub token: String,
+    #[schema(value_type = String, format = Password)]
+    pub password: SecretString,
+}
+
+/// Sends a password reset link to a user.
+///
+/// The response is the same whether or not the email belongs to an active user, so
+/// the endpoint cannot be used to discover accounts.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `request` - A JSON payload with the user's `email`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `202 Accepted` with [`RESET_REQUESTED`].
+#[utoipa::path(
+    post,
+    path = "/forgot_password",
+    tag = "auth",
+    request_body = ForgotPasswordRequest,
+    responses(
+        (status = 202, description = "Request received, whether or not the email is registered", body = String),
+    )
+)]
+pub async fn forgot_password(pool: web::Data<Pool<Mssql>>, request: web::Json<ForgotPasswordRequest>) -> Result<HttpResponse, AppError> {
+    request_reset(pool.get_ref(), request.email.trim()).await?;
+    Ok(HttpResponse::Accepted().json(RESET_REQUESTED))
+}
+
+/// Sets a new password with the token from a reset link.
+///
+/// The new password must pass the same checks as at registration. Every session of
+/// the user ends, so they have to log in again everywhere.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `breach` - The breach check configured with `BREACH_CHECK`, if any.
+/// * `request` - A JSON payload with the `token` and the new `password`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `204 No Content`, or `400 Bad Request` if the
+///   token is invalid, expired or already used (`invalid_reset_token`), or the password
+///   is rejected (`password_too_short`, `password_breached`).
+#[utoipa::path(
+    post,
+    path = "/reset_password",
+    tag = "auth",
+    request_body = ResetPasswordRequest,
+    responses(
+        (status = 204, description = "The password has been changed and every session ended"),
+        (status = 400, description = "The token is invalid or the password is rejected", body = ErrorBody),
+    )
+)]
+pub async fn reset_password(pool: web::Data<Pool<Mssql>>, breach: Option<web::Data<Arc<dyn BreachCheck>>>, request: web::Json<ResetPasswordRequest>) -> Result<HttpResponse, AppError> {
+    let invalid = || AppError::Validation {
+        code: "invalid_reset_token",
+        message: "The reset link is invalid or has expired.".to_string(),
+        details: None,
+    };
+
+    let claims = verify_token(&request.token).map_err(|_| invalid())?;
+    check_new_password(request.password.expose_secret(), breach.as_ref().map(|breach| breach.get_ref())).await?;
+    let password_hash = hash_password(request.password.expose_secret())?;
+
+    if !reset_password_with_token(pool.get_ref(), &claims, &request.token, &password_hash).await? {
+        return Err(invalid());
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset_tokens_are_not_interchangeable() {
+        let token = issue_token("user").unwrap();
+        assert_eq!(verify_token(&token).unwrap().sub, "user");
+
+        let verification = crate::email_verification::issue_token("user", "ana@example.com").unwrap();
+        assert!(verify_token(&verification).is_err());
+        assert!(crate::email_verification::verify_token(&token).is_err());
+        assert!(crate::auth::validate_jwt(&token).is_err());
+    }
+}
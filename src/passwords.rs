@@ -0,0 +1,241 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::Duration;
+use rand::rngs::OsRng;
+use secrecy::SecretString;
+use serde::Deserialize;
+use sqlx::FromRow;
+use std::env;
+use std::sync::OnceLock;
+use utoipa::ToSchema;
+use validator::{Validate, ValidationErrors};
+use crate::models::CreateUserRequest;
+use crate::timestamp::Timestamp;
+
+/// Minimum number of characters accepted for a new password.
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Body of every failed `/login` response, whatever the reason.
+pub const INVALID_CREDENTIALS: &str = "Invalid email or password.";
+
+/// Payload of a `/register` request: the user's profile plus the chosen password.
+///
+/// Passwords are held as [`SecretString`]s, zeroed on drop and redacted from `Debug`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RegisterRequest {
+    #[serde(flatten)]
+    pub user: CreateUserRequest,
+    #[schema(value_type = String, format = Password)]
+    pub password: SecretString,
+}
+
+/// Checks the user's fields; the password length is checked by `/register` itself.
+impl Validate for RegisterRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        self.user.validate()
+    }
+}
+
+/// Payload of a `/login` request.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct LoginRequest {
+    pub email: String,
+    #[schema(value_type = String, format = Password)]
+    pub password: SecretString,
+}
+
+/// The stored credentials of a user, looked up by email.
+#[derive(Debug, FromRow)]
+pub struct Credentials {
+    pub id: String,
+    /// `None` for users created without a password, who cannot log in.
+    pub password_hash: Option<String>,
+    /// The account status, one of the constants in [`approvals`](crate::approvals).
+    pub status: String,
+    /// `true` when the account's `expires_at` has passed, even if the background task
+    /// has not marked it [`EXPIRED`](crate::approvals::EXPIRED) yet.
+    pub expired: bool,
+    /// `false` until the user opens the link sent to verify their email address.
+    pub email_verified: bool,
+    /// When the password was last set; `None` for users without a password.
+    pub password_changed_at: Option<Timestamp>,
+    /// `true` while an administrator has the account locked.
+    pub locked: bool,
+    /// `true` when an administrator required a password reset that has not happened yet.
+    pub password_reset_required: bool,
+}
+
+/// Header set on `/login` responses when the password expires within
+/// `PASSWORD_EXPIRY_WARNING_DAYS`, holding the [`Timestamp`] it expires at.
+pub const PASSWORD_EXPIRES_HEADER: &str = "X-Password-Expires-At";
+
+/// How long passwords stay valid, for deployments whose compliance rules require
+/// regular rotation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    /// Days a password stays valid after it is set, from `PASSWORD_MAX_AGE_DAYS`;
+    /// `None`, the default, lets passwords live forever.
+    pub max_age_days: Option<i64>,
+    /// Days before expiry during which logins are warned, from
+    /// `PASSWORD_EXPIRY_WARNING_DAYS` (default 14).
+    pub warning_days: i64,
+}
+
+/// Where a password stands under a [`PasswordPolicy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordAge {
+    /// The password is not close to expiring, or never expires.
+    Valid,
+    /// The password expires at the given time, within the warning period.
+    ExpiresSoon(Timestamp),
+    /// The password has expired and must be reset before the next login.
+    Expired,
+}
+
+impl PasswordPolicy {
+    /// Reads the policy from the environment.
+    pub fn from_env() -> Self {
+        PasswordPolicy::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Reads the policy from the variables returned by `var`. Values that are not
+    /// positive numbers are ignored.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let days = |name: &str| var(name).and_then(|days| days.trim().parse::<i64>().ok()).filter(|days| *days > 0);
+        PasswordPolicy {
+            max_age_days: days("PASSWORD_MAX_AGE_DAYS"),
+            warning_days: days("PASSWORD_EXPIRY_WARNING_DAYS").unwrap_or(14),
+        }
+    }
+
+    /// Returns where a password set at `changed_at` stands at `now`. Passwords with
+    /// no recorded change never expire.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::passwords::{PasswordAge, PasswordPolicy};
+    ///
+    /// let policy = PasswordPolicy { max_age_days: Some(90), warning_days: 14 };
+    /// let changed_at = Some("2024-01-01T00:00:00Z".parse().unwrap());
+    /// assert_eq!(policy.age(changed_at, "2024-02-01T00:00:00Z".parse().unwrap()), PasswordAge::Valid);
+    /// assert_eq!(policy.age(changed_at, "2024-03-25T00:00:00Z".parse().unwrap()), PasswordAge::ExpiresSoon("2024-03-31T00:00:00Z".parse().unwrap()));
+    /// assert_eq!(policy.age(changed_at, "2024-03-31T00:00:00Z".parse().unwrap()), PasswordAge::Expired);
+    /// ```
+    pub fn age(&self, changed_at: Option<Timestamp>, now: Timestamp) -> PasswordAge {
+        let (max_age_days, changed_at) = match (self.max_age_days, changed_at) {
+            (Some(max_age_days), Some(changed_at)) => (max_age_days, changed_at),
+            _ => return PasswordAge::Valid,
+        };
+        let expires_at = Timestamp(changed_at.0 + Duration::days(max_age_days));
+        if expires_at <= now {
+            PasswordAge::Expired
+        } else if expires_at.0 - Duration::days(self.warning_days) <= now.0 {
+            PasswordAge::ExpiresSoon(expires_at)
+        } else {
+            PasswordAge::Valid
+        }
+    }
+}
+
+/// Hashes a password with Argon2id and a random salt.
+///
+/// # Returns
+///
+/// * `Result<String, argon2::password_hash::Error>` - The hash in PHC string format, ready to store.
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+/// Returns `true` if `password` matches a hash produced by [`hash_password`].
+///
+/// Malformed hashes never match.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Verifies `password` against the stored credentials, if any.
+///
+/// When the user is unknown or has no password, a dummy hash is verified instead
+/// so the call takes as long as a real check and response timing does not reveal
+/// which emails are registered.
+pub fn verify_credentials(credentials: Option<&Credentials>, password: &str) -> bool {
+    match credentials.and_then(|credentials| credentials.password_hash.as_deref()) {
+        Some(hash) => verify_password(password, hash),
+        None => {
+            verify_password(password, dummy_hash());
+            false
+        }
+    }
+}
+
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash_password("dummy password").expect("Failed to hash dummy password"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::approvals::ACTIVE;
+
+    #[test]
+    fn test_hash_and_verify_password() {
+        let hash = hash_password("correct horse").unwrap();
+
+        assert_ne!(hash, "correct horse");
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("wrong horse", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+
+    #[test]
+    fn test_verify_credentials_rejects_unknown_users() {
+        let known = Credentials {
+            id: "1".to_string(),
+            password_hash: Some(hash_password("correct horse").unwrap()),
+            status: ACTIVE.to_string(),
+            expired: false,
+            email_verified: true,
+            password_changed_at: None,
+            locked: false,
+            password_reset_required: false,
+        };
+        let without_password = Credentials {
+            id: "2".to_string(),
+            password_hash: None,
+            status: ACTIVE.to_string(),
+            expired: false,
+            email_verified: true,
+            password_changed_at: None,
+            locked: false,
+            password_reset_required: false,
+        };
+
+        assert!(verify_credentials(Some(&known), "correct horse"));
+        assert!(!verify_credentials(Some(&without_password), "dummy password"));
+        assert!(!verify_credentials(None, "dummy password"));
+    }
+
+    #[test]
+    fn test_password_policy_is_off_by_default() {
+        let policy = PasswordPolicy::from_vars(|_| None);
+        assert_eq!(policy, PasswordPolicy { max_age_days: None, warning_days: 14 });
+        let long_ago = "2000-01-01T00:00:00Z".parse().ok();
+        assert_eq!(policy.age(long_ago, Timestamp::now()), PasswordAge::Valid);
+
+        let policy = PasswordPolicy::from_vars(|name| (name == "PASSWORD_MAX_AGE_DAYS").then(|| "90".to_string()));
+        assert_eq!(policy.age(long_ago, Timestamp::now()), PasswordAge::Expired);
+        assert_eq!(policy.age(None, Timestamp::now()), PasswordAge::Valid);
+    }
+
+    #[test]
+    fn test_login_request_debug_redacts_password() {
+        let request: LoginRequest = serde_json::from_str(r#"{"email":"a@b.c","password":"hunter22"}"#).unwrap();
+        assert!(!format!("{:?}", request).contains("hunter22"));
+    }
+}
@@ -0,0 +1,175 @@
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Mssql, Pool};
+use std::collections::BTreeMap;
+use std::env;
+use utoipa::ToSchema;
+use crate::audit;
+use crate::outbox;
+
+/// Billing plans and the entitlements each one grants, copied into the `ent` claim of
+/// issued tokens so downstream services can gate features without a lookup.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PlanCatalog {
+    /// The entitlements of each plan, from `PLAN_ENTITLEMENTS`.
+    pub plans: BTreeMap<String, Vec<String>>,
+    /// The plan of users whose own plan and tenant plan are unset, from `DEFAULT_PLAN`.
+    pub default_plan: Option<String>,
+}
+
+/// Payload of `PUT /admin/users/{id}/plan` and `PUT /admin/tenants/{tenant_id}/plan`;
+/// `null` removes the plan.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PlanAssignment {
+    pub plan: Option<String>,
+}
+
+impl PlanCatalog {
+    /// Reads the catalog from the environment.
+    pub fn from_env() -> Self {
+        PlanCatalog::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Reads the catalog from the variables returned by `var`. `PLAN_ENTITLEMENTS` lists
+    /// plans separated by `;`, each as `plan=entitlement,entitlement`; a plan without
+    /// `=` grants nothing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::plans::PlanCatalog;
+    ///
+    /// let catalog = PlanCatalog::from_vars(|name| match name {
+    ///     "PLAN_ENTITLEMENTS" => Some("free; pro=export, api".to_string()),
+    ///     "DEFAULT_PLAN" => Some("free".to_string()),
+    ///     _ => None,
+    /// });
+    /// assert_eq!(catalog.entitlements_of(Some("pro")), ["export", "api"]);
+    /// assert!(catalog.entitlements_of(None).is_empty());
+    /// assert!(catalog.is_known("free") && !catalog.is_known("enterprise"));
+    /// ```
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let plans = var("PLAN_ENTITLEMENTS")
+            .unwrap_or_default()
+            .split(';')
+            .map(|plan| plan.split_once('=').unwrap_or((plan, "")))
+            .filter(|(plan, _)| !plan.trim().is_empty())
+            .map(|(plan, entitlements)| {
+                let entitlements = entitlements.split(',').map(str::trim).filter(|entitlement| !entitlement.is_empty()).map(String::from).collect();
+                (plan.trim().to_string(), entitlements)
+            })
+            .collect();
+        let default_plan = var("DEFAULT_PLAN").map(|plan| plan.trim().to_string()).filter(|plan| !plan.is_empty());
+        PlanCatalog { plans, default_plan }
+    }
+
+    /// Returns `true` if `plan` is listed in `PLAN_ENTITLEMENTS`.
+    pub fn is_known(&self, plan: &str) -> bool {
+        self.plans.contains_key(plan)
+    }
+
+    /// Returns the entitlements of `plan`; unknown plans grant nothing.
+    pub fn entitlements_of(&self, plan: Option<&str>) -> Vec<String> {
+        plan.and_then(|plan| self.plans.get(plan)).cloned().unwrap_or_default()
+    }
+
+    /// Returns an error message if `plan` may not be assigned.
+    pub fn check(&self, plan: Option<&str>) -> Result<(), String> {
+        match plan {
+            Some(plan) if !self.is_known(plan) => {
+                let known: Vec<&str> = self.plans.keys().map(String::as_str).collect();
+                Err(format!("`{}` is not a plan in PLAN_ENTITLEMENTS, expected one of: {}.", plan, known.join(", ")))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the plan of a user: their own plan, else the plan of their tenant, else
+/// the [`default_plan`](PlanCatalog::default_plan) of `catalog`.
+pub async fn effective_plan(pool: &Pool<Mssql>, catalog: &PlanCatalog, user_id: &str) -> Result<Option<String>, sqlx::Error> {
+    let plan = sqlx::query_scalar!(
+        r#"
+        SELECT COALESCE(u.[Plan], t.[Plan]) AS "plan?: String"
+        FROM [users] u
+        LEFT JOIN [tenant_plans] t ON t.TenantId = u.TenantId
+        WHERE u.id = @p1
+        "#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .flatten();
+    Ok(plan.or_else(|| catalog.default_plan.clone()))
+}
+
+/// Sets or removes the plan of a user on behalf of `actor`, returning `Ok(false)` if
+/// the user does not exist. The change is recorded in the user's timeline and
+/// applies to tokens issued afterwards.
+pub async fn set_user_plan(pool: &Pool<Mssql>, user_id: &str, plan: Option<&str>, actor: Option<&str>) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let result = sqlx::query!(
+        "UPDATE [users] SET [Plan] = @p2, UpdatedAt = SYSUTCDATETIME() WHERE id = @p1 AND DeletedAt IS NULL",
+        user_id,
+        plan
+    )
+    .execute(&mut tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    audit::record(&mut tx, user_id, audit::PLAN_CHANGED, actor, Some(&json!({ "plan": plan }))).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Sets or removes the plan of a tenant on behalf of `actor`, inherited by its users that
+/// have no plan of their own, together with its `tenant.plan_changed` event.
+pub async fn set_tenant_plan(pool: &Pool<Mssql>, tenant_id: &str, plan: Option<&str>, actor: Option<&str>) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    match plan {
+        Some(plan) => {
+            sqlx::query!(
+                r#"
+                MERGE [tenant_plans] WITH (HOLDLOCK) AS target
+                USING (SELECT @p1 AS TenantId, @p2 AS [Plan]) AS source
+                ON target.TenantId = source.TenantId
+                WHEN MATCHED THEN UPDATE SET [Plan] = source.[Plan], UpdatedAt = SYSUTCDATETIME()
+                WHEN NOT MATCHED THEN INSERT (TenantId, [Plan]) VALUES (source.TenantId, source.[Plan]);
+                "#,
+                tenant_id,
+                plan
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        None => {
+            sqlx::query!("DELETE FROM [tenant_plans] WHERE TenantId = @p1", tenant_id)
+                .execute(&mut tx)
+                .await?;
+        }
+    }
+
+    let payload = json!({ "tenant_id": tenant_id, "plan": plan, "actor_id": actor });
+    outbox::enqueue(&mut tx, outbox::TENANT_PLAN_CHANGED, tenant_id, &payload).await?;
+    tx.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_listed_plans_can_be_assigned() {
+        let catalog = PlanCatalog::from_vars(|name| (name == "PLAN_ENTITLEMENTS").then(|| "free=reports;pro=reports,export;".to_string()));
+        assert_eq!(catalog.plans.len(), 2);
+        assert!(catalog.check(Some("pro")).is_ok());
+        assert!(catalog.check(None).is_ok());
+        assert!(catalog.check(Some("gold")).unwrap_err().contains("free, pro"));
+        assert_eq!(catalog.default_plan, None);
+        assert!(catalog.entitlements_of(Some("gold")).is_empty());
+    }
+}
@@ -0,0 +1,522 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpMessage, HttpResponse, ResponseError};
+use serde_json::json;
+use sqlx::{Mssql, Pool};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use crate::auth::Claims;
+use crate::error::AppError;
+use crate::extractors::UserId;
+use crate::rbac;
+
+/// Policy used when no `POLICY_FILE` is configured: every authenticated action is allowed.
+pub const DEFAULT_POLICY: &str = "allow *";
+
+/// Attributes describing a subject or a resource. Each attribute may hold several
+/// values (a user can have several roles).
+pub type Attributes = HashMap<String, Vec<String>>;
+
+/// Error returned when a policy document cannot be loaded or parsed.
+#[derive(Debug)]
+pub struct PolicyError {
+    /// The 1-based line the error was found on, `0` for errors not tied to a line.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "line {}: {}", self.line, self.message)
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Effect {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Subject(String),
+    Resource(String),
+    Literal(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Eq,
+    NotEq,
+}
+
+#[derive(Debug, Clone)]
+struct Condition {
+    left: Operand,
+    operator: Operator,
+    right: Operand,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    effect: Effect,
+    action: String,
+    conditions: Vec<Condition>,
+}
+
+/// Attribute-based access control engine.
+///
+/// A policy is a text document with one rule per line; blank lines and lines
+/// starting with `#` are ignored:
+///
+/// ```text
+/// # managers may read users in their own org unit
+/// allow users:read when subject.role == "manager" and subject.org_unit == resource.org_unit
+/// allow users:read when subject.id == resource.id
+/// deny users:delete when resource.role == "admin"
+/// ```
+///
+/// Actions support the same `*` and `prefix:*` wildcards as role permissions.
+/// A comparison holds when any value of the left operand equals (`==`) or no value
+/// equals (`!=`) any value of the right one. An action is permitted when at least one
+/// `allow` rule matches and no `deny` rule does.
+#[derive(Debug, Clone, Default)]
+pub struct PolicyEngine {
+    rules: Vec<Rule>,
+}
+
+fn tokenize(line: &str, line_number: usize) -> Result<Vec<String>, PolicyError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut literal = String::from("\"");
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => literal.push(c),
+                    None => {
+                        return Err(PolicyError { line: line_number, message: "unterminated string literal".into() });
+                    }
+                }
+            }
+            tokens.push(literal);
+        } else if c == '=' || c == '!' {
+            chars.next();
+            if chars.next() != Some('=') {
+                return Err(PolicyError { line: line_number, message: format!("expected `{}=`", c) });
+            }
+            tokens.push(format!("{}=", c));
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '"' || c == '=' || c == '!' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(word);
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_operand(token: &str, line: usize) -> Result<Operand, PolicyError> {
+    if let Some(literal) = token.strip_prefix('"') {
+        Ok(Operand::Literal(literal.to_string()))
+    } else if let Some(attribute) = token.strip_prefix("subject.") {
+        Ok(Operand::Subject(attribute.to_string()))
+    } else if let Some(attribute) = token.strip_prefix("resource.") {
+        Ok(Operand::Resource(attribute.to_string()))
+    } else {
+        Err(PolicyError {
+            line,
+            message: format!("expected `subject.<attr>`, `resource.<attr>` or a quoted string, found `{}`", token),
+        })
+    }
+}
+
+fn parse_rule(line: &str, line_number: usize) -> Result<Rule, PolicyError> {
+    let tokens = tokenize(line, line_number)?;
+    let error = |message: String| PolicyError { line: line_number, message };
+
+    let effect = match tokens.first().map(String::as_str) {
+        Some("allow") => Effect::Allow,
+        Some("deny") => Effect::Deny,
+        _ => return Err(error("rules must start with `allow` or `deny`".into())),
+    };
+    let action = tokens.get(1).ok_or_else(|| error("missing action".into()))?.clone();
+
+    let mut conditions = Vec::new();
+    let mut rest = &tokens[2..];
+    if !rest.is_empty() {
+        if rest[0] != "when" {
+            return Err(error(format!("expected `when`, found `{}`", rest[0])));
+        }
+        rest = &rest[1..];
+        loop {
+            if rest.len() < 3 {
+                return Err(error("incomplete condition".into()));
+            }
+            let operator = match rest[1].as_str() {
+                "==" => Operator::Eq,
+                "!=" => Operator::NotEq,
+                other => return Err(error(format!("expected `==` or `!=`, found `{}`", other))),
+            };
+            conditions.push(Condition {
+                left: parse_operand(&rest[0], line_number)?,
+                operator,
+                right: parse_operand(&rest[2], line_number)?,
+            });
+            rest = &rest[3..];
+            match rest.first().map(String::as_str) {
+                None => break,
+                Some("and") => rest = &rest[1..],
+                Some(other) => return Err(error(format!("expected `and`, found `{}`", other))),
+            }
+        }
+    }
+
+    Ok(Rule { effect, action, conditions })
+}
+
+fn resolve<'a>(operand: &'a Operand, subject: &'a Attributes, resource: &'a Attributes) -> Vec<&'a str> {
+    let values = match operand {
+        Operand::Literal(value) => return vec![value.as_str()],
+        Operand::Subject(attribute) => subject.get(attribute),
+        Operand::Resource(attribute) => resource.get(attribute),
+    };
+    values.map(|values| values.iter().map(String::as_str).collect()).unwrap_or_default()
+}
+
+impl Condition {
+    fn holds(&self, subject: &Attributes, resource: &Attributes) -> bool {
+        let left = resolve(&self.left, subject, resource);
+        let right = resolve(&self.right, subject, resource);
+        let any_equal = left.iter().any(|value| right.contains(value));
+
+        match self.operator {
+            Operator::Eq => any_equal,
+            // A missing attribute never satisfies a comparison, not even `!=`.
+            Operator::NotEq => !left.is_empty() && !right.is_empty() && !any_equal,
+        }
+    }
+}
+
+impl PolicyEngine {
+    /// Parses a policy document.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<PolicyEngine, PolicyError>` - The engine, or the first syntax error found.
+    pub fn parse(document: &str) -> Result<Self, PolicyError> {
+        let rules = document
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(line_number, line)| parse_rule(line, line_number))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(PolicyEngine { rules })
+    }
+
+    /// Loads and parses the policy document at `path`.
+    pub fn from_file(path: &str) -> Result<Self, PolicyError> {
+        let document = fs::read_to_string(path).map_err(|e| PolicyError {
+            line: 0,
+            message: format!("could not read policy file {}: {}", path, e),
+        })?;
+        PolicyEngine::parse(&document)
+    }
+
+    /// Returns `true` if `action` is permitted for `subject` on `resource`.
+    pub fn is_allowed(&self, action: &str, subject: &Attributes, resource: &Attributes) -> bool {
+        let matching = self.rules.iter().filter(|rule| {
+            rbac::grants(std::slice::from_ref(&rule.action), action)
+                && rule.conditions.iter().all(|condition| condition.holds(subject, resource))
+        });
+
+        let mut allowed = false;
+        for rule in matching {
+            match rule.effect {
+                Effect::Deny => return false,
+                Effect::Allow => allowed = true,
+            }
+        }
+        allowed
+    }
+}
+
+/// Shared, reloadable holder of the active [`PolicyEngine`].
+#[derive(Clone)]
+pub struct PolicyStore {
+    engine: Arc<RwLock<PolicyEngine>>,
+    path: Option<String>,
+}
+
+impl PolicyStore {
+    /// Creates a store that always serves `engine`.
+    pub fn new(engine: PolicyEngine) -> Self {
+        PolicyStore {
+            engine: Arc::new(RwLock::new(engine)),
+            path: None,
+        }
+    }
+
+    /// Creates a store from the file named by the `POLICY_FILE` environment variable,
+    /// falling back to [`DEFAULT_POLICY`] when it is not set.
+    pub fn from_env() -> Result<Self, PolicyError> {
+        match std::env::var("POLICY_FILE") {
+            Ok(path) => Ok(PolicyStore {
+                engine: Arc::new(RwLock::new(PolicyEngine::from_file(&path)?)),
+                path: Some(path),
+            }),
+            Err(_) => Ok(PolicyStore::new(PolicyEngine::parse(DEFAULT_POLICY)?)),
+        }
+    }
+
+    /// Re-reads the policy file, keeping the current policy if the new one is invalid.
+    pub fn reload(&self) -> Result<(), PolicyError> {
+        if let Some(path) = &self.path {
+            let engine = PolicyEngine::from_file(path)?;
+            *self.engine.write().unwrap() = engine;
+        }
+        Ok(())
+    }
+
+    /// Evaluates `action` against the active policy.
+    pub fn is_allowed(&self, action: &str, subject: &Attributes, resource: &Attributes) -> bool {
+        self.engine.read().unwrap().is_allowed(action, subject, resource)
+    }
+}
+
+/// Loads the attributes of a user: `id`, `org_unit` and one `role` value per assigned role.
+pub async fn load_user_attributes(pool: &Pool<Mssql>, user_id: &str) -> Result<Attributes, sqlx::Error> {
+    let mut attributes = Attributes::new();
+    attributes.insert("id".to_string(), vec![user_id.to_lowercase()]);
+
+    let org_unit = sqlx::query_scalar!(
+        r#"SELECT OrgUnit AS "org_unit?: String" FROM [users] WHERE id = @p1"#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .flatten();
+    if let Some(org_unit) = org_unit {
+        attributes.insert("org_unit".to_string(), vec![org_unit]);
+    }
+
+    let roles = rbac::user_roles(pool, user_id).await?;
+    attributes.insert("role".to_string(), roles.into_iter().map(|role| role.name).collect());
+
+    Ok(attributes)
+}
+
+/// Asks the [`PolicyStore`] to re-read its policy file.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `200 OK` when reloaded, `400 Bad Request` with the
+///   syntax error and its line in `details` otherwise.
+pub async fn reload_policy(store: web::Data<PolicyStore>) -> Result<HttpResponse, AppError> {
+    store.reload().map_err(|e| AppError::Validation {
+        code: "invalid_policy",
+        message: format!("Error reloading policy: {}", e.message),
+        details: (e.line > 0).then(|| json!({ "line": e.line })),
+    })?;
+    Ok(HttpResponse::Ok().json("Policy reloaded successfully."))
+}
+
+/// Guard that evaluates the ABAC policy for an action before running the handler.
+///
+/// The subject is the authenticated caller; the resource is the user named by the
+/// `{id}` path segment, if the route has one. It must run after
+/// [`jwt_validator`](crate::auth::jwt_validator) and requires a [`PolicyStore`] in
+/// the app data.
+///
+/// # Examples
+///
+/// ```no_run
+/// use actix_web::{web, App};
+/// use safe_user::handlers::get_all_users;
+/// use safe_user::policy::Authorize;
+///
+/// let app = App::new().service(
+///     web::resource("/users")
+///         .wrap(Authorize::new("users:read"))
+///         .route(web::get().to(get_all_users))
+/// );
+/// ```
+pub struct Authorize {
+    action: &'static str,
+}
+
+impl Authorize {
+    /// Creates a guard for `action`.
+    pub fn new(action: &'static str) -> Self {
+        Authorize { action }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Authorize
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthorizeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthorizeMiddleware {
+            service: Rc::new(service),
+            action: self.action,
+        }))
+    }
+}
+
+/// The service produced by [`Authorize`].
+pub struct AuthorizeMiddleware<S> {
+    service: Rc<S>,
+    action: &'static str,
+}
+
+async fn evaluate(req: &ServiceRequest, action: &str) -> Result<bool, sqlx::Error> {
+    let subject_id = match req.extensions().get::<Claims>() {
+        Some(claims) => claims.sub.clone(),
+        None => return Ok(false),
+    };
+    let (store, pool) = match (req.app_data::<web::Data<PolicyStore>>(), req.app_data::<web::Data<Pool<Mssql>>>()) {
+        (Some(store), Some(pool)) => (store.clone(), pool.clone()),
+        _ => return Ok(false),
+    };
+
+    let subject = load_user_attributes(&pool, &subject_id).await?;
+    // Malformed ids are left for the `Path<UserId>` extractor to reject with a 400.
+    let resource_id = req
+        .match_info()
+        .get("id")
+        .and_then(|id| UserId::try_from(id.to_string()).ok());
+    let resource = match resource_id {
+        Some(resource_id) => load_user_attributes(&pool, &resource_id.to_string()).await?,
+        None => Attributes::new(),
+    };
+
+    Ok(store.is_allowed(action, &subject, &resource))
+}
+
+impl<S, B> Service<ServiceRequest> for AuthorizeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let action = self.action;
+
+        Box::pin(async move {
+            let allowed = evaluate(&req, action).await.unwrap_or_else(|e| {
+                eprintln!("Error evaluating policy: {:?}", e);
+                false
+            });
+
+            if !allowed {
+                let response = AppError::forbidden("action_not_allowed", format!("Not allowed to perform {}", action)).error_response();
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            service.call(req).await.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(pairs: &[(&str, &[&str])]) -> Attributes {
+        pairs
+            .iter()
+            .map(|(key, values)| (key.to_string(), values.iter().map(|v| v.to_string()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_manager_reads_own_org_unit() {
+        let engine = PolicyEngine::parse(
+            r#"
+            # managers may read users in their own org unit
+            allow users:read when subject.role == "manager" and subject.org_unit == resource.org_unit
+            "#,
+        ).expect("Policy should parse");
+
+        let manager = attributes(&[("role", &["manager"]), ("org_unit", &["sales"])]);
+        let same_unit = attributes(&[("org_unit", &["sales"])]);
+        let other_unit = attributes(&[("org_unit", &["finance"])]);
+
+        assert!(engine.is_allowed("users:read", &manager, &same_unit));
+        assert!(!engine.is_allowed("users:read", &manager, &other_unit));
+        assert!(!engine.is_allowed("users:delete", &manager, &same_unit));
+    }
+
+    #[test]
+    fn test_deny_overrides_allow() {
+        let engine = PolicyEngine::parse(
+            "allow users:*\ndeny users:delete when resource.role == \"admin\"",
+        ).unwrap();
+
+        let subject = attributes(&[("id", &["1"])]);
+        let admin = attributes(&[("role", &["user", "admin"])]);
+
+        assert!(engine.is_allowed("users:delete", &subject, &Attributes::new()));
+        assert!(!engine.is_allowed("users:delete", &subject, &admin));
+    }
+
+    #[test]
+    fn test_missing_attribute_never_matches() {
+        let engine = PolicyEngine::parse("allow users:read when subject.org_unit != \"sales\"").unwrap();
+        assert!(!engine.is_allowed("users:read", &Attributes::new(), &Attributes::new()));
+    }
+
+    #[test]
+    fn test_parse_errors_report_line() {
+        let error = PolicyEngine::parse("allow *\npermit users:read").unwrap_err();
+        assert_eq!(error.line, 2);
+
+        assert!(PolicyEngine::parse("allow users:read when subject.role ==").is_err());
+        assert!(PolicyEngine::parse("allow users:read when role == \"admin\"").is_err());
+        assert!(PolicyEngine::parse("allow users:read when subject.role == \"admin").is_err());
+    }
+
+    #[test]
+    fn test_default_policy_allows_everything() {
+        let engine = PolicyEngine::parse(DEFAULT_POLICY).unwrap();
+        assert!(engine.is_allowed("users:read", &Attributes::new(), &Attributes::new()));
+    }
+}
@@ -0,0 +1,181 @@
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::sync::Arc;
+use utoipa::ToSchema;
+use crate::error::AppError;
+use crate::extractors::AuthenticatedUser;
+use crate::field_metadata::{self, FieldMetadata, MetadataQuery};
+use crate::models::UserResponse;
+use crate::repository::UserRepository;
+
+/// The profile fields users must fill in beyond the ones required at sign-up, for
+/// everyone, by role and by plan.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProfileRequirements {
+    /// Fields every user must fill in.
+    pub everyone: BTreeSet<String>,
+    /// Fields required of the users holding each role.
+    pub roles: BTreeMap<String, BTreeSet<String>>,
+    /// Fields required of the users on each plan.
+    pub plans: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl ProfileRequirements {
+    /// Reads the requirements from the environment.
+    pub fn from_env() -> Self {
+        ProfileRequirements::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Reads the requirements from the variables returned by `var`.
+    /// `PROFILE_REQUIRED_FIELDS` lists rules separated by `;`, each as
+    /// `target=field,field` where the target is `*`, `role:<role>` or `plan:<plan>`;
+    /// rules with another target are ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::profile_gaps::ProfileRequirements;
+    ///
+    /// let requirements = ProfileRequirements::from_vars(|name| {
+    ///     (name == "PROFILE_REQUIRED_FIELDS").then(|| "*=phone; role:admin=org_unit; plan:pro=address, phone".to_string())
+    /// });
+    /// assert_eq!(requirements.required_for(&["user".to_string()], None), ["phone"]);
+    /// assert_eq!(requirements.required_for(&["admin".to_string()], Some("pro")), ["address", "org_unit", "phone"]);
+    /// ```
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let mut requirements = ProfileRequirements::default();
+        for rule in var("PROFILE_REQUIRED_FIELDS").unwrap_or_default().split(';') {
+            let (target, fields) = match rule.split_once('=') {
+                Some(rule) => rule,
+                None => continue,
+            };
+            let fields = fields.split(',').map(str::trim).filter(|field| !field.is_empty()).map(String::from);
+            let target = target.trim();
+            if target == "*" {
+                requirements.everyone.extend(fields);
+            } else if let Some(role) = target.strip_prefix("role:") {
+                requirements.roles.entry(role.trim().to_string()).or_default().extend(fields);
+            } else if let Some(plan) = target.strip_prefix("plan:") {
+                requirements.plans.entry(plan.trim().to_string()).or_default().extend(fields);
+            }
+        }
+        requirements
+    }
+
+    /// Returns the names of the fields required of a user holding `roles` on `plan`,
+    /// in alphabetical order.
+    pub fn required_for(&self, roles: &[String], plan: Option<&str>) -> Vec<&str> {
+        let by_role = roles.iter().filter_map(|role| self.roles.get(role));
+        let by_plan = plan.and_then(|plan| self.plans.get(plan));
+        let fields: BTreeSet<&str> = std::iter::once(&self.everyone).chain(by_role).chain(by_plan).flatten().map(String::as_str).collect();
+        fields.into_iter().collect()
+    }
+}
+
+/// Body of `GET /me/profile_gaps`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ProfileGaps {
+    /// `true` once no required field is missing.
+    pub complete: bool,
+    /// The required fields the user has not filled in, in form order, with their
+    /// labels and validation messages as in `GET /metadata/users/fields`.
+    pub missing: Vec<FieldMetadata>,
+}
+
+/// Returns the fields of `fields` that are required, by the user field metadata or by
+/// `required`, and that `user` has left empty.
+pub fn missing_fields(user: &UserResponse, fields: Vec<FieldMetadata>, required: &[&str]) -> Vec<FieldMetadata> {
+    let values = serde_json::to_value(user).unwrap_or_default();
+    fields
+        .into_iter()
+        .filter(|field| field.required || required.contains(&field.name))
+        .filter(|field| match values.get(field.name) {
+            None | Some(Value::Null) => true,
+            Some(Value::String(value)) => value.trim().is_empty(),
+            Some(_) => false,
+        })
+        .collect()
+}
+
+/// Lists the profile fields the caller still has to fill in for their roles and
+/// plan, so frontends can prompt for them a few at a time instead of in one long form.
+///
+/// # Arguments
+///
+/// * `req` - The request, whose `Accept-Language` header picks the locale of the labels
+///   and with the [`ProfileRequirements`], if configured.
+/// * `query` - The `locale`, which takes precedence over the header.
+/// * `users` - The user repository.
+/// * `caller` - The caller, whose `roles` and `plan` claims pick the requirements.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - The [`ProfileGaps`] of the caller, with the
+///   locale in `Content-Language`.
+#[utoipa::path(
+    get,
+    path = "/me/profile_gaps",
+    tag = "users",
+    params(MetadataQuery),
+    responses(
+        (status = 200, description = "The required fields the caller has not filled in", body = ProfileGaps),
+        (status = 401, description = "Missing, invalid or revoked token", body = ErrorBody),
+        (status = 404, description = "The caller's account no longer exists", body = ErrorBody),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn profile_gaps(
+    req: HttpRequest,
+    query: web::Query<MetadataQuery>,
+    users: web::Data<Arc<dyn UserRepository>>,
+    caller: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    let user = users.get(caller.id()).await?.ok_or(AppError::NotFound("user"))?;
+    let locale = field_metadata::request_locale(&req, query.locale);
+    let requirements = req.app_data::<web::Data<ProfileRequirements>>().map(|data| data.get_ref().clone()).unwrap_or_default();
+    let required = requirements.required_for(&caller.roles, caller.plan.as_deref());
+    let missing = missing_fields(&user, field_metadata::user_fields_metadata(locale).fields, &required);
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_LANGUAGE, HeaderValue::from_static(locale.tag())))
+        .insert_header((header::VARY, HeaderValue::from_static("Accept-Language")))
+        .json(ProfileGaps { complete: missing.is_empty(), missing }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::field_metadata::{user_fields_metadata, Locale};
+
+    fn user() -> UserResponse {
+        serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "user_id": "jdoe",
+            "name": "John",
+            "last_name": "Doe",
+            "email": "john@example.com",
+            "age": 30,
+            "phone": null,
+            "address": " ",
+            "birthdate": "1990-01-01",
+            "place_birth": null,
+            "org_unit": "sales",
+            "expires_at": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_only_required_empty_fields_are_missing() {
+        let missing = missing_fields(&user(), user_fields_metadata(Locale::Es).fields, &["address", "age", "org_unit", "phone"]);
+        let names: Vec<&str> = missing.iter().map(|field| field.name).collect();
+        assert_eq!(names, ["phone", "address"]);
+        assert_eq!(missing[0].label, "Teléfono");
+
+        assert!(missing_fields(&user(), user_fields_metadata(Locale::En).fields, &[]).is_empty());
+    }
+}
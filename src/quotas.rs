@@ -0,0 +1,219 @@
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{Mssql, Transaction};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use utoipa::ToSchema;
+use crate::auth::Claims;
+use crate::error::AppError;
+use crate::outbox;
+use crate::repository::UserRepository;
+use crate::tenancy::{TenantKeys, TENANT_HEADER};
+
+/// Caps on the number of users of each tenant in multi-tenant mode, for plan-based billing.
+///
+/// The quotas are soft: they are checked before users are created, so concurrent
+/// creations can take a tenant slightly past its cap. Deleted users do not count.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TenantQuotas {
+    /// The most users of each tenant, from `TENANT_USER_QUOTAS`; tenants left out have no cap.
+    pub limits: HashMap<String, i64>,
+    /// How full a tenant gets, in percent of its cap, before a `tenant.quota_warning`
+    /// event is emitted, from `TENANT_QUOTA_WARNING_PERCENT` (default 80).
+    pub warning_percent: i64,
+}
+
+/// The users of a tenant and its cap, as returned by `GET /protected/admin/tenants/{tenant_id}/usage`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TenantUsage {
+    pub tenant_id: String,
+    /// Users of the tenant that are not deleted.
+    pub users: i64,
+    /// The cap; `None` if the tenant has none.
+    pub limit: Option<i64>,
+    /// Users that can still be created; `None` if the tenant has no cap.
+    pub remaining: Option<i64>,
+}
+
+impl TenantQuotas {
+    /// Reads the quotas from the environment.
+    pub fn from_env() -> Self {
+        TenantQuotas::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Reads the quotas from the variables returned by `var`. `TENANT_USER_QUOTAS` is a
+    /// comma-separated list of `tenant=limit` pairs; pairs without a positive limit
+    /// are ignored.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let limits = var("TENANT_USER_QUOTAS")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .filter_map(|(tenant_id, limit)| Some((tenant_id.trim().to_string(), limit.trim().parse::<i64>().ok().filter(|limit| *limit > 0)?)))
+            .collect();
+        let warning_percent = var("TENANT_QUOTA_WARNING_PERCENT")
+            .and_then(|percent| percent.trim().parse::<i64>().ok())
+            .filter(|percent| (1..=100).contains(percent))
+            .unwrap_or(80);
+        TenantQuotas { limits, warning_percent }
+    }
+
+    /// Returns the cap of `tenant_id`, if any.
+    pub fn limit_for(&self, tenant_id: &str) -> Option<i64> {
+        self.limits.get(tenant_id).copied()
+    }
+
+    /// Returns the number of users at which `tenant_id` is warned that it is close to
+    /// its cap, if it has one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::quotas::TenantQuotas;
+    ///
+    /// let quotas = TenantQuotas::from_vars(|name| (name == "TENANT_USER_QUOTAS").then(|| "acme=10, globex=3".to_string()));
+    /// assert_eq!(quotas.warning_threshold("acme"), Some(8));
+    /// assert_eq!(quotas.warning_threshold("globex"), Some(3));
+    /// assert_eq!(quotas.warning_threshold("initech"), None);
+    /// ```
+    pub fn warning_threshold(&self, tenant_id: &str) -> Option<i64> {
+        self.limit_for(tenant_id).map(|limit| (limit * self.warning_percent + 99) / 100)
+    }
+
+    /// Returns an error if creating `new_users` more users would take `tenant_id`,
+    /// which has `users` users, past its cap.
+    pub fn check(&self, tenant_id: &str, users: i64, new_users: usize) -> Result<(), AppError> {
+        match self.limit_for(tenant_id) {
+            Some(limit) if users + new_users as i64 > limit => Err(AppError::QuotaExceeded { tenant_id: tenant_id.to_string(), limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the usage of `tenant_id`, which has `users` users.
+    pub fn usage(&self, tenant_id: &str, users: i64) -> TenantUsage {
+        let limit = self.limit_for(tenant_id);
+        TenantUsage {
+            tenant_id: tenant_id.to_string(),
+            users,
+            limit,
+            remaining: limit.map(|limit| (limit - users).max(0)),
+        }
+    }
+}
+
+/// Returns the tenant new users are created in: the tenant named by the
+/// `X-Tenant-Id` header in multi-tenant mode, and `None` otherwise.
+///
+/// An unknown or missing tenant gets `400 Bad Request`, like at `/login`. A caller
+/// whose token was issued for another tenant gets `401 Unauthorized`.
+pub fn request_tenant(req: &HttpRequest) -> Result<Option<String>, AppError> {
+    let keys = match req.app_data::<web::Data<TenantKeys>>() {
+        Some(keys) => keys,
+        None => return Ok(None),
+    };
+    let tenant_id = req.headers().get(TENANT_HEADER).and_then(|value| value.to_str().ok());
+    let key = tenant_id
+        .and_then(|tenant_id| keys.signing_key_for(tenant_id))
+        .ok_or_else(|| AppError::validation("Unknown or missing tenant."))?;
+
+    let issuer = req.extensions().get::<Claims>().and_then(|claims| claims.iss.clone());
+    if issuer.is_some_and(|issuer| issuer != key.issuer) {
+        return Err(AppError::auth("tenant_mismatch", "The token was not issued for this tenant."));
+    }
+    Ok(Some(key.tenant_id))
+}
+
+/// Returns an error if creating `new_users` more users would take `tenant_id` past
+/// its cap. Users created outside multi-tenant mode have no tenant and no cap.
+pub(crate) async fn enforce(users: &dyn UserRepository, tenant_id: Option<&str>, new_users: usize) -> Result<(), AppError> {
+    let quotas = TenantQuotas::from_env();
+    match tenant_id {
+        Some(tenant_id) if quotas.limit_for(tenant_id).is_some() => quotas.check(tenant_id, users.count_in_tenant(tenant_id).await?, new_users),
+        _ => Ok(()),
+    }
+}
+
+/// Emits a `tenant.quota_warning` event, as part of `tx`, if the user just created
+/// brought `tenant_id` to its warning threshold; a tenant is warned once each time it
+/// reaches the threshold.
+pub(crate) async fn warn_if_near_limit(tx: &mut Transaction<'_, Mssql>, tenant_id: &str) -> Result<(), sqlx::Error> {
+    let quotas = TenantQuotas::from_env();
+    let (limit, threshold) = match (quotas.limit_for(tenant_id), quotas.warning_threshold(tenant_id)) {
+        (Some(limit), Some(threshold)) => (limit, threshold),
+        _ => return Ok(()),
+    };
+
+    let users = sqlx::query_scalar!(
+        r#"SELECT COUNT_BIG(*) AS "users!: i64" FROM [users] WHERE TenantId = @p1 AND DeletedAt IS NULL"#,
+        tenant_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if users == threshold {
+        let payload = json!({ "tenant_id": tenant_id, "users": users, "limit": limit });
+        outbox::enqueue(tx, outbox::TENANT_QUOTA_WARNING, tenant_id, &payload).await?;
+    }
+    Ok(())
+}
+
+/// Returns how many users a tenant has and how many more it may create.
+///
+/// # Arguments
+///
+/// * `users` - The user repository.
+/// * `path` - The id of the tenant.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - A [`TenantUsage`] as JSON.
+#[utoipa::path(
+    get,
+    path = "/protected/admin/tenants/{tenant_id}/usage",
+    tag = "users",
+    params(("tenant_id" = String, Path, description = "The id of the tenant")),
+    responses(
+        (status = 200, description = "The users of the tenant and its cap", body = TenantUsage),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn get_tenant_usage(users: web::Data<Arc<dyn UserRepository>>, path: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let tenant_id = path.into_inner();
+    let count = users.count_in_tenant(&tenant_id).await?;
+    Ok(HttpResponse::Ok().json(TenantQuotas::from_env().usage(&tenant_id, count)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quotas() -> TenantQuotas {
+        TenantQuotas::from_vars(|name| match name {
+            "TENANT_USER_QUOTAS" => Some("acme=5, globex=oops, initech=0".to_string()),
+            "TENANT_QUOTA_WARNING_PERCENT" => Some("60".to_string()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_quotas_are_read_from_the_environment() {
+        let quotas = quotas();
+        assert_eq!(quotas.limits, HashMap::from([("acme".to_string(), 5)]));
+        assert_eq!(quotas.warning_threshold("acme"), Some(3));
+        assert_eq!(TenantQuotas::from_vars(|_| None).warning_percent, 80);
+    }
+
+    #[test]
+    fn test_check_refuses_creations_past_the_cap() {
+        let quotas = quotas();
+        assert!(quotas.check("acme", 4, 1).is_ok());
+        assert!(matches!(quotas.check("acme", 4, 2), Err(AppError::QuotaExceeded { limit: 5, .. })));
+        assert!(quotas.check("globex", 1000, 1).is_ok());
+
+        let usage = quotas.usage("acme", 7);
+        assert_eq!((usage.limit, usage.remaining), (Some(5), Some(0)));
+        assert_eq!(quotas.usage("globex", 7).remaining, None);
+    }
+}
@@ -0,0 +1,520 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, ResponseError};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Mssql, Pool};
+use std::collections::HashMap;
+use std::env;
+use std::future::{ready, Future, Ready};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use crate::auth::Claims;
+use crate::error::AppError;
+
+/// Requests per minute allowed to users none of whose roles has a limit, when
+/// `RATE_LIMIT_PER_MINUTE` is not set.
+pub const DEFAULT_REQUESTS_PER_MINUTE: u32 = 60;
+
+/// Requests per minute allowed from one IP address on an unauthenticated endpoint
+/// when its `<PREFIX>_RATE_LIMIT_PER_MINUTE` variable is not set.
+pub const DEFAULT_IP_REQUESTS_PER_MINUTE: u32 = 10;
+
+/// Length of the window requests are counted over.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Number of tracked callers above which finished windows are dropped.
+const MAX_TRACKED_CALLERS: usize = 10_000;
+
+/// The limit configured for a role, as stored in the `[rate_limits]` table.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct RoleRateLimit {
+    pub role_id: i32,
+    pub role: String,
+    /// `None` for roles whose users are not limited.
+    pub requests_per_minute: Option<i32>,
+}
+
+/// Payload used to set the limit of a role; `null` lifts the limit.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RateLimitInput {
+    pub requests_per_minute: Option<i32>,
+}
+
+/// Requests counted for a caller in the current window.
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+/// Per-user request limits, chosen by the roles carried in the caller's token.
+///
+/// A user gets the most generous limit among their roles, and no limit at all if
+/// one of their roles is unlimited; users whose roles have no limit configured get
+/// the default. Limits are kept in memory and shared by every worker, so edits made
+/// through the admin API apply as soon as [`reload`](RateLimiter::reload) returns.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::rate_limit::RateLimiter;
+///
+/// let limiter = RateLimiter::new(60);
+/// limiter.set_limits([("admin".to_string(), None), ("free".to_string(), Some(60))]);
+///
+/// assert_eq!(limiter.limit_for(&["free".to_string(), "admin".to_string()]), None);
+/// assert_eq!(limiter.limit_for(&[]), Some(60));
+/// ```
+#[derive(Debug)]
+pub struct RateLimiter {
+    default_limit: u32,
+    limits: RwLock<HashMap<String, Option<u32>>>,
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `default_limit` requests per minute and no role limits.
+    pub fn new(default_limit: u32) -> Self {
+        RateLimiter {
+            default_limit,
+            limits: RwLock::new(HashMap::new()),
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates a limiter with the default from `RATE_LIMIT_PER_MINUTE` and the role
+    /// limits stored in the database.
+    pub async fn load(pool: &Pool<Mssql>) -> Result<Self, sqlx::Error> {
+        let default_limit = env::var("RATE_LIMIT_PER_MINUTE")
+            .ok()
+            .and_then(|limit| limit.parse().ok())
+            .unwrap_or(DEFAULT_REQUESTS_PER_MINUTE);
+
+        let limiter = RateLimiter::new(default_limit);
+        limiter.reload(pool).await?;
+        Ok(limiter)
+    }
+
+    /// Replaces the role limits with the ones stored in the database.
+    pub async fn reload(&self, pool: &Pool<Mssql>) -> Result<(), sqlx::Error> {
+        let limits = list_rate_limits(pool).await?;
+        self.set_limits(
+            limits
+                .into_iter()
+                .map(|limit| (limit.role, limit.requests_per_minute.map(|rpm| rpm.max(0) as u32))),
+        );
+        Ok(())
+    }
+
+    /// Replaces the role limits, given as role names and requests per minute.
+    pub fn set_limits(&self, limits: impl IntoIterator<Item = (String, Option<u32>)>) {
+        *self.limits.write().unwrap() = limits.into_iter().collect();
+    }
+
+    /// Returns the requests per minute allowed to the default tier.
+    pub fn default_limit(&self) -> u32 {
+        self.default_limit
+    }
+
+    /// Returns the requests per minute allowed to a user with `roles`, or `None` if they are not limited.
+    pub fn limit_for(&self, roles: &[String]) -> Option<u32> {
+        let limits = self.limits.read().unwrap();
+        let mut configured = roles.iter().filter_map(|role| limits.get(role)).peekable();
+
+        if configured.peek().is_none() {
+            return Some(self.default_limit);
+        }
+        configured.try_fold(0, |most, limit| limit.map(|limit| most.max(limit)))
+    }
+
+    /// Counts a request from `subject`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), u64>` - `Err` with the seconds until the window resets if the request is over the limit.
+    pub fn check(&self, subject: &str, roles: &[String]) -> Result<(), u64> {
+        let limit = match self.limit_for(roles) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() > MAX_TRACKED_CALLERS {
+            windows.retain(|_, window| now.duration_since(window.started) < WINDOW);
+        }
+
+        let window = windows.entry(subject.to_string()).or_insert(Window { started: now, count: 0 });
+        if now.duration_since(window.started) >= WINDOW {
+            *window = Window { started: now, count: 0 };
+        }
+
+        if window.count >= limit {
+            let remaining = WINDOW.saturating_sub(now.duration_since(window.started));
+            return Err(remaining.as_secs().max(1));
+        }
+        window.count += 1;
+        Ok(())
+    }
+}
+
+/// Middleware function that answers `429 Too Many Requests` once the caller has used
+/// up the requests allowed to their roles for the current minute.
+///
+/// It must run after the JWT validator, since callers are identified by the `sub`
+/// and `roles` claims. Requests without claims, and apps without a [`RateLimiter`]
+/// registered as app data, are passed through.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{middleware::from_fn, web, App};
+/// use actix_web_httpauth::middleware::HttpAuthentication;
+/// use safe_user::auth::jwt_validator;
+/// use safe_user::rate_limit::{enforce_rate_limit, RateLimiter};
+///
+/// let app = App::new()
+///     .app_data(web::Data::new(RateLimiter::new(60)))
+///     .service(
+///         web::scope("/protected")
+///             .wrap(from_fn(enforce_rate_limit))
+///             .wrap(HttpAuthentication::bearer(jwt_validator))
+///     );
+/// ```
+pub async fn enforce_rate_limit(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let limited = match (req.app_data::<web::Data<RateLimiter>>(), req.extensions().get::<Claims>()) {
+        (Some(limiter), Some(claims)) => limiter.check(&claims.sub, &claims.roles).err(),
+        _ => None,
+    };
+
+    if let Some(retry_after) = limited {
+        let response = AppError::RateLimited(retry_after).error_response();
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+/// The tokens left to an IP address, refilled continuously.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Per-IP token buckets for unauthenticated endpoints such as login and sign-up.
+///
+/// Each address may send `burst` requests at once, and gets tokens back at
+/// `per_minute` per minute. Buckets are kept in memory, so each instance of the
+/// server limits on its own. Addresses are read from the socket rather than from
+/// `X-Forwarded-For`, which clients could forge; behind a reverse proxy every
+/// request shares the proxy's bucket, so limit there instead.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::rate_limit::IpRateLimiter;
+///
+/// let limiter = IpRateLimiter::new(2, 60);
+/// let ip = "203.0.113.7".parse().unwrap();
+///
+/// assert!(limiter.check(ip).is_ok());
+/// assert!(limiter.check(ip).is_ok());
+/// assert_eq!(limiter.check(ip), Err(1));
+/// ```
+#[derive(Debug)]
+pub struct IpRateLimiter {
+    burst: u32,
+    per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl IpRateLimiter {
+    /// Creates a limiter allowing bursts of `burst` requests and `per_minute` requests per minute after that.
+    pub fn new(burst: u32, per_minute: u32) -> Self {
+        IpRateLimiter {
+            burst: burst.max(1),
+            per_minute: per_minute.max(1),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates a limiter from `<prefix>_RATE_LIMIT_PER_MINUTE` (default
+    /// [`DEFAULT_IP_REQUESTS_PER_MINUTE`]) and `<prefix>_RATE_LIMIT_BURST` (default the
+    /// per-minute limit).
+    pub fn from_env(prefix: &str) -> Self {
+        let var = |name: &str| env::var(format!("{}_{}", prefix, name)).ok().and_then(|value| value.parse().ok());
+        let per_minute = var("RATE_LIMIT_PER_MINUTE").unwrap_or(DEFAULT_IP_REQUESTS_PER_MINUTE);
+        IpRateLimiter::new(var("RATE_LIMIT_BURST").unwrap_or(per_minute), per_minute)
+    }
+
+    /// Takes a token from the bucket of `ip`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), u64>` - `Err` with the seconds until a token is available if the bucket is empty.
+    pub fn check(&self, ip: IpAddr) -> Result<(), u64> {
+        let now = Instant::now();
+        let per_second = self.per_minute as f64 / 60.0;
+        let burst = self.burst as f64;
+        let refill = |bucket: &TokenBucket| (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second).min(burst);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_TRACKED_CALLERS {
+            buckets.retain(|_, bucket| refill(bucket) < burst);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(TokenBucket { tokens: burst, updated: now });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(((1.0 - bucket.tokens) / per_second).ceil().max(1.0) as u64);
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// Middleware answering `429 Too Many Requests` with `Retry-After` once the client's
+/// address has used up its [`IpRateLimiter`] bucket.
+///
+/// Requests whose peer address is unknown are passed through.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{web, App};
+/// use safe_user::handlers::login;
+/// use safe_user::rate_limit::{IpRateLimiter, LimitByIp};
+/// use std::sync::Arc;
+///
+/// let limiter = Arc::new(IpRateLimiter::from_env("LOGIN"));
+/// let app = App::new().service(
+///     web::resource("/login")
+///         .wrap(LimitByIp::new(limiter))
+///         .route(web::post().to(login))
+/// );
+/// ```
+pub struct LimitByIp {
+    limiter: Arc<IpRateLimiter>,
+}
+
+impl LimitByIp {
+    /// Creates a guard taking tokens from `limiter`, which may be shared by several resources.
+    pub fn new(limiter: Arc<IpRateLimiter>) -> Self {
+        LimitByIp { limiter }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for LimitByIp
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = LimitByIpMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(LimitByIpMiddleware {
+            service: Rc::new(service),
+            limiter: Arc::clone(&self.limiter),
+        }))
+    }
+}
+
+/// The service produced by [`LimitByIp`].
+pub struct LimitByIpMiddleware<S> {
+    service: Rc<S>,
+    limiter: Arc<IpRateLimiter>,
+}
+
+impl<S, B> Service<ServiceRequest> for LimitByIpMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let limited = req.peer_addr().and_then(|addr| self.limiter.check(addr.ip()).err());
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            if let Some(retry_after) = limited {
+                let response = AppError::RateLimited(retry_after).error_response();
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            service.call(req).await.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+/// Lists the roles that have a limit configured.
+pub async fn list_rate_limits(pool: &Pool<Mssql>) -> Result<Vec<RoleRateLimit>, sqlx::Error> {
+    sqlx::query_as!(
+        RoleRateLimit,
+        r#"
+        SELECT
+            r.id                  AS "role_id!",
+            r.Name                AS "role!",
+            l.RequestsPerMinute   AS "requests_per_minute?"
+        FROM [rate_limits] l
+        JOIN [roles] r ON r.id = l.RoleId
+        ORDER BY r.Name
+        "#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Sets the limit of a role, replacing any previous one; `None` makes the role unlimited.
+///
+/// # Returns
+///
+/// * `Result<bool, sqlx::Error>` - `Ok(false)` if the role does not exist.
+pub async fn set_rate_limit(pool: &Pool<Mssql>, role_id: i32, requests_per_minute: Option<i32>) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("DELETE FROM [rate_limits] WHERE RoleId = @p1", role_id)
+        .execute(&mut tx)
+        .await?;
+    let result = sqlx::query!(
+        "INSERT INTO [rate_limits] (RoleId, RequestsPerMinute) SELECT id, @p2 FROM [roles] WHERE id = @p1",
+        role_id,
+        requests_per_minute
+    )
+    .execute(&mut tx)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Removes the limit of a role, whose users fall back to the default.
+///
+/// # Returns
+///
+/// * `Result<bool, sqlx::Error>` - `Ok(false)` if the role had no limit.
+pub async fn clear_rate_limit(pool: &Pool<Mssql>, role_id: i32) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM [rate_limits] WHERE RoleId = @p1", role_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::Service;
+    use actix_web::{http::StatusCode, middleware::from_fn, App, HttpResponse};
+
+    fn roles(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_limit_follows_most_generous_role() {
+        let limiter = RateLimiter::new(60);
+        limiter.set_limits([
+            ("admin".to_string(), None),
+            ("free".to_string(), Some(60)),
+            ("pro".to_string(), Some(600)),
+        ]);
+
+        assert_eq!(limiter.limit_for(&roles(&["free", "pro"])), Some(600));
+        assert_eq!(limiter.limit_for(&roles(&["pro", "admin"])), None);
+        assert_eq!(limiter.limit_for(&roles(&["auditor"])), Some(60));
+    }
+
+    #[test]
+    fn test_check_counts_per_subject() {
+        let limiter = RateLimiter::new(2);
+
+        assert!(limiter.check("alice", &[]).is_ok());
+        assert!(limiter.check("alice", &[]).is_ok());
+        let retry_after = limiter.check("alice", &[]).unwrap_err();
+        assert!((1..=60).contains(&retry_after));
+
+        assert!(limiter.check("bob", &[]).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_middleware_answers_429_with_retry_after() {
+        let limiter = RateLimiter::new(1);
+        limiter.set_limits([("admin".to_string(), None)]);
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(limiter))
+                .wrap(from_fn(enforce_rate_limit))
+                .wrap_fn(|req, srv| {
+                    let roles = req.headers().get("x-roles").map(|roles| vec![roles.to_str().unwrap().to_string()]).unwrap_or_default();
+                    req.extensions_mut().insert(Claims::new("alice", 0, &roles));
+                    srv.call(req)
+                })
+                .route("/", web::get().to(HttpResponse::Ok))
+        ).await;
+
+        let resp = actix_web::test::call_service(&app, actix_web::test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = actix_web::test::call_service(&app, actix_web::test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key("retry-after"));
+
+        let req = actix_web::test::TestRequest::get().uri("/").insert_header(("x-roles", "admin")).to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_token_bucket_refills_per_ip() {
+        let limiter = IpRateLimiter::new(1, 6_000);
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        assert!(limiter.check(ip).is_ok());
+        assert_eq!(limiter.check(ip), Err(1));
+        assert!(limiter.check("203.0.113.8".parse().unwrap()).is_ok());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter.check(ip).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_ip_limit_answers_429_with_retry_after() {
+        let limiter = Arc::new(IpRateLimiter::new(1, 1));
+        let app = actix_web::test::init_service(
+            App::new().service(
+                web::resource("/login")
+                    .wrap(LimitByIp::new(limiter))
+                    .route(web::post().to(HttpResponse::Ok))
+            )
+        ).await;
+        let request = |addr: &str| actix_web::test::TestRequest::post().uri("/login").peer_addr(addr.parse().unwrap()).to_request();
+
+        let resp = actix_web::test::call_service(&app, request("203.0.113.7:5000")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = actix_web::test::call_service(&app, request("203.0.113.7:5001")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "60");
+
+        let resp = actix_web::test::call_service(&app, request("198.51.100.1:5000")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
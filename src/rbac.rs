@@ -0,0 +1,640 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpMessage, ResponseError};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, Mssql, Pool, Transaction};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use crate::audit;
+use crate::auth::Claims;
+use crate::error::AppError;
+use crate::extractors::UserId;
+
+/// Permission required to manage roles and role assignments through the admin API.
+pub const MANAGE_ROLES: &str = "roles:manage";
+
+/// Permission required to create users through the admin API.
+pub const MANAGE_USERS: &str = "users:manage";
+
+/// Permission required to revoke the sessions of other users.
+pub const MANAGE_SESSIONS: &str = "sessions:manage";
+
+/// Permission required to export every user at once.
+pub const EXPORT_USERS: &str = "users:export";
+
+/// Permission required to read the activity timeline of other users.
+pub const VIEW_TIMELINE: &str = "users:timeline";
+
+/// Permission required to search the whole audit log.
+pub const READ_AUDIT: &str = "audit:read";
+
+/// Permission required to verify the integrity of the audit log.
+pub const VERIFY_AUDIT: &str = "audit:verify";
+
+/// Permission required to mint signed URLs for the resources under `/shared`.
+pub const SIGN_URLS: &str = "urls:sign";
+
+/// Largest number of `user_ids` accepted by one bulk role assignment.
+pub const MAX_BULK_USER_IDS: usize = 1_000;
+
+/// A role stored in the `[roles]` table.
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Role {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// Payload used to create or rename a role.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoleInput {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// The full set of permissions granted by a role.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PermissionSet {
+    pub permissions: Vec<String>,
+}
+
+/// The full set of roles assigned to a user.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoleAssignment {
+    pub role_ids: Vec<i32>,
+}
+
+/// The users a role is added to in bulk: either a list of ids or everyone matching a filter.
+#[derive(Debug, Deserialize)]
+pub struct BulkRoleAssignment {
+    pub user_ids: Option<Vec<UserId>>,
+    pub filter: Option<UserFilter>,
+}
+
+/// Selects users by their attributes, e.g. a whole department.
+#[derive(Debug, Deserialize)]
+pub struct UserFilter {
+    pub org_unit: String,
+}
+
+impl BulkRoleAssignment {
+    /// Checks that exactly one of `user_ids` and `filter` is given, and that it selects something.
+    pub fn check(&self) -> Result<(), String> {
+        match (&self.user_ids, &self.filter) {
+            (Some(_), Some(_)) | (None, None) => Err("Give either `user_ids` or `filter`.".to_string()),
+            (Some(user_ids), None) if user_ids.is_empty() => Err("`user_ids` must not be empty.".to_string()),
+            (Some(user_ids), None) if user_ids.len() > MAX_BULK_USER_IDS => {
+                Err(format!("`user_ids` may hold at most {} ids.", MAX_BULK_USER_IDS))
+            }
+            (None, Some(filter)) if filter.org_unit.trim().is_empty() => Err("`filter.org_unit` must not be empty.".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns `true` if any of `granted` satisfies `required`.
+///
+/// A granted permission matches exactly, or acts as a wildcard when it is `*`
+/// or ends in `:*` (e.g. `users:*` grants `users:read`).
+pub fn grants(granted: &[String], required: &str) -> bool {
+    granted.iter().any(|permission| {
+        permission == "*"
+            || permission == required
+            || permission
+                .strip_suffix(":*")
+                .map(|prefix| required.starts_with(prefix) && required[prefix.len()..].starts_with(':'))
+                .unwrap_or(false)
+    })
+}
+
+/// Lists every role.
+pub async fn list_roles(pool: &Pool<Mssql>) -> Result<Vec<Role>, sqlx::Error> {
+    sqlx::query_as!(
+        Role,
+        r#"
+        SELECT
+            id          AS "id!",
+            Name        AS "name!",
+            Description AS "description?"
+        FROM [roles]
+        ORDER BY Name
+        "#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Creates a role and returns it with its generated id.
+pub async fn create_role(pool: &Pool<Mssql>, input: &RoleInput) -> Result<Role, sqlx::Error> {
+    sqlx::query_as!(
+        Role,
+        r#"
+        INSERT INTO [roles] (Name, Description)
+        OUTPUT
+            INSERTED.id          AS "id!",
+            INSERTED.Name        AS "name!",
+            INSERTED.Description AS "description?"
+        VALUES (@p1, @p2)
+        "#,
+        input.name,
+        input.description
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Updates a role, returning `Ok(false)` if it does not exist.
+pub async fn update_role(pool: &Pool<Mssql>, id: i32, input: &RoleInput) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE [roles] SET Name = @p1, Description = @p2 WHERE id = @p3",
+        input.name,
+        input.description,
+        id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// What deleting a role removed, or would remove in a dry run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RoleDeletion {
+    pub permissions: u64,
+    /// Users who held the role.
+    pub assignments: u64,
+    /// Whether the role had its own rate limit.
+    pub rate_limit: bool,
+}
+
+/// Deletes a role along with its permissions and assignments, returning `Ok(None)`
+/// if it does not exist. With `dry_run` the deletion is rolled back, so only what it
+/// would remove is reported.
+pub async fn delete_role(pool: &Pool<Mssql>, id: i32, dry_run: bool) -> Result<Option<RoleDeletion>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let permissions = sqlx::query!("DELETE FROM [role_permissions] WHERE RoleId = @p1", id)
+        .execute(&mut tx)
+        .await?;
+    let assignments = sqlx::query!("DELETE FROM [user_roles] WHERE RoleId = @p1", id)
+        .execute(&mut tx)
+        .await?;
+    let rate_limit = sqlx::query!("DELETE FROM [rate_limits] WHERE RoleId = @p1", id)
+        .execute(&mut tx)
+        .await?;
+    let result = sqlx::query!("DELETE FROM [roles] WHERE id = @p1", id)
+        .execute(&mut tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    let deletion = RoleDeletion {
+        permissions: permissions.rows_affected(),
+        assignments: assignments.rows_affected(),
+        rate_limit: rate_limit.rows_affected() > 0,
+    };
+    if dry_run {
+        tx.rollback().await?;
+    } else {
+        tx.commit().await?;
+    }
+    Ok(Some(deletion))
+}
+
+/// Returns the permissions granted by a role.
+pub async fn role_permissions(pool: &Pool<Mssql>, role_id: i32) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT Permission AS "permission!: String"
+        FROM [role_permissions]
+        WHERE RoleId = @p1
+        ORDER BY Permission
+        "#,
+        role_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Replaces the permissions granted by a role.
+pub async fn set_role_permissions(pool: &Pool<Mssql>, role_id: i32, permissions: &[String]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("DELETE FROM [role_permissions] WHERE RoleId = @p1", role_id)
+        .execute(&mut tx)
+        .await?;
+    for permission in permissions {
+        sqlx::query!(
+            "INSERT INTO [role_permissions] (RoleId, Permission) VALUES (@p1, @p2)",
+            role_id,
+            permission
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
+
+/// Returns the roles assigned to a user.
+pub async fn user_roles(pool: &Pool<Mssql>, user_id: &str) -> Result<Vec<Role>, sqlx::Error> {
+    sqlx::query_as!(
+        Role,
+        r#"
+        SELECT
+            r.id          AS "id!",
+            r.Name        AS "name!",
+            r.Description AS "description?"
+        FROM [roles] r
+        INNER JOIN [user_roles] ur ON ur.RoleId = r.id
+        WHERE ur.UserId = @p1
+        ORDER BY r.Name
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Replaces the roles assigned to a user as part of `tx`.
+pub async fn set_user_roles(tx: &mut Transaction<'_, Mssql>, user_id: &str, role_ids: &[i32]) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM [user_roles] WHERE UserId = @p1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    for role_id in role_ids {
+        sqlx::query!(
+            "INSERT INTO [user_roles] (UserId, RoleId) VALUES (@p1, @p2)",
+            user_id,
+            role_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Adds a role to many users in one transaction, recording a
+/// [`ROLE_ASSIGNED`](audit::ROLE_ASSIGNED) audit entry for each user that did not have it yet.
+///
+/// Unknown user ids and users who already hold the role are skipped. With `dry_run`
+/// the transaction is rolled back, so the users are the ones the role would be added to.
+///
+/// # Returns
+///
+/// * `Result<Option<Vec<String>>, sqlx::Error>` - The ids of the users the role was added to,
+///   or `None` if the role does not exist.
+pub async fn assign_role(pool: &Pool<Mssql>, role_id: i32, assignment: &BulkRoleAssignment, actor_id: Option<&str>, dry_run: bool) -> Result<Option<Vec<String>>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let role_exists = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!: i32" FROM [roles] WHERE id = @p1"#, role_id)
+        .fetch_one(&mut tx)
+        .await?;
+    if role_exists == 0 {
+        return Ok(None);
+    }
+
+    let mut assigned = Vec::new();
+    for user_id in assignment.user_ids.iter().flatten() {
+        let inserted = sqlx::query_scalar!(
+            r#"
+            INSERT INTO [user_roles] (UserId, RoleId)
+            OUTPUT CAST(INSERTED.UserId AS VARCHAR(36)) AS "user_id!: String"
+            SELECT u.id, @p2
+            FROM [users] u
+            WHERE u.id = @p1 AND u.DeletedAt IS NULL
+                AND NOT EXISTS (SELECT 1 FROM [user_roles] ur WHERE ur.UserId = u.id AND ur.RoleId = @p2)
+            "#,
+            user_id.to_string(),
+            role_id
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        assigned.extend(inserted);
+    }
+    if let Some(filter) = &assignment.filter {
+        let inserted = sqlx::query_scalar!(
+            r#"
+            INSERT INTO [user_roles] (UserId, RoleId)
+            OUTPUT CAST(INSERTED.UserId AS VARCHAR(36)) AS "user_id!: String"
+            SELECT u.id, @p2
+            FROM [users] u
+            WHERE u.OrgUnit = @p1 AND u.DeletedAt IS NULL
+                AND NOT EXISTS (SELECT 1 FROM [user_roles] ur WHERE ur.UserId = u.id AND ur.RoleId = @p2)
+            "#,
+            filter.org_unit.trim(),
+            role_id
+        )
+        .fetch_all(&mut tx)
+        .await?;
+        assigned.extend(inserted);
+    }
+
+    let details = json!({ "role_id": role_id });
+    for user_id in &assigned {
+        audit::record(&mut tx, user_id, audit::ROLE_ASSIGNED, actor_id, Some(&details)).await?;
+    }
+
+    if dry_run {
+        tx.rollback().await?;
+    } else {
+        tx.commit().await?;
+    }
+    Ok(Some(assigned))
+}
+
+/// Returns every permission granted to a user through their roles.
+pub async fn permissions_for_user(pool: &Pool<Mssql>, user_id: &str) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT DISTINCT rp.Permission AS "permission!: String"
+        FROM [role_permissions] rp
+        INNER JOIN [user_roles] ur ON ur.RoleId = rp.RoleId
+        WHERE ur.UserId = @p1
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Middleware that only lets requests through when the caller holds a permission.
+///
+/// Permissions are looked up in the database on every request, so changes made
+/// through the admin API apply immediately. It must run after
+/// [`jwt_validator`](crate::auth::jwt_validator), which stores the caller's
+/// [`Claims`] in the request extensions.
+///
+/// # Examples
+///
+/// ```no_run
+/// use actix_web::{web, App};
+/// use actix_web_httpauth::middleware::HttpAuthentication;
+/// use safe_user::auth::jwt_validator;
+/// use safe_user::handlers::get_all_users;
+/// use safe_user::rbac::RequirePermission;
+///
+/// let app = App::new().service(
+///     web::scope("/protected")
+///         .wrap(RequirePermission::new("users:read"))
+///         .wrap(HttpAuthentication::bearer(jwt_validator))
+///         .route("/users", web::get().to(get_all_users))
+/// );
+/// ```
+pub struct RequirePermission {
+    permission: &'static str,
+}
+
+impl RequirePermission {
+    /// Creates a guard requiring `permission`.
+    pub fn new(permission: &'static str) -> Self {
+        RequirePermission { permission }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePermission
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequirePermissionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequirePermissionMiddleware {
+            service: Rc::new(service),
+            permission: self.permission,
+        }))
+    }
+}
+
+/// The service produced by [`RequirePermission`].
+pub struct RequirePermissionMiddleware<S> {
+    service: Rc<S>,
+    permission: &'static str,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirePermissionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let permission = self.permission;
+
+        Box::pin(async move {
+            let subject = req.extensions().get::<Claims>().map(|claims| claims.sub.clone());
+            let pool = req.app_data::<web::Data<Pool<Mssql>>>().cloned();
+
+            let allowed = match (subject, pool) {
+                (Some(subject), Some(pool)) => match permissions_for_user(&pool, &subject).await {
+                    Ok(granted) => grants(&granted, permission),
+                    Err(e) => {
+                        eprintln!("Error loading permissions: {:?}", e);
+                        false
+                    }
+                },
+                _ => false,
+            };
+
+            if !allowed {
+                let response = AppError::Forbidden {
+                    code: "missing_permission",
+                    message: format!("Missing permission: {}", permission),
+                    details: Some(json!({ "permission": permission })),
+                }
+                .error_response();
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            service.call(req).await.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+/// Middleware rejecting requests whose token does not carry a role.
+///
+/// Unlike [`RequirePermission`], the check uses only the `roles` claim and needs no
+/// database lookup; role changes apply to tokens issued after the change.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{web, App};
+/// use actix_web_httpauth::middleware::HttpAuthentication;
+/// use safe_user::auth::jwt_validator;
+/// use safe_user::handlers::protected_route;
+/// use safe_user::rbac::RequireRole;
+///
+/// let app = App::new().service(
+///     web::scope("/protected")
+///         .wrap(HttpAuthentication::bearer(jwt_validator))
+///         .service(
+///             web::resource("/route")
+///                 .wrap(RequireRole::new("admin"))
+///                 .route(web::get().to(protected_route))
+///         )
+/// );
+/// ```
+pub struct RequireRole {
+    role: &'static str,
+}
+
+impl RequireRole {
+    /// Creates a guard requiring `role`.
+    pub fn new(role: &'static str) -> Self {
+        RequireRole { role }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireRoleMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireRoleMiddleware {
+            service: Rc::new(service),
+            role: self.role,
+        }))
+    }
+}
+
+/// The service produced by [`RequireRole`].
+pub struct RequireRoleMiddleware<S> {
+    service: Rc<S>,
+    role: &'static str,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireRoleMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let role = self.role;
+
+        Box::pin(async move {
+            let allowed = req
+                .extensions()
+                .get::<Claims>()
+                .map(|claims| claims.roles.iter().any(|granted| granted == role))
+                .unwrap_or(false);
+
+            if !allowed {
+                let response = AppError::Forbidden {
+                    code: "missing_role",
+                    message: format!("Missing role: {}", role),
+                    details: Some(json!({ "role": role })),
+                }
+                .error_response();
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            service.call(req).await.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, App, HttpResponse, Responder};
+
+    async fn ok() -> impl Responder {
+        HttpResponse::Ok().json("ok")
+    }
+
+    #[test]
+    fn test_grants_exact_and_wildcards() {
+        let granted = vec!["users:read".to_string(), "roles:*".to_string()];
+
+        assert!(grants(&granted, "users:read"));
+        assert!(grants(&granted, "roles:manage"));
+        assert!(!grants(&granted, "users:write"));
+        assert!(!grants(&granted, "rolesx:manage"));
+        assert!(grants(&["*".to_string()], "anything:at_all"));
+    }
+
+    #[test]
+    fn test_bulk_assignment_needs_exactly_one_target() {
+        let parse = |json: &str| serde_json::from_str::<BulkRoleAssignment>(json).unwrap().check();
+
+        assert!(parse(r#"{"user_ids": ["813b6b04-dfbb-4eed-b820-2372216a2367"]}"#).is_ok());
+        assert!(parse(r#"{"filter": {"org_unit": "sales"}}"#).is_ok());
+        assert!(parse(r#"{}"#).is_err());
+        assert!(parse(r#"{"user_ids": []}"#).is_err());
+        assert!(parse(r#"{"user_ids": [], "filter": {"org_unit": "sales"}}"#).is_err());
+        assert!(parse(r#"{"filter": {"org_unit": " "}}"#).is_err());
+        assert!(serde_json::from_str::<BulkRoleAssignment>(r#"{"user_ids": ["42"]}"#).is_err());
+    }
+
+    /// Requests without authenticated claims must be rejected before any DB lookup.
+    #[actix_web::test]
+    async fn test_require_permission_without_claims() {
+        let app = actix_web::test::init_service(
+            App::new().service(
+                web::scope("/admin")
+                    .wrap(RequirePermission::new(MANAGE_ROLES))
+                    .route("/roles", web::get().to(ok))
+            )
+        ).await;
+
+        let req = actix_web::test::TestRequest::get().uri("/admin/roles").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_require_role_checks_roles_claim() {
+        let app = actix_web::test::init_service(
+            App::new().service(
+                web::scope("/protected")
+                    .wrap(RequireRole::new("admin"))
+                    .route("/route", web::get().to(ok))
+            )
+        ).await;
+
+        for (roles, expected) in [(vec!["admin"], StatusCode::OK), (vec!["viewer"], StatusCode::FORBIDDEN)] {
+            let req = actix_web::test::TestRequest::get().uri("/protected/route").to_request();
+            req.extensions_mut().insert(Claims {
+                sub: "tester".to_string(),
+                exp: 0,
+                iss: None,
+                ver: 0,
+                roles: roles.into_iter().map(String::from).collect(),
+                jti: None,
+                sid: None,
+                plan: None,
+                ent: Vec::new(),
+            });
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), expected);
+        }
+    }
+}
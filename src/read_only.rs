@@ -0,0 +1,203 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse, Responder, ResponseError};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use crate::error::AppError;
+use crate::routes::unversioned;
+
+/// Paths that keep accepting mutating requests while read-only mode is active.
+///
+/// Only the toggle itself, which must stay reachable so operators can turn read-only
+/// mode off again. Token issuance is not exempt: logging in and refreshing record
+/// sessions, refresh tokens and failed attempts in the database.
+pub const READ_ONLY_EXEMPT_PATHS: &[&str] = &["/protected/admin/read_only"];
+
+/// Global switch that rejects every mutating request during primary-DB failovers.
+///
+/// The flag is shared between all workers, so toggling it through the admin
+/// endpoint takes effect immediately for the whole server. It is not stored in the
+/// database, which may be the very thing failing over: each replica keeps its own
+/// flag, so the endpoint must be called on every instance.
+#[derive(Clone, Debug, Default)]
+pub struct ReadOnlyMode {
+    enabled: Arc<AtomicBool>,
+}
+
+/// Body accepted and returned by the read-only admin endpoint.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadOnlyStatus {
+    pub read_only: bool,
+}
+
+impl ReadOnlyMode {
+    /// Creates a new `ReadOnlyMode` with the given initial state.
+    pub fn new(enabled: bool) -> Self {
+        ReadOnlyMode {
+            enabled: Arc::new(AtomicBool::new(enabled)),
+        }
+    }
+
+    /// Returns `true` if mutating requests are currently rejected.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Enables or disables read-only mode.
+    pub fn set(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+    }
+}
+
+/// Returns `true` if a `GET` to `path` writes to the database: opening an email
+/// verification link marks the email verified, and the OAuth callback links accounts
+/// and starts sessions.
+fn is_get_writer(path: &str) -> bool {
+    path == "/verify_email" || path.strip_prefix("/oauth/").is_some_and(|rest| rest.ends_with("/callback"))
+}
+
+/// Returns `true` if a request with `method` to the unversioned `path` can modify
+/// server state.
+fn is_mutating(method: &Method, path: &str) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) || (*method == Method::GET && is_get_writer(path))
+}
+
+/// Middleware function that answers mutating requests with `503 Service Unavailable`
+/// while read-only mode is active.
+///
+/// Reads are passed through untouched, except for the few `GET` endpoints that
+/// write, as are the paths listed in
+/// [`READ_ONLY_EXEMPT_PATHS`]. The middleware is a no-op when no `ReadOnlyMode` has
+/// been registered as app data.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{middleware::from_fn, web, App};
+/// use safe_user::read_only::{reject_writes_when_read_only, ReadOnlyMode};
+///
+/// let app = App::new()
+///     .app_data(web::Data::new(ReadOnlyMode::new(false)))
+///     .wrap(from_fn(reject_writes_when_read_only));
+/// ```
+pub async fn reject_writes_when_read_only(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let read_only = req
+        .app_data::<web::Data<ReadOnlyMode>>()
+        .map(|mode| mode.is_enabled())
+        .unwrap_or(false);
+
+    let path = unversioned(req.path());
+    if read_only && is_mutating(req.method(), path) && !READ_ONLY_EXEMPT_PATHS.contains(&path) {
+        let response = AppError::ReadOnly.error_response();
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+/// Returns the current state of the read-only switch.
+pub async fn get_read_only(mode: web::Data<ReadOnlyMode>) -> impl Responder {
+    HttpResponse::Ok().json(ReadOnlyStatus { read_only: mode.is_enabled() })
+}
+
+/// Turns read-only mode on or off.
+///
+/// # Arguments
+///
+/// * `mode` - The shared read-only switch.
+/// * `status` - A JSON payload with the desired `read_only` state.
+///
+/// # Returns
+///
+/// * `HttpResponse` - A JSON response echoing the new state.
+pub async fn set_read_only(mode: web::Data<ReadOnlyMode>, status: web::Json<ReadOnlyStatus>) -> impl Responder {
+    mode.set(status.read_only);
+    eprintln!("Read-only mode set to {}", status.read_only);
+
+    HttpResponse::Ok().json(ReadOnlyStatus { read_only: mode.is_enabled() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::{http::StatusCode, test, App};
+
+    async fn ok() -> impl Responder {
+        HttpResponse::Ok().json("ok")
+    }
+
+    /// Checks that writes are rejected and reads still served while read-only mode is on.
+    #[actix_web::test]
+    async fn test_read_only_rejects_writes() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(ReadOnlyMode::new(true)))
+                .wrap(from_fn(reject_writes_when_read_only))
+                .route("/create_user", web::post().to(ok))
+                .route("/login", web::post().to(ok))
+                .route("/users", web::get().to(ok))
+        ).await;
+
+        for uri in ["/create_user", "/login", "/api/v1/login"] {
+            let req = test::TestRequest::post().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE, "{} should be rejected", uri);
+        }
+
+        let req = test::TestRequest::get().uri("/users").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    /// Checks that the `GET` endpoints that write are rejected too.
+    #[actix_web::test]
+    async fn test_read_only_rejects_writing_gets() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(ReadOnlyMode::new(true)))
+                .wrap(from_fn(reject_writes_when_read_only))
+                .route("/verify_email", web::get().to(ok))
+                .route("/oauth/{provider}/callback", web::get().to(ok))
+                .route("/oauth/{provider}/start", web::get().to(ok))
+        ).await;
+
+        for uri in ["/verify_email?token=abc", "/oauth/google/callback?code=abc", "/api/v1/oauth/github/callback"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE, "{} should be rejected", uri);
+        }
+
+        let req = test::TestRequest::get().uri("/oauth/google/start").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    /// Checks that the toggle endpoint stays writable and disables read-only mode.
+    #[actix_web::test]
+    async fn test_read_only_toggle() {
+        let mode = ReadOnlyMode::new(true);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(mode.clone()))
+                .wrap(from_fn(reject_writes_when_read_only))
+                .route("/protected/admin/read_only", web::put().to(set_read_only))
+                .route("/create_user", web::post().to(ok))
+        ).await;
+
+        let req = test::TestRequest::put()
+            .uri("/protected/admin/read_only")
+            .set_json(ReadOnlyStatus { read_only: false })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!mode.is_enabled(), "Read-only mode should have been turned off");
+
+        let req = test::TestRequest::post().uri("/create_user").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,334 @@
+use actix_web::{web, HttpResponse};
+use chrono::Duration as ChronoDuration;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Mssql, Pool, Transaction};
+use std::env;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+use crate::error::AppError;
+use crate::timestamp::Timestamp;
+
+/// Writes made by another region this long before a local one are taken as
+/// concurrent with it, unless `REPLICATION_CONFLICT_WINDOW_SECONDS` says otherwise.
+const DEFAULT_CONFLICT_WINDOW: Duration = Duration::from_secs(30);
+
+/// Most conflicts `GET /admin/replication/conflicts` returns.
+const MAX_CONFLICTS: i32 = 1000;
+
+/// Who last wrote a user: the region, from the `OriginRegion` column, and when.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteStamp {
+    pub region: Option<String>,
+    pub updated_at: Option<Timestamp>,
+}
+
+/// A local write to a user that another region wrote to within the conflict window,
+/// probably before the local writer could see that change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteConflict {
+    pub user_id: String,
+    /// The write already stored, replicated from another region.
+    pub stored: WriteStamp,
+    /// The write being made in this region.
+    pub incoming: WriteStamp,
+}
+
+/// What to do with the incoming write of a [`WriteConflict`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    /// Apply the incoming write over the stored one.
+    AppliedIncoming,
+    /// Discard the incoming write and keep the stored one.
+    KeptStored,
+}
+
+impl Resolution {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::AppliedIncoming => "applied_incoming",
+            Resolution::KeptStored => "kept_stored",
+        }
+    }
+}
+
+/// Decides which of two concurrent writes to a user survives. Every region must use
+/// the same resolver, or the regions may settle on different versions.
+pub trait ConflictResolver: Send + Sync + Debug {
+    fn resolve(&self, conflict: &WriteConflict) -> Resolution;
+}
+
+/// The default [`ConflictResolver`]: the write with the later `updated_at` wins, and a
+/// tie goes to the region whose name sorts last, so every region picks the same one.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::replication::{ConflictResolver, LastWriterWins, Resolution, WriteConflict, WriteStamp};
+///
+/// let stamp = |region: &str, at: &str| WriteStamp { region: Some(region.to_string()), updated_at: Some(at.parse().unwrap()) };
+/// let conflict = WriteConflict {
+///     user_id: "42".to_string(),
+///     stored: stamp("eu-west", "2024-05-01T10:00:05Z"),
+///     incoming: stamp("us-east", "2024-05-01T10:00:03Z"),
+/// };
+/// assert_eq!(LastWriterWins.resolve(&conflict), Resolution::KeptStored);
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LastWriterWins;
+
+impl ConflictResolver for LastWriterWins {
+    fn resolve(&self, conflict: &WriteConflict) -> Resolution {
+        fn key(stamp: &WriteStamp) -> (Option<Timestamp>, Option<&str>) {
+            (stamp.updated_at, stamp.region.as_deref())
+        }
+        if key(&conflict.incoming) >= key(&conflict.stored) {
+            Resolution::AppliedIncoming
+        } else {
+            Resolution::KeptStored
+        }
+    }
+}
+
+/// Settings of an active-active deployment, in which every region accepts writes to
+/// users and replicates them to the others.
+///
+/// Users remember the region of their last update in `OriginRegion`. An update made
+/// within [`window`](Replication::window) of another region's is a conflict: it is
+/// settled by the [`ConflictResolver`] and recorded in `[replication_conflicts]`.
+#[derive(Debug, Clone)]
+pub struct Replication {
+    /// The name of this region, from `REGION`.
+    pub region: String,
+    pub window: Duration,
+    pub resolver: Arc<dyn ConflictResolver>,
+}
+
+impl Replication {
+    /// Reads the settings from the environment.
+    pub fn from_env() -> Option<Self> {
+        Replication::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Reads the settings from the variables returned by `var`: the `REGION` of this
+    /// deployment and `REPLICATION_CONFLICT_WINDOW_SECONDS`. Returns `None` for
+    /// single-region deployments, which have no `REGION`. Conflicts are resolved with
+    /// [`LastWriterWins`].
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let region = var("REGION").map(|region| region.trim().to_string()).filter(|region| !region.is_empty())?;
+        let window = var("REPLICATION_CONFLICT_WINDOW_SECONDS")
+            .and_then(|secs| secs.trim().parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_CONFLICT_WINDOW);
+        Some(Replication { region, window, resolver: Arc::new(LastWriterWins) })
+    }
+
+    /// Settles conflicts with `resolver` instead.
+    pub fn with_resolver(mut self, resolver: Arc<dyn ConflictResolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Returns the conflict of a write made in this region at `now` to `user_id`,
+    /// last written as `stored`, if another region wrote it within the window.
+    pub fn detect(&self, user_id: &str, stored: WriteStamp, now: Timestamp) -> Option<WriteConflict> {
+        let other_region = stored.region.as_deref().is_some_and(|region| region != self.region);
+        let window = ChronoDuration::from_std(self.window).unwrap_or_else(|_| ChronoDuration::zero());
+        let recent = stored.updated_at.is_some_and(|updated_at| *updated_at > *now - window);
+        (other_region && recent).then(|| WriteConflict {
+            user_id: user_id.to_string(),
+            stored,
+            incoming: WriteStamp { region: Some(self.region.clone()), updated_at: Some(now) },
+        })
+    }
+
+    /// Checks an `operation` on the locked user `user_id` for a conflict before it is
+    /// applied in `tx`, recording the conflict and its resolution if there is one.
+    /// Returns the conflict if the write lost it and must not be applied; without a
+    /// conflict, it is applied.
+    pub async fn admit(&self, tx: &mut Transaction<'_, Mssql>, user_id: &str, operation: &str) -> Result<Option<WriteConflict>, sqlx::Error> {
+        let stored = write_stamp(tx, user_id).await?;
+        let conflict = match self.detect(user_id, stored, Timestamp::now()) {
+            Some(conflict) => conflict,
+            None => return Ok(None),
+        };
+        let resolution = self.resolver.resolve(&conflict);
+        record_conflict(tx, &conflict, operation, resolution).await?;
+        eprintln!(
+            "Replication conflict on user {}: {} write vs {} write, {}",
+            user_id,
+            conflict.stored.region.as_deref().unwrap_or("-"),
+            self.region,
+            resolution.as_str()
+        );
+        Ok((resolution == Resolution::KeptStored).then_some(conflict))
+    }
+
+    /// Marks the user `user_id` as last written by this region.
+    pub async fn stamp(&self, tx: &mut Transaction<'_, Mssql>, user_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!("UPDATE [users] SET OriginRegion = @p2 WHERE id = @p1", user_id, self.region)
+            .execute(&mut *tx)
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct StampRow {
+    region: Option<String>,
+    updated_at: Option<Timestamp>,
+}
+
+async fn write_stamp(tx: &mut Transaction<'_, Mssql>, user_id: &str) -> Result<WriteStamp, sqlx::Error> {
+    let row = sqlx::query_as!(
+        StampRow,
+        r#"
+        SELECT
+            OriginRegion                         AS "region?",
+            CONVERT(VARCHAR(33), UpdatedAt, 126) AS "updated_at?: Timestamp"
+        FROM [users]
+        WHERE id = @p1
+        "#,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    Ok(row.map_or(WriteStamp { region: None, updated_at: None }, |row| WriteStamp { region: row.region, updated_at: row.updated_at }))
+}
+
+async fn record_conflict(tx: &mut Transaction<'_, Mssql>, conflict: &WriteConflict, operation: &str, resolution: Resolution) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO [replication_conflicts] (UserId, Operation, StoredRegion, StoredUpdatedAt, IncomingRegion, IncomingUpdatedAt, Resolution)
+        VALUES (@p1, @p2, @p3, @p4, @p5, @p6, @p7)
+        "#,
+        conflict.user_id,
+        operation,
+        conflict.stored.region,
+        conflict.stored.updated_at,
+        conflict.incoming.region,
+        conflict.incoming.updated_at,
+        resolution.as_str()
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// A conflict between writes of two regions, as listed by `GET /admin/replication/conflicts`.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct ReplicationConflict {
+    pub id: i64,
+    pub user_id: String,
+    /// `update` or `patch`.
+    pub operation: String,
+    /// The region whose write was already stored.
+    pub stored_region: Option<String>,
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub stored_updated_at: Option<Timestamp>,
+    /// The region that made the conflicting write.
+    pub incoming_region: String,
+    #[schema(value_type = String, format = DateTime)]
+    pub incoming_updated_at: Timestamp,
+    /// `applied_incoming` or `kept_stored`.
+    pub resolution: String,
+    #[schema(value_type = String, format = DateTime)]
+    pub detected_at: Timestamp,
+}
+
+/// Query parameters of `GET /admin/replication/conflicts`.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ConflictQuery {
+    /// Only conflicts on this user.
+    pub user_id: Option<String>,
+    /// How many conflicts to return, newest first; 100 by default, at most 1000.
+    pub limit: Option<i32>,
+}
+
+/// Returns the latest recorded conflicts, newest first.
+pub async fn conflicts(pool: &Pool<Mssql>, user_id: Option<&str>, limit: i32) -> Result<Vec<ReplicationConflict>, sqlx::Error> {
+    sqlx::query_as!(
+        ReplicationConflict,
+        r#"
+        SELECT TOP (@p2)
+            id                                           AS "id!: i64",
+            UserId                                       AS "user_id!",
+            Operation                                    AS "operation!",
+            StoredRegion                                 AS "stored_region?",
+            CONVERT(VARCHAR(33), StoredUpdatedAt, 126)   AS "stored_updated_at?: Timestamp",
+            IncomingRegion                               AS "incoming_region!",
+            CONVERT(VARCHAR(33), IncomingUpdatedAt, 126) AS "incoming_updated_at!: Timestamp",
+            Resolution                                   AS "resolution!",
+            CONVERT(VARCHAR(33), DetectedAt, 126)        AS "detected_at!: Timestamp"
+        FROM [replication_conflicts]
+        WHERE @p1 IS NULL OR UserId = @p1
+        ORDER BY DetectedAt DESC, id DESC
+        "#,
+        user_id,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Lists the conflicts between concurrent writes of different regions, and how each
+/// was settled, so operators can review writes that were overwritten or discarded.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - The [`ReplicationConflict`]s, newest first.
+#[utoipa::path(
+    get,
+    path = "/admin/replication/conflicts",
+    tag = "users",
+    params(ConflictQuery),
+    responses(
+        (status = 200, description = "The latest conflicts, newest first", body = [ReplicationConflict]),
+        (status = 403, description = "The caller is not an admin", body = ErrorBody),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn list_conflicts(pool: web::Data<Pool<Mssql>>, query: web::Query<ConflictQuery>) -> Result<HttpResponse, AppError> {
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_CONFLICTS);
+    let conflicts = conflicts(pool.get_ref(), query.user_id.as_deref(), limit).await?;
+    Ok(HttpResponse::Ok().json(conflicts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replication() -> Replication {
+        Replication::from_vars(|name| (name == "REGION").then(|| "us-east".to_string())).unwrap()
+    }
+
+    fn stamp(region: &str, at: &str) -> WriteStamp {
+        WriteStamp { region: Some(region.to_string()), updated_at: Some(at.parse().unwrap()) }
+    }
+
+    #[test]
+    fn test_only_recent_writes_of_other_regions_conflict() {
+        let replication = replication();
+        let now: Timestamp = "2024-05-01T10:00:00Z".parse().unwrap();
+
+        let conflict = replication.detect("42", stamp("eu-west", "2024-05-01T09:59:50Z"), now).unwrap();
+        assert_eq!(conflict.incoming.region.as_deref(), Some("us-east"));
+        assert_eq!(replication.resolver.resolve(&conflict), Resolution::AppliedIncoming);
+
+        assert!(replication.detect("42", stamp("eu-west", "2024-05-01T09:59:00Z"), now).is_none());
+        assert!(replication.detect("42", stamp("us-east", "2024-05-01T09:59:50Z"), now).is_none());
+        assert!(replication.detect("42", WriteStamp { region: None, updated_at: Some(now) }, now).is_none());
+        assert!(Replication::from_vars(|_| None).is_none());
+    }
+
+    #[test]
+    fn test_ties_go_to_the_same_region_everywhere() {
+        let at = "2024-05-01T10:00:00Z";
+        let conflict = |stored: &str, incoming: &str| WriteConflict { user_id: "42".to_string(), stored: stamp(stored, at), incoming: stamp(incoming, at) };
+        assert_eq!(LastWriterWins.resolve(&conflict("eu-west", "us-east")), Resolution::AppliedIncoming);
+        assert_eq!(LastWriterWins.resolve(&conflict("us-east", "eu-west")), Resolution::KeptStored);
+    }
+}
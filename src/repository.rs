@@ -0,0 +1,785 @@
+use async_trait::async_trait;
+use serde_json::json;
+use sqlx::{Mssql, Pool, Transaction};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use crate::approvals::{ACTIVE, EXPIRED};
+use crate::audit;
+use crate::birthdate::Birthdate;
+use crate::db::{Idempotency, RetryPolicy};
+use crate::email_verification;
+use crate::ids::{IdGenerator, UuidV4};
+use crate::models::{CreateUserRequest, Email, Phone, UpdateUserRequest, User, UserId, UserResponse};
+use crate::oauth;
+use crate::outbox;
+use crate::pagination::{UserPage, UserQuery};
+use crate::passwords::Credentials;
+use crate::quotas;
+use crate::replication::Replication;
+use crate::timestamp::Timestamp;
+
+/// Storage for users, injected into handlers as `web::Data<Arc<dyn UserRepository>>`.
+///
+/// [`MssqlUserRepository`] is the production implementation; tests can supply an
+/// in-memory one so handlers run without a database.
+///
+/// # Examples
+///
+/// ```no_run
+/// use actix_web::{web, App};
+/// use safe_user::config::AppConfig;
+/// use safe_user::db::DbPool;
+/// use safe_user::handlers::get_all_users;
+/// use safe_user::repository::{MssqlUserRepository, UserRepository};
+/// use std::sync::Arc;
+///
+/// # async fn run() {
+/// let config = AppConfig::load().unwrap();
+/// let pool = DbPool::connect(&config.database_url).await.unwrap().pool;
+/// let users: Arc<dyn UserRepository> = Arc::new(MssqlUserRepository::new(pool));
+/// let app = App::new()
+///     .app_data(web::Data::new(users))
+///     .route("/users", web::get().to(get_all_users));
+/// # }
+/// ```
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// Stores a new user on behalf of `actor`, with an optional password hash, an
+    /// account status from [`approvals`](crate::approvals) and the tenant it belongs to
+    /// in multi-tenant mode, and returns its id.
+    async fn create(&self, user: &CreateUserRequest, password_hash: Option<&str>, status: &str, tenant_id: Option<&str>, actor: Option<&str>) -> Result<String, sqlx::Error>;
+
+    /// Stores several users without passwords in `tenant_id` on behalf of `actor`, all or none.
+    ///
+    /// Users whose email or `user_id` is already registered, including earlier in
+    /// `users`, are skipped. Returns, in order, the id of each created user or `None` if skipped.
+    async fn create_many(&self, users: &[CreateUserRequest], status: &str, tenant_id: Option<&str>, actor: Option<&str>) -> Result<Vec<Option<String>>, sqlx::Error>;
+
+    /// Stores a new user without a password who signed up with the identity `subject`
+    /// of `provider`, and links them to it, all or none. The email counts as verified,
+    /// since the provider verified it. Returns its id.
+    async fn create_linked(&self, user: &CreateUserRequest, status: &str, tenant_id: Option<&str>, provider: &str, subject: &str) -> Result<String, sqlx::Error>;
+
+    /// Returns the page of users selected by `query`, with the total number of matches.
+    async fn list(&self, query: &UserQuery) -> Result<UserPage, sqlx::Error>;
+
+    /// Returns the user with the given id.
+    async fn get(&self, id: &str) -> Result<Option<UserResponse>, sqlx::Error>;
+
+    /// Returns the number of users of a tenant that are not deleted.
+    async fn count_in_tenant(&self, tenant_id: &str) -> Result<i64, sqlx::Error>;
+
+    /// Returns the credentials of the user with the given email.
+    async fn credentials_by_email(&self, email: &str) -> Result<Option<Credentials>, sqlx::Error>;
+
+    /// Replaces every field of a user on behalf of `actor`.
+    async fn update(&self, id: &str, user: &CreateUserRequest, actor: Option<&str>) -> Result<UpdateOutcome, sqlx::Error>;
+
+    /// Changes the fields present in `changes` on behalf of `actor`.
+    async fn patch(&self, id: &str, changes: &UpdateUserRequest, actor: Option<&str>) -> Result<UpdateOutcome, sqlx::Error>;
+
+    /// Soft-deletes a user on behalf of `actor` and revokes their sessions, returning
+    /// `Ok(false)` if the user does not exist or is already deleted.
+    ///
+    /// Deleted users keep their roles and saved searches, but are hidden from every
+    /// lookup and cannot log in until restored.
+    async fn delete(&self, id: &str, actor: Option<&str>) -> Result<bool, sqlx::Error>;
+
+    /// Restores a soft-deleted user on behalf of `actor`, returning `Ok(false)` if no
+    /// deleted user has that id.
+    async fn restore(&self, id: &str, actor: Option<&str>) -> Result<bool, sqlx::Error>;
+}
+
+/// What became of an [`UserRepository::update`] or [`UserRepository::patch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    /// The change was applied.
+    Updated,
+    /// No user has that id.
+    NotFound,
+    /// Another region changed the user at the same time and its change won the
+    /// conflict, so this one was discarded. Holds the winning region, if known.
+    Superseded(Option<String>),
+}
+
+/// [`UserRepository`] backed by the `[users]` table.
+pub struct MssqlUserRepository {
+    pool: Pool<Mssql>,
+    ids: Arc<dyn IdGenerator>,
+    retry: RetryPolicy,
+    replication: Option<Replication>,
+}
+
+impl MssqlUserRepository {
+    /// Creates a repository using `pool`, giving new users random UUIDv4 ids and
+    /// retrying transient errors with the default [`RetryPolicy`].
+    pub fn new(pool: Pool<Mssql>) -> Self {
+        MssqlUserRepository { pool, ids: Arc::new(UuidV4), retry: RetryPolicy::default(), replication: None }
+    }
+
+    /// Uses `ids` for the ids of new users instead.
+    pub fn with_id_generator(mut self, ids: Arc<dyn IdGenerator>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    /// Retries transient database errors according to `retry` instead.
+    ///
+    /// Reads, `update` and `patch` are retried after any transient error. Inserts,
+    /// `delete` and `restore` are only retried when the failed attempt rolled back,
+    /// since running them twice would be visible: a second `delete` reports a 404.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Stamps updates with the region of `replication` and settles the ones that
+    /// conflict with another region's, for active-active deployments. An update that
+    /// loses its conflict is discarded and reported as [`UpdateOutcome::Superseded`],
+    /// so the caller can read the winning version before trying again.
+    pub fn with_replication(mut self, replication: Option<Replication>) -> Self {
+        self.replication = replication;
+        self
+    }
+
+    /// Settles a conflict of `operation` on `id` with another region's write,
+    /// returning the [`UpdateOutcome::Superseded`] outcome if the write lost and must
+    /// not be applied.
+    async fn admit(&self, tx: &mut Transaction<'_, Mssql>, id: &str, operation: &str) -> Result<Option<UpdateOutcome>, sqlx::Error> {
+        match &self.replication {
+            Some(replication) => Ok(replication.admit(tx, id, operation).await?.map(|conflict| UpdateOutcome::Superseded(conflict.stored.region))),
+            None => Ok(None),
+        }
+    }
+
+    /// Starts a [`UnitOfWork`] on the repository's pool.
+    pub async fn begin(&self) -> Result<UnitOfWork, sqlx::Error> {
+        UnitOfWork::begin(&self.pool).await
+    }
+}
+
+/// A transaction shared by the steps of one operation, such as changing a user and
+/// recording its audit entry and outbox events, so they commit or roll back together.
+///
+/// It dereferences to the underlying [`Transaction`], so it can be passed to
+/// [`audit::record`], [`outbox::enqueue`] and any query. Nothing is written until
+/// [`commit`](UnitOfWork::commit): a unit of work dropped before, for instance when a
+/// step returns early with `?`, is rolled back.
+///
+/// # Examples
+///
+/// ```no_run
+/// use safe_user::{approvals, audit};
+/// use safe_user::repository::UnitOfWork;
+/// use sqlx::{Mssql, Pool};
+///
+/// # async fn run(pool: Pool<Mssql>, user_id: &str, admin_id: &str) -> Result<(), sqlx::Error> {
+/// let mut work = UnitOfWork::begin(&pool).await?;
+/// if approvals::decide(&mut work, user_id, true).await? {
+///     audit::record(&mut *work, user_id, audit::REGISTRATION_APPROVED, Some(admin_id), None).await?;
+/// }
+/// work.commit().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct UnitOfWork {
+    tx: Transaction<'static, Mssql>,
+}
+
+impl UnitOfWork {
+    /// Starts a transaction on `pool`.
+    pub async fn begin(pool: &Pool<Mssql>) -> Result<Self, sqlx::Error> {
+        Ok(UnitOfWork { tx: pool.begin().await? })
+    }
+
+    /// Inserts a user with id `id`, unverified, together with its `user.created` and
+    /// `user.verification_requested` events, its audit entry and, when it brings its
+    /// tenant close to its cap, a `tenant.quota_warning` event.
+    pub async fn create_user(&mut self, id: &str, user: &CreateUserRequest, password_hash: Option<&str>, status: &str, tenant_id: Option<&str>, actor: Option<&str>) -> Result<(), sqlx::Error> {
+        insert_user(&mut self.tx, id, user, password_hash, status, tenant_id, actor).await?;
+        request_verification(&mut self.tx, id, user).await
+    }
+
+    /// Inserts a user with id `id` who signed up with the identity `subject` of
+    /// `provider` and links them to it, verified, together with its `user.created`
+    /// event, its audit entries and, when it brings its tenant close to its cap, a
+    /// `tenant.quota_warning` event.
+    pub async fn create_linked_user(&mut self, id: &str, user: &CreateUserRequest, status: &str, tenant_id: Option<&str>, provider: &str, subject: &str) -> Result<(), sqlx::Error> {
+        insert_user(&mut self.tx, id, user, None, status, tenant_id, None).await?;
+        oauth::link(&mut self.tx, provider, subject, id).await
+    }
+
+    /// Makes every step of the unit of work permanent.
+    pub async fn commit(self) -> Result<(), sqlx::Error> {
+        self.tx.commit().await
+    }
+
+    /// Discards every step of the unit of work, like dropping it, but reports errors.
+    pub async fn rollback(self) -> Result<(), sqlx::Error> {
+        self.tx.rollback().await
+    }
+}
+
+impl Deref for UnitOfWork {
+    type Target = Transaction<'static, Mssql>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tx
+    }
+}
+
+impl DerefMut for UnitOfWork {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tx
+    }
+}
+
+/// Reads a user inside `tx`, locking the row until the transaction ends so the
+/// values diffed for `user.updated` are exactly the ones overwritten.
+async fn locked_user(tx: &mut Transaction<'_, Mssql>, id: &str) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        r#"
+        SELECT
+            CAST(id AS VARCHAR(36))         AS "id!",
+            UserId                          AS "user_id!: UserId",
+            Name                            AS "name!",
+            LastName                        AS "last_name!",
+            Email                           AS "email!: Email",
+            Age                             AS "age?",
+            Phone                           AS "phone?: Phone",
+            Address                         AS "address?",
+            CONVERT(VARCHAR, BirthDate, 23) AS "birthdate!: Birthdate",
+            PlaceBirth                      AS "place_birth?",
+            OrgUnit                         AS "org_unit?",
+            CONVERT(VARCHAR(33), ExpiresAt, 126) AS "expires_at?: Timestamp",
+            CONVERT(VARCHAR(33), CreatedAt, 126) AS "created_at?: Timestamp",
+            CONVERT(VARCHAR(33), UpdatedAt, 126) AS "updated_at?: Timestamp",
+            CONVERT(VARCHAR(33), LastSeenAt, 126) AS "last_seen_at?: Timestamp",
+            CONVERT(VARCHAR(33), DeletedAt, 126) AS "deleted_at?: Timestamp",
+            [Plan]                          AS "plan?"
+        FROM [users] WITH (UPDLOCK)
+        WHERE id = @p1 AND DeletedAt IS NULL
+        "#,
+        id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+}
+
+/// Returns the `changes` diff between two versions of a user, redacting the
+/// [`outbox::redacted_fields`]; it goes both into the `user.updated` event and the
+/// audit log.
+fn user_changes(before: &User, after: &User) -> serde_json::Map<String, serde_json::Value> {
+    let to_json = |user: &User| serde_json::to_value(user).unwrap_or(serde_json::Value::Null);
+    outbox::field_changes(&to_json(before), &to_json(after), &outbox::redacted_fields())
+}
+
+/// Inserts the user, unverified, with its `user.created` outbox event, its audit
+/// entry and, when it brings its tenant close to its cap, a `tenant.quota_warning`
+/// event, as part of `tx`.
+async fn insert_user(tx: &mut Transaction<'_, Mssql>, id: &str, user: &CreateUserRequest, password_hash: Option<&str>, status: &str, tenant_id: Option<&str>, actor: Option<&str>) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO [users] (
+            id,
+            UserId,
+            Name,
+            LastName,
+            Email,
+            Age,
+            Phone,
+            Address,
+            BirthDate,
+            PlaceBirth,
+            OrgUnit,
+            PasswordHash,
+            PasswordChangedAt,
+            Status,
+            ExpiresAt,
+            EmailVerified,
+            TenantId
+        )
+        VALUES (
+            @p1, @p2, @p3, @p4, @p5,
+            @p6, @p7, @p8, @p9, @p10,
+            @p11, @p12, CASE WHEN @p12 IS NULL THEN NULL ELSE SYSUTCDATETIME() END, @p13, @p14, 0,
+            @p15
+        )
+        "#,
+        id,
+        user.user_id,
+        user.name,
+        user.last_name,
+        user.email,
+        user.age,
+        user.phone,
+        user.address,
+        user.birthdate,
+        user.place_birth,
+        user.org_unit,
+        password_hash,
+        status,
+        user.expires_at,
+        tenant_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let payload = json!({
+        "id": id,
+        "user_id": user.user_id,
+        "name": user.name,
+        "last_name": user.last_name,
+        "email": user.email,
+    });
+    outbox::enqueue(tx, outbox::USER_CREATED, id, &payload).await?;
+    audit::record(&mut *tx, id, audit::ACCOUNT_CREATED, actor, None).await?;
+    if let Some(tenant_id) = tenant_id {
+        quotas::warn_if_near_limit(tx, tenant_id).await?;
+    }
+
+    Ok(())
+}
+
+/// Stores the digest of a new email verification token for the user and enqueues the
+/// `user.verification_requested` event carrying it, as part of `tx`.
+async fn request_verification(tx: &mut Transaction<'_, Mssql>, id: &str, user: &CreateUserRequest) -> Result<(), sqlx::Error> {
+    let verification_token = email_verification::issue_token(id, &user.email).map_err(|e| sqlx::Error::Configuration(Box::new(e)))?;
+    sqlx::query!(
+        "UPDATE [users] SET EmailVerificationHash = @p2 WHERE id = @p1",
+        id,
+        email_verification::hash_token(&verification_token)
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let verification = json!({
+        "id": id,
+        "email": user.email,
+        "name": user.name,
+        "verification_token": verification_token,
+    });
+    outbox::enqueue(tx, outbox::USER_VERIFICATION_REQUESTED, id, &verification).await
+}
+
+#[async_trait]
+impl UserRepository for MssqlUserRepository {
+    /// Inserts the user in a single transaction with its events.
+    ///
+    /// If any statement fails the transaction is rolled back on drop, so no event
+    /// is ever recorded for a user that was not persisted.
+    async fn create(&self, user: &CreateUserRequest, password_hash: Option<&str>, status: &str, tenant_id: Option<&str>, actor: Option<&str>) -> Result<String, sqlx::Error> {
+        self.retry.run(Idempotency::NotIdempotent, || async move {
+            let id = self.ids.next_id().await?;
+            let mut work = self.begin().await?;
+            work.create_user(&id, user, password_hash, status, tenant_id, actor).await?;
+            work.commit().await?;
+            Ok(id)
+        })
+        .await
+    }
+
+    /// Inserts the user and its identity in a single transaction, so a failed link
+    /// leaves no account behind that the provider's sign-in would no longer find.
+    async fn create_linked(&self, user: &CreateUserRequest, status: &str, tenant_id: Option<&str>, provider: &str, subject: &str) -> Result<String, sqlx::Error> {
+        self.retry.run(Idempotency::NotIdempotent, || async move {
+            let id = self.ids.next_id().await?;
+            let mut work = self.begin().await?;
+            work.create_linked_user(&id, user, status, tenant_id, provider, subject).await?;
+            work.commit().await?;
+            Ok(id)
+        })
+        .await
+    }
+
+    /// Inserts the users one by one in a single transaction, so a failed import
+    /// leaves no user or event behind.
+    async fn create_many(&self, users: &[CreateUserRequest], status: &str, tenant_id: Option<&str>, actor: Option<&str>) -> Result<Vec<Option<String>>, sqlx::Error> {
+        self.retry.run(Idempotency::NotIdempotent, || async move {
+            let mut work = self.begin().await?;
+            let mut ids = Vec::with_capacity(users.len());
+
+            for user in users {
+                let taken = sqlx::query_scalar!(
+                    r#"SELECT COUNT(*) AS "taken!: i32" FROM [users] WITH (UPDLOCK, HOLDLOCK) WHERE Email = @p1 OR UserId = @p2"#,
+                    &user.email,
+                    &user.user_id
+                )
+                .fetch_one(&mut *work)
+                .await?;
+                if taken > 0 {
+                    ids.push(None);
+                    continue;
+                }
+
+                let id = self.ids.next_id().await?;
+                work.create_user(&id, user, None, status, tenant_id, actor).await?;
+                ids.push(Some(id));
+            }
+
+            work.commit().await?;
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn list(&self, query: &UserQuery) -> Result<UserPage, sqlx::Error> {
+        self.retry.run(Idempotency::Idempotent, || async move {
+            let name_pattern = query.name_pattern();
+
+            // ORDER BY cannot be parameterized, so each sortable column gets a CASE per direction.
+            let users = sqlx::query_as!(
+                User,
+                r#"
+                SELECT
+                    CAST(id AS VARCHAR(36))         AS "id!", -- Cast UUID to String
+                    UserId                          AS "user_id!: UserId",
+                    Name                            AS "name!",
+                    LastName                        AS "last_name!",
+                    Email                           AS "email!: Email",
+                    Age                             AS "age?",
+                    Phone                           AS "phone?: Phone",
+                    Address                         AS "address?",
+                    CONVERT(VARCHAR, BirthDate, 23) AS "birthdate!: Birthdate",
+                    PlaceBirth                      AS "place_birth?",
+                    OrgUnit                         AS "org_unit?",
+                    CONVERT(VARCHAR(33), ExpiresAt, 126) AS "expires_at?: Timestamp",
+                    CONVERT(VARCHAR(33), CreatedAt, 126) AS "created_at?: Timestamp",
+                    CONVERT(VARCHAR(33), UpdatedAt, 126) AS "updated_at?: Timestamp",
+                    CONVERT(VARCHAR(33), LastSeenAt, 126) AS "last_seen_at?: Timestamp",
+                    CONVERT(VARCHAR(33), DeletedAt, 126) AS "deleted_at?: Timestamp",
+                    [Plan]                          AS "plan?"
+                FROM [users]
+                WHERE (@p1 IS NULL OR Email = @p1)
+                    AND (@p2 IS NULL OR Name LIKE @p2 ESCAPE '\')
+                    AND (@p7 IS NULL OR LastSeenAt >= @p7)
+                    AND (@p8 = 1 OR DeletedAt IS NULL)
+                ORDER BY
+                    CASE WHEN @p3 = 'name' AND @p4 = 'asc' THEN Name END ASC,
+                    CASE WHEN @p3 = 'name' AND @p4 = 'desc' THEN Name END DESC,
+                    CASE WHEN @p3 = 'last_name' AND @p4 = 'asc' THEN LastName END ASC,
+                    CASE WHEN @p3 = 'last_name' AND @p4 = 'desc' THEN LastName END DESC,
+                    CASE WHEN @p3 = 'email' AND @p4 = 'asc' THEN Email END ASC,
+                    CASE WHEN @p3 = 'email' AND @p4 = 'desc' THEN Email END DESC,
+                    CASE WHEN @p3 = 'age' AND @p4 = 'asc' THEN Age END ASC,
+                    CASE WHEN @p3 = 'age' AND @p4 = 'desc' THEN Age END DESC,
+                    CASE WHEN @p3 = 'birthdate' AND @p4 = 'asc' THEN BirthDate END ASC,
+                    CASE WHEN @p3 = 'birthdate' AND @p4 = 'desc' THEN BirthDate END DESC,
+                    id ASC
+                OFFSET @p5 ROWS FETCH NEXT @p6 ROWS ONLY
+                "#,
+                query.email,
+                name_pattern,
+                query.sort.as_str(),
+                query.order.as_str(),
+                query.offset() as i32,
+                query.per_page() as i32,
+                query.active_since,
+                query.include_deleted
+            )
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(UserResponse::from)
+            .collect();
+
+            let total = sqlx::query_scalar!(
+                r#"
+                SELECT COUNT_BIG(*) AS "total!: i64"
+                FROM [users]
+                WHERE (@p1 IS NULL OR Email = @p1)
+                    AND (@p2 IS NULL OR Name LIKE @p2 ESCAPE '\')
+                    AND (@p3 IS NULL OR LastSeenAt >= @p3)
+                    AND (@p4 = 1 OR DeletedAt IS NULL)
+                "#,
+                query.email,
+                name_pattern,
+                query.active_since,
+                query.include_deleted
+            )
+            .fetch_one(&self.pool)
+            .await?;
+
+            Ok(UserPage { users, total })
+        })
+        .await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<UserResponse>, sqlx::Error> {
+        self.retry.run(Idempotency::Idempotent, || async move {
+            sqlx::query_as!(
+                User,
+                r#"
+                SELECT
+                    CAST(id AS VARCHAR(36))         AS "id!",
+                    UserId                          AS "user_id!: UserId",
+                    Name                            AS "name!",
+                    LastName                        AS "last_name!",
+                    Email                           AS "email!: Email",
+                    Age                             AS "age?",
+                    Phone                           AS "phone?: Phone",
+                    Address                         AS "address?",
+                    CONVERT(VARCHAR, BirthDate, 23) AS "birthdate!: Birthdate",
+                    PlaceBirth                      AS "place_birth?",
+                    OrgUnit                         AS "org_unit?",
+                    CONVERT(VARCHAR(33), ExpiresAt, 126) AS "expires_at?: Timestamp",
+                    CONVERT(VARCHAR(33), CreatedAt, 126) AS "created_at?: Timestamp",
+                    CONVERT(VARCHAR(33), UpdatedAt, 126) AS "updated_at?: Timestamp",
+                    CONVERT(VARCHAR(33), LastSeenAt, 126) AS "last_seen_at?: Timestamp",
+                    CONVERT(VARCHAR(33), DeletedAt, 126) AS "deleted_at?: Timestamp",
+                    [Plan]                          AS "plan?"
+                FROM [users]
+                WHERE id = @p1 AND DeletedAt IS NULL
+                "#,
+                id
+            )
+            .fetch_optional(&self.pool)
+            .await
+            .map(|user| user.map(UserResponse::from))
+        })
+        .await
+    }
+
+    async fn count_in_tenant(&self, tenant_id: &str) -> Result<i64, sqlx::Error> {
+        self.retry.run(Idempotency::Idempotent, || async move {
+            sqlx::query_scalar!(
+                r#"SELECT COUNT_BIG(*) AS "users!: i64" FROM [users] WHERE TenantId = @p1 AND DeletedAt IS NULL"#,
+                tenant_id
+            )
+            .fetch_one(&self.pool)
+            .await
+        })
+        .await
+    }
+
+    async fn credentials_by_email(&self, email: &str) -> Result<Option<Credentials>, sqlx::Error> {
+        self.retry.run(Idempotency::Idempotent, || async move {
+            sqlx::query_as!(
+                Credentials,
+                r#"
+                SELECT
+                    CAST(id AS VARCHAR(36)) AS "id!",
+                    PasswordHash            AS "password_hash?",
+                    Status                  AS "status!",
+                    CAST(CASE WHEN ExpiresAt <= SYSUTCDATETIME() THEN 1 ELSE 0 END AS BIT) AS "expired!",
+                    EmailVerified           AS "email_verified!",
+                    CONVERT(VARCHAR(33), PasswordChangedAt, 126) AS "password_changed_at?: Timestamp",
+                    CAST(CASE WHEN LockedAt IS NOT NULL THEN 1 ELSE 0 END AS BIT) AS "locked!",
+                    PasswordResetRequired   AS "password_reset_required!"
+                FROM [users]
+                WHERE Email = @p1 AND DeletedAt IS NULL
+                "#,
+                email
+            )
+            .fetch_optional(&self.pool)
+            .await
+        })
+        .await
+    }
+
+    async fn update(&self, id: &str, user: &CreateUserRequest, actor: Option<&str>) -> Result<UpdateOutcome, sqlx::Error> {
+        self.retry.run(Idempotency::Idempotent, || async move {
+            let mut tx = self.pool.begin().await?;
+
+            let before = match locked_user(&mut tx, id).await? {
+                Some(before) => before,
+                None => return Ok(UpdateOutcome::NotFound),
+            };
+            if let Some(superseded) = self.admit(&mut tx, id, "update").await? {
+                tx.commit().await?;
+                return Ok(superseded);
+            }
+
+            let result = sqlx::query!(
+                r#"
+                UPDATE [users]
+                SET
+                    UserId = @p2,
+                    Name = @p3,
+                    LastName = @p4,
+                    Email = @p5,
+                    Age = @p6,
+                    Phone = @p7,
+                    Address = @p8,
+                    BirthDate = @p9,
+                    PlaceBirth = @p10,
+                    OrgUnit = @p11,
+                    ExpiresAt = @p12,
+                    UpdatedAt = SYSUTCDATETIME(),
+                    ExpiryReminderSentAt = CASE WHEN ExpiresAt = @p12 THEN ExpiryReminderSentAt ELSE NULL END,
+                    Status = CASE WHEN Status = @p13 AND (@p12 IS NULL OR @p12 > SYSUTCDATETIME()) THEN @p14 ELSE Status END
+                WHERE id = @p1
+                "#,
+                id,
+                user.user_id,
+                user.name,
+                user.last_name,
+                user.email,
+                user.age,
+                user.phone,
+                user.address,
+                user.birthdate,
+                user.place_birth,
+                user.org_unit,
+                user.expires_at,
+                EXPIRED,
+                ACTIVE
+            )
+            .execute(&mut tx)
+            .await?;
+
+            if result.rows_affected() == 0 {
+                return Ok(UpdateOutcome::NotFound);
+            }
+            if let Some(replication) = &self.replication {
+                replication.stamp(&mut tx, id).await?;
+            }
+
+            let after = locked_user(&mut tx, id).await?.unwrap_or_else(|| before.clone());
+            let diff = user_changes(&before, &after);
+            let changed: Vec<&String> = diff.keys().collect();
+            let payload = json!({
+                "id": id,
+                "user_id": user.user_id,
+                "name": user.name,
+                "last_name": user.last_name,
+                "email": user.email,
+                "changes": diff,
+            });
+            outbox::enqueue(&mut tx, outbox::USER_UPDATED, id, &payload).await?;
+            audit::record(&mut tx, id, audit::PROFILE_UPDATED, actor, Some(&json!({ "changed": changed, "changes": diff }))).await?;
+
+            tx.commit().await?;
+            Ok(UpdateOutcome::Updated)
+        })
+        .await
+    }
+
+    async fn patch(&self, id: &str, changes: &UpdateUserRequest, actor: Option<&str>) -> Result<UpdateOutcome, sqlx::Error> {
+        self.retry.run(Idempotency::Idempotent, || async move {
+            let mut tx = self.pool.begin().await?;
+
+            let before = match locked_user(&mut tx, id).await? {
+                Some(before) => before,
+                None => return Ok(UpdateOutcome::NotFound),
+            };
+            if let Some(superseded) = self.admit(&mut tx, id, "patch").await? {
+                tx.commit().await?;
+                return Ok(superseded);
+            }
+
+            let result = sqlx::query!(
+                r#"
+                UPDATE [users]
+                SET
+                    UserId = COALESCE(@p2, UserId),
+                    Name = COALESCE(@p3, Name),
+                    LastName = COALESCE(@p4, LastName),
+                    Email = COALESCE(@p5, Email),
+                    Age = COALESCE(@p6, Age),
+                    Phone = COALESCE(@p7, Phone),
+                    Address = COALESCE(@p8, Address),
+                    BirthDate = COALESCE(@p9, BirthDate),
+                    PlaceBirth = COALESCE(@p10, PlaceBirth),
+                    OrgUnit = COALESCE(@p11, OrgUnit),
+                    ExpiresAt = COALESCE(@p12, ExpiresAt),
+                    UpdatedAt = SYSUTCDATETIME(),
+                    ExpiryReminderSentAt = CASE WHEN @p12 IS NULL OR ExpiresAt = @p12 THEN ExpiryReminderSentAt ELSE NULL END,
+                    Status = CASE WHEN Status = @p13 AND @p12 > SYSUTCDATETIME() THEN @p14 ELSE Status END
+                WHERE id = @p1
+                "#,
+                id,
+                changes.user_id,
+                changes.name,
+                changes.last_name,
+                changes.email,
+                changes.age,
+                changes.phone,
+                changes.address,
+                changes.birthdate,
+                changes.place_birth,
+                changes.org_unit,
+                changes.expires_at,
+                EXPIRED,
+                ACTIVE
+            )
+            .execute(&mut tx)
+            .await?;
+
+            if result.rows_affected() == 0 {
+                return Ok(UpdateOutcome::NotFound);
+            }
+            if let Some(replication) = &self.replication {
+                replication.stamp(&mut tx, id).await?;
+            }
+
+            let after = locked_user(&mut tx, id).await?.unwrap_or_else(|| before.clone());
+            let diff = user_changes(&before, &after);
+            let changed: Vec<&String> = diff.keys().collect();
+            outbox::enqueue(&mut tx, outbox::USER_UPDATED, id, &json!({ "id": id, "changed": changed, "changes": diff })).await?;
+            audit::record(&mut tx, id, audit::PROFILE_UPDATED, actor, Some(&json!({ "changed": changed, "changes": diff }))).await?;
+
+            tx.commit().await?;
+            Ok(UpdateOutcome::Updated)
+        })
+        .await
+    }
+
+    async fn delete(&self, id: &str, actor: Option<&str>) -> Result<bool, sqlx::Error> {
+        self.retry.run(Idempotency::NotIdempotent, || async move {
+            let mut tx = self.pool.begin().await?;
+
+            let before = match locked_user(&mut tx, id).await? {
+                Some(before) => before,
+                None => return Ok(false),
+            };
+
+            sqlx::query!(
+                "UPDATE [users] SET DeletedAt = SYSUTCDATETIME(), UpdatedAt = SYSUTCDATETIME() WHERE id = @p1",
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+
+            sqlx::query!(
+                "UPDATE [refresh_tokens] SET RevokedAt = SYSUTCDATETIME() WHERE UserId = @p1 AND RevokedAt IS NULL",
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+
+            outbox::enqueue(&mut tx, outbox::USER_DELETED, id, &json!({ "id": id })).await?;
+            let details = json!({ "email": before.email, "name": before.name, "last_name": before.last_name });
+            audit::record(&mut tx, id, audit::ACCOUNT_DELETED, actor, Some(&details)).await?;
+
+            tx.commit().await?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn restore(&self, id: &str, actor: Option<&str>) -> Result<bool, sqlx::Error> {
+        self.retry.run(Idempotency::NotIdempotent, || async move {
+            let mut tx = self.pool.begin().await?;
+
+            let result = sqlx::query!(
+                "UPDATE [users] SET DeletedAt = NULL, UpdatedAt = SYSUTCDATETIME() WHERE id = @p1 AND DeletedAt IS NOT NULL",
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+
+            if result.rows_affected() == 0 {
+                return Ok(false);
+            }
+
+            outbox::enqueue(&mut tx, outbox::USER_RESTORED, id, &json!({ "id": id })).await?;
+            audit::record(&mut tx, id, audit::ACCOUNT_RESTORED, actor, None).await?;
+
+            tx.commit().await?;
+            Ok(true)
+        })
+        .await
+    }
+}
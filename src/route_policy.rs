@@ -0,0 +1,344 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceFactory, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::Method;
+use actix_web::{web, App, Error, FromRequest, Handler, HttpMessage, Responder, ResponseError, Scope};
+use sqlx::{Mssql, Pool};
+use std::fmt;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use crate::auth::Claims;
+use crate::error::AppError;
+use crate::rbac::{grants, permissions_for_user};
+
+/// An authorization requirement declared next to a route, usually built with [`policy!`](crate::policy).
+///
+/// `Role` checks the `roles` claim of the caller's token; `Scope` checks a permission
+/// granted through the caller's roles, exactly like [`RequirePermission`](crate::rbac::RequirePermission).
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoutePolicy {
+    Role(&'static str),
+    Scope(&'static str),
+    Any(Vec<RoutePolicy>),
+    All(Vec<RoutePolicy>),
+}
+
+impl RoutePolicy {
+    /// Returns a policy satisfied by any of `policies`, or the policy itself if there is only one.
+    pub fn any(mut policies: Vec<RoutePolicy>) -> Self {
+        if policies.len() == 1 {
+            policies.remove(0)
+        } else {
+            RoutePolicy::Any(policies)
+        }
+    }
+
+    /// Returns a policy satisfied by all of `policies`, or the policy itself if there is only one.
+    pub fn all(mut policies: Vec<RoutePolicy>) -> Self {
+        if policies.len() == 1 {
+            policies.remove(0)
+        } else {
+            RoutePolicy::All(policies)
+        }
+    }
+
+    /// Returns `true` if the caller's roles and permissions satisfy the policy.
+    pub fn allows(&self, roles: &[String], permissions: &[String]) -> bool {
+        match self {
+            RoutePolicy::Role(role) => roles.iter().any(|granted| granted == role),
+            RoutePolicy::Scope(permission) => grants(permissions, permission),
+            RoutePolicy::Any(policies) => policies.iter().any(|policy| policy.allows(roles, permissions)),
+            RoutePolicy::All(policies) => policies.iter().all(|policy| policy.allows(roles, permissions)),
+        }
+    }
+
+    /// Returns `true` if evaluating the policy needs the caller's permissions from the database.
+    fn needs_permissions(&self) -> bool {
+        match self {
+            RoutePolicy::Role(_) => false,
+            RoutePolicy::Scope(_) => true,
+            RoutePolicy::Any(policies) | RoutePolicy::All(policies) => policies.iter().any(RoutePolicy::needs_permissions),
+        }
+    }
+}
+
+/// Prints the policy in the syntax accepted by [`policy!`](crate::policy).
+impl fmt::Display for RoutePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |f: &mut fmt::Formatter<'_>, policies: &[RoutePolicy], separator: &str| {
+            for (i, policy) in policies.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " {} ", separator)?;
+                }
+                match policy {
+                    RoutePolicy::Any(_) | RoutePolicy::All(_) => write!(f, "({})", policy)?,
+                    _ => write!(f, "{}", policy)?,
+                }
+            }
+            Ok(())
+        };
+
+        match self {
+            RoutePolicy::Role(role) => write!(f, "role {:?}", role),
+            RoutePolicy::Scope(permission) => write!(f, "scope {:?}", permission),
+            RoutePolicy::Any(policies) => join(f, policies, "or"),
+            RoutePolicy::All(policies) => join(f, policies, "and"),
+        }
+    }
+}
+
+/// Builds a [`RoutePolicy`] from `role` and `scope` requirements combined with `and`,
+/// `or` and parentheses. `and` binds tighter than `or`.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::policy;
+/// use safe_user::rbac::MANAGE_USERS;
+/// use safe_user::route_policy::RoutePolicy;
+///
+/// let policy = policy!(role "admin" or scope "users:read");
+/// assert_eq!(policy, RoutePolicy::Any(vec![RoutePolicy::Role("admin"), RoutePolicy::Scope("users:read")]));
+///
+/// let policy = policy!(role "support" and (scope MANAGE_USERS or role "manager"));
+/// assert_eq!(policy.to_string(), r#"role "support" and (scope "users:manage" or role "manager")"#);
+/// ```
+#[macro_export]
+macro_rules! policy {
+    // Splits the input on `or`, keeping the finished alternatives and the tokens of the current one.
+    (@or [$($alternatives:expr,)*] [$($term:tt)*]) => {
+        $crate::route_policy::RoutePolicy::any(vec![$($alternatives,)* $crate::policy!(@and [] [] $($term)*)])
+    };
+    (@or [$($alternatives:expr,)*] [$($term:tt)*] or $($rest:tt)*) => {
+        $crate::policy!(@or [$($alternatives,)* $crate::policy!(@and [] [] $($term)*),] [] $($rest)*)
+    };
+    (@or [$($alternatives:expr,)*] [$($term:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::policy!(@or [$($alternatives,)*] [$($term)* $next] $($rest)*)
+    };
+
+    // Splits one alternative on `and`.
+    (@and [$($requirements:expr,)*] [$($atom:tt)*]) => {
+        $crate::route_policy::RoutePolicy::all(vec![$($requirements,)* $crate::policy!(@atom $($atom)*)])
+    };
+    (@and [$($requirements:expr,)*] [$($atom:tt)*] and $($rest:tt)*) => {
+        $crate::policy!(@and [$($requirements,)* $crate::policy!(@atom $($atom)*),] [] $($rest)*)
+    };
+    (@and [$($requirements:expr,)*] [$($atom:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::policy!(@and [$($requirements,)*] [$($atom)* $next] $($rest)*)
+    };
+
+    (@atom role $role:expr) => {
+        $crate::route_policy::RoutePolicy::Role($role)
+    };
+    (@atom scope $permission:expr) => {
+        $crate::route_policy::RoutePolicy::Scope($permission)
+    };
+    (@atom ($($inner:tt)+)) => {
+        $crate::policy!($($inner)+)
+    };
+
+    ($($tokens:tt)+) => {
+        $crate::policy!(@or [] [] $($tokens)+)
+    };
+}
+
+/// Registers routes guarded by a [`RoutePolicy`], so each route's authorization is
+/// declared on the same line as its path and handler.
+///
+/// Implemented for [`App`] and [`Scope`]. The routes must sit behind
+/// [`jwt_validator`](crate::auth::jwt_validator), which stores the caller's [`Claims`].
+///
+/// # Examples
+///
+/// ```no_run
+/// use actix_web::http::Method;
+/// use actix_web::{web, App};
+/// use actix_web_httpauth::middleware::HttpAuthentication;
+/// use safe_user::auth::jwt_validator;
+/// use safe_user::handlers::get_all_users;
+/// use safe_user::policy;
+/// use safe_user::route_policy::RoutePolicyExt;
+///
+/// let app = App::new().service(
+///     web::scope("/protected")
+///         .wrap(HttpAuthentication::bearer(jwt_validator))
+///         .route_with_policy("/users", Method::GET, policy!(role "admin" or scope "users:read"), get_all_users)
+/// );
+/// ```
+pub trait RoutePolicyExt: Sized {
+    /// Registers `handler` for `method` requests to `path`, rejecting callers that do
+    /// not satisfy `policy` with `403 Forbidden`.
+    fn route_with_policy<F, Args>(self, path: &str, method: Method, policy: RoutePolicy, handler: F) -> Self
+    where
+        F: Handler<Args>,
+        Args: FromRequest + 'static,
+        F::Output: Responder + 'static;
+}
+
+impl<T> RoutePolicyExt for Scope<T>
+where
+    T: ServiceFactory<ServiceRequest, Config = (), Error = Error, InitError = ()>,
+{
+    fn route_with_policy<F, Args>(self, path: &str, method: Method, policy: RoutePolicy, handler: F) -> Self
+    where
+        F: Handler<Args>,
+        Args: FromRequest + 'static,
+        F::Output: Responder + 'static,
+    {
+        self.route(path, web::method(method).to(handler).wrap(RequirePolicy::new(policy)))
+    }
+}
+
+impl<T> RoutePolicyExt for App<T>
+where
+    T: ServiceFactory<ServiceRequest, Config = (), Error = Error, InitError = ()>,
+{
+    fn route_with_policy<F, Args>(self, path: &str, method: Method, policy: RoutePolicy, handler: F) -> Self
+    where
+        F: Handler<Args>,
+        Args: FromRequest + 'static,
+        F::Output: Responder + 'static,
+    {
+        self.route(path, web::method(method).to(handler).wrap(RequirePolicy::new(policy)))
+    }
+}
+
+/// Middleware rejecting requests whose caller does not satisfy a [`RoutePolicy`].
+///
+/// Permissions are only loaded from the database when the policy contains a `scope`
+/// requirement.
+pub struct RequirePolicy {
+    policy: Rc<RoutePolicy>,
+}
+
+impl RequirePolicy {
+    /// Creates a guard enforcing `policy`.
+    pub fn new(policy: RoutePolicy) -> Self {
+        RequirePolicy { policy: Rc::new(policy) }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePolicy
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequirePolicyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequirePolicyMiddleware {
+            service: Rc::new(service),
+            policy: Rc::clone(&self.policy),
+        }))
+    }
+}
+
+/// The service produced by [`RequirePolicy`].
+pub struct RequirePolicyMiddleware<S> {
+    service: Rc<S>,
+    policy: Rc<RoutePolicy>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirePolicyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let policy = Rc::clone(&self.policy);
+
+        Box::pin(async move {
+            let claims = req.extensions().get::<Claims>().cloned();
+
+            let allowed = match claims {
+                Some(claims) => {
+                    let permissions = if policy.needs_permissions() {
+                        match req.app_data::<web::Data<Pool<Mssql>>>() {
+                            Some(pool) => permissions_for_user(pool, &claims.sub).await.unwrap_or_else(|e| {
+                                eprintln!("Error loading permissions: {:?}", e);
+                                Vec::new()
+                            }),
+                            None => Vec::new(),
+                        }
+                    } else {
+                        Vec::new()
+                    };
+                    policy.allows(&claims.roles, &permissions)
+                }
+                None => false,
+            };
+
+            if !allowed {
+                let response = AppError::forbidden("access_denied", format!("Access requires: {}", policy)).error_response();
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            service.call(req).await.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, HttpResponse};
+
+    async fn ok() -> impl Responder {
+        HttpResponse::Ok().json("ok")
+    }
+
+    fn roles(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_policy_macro_precedence() {
+        let policy = policy!(role "admin" or role "support" and scope "users:read");
+        assert_eq!(
+            policy,
+            RoutePolicy::Any(vec![
+                RoutePolicy::Role("admin"),
+                RoutePolicy::All(vec![RoutePolicy::Role("support"), RoutePolicy::Scope("users:read")]),
+            ])
+        );
+        assert_eq!(policy.to_string(), r#"role "admin" or (role "support" and scope "users:read")"#);
+
+        assert!(policy.allows(&roles(&["admin"]), &[]));
+        assert!(policy.allows(&roles(&["support"]), &roles(&["users:*"])));
+        assert!(!policy.allows(&roles(&["support"]), &[]));
+    }
+
+    #[actix_web::test]
+    async fn test_route_with_policy_checks_roles_claim() {
+        let app = actix_web::test::init_service(
+            App::new().service(
+                web::scope("/protected").route_with_policy("/route", Method::GET, policy!(role "admin"), ok)
+            )
+        ).await;
+
+        for (roles, expected) in [(vec!["admin"], StatusCode::OK), (vec!["viewer"], StatusCode::FORBIDDEN)] {
+            let req = actix_web::test::TestRequest::get().uri("/protected/route").to_request();
+            req.extensions_mut().insert(Claims {
+                roles: roles.into_iter().map(String::from).collect(),
+                ..Claims::new("tester", 0, &[])
+            });
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), expected);
+        }
+
+        let req = actix_web::test::TestRequest::get().uri("/protected/route").to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+}
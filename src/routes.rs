@@ -0,0 +1,318 @@
+use actix_web::http::Method;
+use actix_web::middleware::from_fn;
+use actix_web::web::{self, ServiceConfig};
+use actix_web_httpauth::middleware::HttpAuthentication;
+use std::sync::{Arc, OnceLock};
+use crate::admin;
+use crate::audit::{get_audit_log, get_user_timeline};
+use crate::audit_chain::verify_audit_log;
+use crate::challenge::{get_challenge, require_challenge};
+use crate::cookie_sessions::session_or_bearer;
+use crate::device::{device_page, oauth_token, request_device_code, verify_device, VERIFICATION_PATH};
+use crate::email_verification::verify_email;
+use crate::export::export_users;
+use crate::features::{get_features, set_features};
+use crate::field_metadata::{self, FIELD_METADATA_PATH};
+use crate::handlers::{create_user, delete_user, get_all_users, get_user, login, logout, patch_user, protected_route, refresh_token, register, restore_user, stream_all_users, update_user};
+use crate::import::import_users;
+use crate::introspection::token_info;
+use crate::mfa;
+use crate::oauth;
+use crate::org_chart::get_user_graph;
+use crate::password_reset::{forgot_password, reset_password};
+use crate::policy::{reload_policy, Authorize};
+use crate::policy;
+use crate::profile_gaps::profile_gaps;
+use crate::quotas::get_tenant_usage;
+use crate::rate_limit::{enforce_rate_limit, IpRateLimiter, LimitByIp};
+use crate::rbac::{RequirePermission, EXPORT_USERS, MANAGE_ROLES, MANAGE_SESSIONS, MANAGE_USERS, READ_AUDIT, SIGN_URLS, VERIFY_AUDIT, VIEW_TIMELINE};
+use crate::read_only::{get_read_only, set_read_only};
+use crate::replication::list_conflicts;
+use crate::route_policy::RoutePolicyExt;
+use crate::saved_searches::{create_search, delete_search, get_search_results, get_searches};
+use crate::signed_urls::create_signed_url;
+use crate::suggest::suggest;
+
+/// Prefix of version 1 of the API.
+pub const API_V1: &str = "/api/v1";
+
+/// The per-IP limiters of the public endpoints, shared by every worker and by the
+/// versioned and unversioned copies of each route.
+struct IpLimiters {
+    token: Arc<IpRateLimiter>,
+    signup: Arc<IpRateLimiter>,
+}
+
+static IP_LIMITERS: OnceLock<IpLimiters> = OnceLock::new();
+
+fn ip_limiters() -> &'static IpLimiters {
+    IP_LIMITERS.get_or_init(|| IpLimiters {
+        token: Arc::new(IpRateLimiter::from_env("TOKEN")),
+        signup: Arc::new(IpRateLimiter::from_env("SIGNUP")),
+    })
+}
+
+/// Returns `path` without the [`API_V1`] prefix, so middleware matching fixed paths
+/// treats `/api/v1/login` like `/login`.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::routes::unversioned;
+///
+/// assert_eq!(unversioned("/api/v1/protected/users"), "/protected/users");
+/// assert_eq!(unversioned("/login"), "/login");
+/// assert_eq!(unversioned("/api/v10/login"), "/api/v10/login");
+/// ```
+pub fn unversioned(path: &str) -> &str {
+    match path.strip_prefix(API_V1) {
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => path,
+    }
+}
+
+/// Registers the routes of version 1 of the API: the public authentication
+/// endpoints and the `/protected` and `/admin` scopes.
+///
+/// `main` mounts them under [`API_V1`] and, for clients written before versioning,
+/// at the root as well. A later version gets its own `configure_v2` mounted under
+/// `/api/v2`, leaving these untouched. Probes, metrics, the OIDC documents, the API
+/// documentation and `/shared` stay at the root, outside any version.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{web, App};
+/// use safe_user::routes::{configure_v1, API_V1};
+///
+/// let app = App::new().service(web::scope(API_V1).configure(configure_v1)).configure(configure_v1);
+/// ```
+pub fn configure_v1(cfg: &mut ServiceConfig) {
+    let limiters = ip_limiters();
+
+    cfg
+        .service(
+            web::resource("/create_user")
+                .wrap(from_fn(require_challenge))
+                .wrap(LimitByIp::new(limiters.signup.clone()))
+                .route(web::post().to(create_user))
+        )
+        .service(
+            web::resource("/register")
+                .wrap(from_fn(require_challenge))
+                .wrap(LimitByIp::new(limiters.signup.clone()))
+                .route(web::post().to(register))
+        )
+        .service(
+            web::resource("/verify_email")
+                .wrap(LimitByIp::new(limiters.signup.clone()))
+                .route(web::get().to(verify_email))
+        )
+        .service(
+            web::resource("/forgot_password")
+                .wrap(from_fn(require_challenge))
+                .wrap(LimitByIp::new(limiters.token.clone()))
+                .route(web::post().to(forgot_password))
+        )
+        .service(
+            web::resource("/reset_password")
+                .wrap(LimitByIp::new(limiters.token.clone()))
+                .route(web::post().to(reset_password))
+        )
+        .service(
+            web::resource("/challenge")
+                .wrap(LimitByIp::new(limiters.token.clone()))
+                .route(web::get().to(get_challenge))
+        )
+        .service(
+            web::resource("/login")
+                .wrap(from_fn(require_challenge))
+                .wrap(LimitByIp::new(limiters.token.clone()))
+                .route(web::post().to(login))
+        )
+        .service(
+            web::resource("/mfa/verify")
+                .wrap(from_fn(require_challenge))
+                .wrap(LimitByIp::new(limiters.token.clone()))
+                .route(web::post().to(mfa::verify))
+        )
+        .service(
+            web::resource("/refresh_token")
+                .wrap(LimitByIp::new(limiters.token.clone()))
+                .route(web::post().to(refresh_token))
+        )
+        .service(
+            web::resource("/oauth/{provider}/start")
+                .wrap(LimitByIp::new(limiters.token.clone()))
+                .route(web::get().to(oauth::start))
+        )
+        .service(
+            web::resource("/oauth/{provider}/callback")
+                .wrap(LimitByIp::new(limiters.token.clone()))
+                .route(web::get().to(oauth::callback))
+        )
+        .service(
+            web::resource("/oauth/signup")
+                .wrap(from_fn(require_challenge))
+                .wrap(LimitByIp::new(limiters.signup.clone()))
+                .route(web::post().to(oauth::signup))
+        )
+        .service(
+            web::resource("/oauth/device/code")
+                .wrap(LimitByIp::new(limiters.signup.clone()))
+                .route(web::post().to(request_device_code))
+        )
+        .service(
+            web::resource("/oauth/token")
+                .wrap(LimitByIp::new(limiters.token.clone()))
+                .route(web::post().to(oauth_token))
+        )
+        .service(
+            web::resource(VERIFICATION_PATH)
+                .wrap(from_fn(require_challenge))
+                .wrap(LimitByIp::new(limiters.token.clone()))
+                .route(web::get().to(device_page))
+                .route(web::post().to(verify_device))
+        )
+        .service(
+            web::resource("/logout")
+                .wrap(HttpAuthentication::with_fn(session_or_bearer))
+                .route(web::post().to(logout))
+        )
+        .service(
+            web::resource("/me/token")
+                .wrap(HttpAuthentication::with_fn(session_or_bearer))
+                .route(web::get().to(token_info))
+        )
+        .service(
+            web::resource("/me/profile_gaps")
+                .wrap(HttpAuthentication::with_fn(session_or_bearer))
+                .route(web::get().to(profile_gaps))
+        )
+        .route(FIELD_METADATA_PATH, web::get().to(field_metadata::user_fields))
+        .service(
+            web::scope("/protected")
+                .wrap(from_fn(enforce_rate_limit))
+                .wrap(HttpAuthentication::with_fn(session_or_bearer))
+                .service(
+                    web::resource("/users")
+                        .wrap(Authorize::new("users:read"))
+                        .route(web::get().to(get_all_users))
+                )
+                .service(
+                    web::resource("/users/stream")
+                        .wrap(Authorize::new("users:read"))
+                        .route(web::get().to(stream_all_users))
+                )
+                .service(
+                    web::resource("/users/suggest")
+                        .wrap(Authorize::new("users:read"))
+                        .route(web::get().to(suggest))
+                )
+                .service(
+                    web::resource("/users/searches")
+                        .wrap(Authorize::new("users:read"))
+                        .route(web::get().to(get_searches))
+                        .route(web::post().to(create_search))
+                )
+                .service(
+                    web::resource("/users/searches/{id}")
+                        .wrap(Authorize::new("users:read"))
+                        .route(web::delete().to(delete_search))
+                )
+                .service(
+                    web::resource("/users/searches/{id}/results")
+                        .wrap(Authorize::new("users:read"))
+                        .route(web::get().to(get_search_results))
+                )
+                .route_with_policy("/users/import", Method::POST, policy!(scope MANAGE_USERS), import_users)
+                .route_with_policy("/users/export", Method::GET, policy!(scope EXPORT_USERS), export_users)
+                .service(
+                    web::resource("/users/{id}")
+                        .route(web::get().to(get_user).wrap(Authorize::new("users:read")))
+                        .route(web::put().to(update_user).wrap(Authorize::new("users:update")))
+                        .route(web::patch().to(patch_user).wrap(Authorize::new("users:update")))
+                        .route(web::delete().to(delete_user).wrap(Authorize::new("users:delete")))
+                )
+                .service(
+                    web::resource("/users/{id}/restore")
+                        .wrap(Authorize::new("users:delete"))
+                        .route(web::post().to(restore_user))
+                )
+                .service(
+                    web::resource("/users/{id}/graph")
+                        .wrap(Authorize::new("users:read"))
+                        .route(web::get().to(get_user_graph))
+                )
+                .route_with_policy("/users/{id}/timeline", Method::GET, policy!(scope VIEW_TIMELINE), get_user_timeline)
+                .route_with_policy("/audit", Method::GET, policy!(scope READ_AUDIT), get_audit_log)
+                .route("/route", web::get().to(protected_route))
+                .route("/mfa/enroll", web::post().to(mfa::enroll))
+                .route("/mfa/confirm", web::post().to(mfa::confirm))
+                .route_with_policy("/signed_urls", Method::POST, policy!(scope SIGN_URLS and scope EXPORT_USERS), create_signed_url)
+                .route_with_policy("/admin/read_only", Method::GET, policy!(role "admin"), get_read_only)
+                .route_with_policy("/admin/read_only", Method::PUT, policy!(role "admin"), set_read_only)
+                .route_with_policy("/admin/features", Method::GET, policy!(role "admin"), get_features)
+                .route_with_policy("/admin/features", Method::PUT, policy!(role "admin"), set_features)
+                .route_with_policy("/admin/tenants/{tenant_id}/usage", Method::GET, policy!(role "admin"), get_tenant_usage)
+        )
+        .service(
+            web::scope("/admin")
+                .wrap(from_fn(enforce_rate_limit))
+                .wrap(HttpAuthentication::with_fn(session_or_bearer))
+                .route_with_policy("/users", Method::POST, policy!(scope MANAGE_USERS), admin::create_user)
+                .route_with_policy("/users", Method::GET, policy!(role "admin"), admin::list_users)
+                .route_with_policy("/users/{id}/revoke_sessions", Method::POST, policy!(scope MANAGE_SESSIONS or role "admin"), admin::revoke_sessions)
+                .route_with_policy("/users/{id}/lock", Method::POST, policy!(role "admin"), admin::lock_user)
+                .route_with_policy("/users/{id}/unlock", Method::POST, policy!(role "admin"), admin::unlock_user)
+                .route_with_policy("/users/{id}/force_password_reset", Method::POST, policy!(role "admin"), admin::force_password_reset)
+                .route_with_policy("/users/{id}/mfa", Method::DELETE, policy!(role "admin"), admin::reset_mfa)
+                .route_with_policy("/users/{id}/honeytoken", Method::PUT, policy!(role "admin"), admin::set_honeytoken)
+                .route_with_policy("/users/{id}/disable", Method::POST, policy!(scope MANAGE_USERS), admin::disable_user)
+                .route_with_policy("/users/{id}/manager", Method::PUT, policy!(scope MANAGE_USERS), admin::set_manager)
+                .route_with_policy("/users/{id}/plan", Method::PUT, policy!(scope MANAGE_USERS), admin::set_user_plan)
+                .route_with_policy("/tenants/{tenant_id}/plan", Method::PUT, policy!(scope MANAGE_USERS), admin::set_tenant_plan)
+                .route_with_policy("/replication/conflicts", Method::GET, policy!(role "admin"), list_conflicts)
+                .route_with_policy("/audit/verify", Method::GET, policy!(scope VERIFY_AUDIT), verify_audit_log)
+                .route_with_policy("/approvals", Method::GET, policy!(scope MANAGE_USERS), admin::list_approvals)
+                .route_with_policy("/approvals/{id}/approve", Method::POST, policy!(scope MANAGE_USERS), admin::approve_user)
+                .route_with_policy("/approvals/{id}/reject", Method::POST, policy!(scope MANAGE_USERS), admin::reject_user)
+                .service(
+                    web::scope("")
+                        .wrap(RequirePermission::new(MANAGE_ROLES))
+                        .route("/roles", web::get().to(admin::list_roles))
+                        .route("/roles", web::post().to(admin::create_role))
+                        .route("/roles/{id}", web::put().to(admin::update_role))
+                        .route("/roles/{id}", web::delete().to(admin::delete_role))
+                        .route("/roles/{id}/permissions", web::get().to(admin::get_role_permissions))
+                        .route("/roles/{id}/permissions", web::put().to(admin::set_role_permissions))
+                        .route("/roles/{id}/assign", web::post().to(admin::assign_role))
+                        .route("/roles/{id}/rate_limit", web::put().to(admin::set_rate_limit))
+                        .route("/roles/{id}/rate_limit", web::delete().to(admin::clear_rate_limit))
+                        .route("/rate_limits", web::get().to(admin::list_rate_limits))
+                        .route("/users/{id}/roles", web::get().to(admin::get_user_roles))
+                        .route("/users/{id}/roles", web::put().to(admin::set_user_roles))
+                        .route("/policy/reload", web::post().to(reload_policy))
+                )
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    async fn test_v1_routes_are_also_served_unversioned() {
+        let app = init_service(App::new().service(web::scope(API_V1).configure(configure_v1)).configure(configure_v1)).await;
+
+        for uri in ["/api/v1/metadata/users/fields", "/metadata/users/fields"] {
+            let resp = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", uri);
+        }
+        let resp = call_service(&app, TestRequest::get().uri("/api/v2/metadata/users/fields").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,361 @@
+use actix_web::http::header::LINK;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, Mssql, Pool};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use crate::error::AppError;
+use crate::extractors::{AuthenticatedUser, UserId};
+use crate::pagination::{PageMeta, SortOrder, UserQuery, UserSort};
+use crate::repository::UserRepository;
+use crate::timestamp::Timestamp;
+use crate::visibility::Viewer;
+
+/// Longest name a saved search may have.
+pub const MAX_SEARCH_NAME_LENGTH: usize = 100;
+
+/// Most searches one account may save.
+pub const MAX_SAVED_SEARCHES: i32 = 50;
+
+/// Message of the `409 Conflict` returned when the caller already has a search with that name.
+pub const SEARCH_NAME_TAKEN: &str = "You already have a saved search with that name.";
+
+/// The filters and sort order of a saved search, as accepted by `GET /protected/users`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct SearchCriteria {
+    #[serde(default)]
+    pub sort: UserSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_contains: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub active_since: Option<Timestamp>,
+    /// Page size used when running the search without `per_page`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_page: Option<u32>,
+}
+
+impl SearchCriteria {
+    /// Returns the user query running these criteria, at the page and page size of
+    /// `paging` when given.
+    pub fn to_query(&self, paging: &UserQuery) -> UserQuery {
+        UserQuery {
+            page: paging.page,
+            per_page: paging.per_page.or(self.per_page),
+            sort: self.sort,
+            order: self.order,
+            email: self.email.clone(),
+            name_contains: self.name_contains.clone(),
+            active_since: self.active_since,
+            ..UserQuery::default()
+        }
+    }
+}
+
+/// Payload of `POST /protected/users/searches`.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewSavedSearch {
+    pub name: String,
+    #[serde(flatten)]
+    pub criteria: SearchCriteria,
+}
+
+impl NewSavedSearch {
+    /// Returns the trimmed name, or an error message if it is empty or too long.
+    fn name(&self) -> Result<&str, String> {
+        match self.name.trim() {
+            "" => Err("`name` must not be empty.".to_string()),
+            name if name.chars().count() > MAX_SEARCH_NAME_LENGTH => Err(format!("`name` must be at most {} characters.", MAX_SEARCH_NAME_LENGTH)),
+            name => Ok(name),
+        }
+    }
+}
+
+/// A search saved by the caller.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SavedSearch {
+    pub id: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub criteria: SearchCriteria,
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: Timestamp,
+}
+
+#[derive(Debug, FromRow)]
+struct SavedSearchRow {
+    id: String,
+    name: String,
+    criteria: String,
+    created_at: Timestamp,
+}
+
+impl SavedSearchRow {
+    fn into_search(self) -> Result<SavedSearch, AppError> {
+        let criteria = serde_json::from_str(&self.criteria).map_err(|e| AppError::Internal(format!("Invalid saved search criteria: {}", e)))?;
+        Ok(SavedSearch {
+            id: self.id,
+            name: self.name,
+            criteria,
+            created_at: self.created_at,
+        })
+    }
+}
+
+/// Returns the caller's id, which saved searches belong to.
+fn owner(user: &AuthenticatedUser) -> Result<String, AppError> {
+    UserId::try_from(user.id().to_string())
+        .map(|id| id.to_string())
+        .map_err(|_| AppError::validation("Saved searches are only available to user accounts."))
+}
+
+/// Returns the searches saved by `owner_id`, by name.
+pub async fn list_searches(pool: &Pool<Mssql>, owner_id: &str) -> Result<Vec<SavedSearch>, AppError> {
+    let rows = sqlx::query_as!(
+        SavedSearchRow,
+        r#"
+        SELECT
+            CAST(id AS VARCHAR(36))                 AS "id!",
+            Name                                    AS "name!",
+            Criteria                                AS "criteria!",
+            CONVERT(VARCHAR(33), CreatedAt, 126)    AS "created_at!: Timestamp"
+        FROM [saved_searches]
+        WHERE OwnerId = @p1
+        ORDER BY Name
+        "#,
+        owner_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    rows.into_iter().map(SavedSearchRow::into_search).collect()
+}
+
+/// Returns the search `id` if it belongs to `owner_id`.
+pub async fn find_search(pool: &Pool<Mssql>, owner_id: &str, id: &str) -> Result<Option<SavedSearch>, AppError> {
+    let row = sqlx::query_as!(
+        SavedSearchRow,
+        r#"
+        SELECT
+            CAST(id AS VARCHAR(36))                 AS "id!",
+            Name                                    AS "name!",
+            Criteria                                AS "criteria!",
+            CONVERT(VARCHAR(33), CreatedAt, 126)    AS "created_at!: Timestamp"
+        FROM [saved_searches]
+        WHERE id = @p1 AND OwnerId = @p2
+        "#,
+        id,
+        owner_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    row.map(SavedSearchRow::into_search).transpose()
+}
+
+/// Saves a named filter and sort order of the user list for the caller.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `user` - The caller, who owns the search.
+/// * `search` - The name of the search and the criteria of `GET /protected/users`.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - `201 Created` with the [`SavedSearch`], `400 Bad
+///   Request` for an invalid name or once [`MAX_SAVED_SEARCHES`] are saved, or `409
+///   Conflict` if the caller already has a search with that name.
+#[utoipa::path(
+    post,
+    path = "/protected/users/searches",
+    tag = "users",
+    request_body = NewSavedSearch,
+    responses(
+        (status = 201, description = "The saved search", body = SavedSearch),
+        (status = 400, description = "The name is invalid or too many searches are saved", body = ErrorBody),
+        (status = 401, description = "Missing, invalid or revoked token"),
+        (status = 409, description = "A search with that name already exists", body = ErrorBody),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn create_search(pool: web::Data<Pool<Mssql>>, user: AuthenticatedUser, search: web::Json<NewSavedSearch>) -> Result<HttpResponse, AppError> {
+    let owner_id = owner(&user)?;
+    let name = search.name().map_err(AppError::validation)?;
+    let criteria = serde_json::to_string(&search.criteria).map_err(|e| AppError::Internal(e.to_string()))?;
+
+    let mut tx = pool.begin().await?;
+    let saved = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!: i32" FROM [saved_searches] WITH (UPDLOCK) WHERE OwnerId = @p1"#, &owner_id)
+        .fetch_one(&mut tx)
+        .await?;
+    if saved >= MAX_SAVED_SEARCHES {
+        return Err(AppError::validation(format!("At most {} searches can be saved.", MAX_SAVED_SEARCHES)));
+    }
+
+    let row = sqlx::query_as!(
+        SavedSearchRow,
+        r#"
+        INSERT INTO [saved_searches] (OwnerId, Name, Criteria)
+        OUTPUT
+            CAST(INSERTED.id AS VARCHAR(36))              AS "id!",
+            INSERTED.Name                                 AS "name!",
+            INSERTED.Criteria                             AS "criteria!",
+            CONVERT(VARCHAR(33), INSERTED.CreatedAt, 126) AS "created_at!: Timestamp"
+        VALUES (@p1, @p2, @p3)
+        "#,
+        &owner_id,
+        name,
+        criteria
+    )
+    .fetch_one(&mut tx)
+    .await
+    .map_err(|e| AppError::conflict_if_unique(e, "saved_search_name_taken", SEARCH_NAME_TAKEN))?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Created().json(row.into_search()?))
+}
+
+/// Lists the searches saved by the caller.
+#[utoipa::path(
+    get,
+    path = "/protected/users/searches",
+    tag = "users",
+    responses(
+        (status = 200, description = "The caller's saved searches, by name", body = [SavedSearch]),
+        (status = 401, description = "Missing, invalid or revoked token"),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn get_searches(pool: web::Data<Pool<Mssql>>, user: AuthenticatedUser) -> Result<HttpResponse, AppError> {
+    let owner_id = owner(&user)?;
+    Ok(HttpResponse::Ok().json(list_searches(pool.get_ref(), &owner_id).await?))
+}
+
+/// Deletes one of the caller's saved searches.
+#[utoipa::path(
+    delete,
+    path = "/protected/users/searches/{id}",
+    tag = "users",
+    params(("id" = String, Path, description = "The id of the saved search")),
+    responses(
+        (status = 204, description = "The search has been deleted"),
+        (status = 401, description = "Missing, invalid or revoked token"),
+        (status = 404, description = "The caller has no search with that id", body = ErrorBody),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn delete_search(pool: web::Data<Pool<Mssql>>, user: AuthenticatedUser, path: web::Path<Uuid>) -> Result<HttpResponse, AppError> {
+    let owner_id = owner(&user)?;
+    let result = sqlx::query!("DELETE FROM [saved_searches] WHERE id = @p1 AND OwnerId = @p2", path.to_string(), &owner_id)
+        .execute(pool.get_ref())
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("saved_search"));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Runs one of the caller's saved searches.
+///
+/// The response has the same shape as `GET /protected/users`, including the `Link`
+/// header; `page` and `per_page` select the page of results.
+///
+/// # Arguments
+///
+/// * `req` - The HTTP request, used to build the `Link` header.
+/// * `pool` - A connection pool to the database, holding the saved searches.
+/// * `users` - The user repository.
+/// * `path` - The id of the saved search.
+/// * `paging` - The page to return.
+/// * `user` - The caller, who must own the search.
+/// * `viewer` - The caller, used to hide sensitive fields they may not see.
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - The page of users, or `404 Not Found` if the
+///   caller has no search with that id.
+#[utoipa::path(
+    get,
+    path = "/protected/users/searches/{id}/results",
+    tag = "users",
+    params(
+        ("id" = String, Path, description = "The id of the saved search"),
+        ("page" = Option<u32>, Query, description = "1-based page number"),
+        ("per_page" = Option<u32>, Query, description = "Page size, overriding the one saved"),
+    ),
+    responses(
+        (status = 200, description = "A page of the users matching the search", body = UserList),
+        (status = 401, description = "Missing, invalid or revoked token"),
+        (status = 404, description = "The caller has no search with that id", body = ErrorBody),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn get_search_results(
+    req: HttpRequest,
+    pool: web::Data<Pool<Mssql>>,
+    users: web::Data<Arc<dyn UserRepository>>,
+    path: web::Path<Uuid>,
+    paging: web::Query<UserQuery>,
+    user: AuthenticatedUser,
+    viewer: Viewer,
+) -> Result<HttpResponse, AppError> {
+    let owner_id = owner(&user)?;
+    let search = find_search(pool.get_ref(), &owner_id, &path.to_string())
+        .await?
+        .ok_or(AppError::NotFound("saved_search"))?;
+
+    let query = search.criteria.to_query(&paging);
+    let page = users.list(&query).await?;
+    let meta = PageMeta::new(&query, page.total);
+
+    Ok(HttpResponse::Ok()
+        .insert_header((LINK, meta.link_header(req.path(), &query)))
+        .json(json!({
+            "data": viewer.present_all(&page.users),
+            "meta": meta,
+        })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_criteria_keep_their_filters_when_paged() {
+        let criteria = SearchCriteria {
+            sort: UserSort::Email,
+            order: SortOrder::Desc,
+            name_contains: Some("jo".to_string()),
+            per_page: Some(50),
+            ..SearchCriteria::default()
+        };
+
+        let paging = UserQuery { page: Some(3), email: Some("ignored@example.com".to_string()), ..UserQuery::default() };
+        let query = criteria.to_query(&paging);
+        assert_eq!((query.page(), query.per_page()), (3, 50));
+        assert_eq!((query.sort, query.order), (UserSort::Email, SortOrder::Desc));
+        assert_eq!(query.name_contains.as_deref(), Some("jo"));
+        assert_eq!(query.email, None);
+
+        assert_eq!(criteria.to_query(&UserQuery { per_page: Some(10), ..UserQuery::default() }).per_page(), 10);
+    }
+
+    #[test]
+    fn test_new_search_needs_a_name() {
+        let search: NewSavedSearch = serde_json::from_str(r#"{"name": " Sales ", "sort": "email", "order": "desc"}"#).unwrap();
+        assert_eq!(search.name(), Ok("Sales"));
+        assert_eq!(search.criteria.sort, UserSort::Email);
+
+        let unnamed: NewSavedSearch = serde_json::from_str(r#"{"name": "  "}"#).unwrap();
+        assert!(unnamed.name().is_err());
+        let long = NewSavedSearch { name: "x".repeat(MAX_SEARCH_NAME_LENGTH + 1), criteria: SearchCriteria::default() };
+        assert!(long.name().is_err());
+    }
+}
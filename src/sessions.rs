@@ -0,0 +1,135 @@
+use chrono::{TimeZone, Utc};
+use sqlx::{FromRow, Mssql, Pool, Transaction};
+use crate::approvals::ACTIVE;
+use crate::auth::Claims;
+use crate::extractors::UserId;
+use crate::timestamp::Timestamp;
+
+/// Returns the current token version of a user, or `None` if the user does not exist.
+///
+/// Every issued token carries the version current at the time (`ver` claim); bumping
+/// the version invalidates all of the user's outstanding tokens at once.
+pub async fn token_version(pool: &Pool<Mssql>, user_id: &str) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT TokenVersion AS "token_version!: i32" FROM [users] WHERE id = @p1"#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Returns `true` unless the token was revoked through [`revoke_token`], was issued
+/// before the user's sessions were revoked, or the user is no longer [`ACTIVE`] or has
+/// passed their `expires_at`.
+///
+/// Tokens whose subject is not a known user are left alone, since there is no
+/// session state to compare them against.
+pub async fn is_current(pool: &Pool<Mssql>, claims: &Claims) -> Result<bool, sqlx::Error> {
+    if let Some(jti) = &claims.jti {
+        if is_revoked(pool, jti).await? {
+            return Ok(false);
+        }
+    }
+
+    let user_id = match UserId::try_from(claims.sub.clone()) {
+        Ok(user_id) => user_id,
+        Err(_) => return Ok(true),
+    };
+
+    let session = sqlx::query_as!(
+        SessionState,
+        r#"
+        SELECT
+            TokenVersion AS "token_version!",
+            CAST(CASE WHEN Status = @p2 AND DeletedAt IS NULL AND (ExpiresAt IS NULL OR ExpiresAt > SYSUTCDATETIME()) THEN 1 ELSE 0 END AS BIT) AS "active!"
+        FROM [users]
+        WHERE id = @p1
+        "#,
+        user_id.to_string(),
+        ACTIVE
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(match session {
+        Some(session) => session.active && claims.ver == session.token_version,
+        None => true,
+    })
+}
+
+/// What [`is_current`] needs to know about a token's subject.
+#[derive(Debug, FromRow)]
+struct SessionState {
+    token_version: i32,
+    active: bool,
+}
+
+/// Revokes a single access token by its `jti` claim until it expires.
+///
+/// Entries of tokens that have since expired are removed along the way, so the table
+/// only holds tokens that could still be presented.
+///
+/// # Returns
+///
+/// * `Result<bool, sqlx::Error>` - `Ok(false)` if the token has no `jti` or was already revoked.
+pub async fn revoke_token(pool: &Pool<Mssql>, claims: &Claims) -> Result<bool, sqlx::Error> {
+    let jti = match &claims.jti {
+        Some(jti) => jti,
+        None => return Ok(false),
+    };
+    let expires_at = Timestamp(Utc.timestamp_opt(claims.exp as i64, 0).single().unwrap_or_else(Utc::now));
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("DELETE FROM [revoked_tokens] WHERE ExpiresAt < SYSUTCDATETIME()")
+        .execute(&mut tx)
+        .await?;
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO [revoked_tokens] (Jti, UserId, ExpiresAt)
+        SELECT @p1, @p2, @p3
+        WHERE NOT EXISTS (SELECT 1 FROM [revoked_tokens] WHERE Jti = @p1)
+        "#,
+        jti,
+        &claims.sub,
+        expires_at
+    )
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Returns `true` if the token with this `jti` was revoked through [`revoke_token`].
+pub async fn is_revoked(pool: &Pool<Mssql>, jti: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT CAST(CASE WHEN EXISTS (SELECT 1 FROM [revoked_tokens] WHERE Jti = @p1) THEN 1 ELSE 0 END AS BIT) AS "revoked!: bool""#,
+        jti
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Revokes every session of a user, as part of `tx`, by bumping their token version
+/// and revoking all of their refresh tokens.
+///
+/// # Returns
+///
+/// * `Result<bool, sqlx::Error>` - `Ok(false)` if the user does not exist.
+pub async fn revoke_sessions(tx: &mut Transaction<'_, Mssql>, user_id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE [users] SET TokenVersion = TokenVersion + 1 WHERE id = @p1",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE [refresh_tokens] SET RevokedAt = SYSUTCDATETIME() WHERE UserId = @p1 AND RevokedAt IS NULL",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
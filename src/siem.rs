@@ -0,0 +1,420 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, Mssql, Pool};
+use std::env;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use crate::leader::JobLease;
+use crate::outbox::SinkError;
+use crate::timestamp::Timestamp;
+
+/// Name of the `[export_cursors]` row tracking the last audit entry shipped to the SIEM.
+pub const SIEM_CURSOR: &str = "siem";
+
+/// Elasticsearch index written to when `SIEM_INDEX` is not set.
+pub const DEFAULT_INDEX: &str = "safe-user-audit";
+
+/// Splunk source type of the exported events.
+pub const SOURCETYPE: &str = "safe_user:audit";
+
+/// Largest number of entries shipped in one request.
+const BATCH_SIZE: i32 = 500;
+
+/// Entries younger than this are left for the next run, so rows of transactions that
+/// took a lower `id` but committed later are not skipped by the cursor.
+const SETTLE_SECONDS: i32 = 2;
+
+/// Longest wait between attempts while the SIEM keeps failing.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Time allowed for a single request to an HTTP collector.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An `[audit_log]` entry as shipped to the SIEM.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    /// Monotonic id of the entry, usable by the SIEM for de-duplication.
+    pub id: i64,
+    pub user_id: String,
+    pub action: String,
+    pub actor_id: Option<String>,
+    pub details: Option<serde_json::Value>,
+    /// When it happened, as an RFC 3339 UTC timestamp.
+    pub created_at: Timestamp,
+}
+
+impl AuditEvent {
+    /// Returns `created_at` as seconds since the Unix epoch, as Splunk expects.
+    fn epoch_seconds(&self) -> f64 {
+        self.created_at.timestamp_millis() as f64 / 1000.0
+    }
+}
+
+/// An `[audit_log]` row, with the details still serialized.
+#[derive(Debug, FromRow)]
+struct AuditRow {
+    id: i64,
+    user_id: String,
+    action: String,
+    actor_id: Option<String>,
+    details: Option<String>,
+    created_at: Timestamp,
+}
+
+impl From<AuditRow> for AuditEvent {
+    fn from(row: AuditRow) -> Self {
+        AuditEvent {
+            id: row.id,
+            user_id: row.user_id,
+            action: row.action,
+            actor_id: row.actor_id,
+            details: row.details.and_then(|details| serde_json::from_str(&details).ok()),
+            created_at: row.created_at,
+        }
+    }
+}
+
+/// Destination for exported audit entries (Splunk, Elasticsearch, syslog, ...).
+///
+/// Delivery is at-least-once: the cursor only moves past a batch after `ship`
+/// returns `Ok`, so a batch that failed halfway is sent again in full and
+/// implementations must tolerate duplicates.
+#[async_trait]
+pub trait AuditSink: Send + Sync {
+    async fn ship(&self, events: &[AuditEvent]) -> Result<(), SinkError>;
+}
+
+/// Sink posting batches to a Splunk HTTP Event Collector.
+pub struct SplunkHecSink {
+    client: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+impl SplunkHecSink {
+    /// Creates a sink posting to the HEC endpoint `url` (e.g.
+    /// `https://splunk:8088/services/collector/event`) with the HEC `token`.
+    pub fn new(url: String, token: String) -> Result<Self, SinkError> {
+        Ok(SplunkHecSink { client: http_client()?, url, token })
+    }
+
+    /// Returns the request body for `events`: one HEC event object per entry, concatenated.
+    pub fn body(events: &[AuditEvent]) -> String {
+        let mut body = String::new();
+        for event in events {
+            let hec = json!({ "time": event.epoch_seconds(), "sourcetype": SOURCETYPE, "event": event });
+            body.push_str(&hec.to_string());
+        }
+        body
+    }
+}
+
+#[async_trait]
+impl AuditSink for SplunkHecSink {
+    async fn ship(&self, events: &[AuditEvent]) -> Result<(), SinkError> {
+        self.client
+            .post(&self.url)
+            .header("Authorization", format!("Splunk {}", self.token))
+            .body(SplunkHecSink::body(events))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Sink indexing batches into Elasticsearch through the `_bulk` API.
+///
+/// Documents are indexed with the entry id as `_id`, so re-sent batches overwrite
+/// instead of duplicating.
+pub struct ElasticSink {
+    client: reqwest::Client,
+    url: String,
+    index: String,
+    api_key: Option<String>,
+}
+
+impl ElasticSink {
+    /// Creates a sink writing to `index` on the cluster at `url`, authenticating with
+    /// `api_key` if given.
+    pub fn new(url: String, index: String, api_key: Option<String>) -> Result<Self, SinkError> {
+        Ok(ElasticSink { client: http_client()?, url, index, api_key })
+    }
+
+    /// Returns the NDJSON `_bulk` body indexing `events` into `index`.
+    pub fn body(index: &str, events: &[AuditEvent]) -> String {
+        let mut body = String::new();
+        for event in events {
+            let action = json!({ "index": { "_index": index, "_id": event.id.to_string() } });
+            let _ = writeln!(body, "{}", action);
+            let _ = writeln!(body, "{}", json!(event));
+        }
+        body
+    }
+}
+
+#[async_trait]
+impl AuditSink for ElasticSink {
+    async fn ship(&self, events: &[AuditEvent]) -> Result<(), SinkError> {
+        let mut request = self
+            .client
+            .post(format!("{}/_bulk", self.url.trim_end_matches('/')))
+            .header("Content-Type", "application/x-ndjson")
+            .body(ElasticSink::body(&self.index, events));
+        if let Some(api_key) = &self.api_key {
+            request = request.header("Authorization", format!("ApiKey {}", api_key));
+        }
+
+        let response: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+        // `_bulk` answers 200 even when some documents were rejected.
+        if response["errors"].as_bool().unwrap_or(false) {
+            return Err("Elasticsearch rejected part of the batch".into());
+        }
+        Ok(())
+    }
+}
+
+/// Sink sending each entry as an RFC 5424 syslog message over UDP.
+pub struct SyslogSink {
+    address: String,
+}
+
+impl SyslogSink {
+    /// Priority of the messages: facility `authpriv` (10), severity `info` (6).
+    const PRIORITY: u8 = 10 * 8 + 6;
+
+    /// Creates a sink sending to the syslog server at `address` (`host:port`).
+    pub fn new(address: String) -> Self {
+        SyslogSink { address }
+    }
+
+    /// Formats `event` as an RFC 5424 message whose `MSGID` is the action and whose
+    /// body is the entry as JSON.
+    pub fn message(event: &AuditEvent) -> String {
+        format!(
+            "<{}>1 {} - safe_user - {} - {}",
+            SyslogSink::PRIORITY,
+            event.created_at,
+            event.action,
+            json!(event)
+        )
+    }
+}
+
+#[async_trait]
+impl AuditSink for SyslogSink {
+    async fn ship(&self, events: &[AuditEvent]) -> Result<(), SinkError> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(&self.address).await?;
+        for event in events {
+            socket.send(SyslogSink::message(event).as_bytes()).await?;
+        }
+        Ok(())
+    }
+}
+
+fn http_client() -> Result<reqwest::Client, SinkError> {
+    Ok(reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?)
+}
+
+/// Builds the sink selected by `SIEM_SINK`, or `None` when exporting is disabled.
+///
+/// * `splunk` - [`SplunkHecSink`], posting to `SIEM_URL` with the HEC token `SIEM_TOKEN`.
+/// * `elastic` - [`ElasticSink`], writing to `SIEM_INDEX` (default [`DEFAULT_INDEX`]) on the
+///   cluster at `SIEM_URL`, with the optional API key `SIEM_TOKEN`.
+/// * `syslog` - [`SyslogSink`], sending to `SIEM_SYSLOG_ADDR`.
+pub fn sink_from_env() -> Result<Option<Arc<dyn AuditSink>>, SinkError> {
+    let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+    let required = |name: &str| var(name).ok_or_else(|| format!("{} must be set for SIEM_SINK", name));
+
+    let sink: Arc<dyn AuditSink> = match var("SIEM_SINK").map(|sink| sink.to_lowercase()).as_deref() {
+        None => return Ok(None),
+        Some("splunk") => Arc::new(SplunkHecSink::new(required("SIEM_URL")?, required("SIEM_TOKEN")?)?),
+        Some("elastic") => Arc::new(ElasticSink::new(
+            required("SIEM_URL")?,
+            var("SIEM_INDEX").unwrap_or_else(|| DEFAULT_INDEX.to_string()),
+            var("SIEM_TOKEN"),
+        )?),
+        Some("syslog") => Arc::new(SyslogSink::new(required("SIEM_SYSLOG_ADDR")?)),
+        Some(other) => return Err(format!("Unknown SIEM_SINK `{}`", other).into()),
+    };
+    Ok(Some(sink))
+}
+
+/// Returns the id of the last entry shipped through the cursor `name`.
+pub async fn cursor_position(pool: &Pool<Mssql>, name: &str) -> Result<i64, sqlx::Error> {
+    let position = sqlx::query_scalar!(
+        r#"SELECT LastId AS "last_id!: i64" FROM [export_cursors] WHERE Name = @p1"#,
+        name
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(position.unwrap_or(0))
+}
+
+/// Ships the next batch of audit entries after the [`SIEM_CURSOR`] and moves the
+/// cursor past them once the sink has accepted them.
+///
+/// # Returns
+///
+/// * `Result<usize, SinkError>` - The number of entries shipped, or the database or
+///   delivery error; the cursor is left untouched on error.
+pub async fn export_batch(pool: &Pool<Mssql>, sink: &dyn AuditSink) -> Result<usize, SinkError> {
+    let position = cursor_position(pool, SIEM_CURSOR).await?;
+
+    let rows = sqlx::query_as!(
+        AuditRow,
+        r#"
+        SELECT TOP (@p2)
+            id                                         AS "id!",
+            UserId                                     AS "user_id!",
+            Action                                     AS "action!",
+            ActorId                                    AS "actor_id?",
+            Details                                    AS "details?",
+            CONVERT(VARCHAR(33), CreatedAt, 126) AS "created_at!: Timestamp"
+        FROM [audit_log]
+        WHERE id > @p1 AND CreatedAt < DATEADD(SECOND, -@p3, SYSUTCDATETIME())
+        ORDER BY id
+        "#,
+        position,
+        BATCH_SIZE,
+        SETTLE_SECONDS
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let last_id = match rows.last() {
+        Some(row) => row.id,
+        None => return Ok(0),
+    };
+    let events: Vec<AuditEvent> = rows.into_iter().map(AuditEvent::from).collect();
+    sink.ship(&events).await?;
+
+    sqlx::query!(
+        "UPDATE [export_cursors] SET LastId = @p2, UpdatedAt = SYSUTCDATETIME() WHERE Name = @p1 AND LastId < @p2",
+        SIEM_CURSOR,
+        last_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(events.len())
+}
+
+/// Returns how long to wait after another failed attempt, doubling up to [`MAX_BACKOFF`].
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::siem::next_backoff;
+/// use std::time::Duration;
+///
+/// assert_eq!(next_backoff(Duration::from_secs(5)), Duration::from_secs(10));
+/// assert_eq!(next_backoff(Duration::from_secs(600)), Duration::from_secs(300));
+/// ```
+pub fn next_backoff(current: Duration) -> Duration {
+    current.saturating_mul(2).min(MAX_BACKOFF)
+}
+
+/// Spawns the background task streaming new audit entries to `sink`.
+///
+/// Entries are read from the database at the exporter's own pace, so a slow or
+/// unavailable SIEM never holds up requests. Full batches are followed immediately by
+/// the next one until the exporter has caught up, after which it polls every
+/// `interval`; failures back off exponentially up to five minutes. Only the replica
+/// holding the `siem_exporter` [`JobLease`] exports; the others check every `interval`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use safe_user::config::AppConfig;
+/// use safe_user::db::DbPool;
+/// use safe_user::siem::{sink_from_env, spawn_exporter};
+///
+/// #[actix_web::main]
+/// async fn main() {
+///     let config = AppConfig::load().unwrap();
+///     let pool = DbPool::connect(&config.database_url).await.unwrap().pool;
+///     if let Some(sink) = sink_from_env().unwrap() {
+///         spawn_exporter(pool, sink, Duration::from_secs(5));
+///     }
+/// }
+/// ```
+pub fn spawn_exporter(pool: Pool<Mssql>, sink: Arc<dyn AuditSink>, interval: Duration) {
+    let lease = JobLease::new(pool.clone(), "siem_exporter", interval);
+    actix_web::rt::spawn(async move {
+        let mut backoff = interval;
+        loop {
+            if !lease.acquire().await {
+                tokio::time::sleep(interval).await;
+                continue;
+            }
+            let wait = match export_batch(&pool, sink.as_ref()).await {
+                Ok(shipped) => {
+                    backoff = interval;
+                    if shipped == BATCH_SIZE as usize {
+                        continue;
+                    }
+                    interval
+                }
+                Err(e) => {
+                    eprintln!("Error exporting audit log: {:?}", e);
+                    backoff = next_backoff(backoff);
+                    backoff
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: i64) -> AuditEvent {
+        AuditEvent {
+            id,
+            user_id: "813B6B04-DFBB-4EED-B820-2372216A2367".to_string(),
+            action: "login".to_string(),
+            actor_id: None,
+            details: None,
+            created_at: "2024-01-31T18:00:00.500Z".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_splunk_body_concatenates_events_with_time() {
+        let body = SplunkHecSink::body(&[event(1), event(2)]);
+        let events: Vec<serde_json::Value> = serde_json::Deserializer::from_str(&body)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["sourcetype"], SOURCETYPE);
+        assert_eq!(events[1]["event"]["id"], 2);
+        assert_eq!(events[0]["time"], 1706724000.5);
+    }
+
+    #[test]
+    fn test_elastic_body_uses_entry_ids() {
+        let body = ElasticSink::body(DEFAULT_INDEX, &[event(7)]);
+        let lines: Vec<serde_json::Value> = body.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+
+        assert_eq!(lines[0]["index"]["_id"], "7");
+        assert_eq!(lines[0]["index"]["_index"], DEFAULT_INDEX);
+        assert_eq!(lines[1]["action"], "login");
+        assert!(body.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_syslog_message_format() {
+        let message = SyslogSink::message(&event(1));
+        assert!(message.starts_with("<86>1 2024-01-31T18:00:00.500Z - safe_user - login - {"));
+    }
+}
@@ -0,0 +1,248 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Mssql, Pool};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use utoipa::{IntoParams, ToSchema};
+use crate::error::AppError;
+use crate::pagination::escape_like;
+
+/// Suggestions returned when `limit` is not given.
+pub const DEFAULT_SUGGESTIONS: u32 = 10;
+
+/// Largest `limit` accepted by the suggestion endpoint.
+pub const MAX_SUGGESTIONS: u32 = 25;
+
+/// Seconds suggestions are cached for when `SUGGEST_CACHE_TTL_SECS` is not set.
+pub const DEFAULT_CACHE_TTL_SECS: u64 = 30;
+
+/// Longest prefix accepted, matching the width of the `Email` column.
+const MAX_QUERY_LENGTH: usize = 100;
+
+/// Number of cached prefixes above which expired entries are dropped.
+const MAX_CACHED_QUERIES: usize = 1_000;
+
+/// Query parameters of the suggestion endpoint: `?q=jo&limit=10`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SuggestQuery {
+    /// Prefix of the first name, last name or email to complete.
+    pub q: String,
+    /// Maximum number of suggestions, at most [`MAX_SUGGESTIONS`].
+    pub limit: Option<u32>,
+}
+
+impl SuggestQuery {
+    /// Returns the prefix with surrounding whitespace removed.
+    pub fn prefix(&self) -> &str {
+        self.q.trim()
+    }
+
+    /// Returns the requested number of suggestions, clamped to `1..=MAX_SUGGESTIONS`.
+    pub fn limit(&self) -> u32 {
+        self.limit.unwrap_or(DEFAULT_SUGGESTIONS).clamp(1, MAX_SUGGESTIONS)
+    }
+}
+
+/// A user matching a typeahead prefix, with only the fields needed to display it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct Suggestion {
+    pub id: String,
+    pub name: String,
+    pub last_name: String,
+    pub email: String,
+}
+
+/// Cached suggestions, keyed by lowercased prefix and limit, with when they were stored.
+type CacheEntries = HashMap<(String, u32), (Instant, Vec<Suggestion>)>;
+
+/// Recently returned suggestions, keyed by lowercased prefix and limit.
+///
+/// Typeahead fires a request per keystroke, often with the same prefixes from several
+/// admins at once, so results are kept for a few seconds instead of querying the
+/// database every time. Edits to users show up once the entry expires.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::suggest::SuggestionCache;
+/// use std::time::Duration;
+///
+/// let cache = SuggestionCache::new(Duration::from_secs(30));
+/// cache.put("Jo", 10, Vec::new());
+///
+/// assert_eq!(cache.get("jo", 10), Some(Vec::new()));
+/// assert_eq!(cache.get("jo", 5), None);
+/// ```
+#[derive(Debug)]
+pub struct SuggestionCache {
+    ttl: Duration,
+    entries: Mutex<CacheEntries>,
+}
+
+impl SuggestionCache {
+    /// Creates an empty cache whose entries live for `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        SuggestionCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates an empty cache with the lifetime from `SUGGEST_CACHE_TTL_SECS`.
+    ///
+    /// A lifetime of `0` disables caching.
+    pub fn from_env() -> Self {
+        let ttl = env::var("SUGGEST_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(DEFAULT_CACHE_TTL_SECS);
+        SuggestionCache::new(Duration::from_secs(ttl))
+    }
+
+    /// Returns the suggestions cached for `prefix` and `limit`, if they have not expired.
+    pub fn get(&self, prefix: &str, limit: u32) -> Option<Vec<Suggestion>> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(&(prefix.to_lowercase(), limit)) {
+            Some((stored, suggestions)) if stored.elapsed() < self.ttl => Some(suggestions.clone()),
+            _ => None,
+        }
+    }
+
+    /// Caches the suggestions returned for `prefix` and `limit`.
+    pub fn put(&self, prefix: &str, limit: u32, suggestions: Vec<Suggestion>) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_CACHED_QUERIES {
+            let ttl = self.ttl;
+            entries.retain(|_, (stored, _)| stored.elapsed() < ttl);
+            if entries.len() >= MAX_CACHED_QUERIES {
+                entries.clear();
+            }
+        }
+        entries.insert((prefix.to_lowercase(), limit), (Instant::now(), suggestions));
+    }
+}
+
+/// Returns up to `limit` users whose first name, last name or email starts with `prefix`,
+/// ordered by name.
+///
+/// Only the columns of [`Suggestion`] are read, and the prefix is matched with a
+/// leading-anchored `LIKE` so the name and email indexes can be used.
+pub async fn suggest_users(pool: &Pool<Mssql>, prefix: &str, limit: u32) -> Result<Vec<Suggestion>, sqlx::Error> {
+    let pattern = format!("{}%", escape_like(prefix));
+
+    sqlx::query_as!(
+        Suggestion,
+        r#"
+        SELECT TOP (@p2)
+            CAST(id AS VARCHAR(36)) AS "id!",
+            Name                    AS "name!",
+            LastName                AS "last_name!",
+            Email                   AS "email!"
+        FROM [users]
+        WHERE (Name LIKE @p1 ESCAPE '\'
+            OR LastName LIKE @p1 ESCAPE '\'
+            OR Email LIKE @p1 ESCAPE '\')
+            AND DeletedAt IS NULL
+        ORDER BY Name, LastName, id
+        "#,
+        pattern,
+        limit as i32
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Suggests users for a typeahead box from a prefix of their name or email.
+///
+/// Meant for autocompletion in admin tools: it reads a handful of columns, matches
+/// prefixes only and serves repeated prefixes from the [`SuggestionCache`], so it is
+/// much cheaper than `GET /protected/users` with `name_contains`.
+///
+/// # Arguments
+///
+/// * `pool` - A connection pool to the database.
+/// * `cache` - The cache of recent suggestions.
+/// * `query` - The prefix (`q`) and the number of suggestions (`limit`).
+///
+/// # Returns
+///
+/// * `Result<HttpResponse, AppError>` - A JSON array of suggestions, or `400 Bad Request`
+///   if `q` is empty or too long.
+#[utoipa::path(
+    get,
+    path = "/protected/users/suggest",
+    tag = "users",
+    params(SuggestQuery),
+    responses(
+        (status = 200, description = "Users whose name or email starts with `q`", body = [Suggestion]),
+        (status = 400, description = "`q` is empty or too long", body = ErrorBody),
+        (status = 401, description = "Missing, invalid or revoked token"),
+        (status = 403, description = "The caller may not read users"),
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn suggest(pool: web::Data<Pool<Mssql>>, cache: web::Data<SuggestionCache>, query: web::Query<SuggestQuery>) -> Result<HttpResponse, AppError> {
+    let prefix = query.prefix();
+    if prefix.is_empty() {
+        return Err(AppError::validation("`q` must not be empty."));
+    }
+    if prefix.chars().count() > MAX_QUERY_LENGTH {
+        return Err(AppError::validation(format!("`q` must be at most {} characters.", MAX_QUERY_LENGTH)));
+    }
+
+    let limit = query.limit();
+    if let Some(suggestions) = cache.get(prefix, limit) {
+        return Ok(HttpResponse::Ok().json(suggestions));
+    }
+
+    let suggestions = suggest_users(pool.get_ref(), prefix, limit).await?;
+    cache.put(prefix, limit, suggestions.clone());
+    Ok(HttpResponse::Ok().json(suggestions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suggestion(name: &str) -> Suggestion {
+        Suggestion {
+            id: "813B6B04-DFBB-4EED-B820-2372216A2367".to_string(),
+            name: name.to_string(),
+            last_name: "Doe".to_string(),
+            email: "jhon@example.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_query_prefix_and_limit() {
+        let query = SuggestQuery { q: "  jo ".to_string(), limit: None };
+        assert_eq!((query.prefix(), query.limit()), ("jo", DEFAULT_SUGGESTIONS));
+
+        let query = SuggestQuery { q: "jo".to_string(), limit: Some(500) };
+        assert_eq!(query.limit(), MAX_SUGGESTIONS);
+    }
+
+    #[test]
+    fn test_cache_is_case_insensitive_and_expires() {
+        let cache = SuggestionCache::new(Duration::from_millis(50));
+        cache.put("JO", 10, vec![suggestion("Jhon")]);
+
+        assert_eq!(cache.get("jo", 10), Some(vec![suggestion("Jhon")]));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get("jo", 10), None);
+    }
+
+    #[test]
+    fn test_zero_ttl_disables_cache() {
+        let cache = SuggestionCache::new(Duration::ZERO);
+        cache.put("jo", 10, vec![suggestion("Jhon")]);
+        assert_eq!(cache.get("jo", 10), None);
+    }
+}
@@ -0,0 +1,185 @@
+use actix_web::web;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use crate::config::{flag, ConfigError};
+use crate::metrics::Metrics;
+
+/// Seconds between two reports when `TELEMETRY_INTERVAL_SECS` is not set.
+pub const DEFAULT_TELEMETRY_INTERVAL_SECS: u64 = 3600;
+
+/// Time allowed for a report to be accepted, so a slow collector never piles up requests.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where and how often anonymous usage reports are sent.
+///
+/// Telemetry is off unless `TELEMETRY_ENABLED=true`, which also acts as the kill
+/// switch: with it unset or `false` nothing is ever sent, whatever else is configured.
+///
+/// * `TELEMETRY_ENDPOINT` (required when enabled) - URL the reports are `POST`ed to.
+/// * `TELEMETRY_INTERVAL_SECS` (default [`DEFAULT_TELEMETRY_INTERVAL_SECS`]) - Time between reports.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::telemetry::TelemetryConfig;
+///
+/// assert!(TelemetryConfig::from_vars(|_| None).unwrap().is_none());
+///
+/// let config = TelemetryConfig::from_vars(|name| match name {
+///     "TELEMETRY_ENABLED" => Some("true".to_string()),
+///     "TELEMETRY_ENDPOINT" => Some("https://telemetry.internal.example/v1/reports".to_string()),
+///     _ => None,
+/// })
+/// .unwrap()
+/// .unwrap();
+/// assert_eq!(config.interval.as_secs(), 3600);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TelemetryConfig {
+    pub endpoint: String,
+    pub interval: Duration,
+}
+
+impl TelemetryConfig {
+    /// Reads the configuration from the variables returned by `var`, `None` when telemetry is off.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Option<Self>, ConfigError> {
+        if !flag(&var, "TELEMETRY_ENABLED")? {
+            return Ok(None);
+        }
+
+        let endpoint = match var("TELEMETRY_ENDPOINT") {
+            Some(endpoint) if endpoint.starts_with("https://") || endpoint.starts_with("http://") => endpoint,
+            Some(endpoint) => return Err(format!("TELEMETRY_ENDPOINT must be an http(s) URL, got `{}`", endpoint).into()),
+            None => return Err("TELEMETRY_ENDPOINT must be set when TELEMETRY_ENABLED is true".into()),
+        };
+        let interval = match var("TELEMETRY_INTERVAL_SECS") {
+            Some(secs) => match secs.parse::<u64>() {
+                Ok(secs) if secs > 0 => secs,
+                _ => return Err(format!("TELEMETRY_INTERVAL_SECS must be a positive number of seconds, got `{}`", secs).into()),
+            },
+            None => DEFAULT_TELEMETRY_INTERVAL_SECS,
+        };
+
+        Ok(Some(TelemetryConfig {
+            endpoint,
+            interval: Duration::from_secs(interval),
+        }))
+    }
+}
+
+/// One anonymous usage report.
+///
+/// It holds no user data, addresses or host names: only request counts by route
+/// pattern (such as `GET /protected/users/{id}`), the service version and uptime,
+/// and an id drawn at random when the process starts so reports of one instance can
+/// be told apart without identifying it.
+#[derive(Debug, Clone, Serialize)]
+pub struct TelemetryReport {
+    pub instance_id: String,
+    pub version: &'static str,
+    pub uptime_seconds: u64,
+    /// Requests handled since the process started, by method and route pattern.
+    pub requests: BTreeMap<String, u64>,
+}
+
+impl TelemetryReport {
+    /// Builds a report from the current request counters.
+    pub fn collect(instance_id: &str, started: Instant, metrics: &Metrics) -> Self {
+        TelemetryReport {
+            instance_id: instance_id.to_string(),
+            version: env!("CARGO_PKG_VERSION"),
+            uptime_seconds: started.elapsed().as_secs(),
+            requests: metrics.request_counts(),
+        }
+    }
+}
+
+/// Spawns the background task sending a [`TelemetryReport`] to `config.endpoint`
+/// every `config.interval`.
+///
+/// A failed report is logged and dropped; the next one carries the updated totals.
+///
+/// # Examples
+///
+/// ```no_run
+/// use actix_web::web;
+/// use safe_user::config::AppConfig;
+/// use safe_user::metrics::Metrics;
+/// use safe_user::telemetry::spawn_reporter;
+///
+/// #[actix_web::main]
+/// async fn main() {
+///     let config = AppConfig::load().unwrap();
+///     let metrics = web::Data::new(Metrics::new());
+///     if let Some(telemetry) = config.telemetry {
+///         spawn_reporter(metrics.clone(), telemetry);
+///     }
+/// }
+/// ```
+pub fn spawn_reporter(metrics: web::Data<Metrics>, config: TelemetryConfig) {
+    let client = reqwest::Client::new();
+    let instance_id = Uuid::new_v4().to_string();
+    let started = Instant::now();
+
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(config.interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let report = TelemetryReport::collect(&instance_id, started, &metrics);
+            let result = client
+                .post(&config.endpoint)
+                .json(&report)
+                .timeout(REPORT_TIMEOUT)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = result {
+                eprintln!("Error sending telemetry report: {:?}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(vars: &[(&str, &str)]) -> Result<Option<TelemetryConfig>, ConfigError> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        TelemetryConfig::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_telemetry_is_off_unless_enabled() {
+        assert_eq!(config(&[("TELEMETRY_ENDPOINT", "https://t.example")]).unwrap(), None);
+        assert_eq!(config(&[("TELEMETRY_ENABLED", "false"), ("TELEMETRY_ENDPOINT", "https://t.example")]).unwrap(), None);
+
+        let enabled = config(&[("TELEMETRY_ENABLED", "true"), ("TELEMETRY_ENDPOINT", "https://t.example"), ("TELEMETRY_INTERVAL_SECS", "60")]).unwrap();
+        assert_eq!(enabled.unwrap().interval, Duration::from_secs(60));
+
+        assert!(config(&[("TELEMETRY_ENABLED", "true")]).is_err());
+        assert!(config(&[("TELEMETRY_ENABLED", "true"), ("TELEMETRY_ENDPOINT", "t.example")]).is_err());
+        assert!(config(&[("TELEMETRY_ENABLED", "true"), ("TELEMETRY_ENDPOINT", "https://t.example"), ("TELEMETRY_INTERVAL_SECS", "0")]).is_err());
+    }
+
+    #[test]
+    fn test_report_only_holds_route_counts() {
+        let metrics = Metrics::new();
+        metrics.observe_request("GET", "/protected/users/{id}", 200, 0.01);
+        metrics.observe_request("GET", "/protected/users/{id}", 404, 0.01);
+        metrics.observe_request("POST", "/login", 401, 0.01);
+
+        let report = TelemetryReport::collect("instance", Instant::now(), &metrics);
+        assert_eq!(report.requests.get("GET /protected/users/{id}"), Some(&2));
+        assert_eq!(report.requests.get("POST /login"), Some(&1));
+
+        let body = serde_json::to_value(&report).unwrap();
+        let mut keys: Vec<&str> = body.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["instance_id", "requests", "uptime_seconds", "version"]);
+    }
+}
@@ -0,0 +1,254 @@
+use jsonwebtoken::{decode, decode_header, encode, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::errors::{Error as JwtError, ErrorKind};
+use sqlx::{FromRow, Mssql, Pool};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use crate::auth::Claims;
+
+/// Header naming the tenant a token is requested for in multi-tenant mode.
+pub const TENANT_HEADER: &str = "X-Tenant-Id";
+
+/// Returns `true` if the `MULTI_TENANT` environment variable enables multi-tenant mode.
+pub fn multi_tenant_enabled() -> bool {
+    env::var("MULTI_TENANT")
+        .map(|value| matches!(value.to_lowercase().as_str(), "true" | "1" | "yes"))
+        .unwrap_or(false)
+}
+
+/// A tenant's signing key, stored in the `[tenant_keys]` table.
+#[derive(Clone, FromRow)]
+pub struct TenantKey {
+    /// Key id, sent in the JWT `kid` header.
+    pub kid: String,
+    pub tenant_id: String,
+    /// Value of the `iss` claim for tokens signed with this key.
+    pub issuer: String,
+    pub secret: String,
+}
+
+impl fmt::Debug for TenantKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TenantKey")
+            .field("kid", &self.kid)
+            .field("tenant_id", &self.tenant_id)
+            .field("issuer", &self.issuer)
+            .field("secret", &"[REDACTED]")
+            .finish()
+    }
+}
+
+/// Minimum time between two reloads of the tenant keys triggered by unknown key ids.
+const MIN_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// In-memory cache of the active tenant keys, indexed by `kid`.
+///
+/// Each token is verified only with the key its `kid` names, and its `iss` must
+/// match that key's issuer, so a leaked secret cannot mint tokens for other tenants.
+#[derive(Clone, Default)]
+pub struct TenantKeys {
+    keys: Arc<RwLock<HashMap<String, TenantKey>>>,
+    last_reload: Arc<Mutex<Option<Instant>>>,
+}
+
+impl TenantKeys {
+    /// Creates a cache holding `keys`.
+    pub fn new(keys: Vec<TenantKey>) -> Self {
+        let cache = TenantKeys::default();
+        cache.replace(keys);
+        cache
+    }
+
+    /// Loads the active keys from the database.
+    pub async fn load(pool: &Pool<Mssql>) -> Result<Self, sqlx::Error> {
+        let cache = TenantKeys::default();
+        cache.refresh(pool).await?;
+        Ok(cache)
+    }
+
+    /// Reloads the active keys from the database, picking up added or revoked keys.
+    pub async fn refresh(&self, pool: &Pool<Mssql>) -> Result<(), sqlx::Error> {
+        let keys = sqlx::query_as!(
+            TenantKey,
+            r#"
+            SELECT
+                Kid      AS "kid!",
+                TenantId AS "tenant_id!",
+                Issuer   AS "issuer!",
+                Secret   AS "secret!"
+            FROM [tenant_keys]
+            WHERE Active = 1
+            "#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        self.replace(keys);
+        Ok(())
+    }
+
+    fn replace(&self, keys: Vec<TenantKey>) {
+        *self.keys.write().unwrap() = keys.into_iter().map(|key| (key.kid.clone(), key)).collect();
+    }
+
+    /// Validates a token with the key named by its `kid` header.
+    ///
+    /// Unknown key ids trigger a reload from `pool` (when given) so keys added
+    /// after startup are accepted without a restart. Reloads happen at most once
+    /// every [`MIN_RELOAD_INTERVAL`], so tokens with made-up key ids cannot make
+    /// every request read the whole table.
+    pub async fn validate(&self, token: &str, pool: Option<&Pool<Mssql>>) -> Result<Claims, JwtError> {
+        let kid = token_kid(token)?;
+
+        let key = match (self.get(&kid), pool) {
+            (Some(key), _) => key,
+            (None, Some(pool)) if self.claim_reload() => {
+                if let Err(e) = self.refresh(pool).await {
+                    eprintln!("Error reloading tenant keys: {:?}", e);
+                }
+                self.get(&kid).ok_or_else(|| JwtError::from(ErrorKind::InvalidToken))?
+            }
+            (None, _) => return Err(ErrorKind::InvalidToken.into()),
+        };
+
+        validate_tenant_jwt(token, &key)
+    }
+
+    /// Returns `true`, and starts a new interval, if no reload for an unknown key id
+    /// happened in the last [`MIN_RELOAD_INTERVAL`].
+    fn claim_reload(&self) -> bool {
+        let mut last_reload = self.last_reload.lock().unwrap();
+        match *last_reload {
+            Some(at) if at.elapsed() < MIN_RELOAD_INTERVAL => false,
+            _ => {
+                *last_reload = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    /// Returns the key with the given `kid`.
+    pub fn get(&self, kid: &str) -> Option<TenantKey> {
+        self.keys.read().unwrap().get(kid).cloned()
+    }
+
+    /// Returns the key used to sign new tokens for a tenant.
+    pub fn signing_key_for(&self, tenant_id: &str) -> Option<TenantKey> {
+        self.keys
+            .read()
+            .unwrap()
+            .values()
+            .filter(|key| key.tenant_id == tenant_id)
+            .max_by(|a, b| a.kid.cmp(&b.kid))
+            .cloned()
+    }
+}
+
+/// Signs `claims` as a JWT with a tenant's key.
+///
+/// # Arguments
+///
+/// * `claims` - The claims of the token; `iss` is replaced with the tenant's issuer.
+/// * `key` - The tenant key; its `kid` goes in the header and its issuer in the `iss` claim.
+///
+/// # Returns
+///
+/// * `Result<String, jsonwebtoken::errors::Error>` - A result containing the generated JWT as a string or an error.
+pub fn generate_tenant_jwt(claims: Claims, key: &TenantKey) -> Result<String, JwtError> {
+    let claims = Claims {
+        iss: Some(key.issuer.clone()),
+        ..claims
+    };
+
+    let header = Header {
+        kid: Some(key.kid.clone()),
+        ..Header::default()
+    };
+    encode(&header, &claims, &EncodingKey::from_secret(key.secret.as_ref()))
+}
+
+/// Returns the `kid` header of a token without verifying it.
+pub fn token_kid(token: &str) -> Result<String, JwtError> {
+    decode_header(token)?
+        .kid
+        .ok_or_else(|| JwtError::from(ErrorKind::InvalidToken))
+}
+
+/// Validates a token against the tenant key named by its `kid` header.
+///
+/// # Returns
+///
+/// * `Result<Claims, jsonwebtoken::errors::Error>` - The claims, or an error if the
+///   key is unknown, the signature is invalid or `iss` does not match the key's issuer.
+pub fn validate_tenant_jwt(token: &str, key: &TenantKey) -> Result<Claims, JwtError> {
+    let mut validation = Validation::default();
+    validation.set_issuer(&[key.issuer.as_str()]);
+    validation.set_required_spec_claims(&["exp", "iss"]);
+
+    let token_data = decode::<Claims>(token, &DecodingKey::from_secret(key.secret.as_ref()), &validation)?;
+    Ok(token_data.claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(kid: &str, tenant_id: &str, secret: &str) -> TenantKey {
+        TenantKey {
+            kid: kid.to_string(),
+            tenant_id: tenant_id.to_string(),
+            issuer: format!("https://{}.example.com", tenant_id),
+            secret: secret.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_tenant_token_roundtrip() {
+        let acme = key("acme-1", "acme", "acme-secret");
+        let token = generate_tenant_jwt(Claims::new("tester", 0, &[]), &acme).unwrap();
+
+        assert_eq!(token_kid(&token).unwrap(), "acme-1");
+        let claims = validate_tenant_jwt(&token, &acme).expect("Token should be valid");
+        assert_eq!(claims.sub, "tester");
+        assert_eq!(claims.iss.as_deref(), Some("https://acme.example.com"));
+    }
+
+    /// A token forged with one tenant's secret but claiming another tenant's key must fail.
+    #[test]
+    fn test_cross_tenant_token_rejected() {
+        let acme = key("acme-1", "acme", "acme-secret");
+        let globex = key("globex-1", "globex", "globex-secret");
+
+        let forged = TenantKey { kid: globex.kid.clone(), ..acme.clone() };
+        let token = generate_tenant_jwt(Claims::new("tester", 0, &[]), &forged).unwrap();
+        assert!(validate_tenant_jwt(&token, &globex).is_err());
+
+        let wrong_issuer = TenantKey { issuer: globex.issuer.clone(), ..acme.clone() };
+        let token = generate_tenant_jwt(Claims::new("tester", 0, &[]), &wrong_issuer).unwrap();
+        assert!(validate_tenant_jwt(&token, &acme).is_err());
+    }
+
+    #[test]
+    fn test_signing_key_for_tenant() {
+        let keys = TenantKeys::new(vec![
+            key("acme-1", "acme", "old"),
+            key("acme-2", "acme", "new"),
+            key("globex-1", "globex", "other"),
+        ]);
+
+        assert_eq!(keys.signing_key_for("acme").unwrap().kid, "acme-2");
+        assert!(keys.signing_key_for("initech").is_none());
+        assert!(keys.get("globex-1").is_some());
+    }
+
+    #[test]
+    fn test_unknown_kid_reloads_are_rate_limited() {
+        let keys = TenantKeys::new(vec![key("acme-1", "acme", "secret")]);
+
+        assert!(keys.claim_reload(), "The first unknown key id may reload the keys");
+        assert!(!keys.claim_reload(), "Further ones wait for the interval to pass");
+        assert!(!keys.clone().claim_reload(), "Clones share the interval");
+    }
+}
@@ -0,0 +1,168 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::encode::IsNull;
+use sqlx::error::BoxDynError;
+use sqlx::mssql::{MssqlTypeInfo, MssqlValueRef};
+use sqlx::{Decode, Encode, Mssql, Type};
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+/// Format of timestamps without an offset, as returned by SQL Server.
+const NAIVE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+/// Format timestamps are sent to SQL Server in, which converts it to `DATETIME2` implicitly.
+const DB_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f";
+
+/// A point in time in UTC, as used for every timestamp of the API except birthdates.
+///
+/// Timestamps are serialized as RFC 3339 in UTC (`2024-01-31T18:00:00Z`). On input they
+/// accept:
+///
+/// * RFC 3339 with any offset, converted to UTC (`2024-01-31T19:00:00+01:00`).
+/// * A date and time without an offset, taken as UTC (`2024-01-31T18:00:00`).
+/// * A date alone, taken as midnight UTC (`2024-01-31`).
+///
+/// The database stores timestamps as `DATETIME2` in UTC. Since sqlx does not map
+/// `DATETIME2` for SQL Server, columns are read as strings (`CONVERT(VARCHAR(33), column, 126)`)
+/// with a `"column: Timestamp"` override, and bound as ISO 8601 strings.
+///
+/// # Examples
+///
+/// ```
+/// use safe_user::timestamp::Timestamp;
+///
+/// let timestamp: Timestamp = "2024-01-31T19:00:00+01:00".parse().unwrap();
+/// assert_eq!(timestamp.to_string(), "2024-01-31T18:00:00Z");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(pub DateTime<Utc>);
+
+impl Timestamp {
+    /// Returns the current time.
+    pub fn now() -> Self {
+        Timestamp(Utc::now())
+    }
+
+    /// Returns the wrapped `DateTime<Utc>`.
+    pub fn into_inner(self) -> DateTime<Utc> {
+        self.0
+    }
+
+    fn to_db(self) -> String {
+        self.0.naive_utc().format(DB_FORMAT).to_string()
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+            return Ok(Timestamp(time.with_timezone(&Utc)));
+        }
+        if let Ok(time) = NaiveDateTime::parse_from_str(value, NAIVE_FORMAT) {
+            return Ok(Timestamp(time.and_utc()));
+        }
+        match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+            Ok(date) => Ok(Timestamp(date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())),
+            Err(_) => Err(format!("`{}` is not a timestamp, expected RFC 3339 such as 2024-01-31T18:00:00Z", value)),
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+    }
+}
+
+impl Deref for Timestamp {
+    type Target = DateTime<Utc>;
+
+    fn deref(&self) -> &DateTime<Utc> {
+        &self.0
+    }
+}
+
+impl From<DateTime<Utc>> for Timestamp {
+    fn from(time: DateTime<Utc>) -> Self {
+        Timestamp(time)
+    }
+}
+
+impl From<Timestamp> for DateTime<Utc> {
+    fn from(timestamp: Timestamp) -> Self {
+        timestamp.0
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Type<Mssql> for Timestamp {
+    fn type_info() -> MssqlTypeInfo {
+        <String as Type<Mssql>>::type_info()
+    }
+
+    fn compatible(ty: &MssqlTypeInfo) -> bool {
+        <String as Type<Mssql>>::compatible(ty)
+    }
+}
+
+impl Encode<'_, Mssql> for Timestamp {
+    fn produces(&self) -> Option<MssqlTypeInfo> {
+        <String as Encode<Mssql>>::produces(&self.to_db())
+    }
+
+    fn encode_by_ref(&self, buf: &mut Vec<u8>) -> IsNull {
+        <String as Encode<Mssql>>::encode_by_ref(&self.to_db(), buf)
+    }
+}
+
+impl<'r> Decode<'r, Mssql> for Timestamp {
+    fn decode(value: MssqlValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(<String as Decode<Mssql>>::decode(value)?.parse()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_offsets_naive_times_and_dates() {
+        let expected = "2024-01-31T18:00:00Z";
+        for input in ["2024-01-31T18:00:00Z", "2024-01-31T13:00:00-05:00", "2024-01-31T18:00:00"] {
+            assert_eq!(input.parse::<Timestamp>().unwrap().to_string(), expected, "{}", input);
+        }
+        assert_eq!("2024-01-31".parse::<Timestamp>().unwrap().to_string(), "2024-01-31T00:00:00Z");
+        assert!("next month".parse::<Timestamp>().is_err());
+    }
+
+    #[test]
+    fn test_reads_sql_server_strings_and_keeps_fractions() {
+        let timestamp: Timestamp = "2024-01-31T18:00:00.1234567".parse().unwrap();
+        assert_eq!(timestamp.to_string(), "2024-01-31T18:00:00.123456700Z");
+        assert_eq!(timestamp.to_db(), "2024-01-31T18:00:00.123456");
+    }
+
+    #[test]
+    fn test_serde_roundtrip() {
+        let json = serde_json::to_string(&"2024-01-31T19:00:00+01:00".parse::<Timestamp>().unwrap()).unwrap();
+        assert_eq!(json, r#""2024-01-31T18:00:00Z""#);
+
+        let timestamp: Timestamp = serde_json::from_str(&json).unwrap();
+        assert_eq!(timestamp.timestamp(), 1706724000);
+    }
+}
@@ -0,0 +1,608 @@
+use actix_web::{web, HttpResponse};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, TimeZone, Utc};
+use jsonwebtoken::jwk::{AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters, Jwk, JwkSet, KeyAlgorithm, PublicKeyUse, RSAKeyParameters};
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use pasetors::claims::{Claims as PasetoClaims, ClaimsValidationRules};
+use pasetors::keys::{AsymmetricPublicKey, AsymmetricSecretKey, SymmetricKey};
+use pasetors::token::UntrustedToken;
+use pasetors::version4::V4;
+use pasetors::{local, public, Local, Public};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use crate::auth::{encode_jwt, validate_jwt, Claims};
+
+/// Path the public verification keys are published at.
+pub const JWKS_PATH: &str = "/.well-known/jwks.json";
+
+/// Error returned by a [`TokenProvider`].
+pub type TokenError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A format for the access tokens issued by `/login` and `/refresh_token`.
+///
+/// The provider is selected with `TOKEN_FORMAT` (see [`provider_from_env`]) and
+/// registered as `web::Data<Arc<dyn TokenProvider>>`; without one, [`JwtProvider`]
+/// is used. Tenant keys in multi-tenant mode always sign JWTs.
+pub trait TokenProvider: Send + Sync {
+    /// Serializes and signs (or encrypts) `claims`.
+    fn issue(&self, claims: &Claims) -> Result<String, TokenError>;
+
+    /// Verifies a token and returns its claims, failing if it has expired.
+    fn verify(&self, token: &str) -> Result<Claims, TokenError>;
+
+    /// Returns the public keys other services can verify tokens with, or `None` for
+    /// formats that rely on a shared secret.
+    fn jwks(&self) -> Option<JwkSet> {
+        None
+    }
+}
+
+/// HS256 JWTs signed with `JWT_SECRET`.
+pub struct JwtProvider;
+
+impl TokenProvider for JwtProvider {
+    fn issue(&self, claims: &Claims) -> Result<String, TokenError> {
+        Ok(encode_jwt(claims)?)
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, TokenError> {
+        Ok(validate_jwt(token)?)
+    }
+}
+
+/// JWTs signed with an RSA (`RS256`) or EC P-256 (`ES256`) private key.
+///
+/// Each token names its signing key in the `kid` header and is verified with the
+/// public key registered under that `kid`, so other services can check tokens
+/// without the signing secret. Several verification keys can be active at once,
+/// which allows rotation: publish the new public key, switch the signing key,
+/// and drop the old public key once the tokens it signed have expired.
+pub struct AsymmetricJwtProvider {
+    algorithm: Algorithm,
+    kid: String,
+    signing_key: EncodingKey,
+    verification_keys: HashMap<String, DecodingKey>,
+    jwks: JwkSet,
+}
+
+impl AsymmetricJwtProvider {
+    /// Creates a provider signing with the PEM private key `signing_pem` under `kid`.
+    ///
+    /// # Arguments
+    ///
+    /// * `algorithm` - `RS256` or `ES256`.
+    /// * `kid` - The key id written to the header of issued tokens.
+    /// * `signing_pem` - The PKCS#8 (or, for RSA, PKCS#1) private key.
+    /// * `verification_keys` - The accepted key ids with their PEM public keys. Must include `kid`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<AsymmetricJwtProvider, TokenError>` - An error if a key cannot be parsed or the
+    ///   public key registered under `kid` does not match the private key.
+    pub fn new(algorithm: Algorithm, kid: &str, signing_pem: &[u8], verification_keys: &[(String, Vec<u8>)]) -> Result<Self, TokenError> {
+        type DecodePem = fn(&[u8]) -> Result<DecodingKey, jsonwebtoken::errors::Error>;
+        let (signing_key, decode_pem): (EncodingKey, DecodePem) = match algorithm {
+            Algorithm::RS256 => (EncodingKey::from_rsa_pem(signing_pem)?, DecodingKey::from_rsa_pem),
+            Algorithm::ES256 => (EncodingKey::from_ec_pem(signing_pem)?, DecodingKey::from_ec_pem),
+            other => return Err(format!("Unsupported JWT algorithm {:?}, expected RS256 or ES256", other).into()),
+        };
+
+        let mut keys = HashMap::new();
+        let mut jwks = JwkSet { keys: Vec::new() };
+        for (key_id, pem) in verification_keys {
+            let key = decode_pem(pem).map_err(|e| format!("Invalid verification key `{}`: {}", key_id, e))?;
+            keys.insert(key_id.clone(), key);
+            jwks.keys.push(public_jwk(algorithm, key_id, pem).map_err(|e| format!("Invalid verification key `{}`: {}", key_id, e))?);
+        }
+        if !keys.contains_key(kid) {
+            return Err(format!("No verification key is registered under the signing key id `{}`", kid).into());
+        }
+
+        let provider = AsymmetricJwtProvider {
+            algorithm,
+            kid: kid.to_string(),
+            signing_key,
+            verification_keys: keys,
+            jwks,
+        };
+        provider
+            .verify(&provider.issue(&Claims::new("key-check", 0, &[]))?)
+            .map_err(|_| format!("The verification key `{}` does not match the signing key", kid))?;
+        Ok(provider)
+    }
+
+    /// Creates a provider from the files named by the environment.
+    ///
+    /// * `JWT_SIGNING_KEY_FILE` - Path to the PEM private key.
+    /// * `JWT_SIGNING_KEY_ID` - Its key id.
+    /// * `JWT_VERIFICATION_KEYS` - Comma-separated `kid=path` pairs of the PEM public keys
+    ///   accepted, e.g. `2024-06=keys/2024-06.pub.pem,2024-01=keys/2024-01.pub.pem`.
+    pub fn from_env(algorithm: Algorithm) -> Result<Self, TokenError> {
+        let read = |path: &str| fs::read(path.trim()).map_err(|e| format!("Could not read key file `{}`: {}", path.trim(), e));
+
+        let signing_path = env::var("JWT_SIGNING_KEY_FILE").map_err(|_| "JWT_SIGNING_KEY_FILE must be set for RS256 and ES256")?;
+        let kid = env::var("JWT_SIGNING_KEY_ID").map_err(|_| "JWT_SIGNING_KEY_ID must be set for RS256 and ES256")?;
+        let mut verification_keys = Vec::new();
+        for entry in env::var("JWT_VERIFICATION_KEYS").unwrap_or_default().split(',').filter(|entry| !entry.trim().is_empty()) {
+            let (key_id, path) = entry.split_once('=').ok_or_else(|| format!("JWT_VERIFICATION_KEYS entry `{}` is not `kid=path`", entry))?;
+            verification_keys.push((key_id.trim().to_string(), read(path)?));
+        }
+
+        AsymmetricJwtProvider::new(algorithm, kid.trim(), &read(&signing_path)?, &verification_keys)
+    }
+}
+
+impl TokenProvider for AsymmetricJwtProvider {
+    fn issue(&self, claims: &Claims) -> Result<String, TokenError> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.clone());
+        Ok(encode(&header, claims, &self.signing_key)?)
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, TokenError> {
+        let kid = decode_header(token)?.kid.ok_or("Token has no key id")?;
+        let key = self.verification_keys.get(&kid).ok_or("Token is signed with an unknown key")?;
+        Ok(decode::<Claims>(token, key, &Validation::new(self.algorithm))?.claims)
+    }
+
+    fn jwks(&self) -> Option<JwkSet> {
+        Some(self.jwks.clone())
+    }
+}
+
+/// Reads one DER element at the start of `input`, returning its tag, its contents and what follows.
+fn der_element(input: &[u8]) -> Result<(u8, &[u8], &[u8]), TokenError> {
+    let (&tag, rest) = input.split_first().ok_or("Truncated DER")?;
+    let (&first, rest) = rest.split_first().ok_or("Truncated DER")?;
+    let (length, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return Err("Invalid DER length".into());
+        }
+        (rest[..count].iter().fold(0, |length, &byte| (length << 8) | byte as usize), &rest[count..])
+    };
+    if rest.len() < length {
+        return Err("Truncated DER".into());
+    }
+    Ok((tag, &rest[..length], &rest[length..]))
+}
+
+/// Returns the JWK of a PEM `PUBLIC KEY` (SubjectPublicKeyInfo) used with `algorithm`.
+fn public_jwk(algorithm: Algorithm, kid: &str, pem: &[u8]) -> Result<Jwk, TokenError> {
+    let spki = rustls_pemfile::public_keys(&mut &pem[..]).next().ok_or("No PUBLIC KEY block found")??;
+    let (_, spki, _) = der_element(spki.as_ref())?;
+    let (_, _, rest) = der_element(spki)?;
+    let (tag, bits, _) = der_element(rest)?;
+    let key = match bits.split_first() {
+        Some((0, key)) if tag == 0x03 => key,
+        _ => return Err("Invalid public key bit string".into()),
+    };
+    let encode = |bytes: &[u8]| {
+        let start = bytes.iter().position(|&byte| byte != 0).unwrap_or(bytes.len());
+        URL_SAFE_NO_PAD.encode(&bytes[start..])
+    };
+
+    let (key_algorithm, parameters) = match algorithm {
+        Algorithm::ES256 => match key {
+            [0x04, point @ ..] if point.len() == 64 => (KeyAlgorithm::ES256, AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                curve: EllipticCurve::P256,
+                x: URL_SAFE_NO_PAD.encode(&point[..32]),
+                y: URL_SAFE_NO_PAD.encode(&point[32..]),
+                ..Default::default()
+            })),
+            _ => return Err("Not an uncompressed P-256 public key".into()),
+        },
+        Algorithm::RS256 => {
+            let (_, sequence, _) = der_element(key)?;
+            let (_, modulus, rest) = der_element(sequence)?;
+            let (_, exponent, _) = der_element(rest)?;
+            (KeyAlgorithm::RS256, AlgorithmParameters::RSA(RSAKeyParameters {
+                n: encode(modulus),
+                e: encode(exponent),
+                ..Default::default()
+            }))
+        }
+        other => return Err(format!("Unsupported JWT algorithm {:?}", other).into()),
+    };
+
+    Ok(Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(key_algorithm),
+            key_id: Some(kid.to_string()),
+            ..Default::default()
+        },
+        algorithm: parameters,
+    })
+}
+
+/// Serves the public keys tokens can be verified with as a JSON Web Key Set.
+///
+/// Resource servers fetch it to check tokens signed with `RS256` or `ES256`
+/// without exchanging keys out of band. The set is empty for HMAC and PASETO
+/// formats, whose keys are never published.
+///
+/// # Returns
+///
+/// * `HttpResponse` - The `{"keys": [...]}` set, cacheable for five minutes.
+pub async fn serve_jwks(tokens: Option<web::Data<Arc<dyn TokenProvider>>>) -> HttpResponse {
+    let jwks = tokens.and_then(|provider| provider.jwks()).unwrap_or(JwkSet { keys: Vec::new() });
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "public, max-age=300"))
+        .json(jwks)
+}
+
+/// PASETO `v4.local` tokens, encrypted with a shared 32-byte key.
+pub struct PasetoLocalProvider {
+    key: SymmetricKey<V4>,
+}
+
+impl PasetoLocalProvider {
+    /// Creates a provider from a 32-byte key.
+    pub fn new(key: &[u8]) -> Result<Self, TokenError> {
+        Ok(PasetoLocalProvider { key: SymmetricKey::<V4>::from(key)? })
+    }
+}
+
+impl TokenProvider for PasetoLocalProvider {
+    fn issue(&self, claims: &Claims) -> Result<String, TokenError> {
+        Ok(local::encrypt(&self.key, &to_paseto_claims(claims)?, None, None)?)
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, TokenError> {
+        let untrusted = UntrustedToken::<Local, V4>::try_from(token)?;
+        let trusted = local::decrypt(&self.key, &untrusted, &ClaimsValidationRules::new(), None, None)?;
+        from_paseto_claims(trusted.payload_claims().ok_or("Token has no claims")?)
+    }
+}
+
+/// PASETO `v4.public` tokens, signed with an Ed25519 key.
+pub struct PasetoPublicProvider {
+    secret: AsymmetricSecretKey<V4>,
+    public: AsymmetricPublicKey<V4>,
+}
+
+impl PasetoPublicProvider {
+    /// Creates a provider from a 64-byte Ed25519 secret key (the seed followed by the public key).
+    pub fn new(secret: &[u8]) -> Result<Self, TokenError> {
+        let secret = AsymmetricSecretKey::<V4>::from(secret)?;
+        let public = AsymmetricPublicKey::<V4>::try_from(&secret)?;
+        Ok(PasetoPublicProvider { secret, public })
+    }
+}
+
+impl TokenProvider for PasetoPublicProvider {
+    fn issue(&self, claims: &Claims) -> Result<String, TokenError> {
+        Ok(public::sign(&self.secret, &to_paseto_claims(claims)?, None, None)?)
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, TokenError> {
+        let untrusted = UntrustedToken::<Public, V4>::try_from(token)?;
+        let trusted = public::verify(&self.public, &untrusted, &ClaimsValidationRules::new(), None, None)?;
+        from_paseto_claims(trusted.payload_claims().ok_or("Token has no claims")?)
+    }
+}
+
+/// Header of the compact JWEs produced by [`JweProvider`].
+const JWE_HEADER: &str = r#"{"alg":"dir","enc":"A256GCM","cty":"JWT"}"#;
+const JWE_NONCE_LENGTH: usize = 12;
+const JWE_TAG_LENGTH: usize = 16;
+
+/// Nested JWTs: tokens of an inner provider encrypted as compact JWEs
+/// (`alg: dir`, `enc: A256GCM`), so intermediaries cannot read the claims.
+///
+/// Verification decrypts the token and hands the inner token to the inner
+/// provider, so the signature is still checked.
+pub struct JweProvider {
+    inner: Arc<dyn TokenProvider>,
+    cipher: Aes256Gcm,
+}
+
+impl JweProvider {
+    /// Wraps `inner`, encrypting with a 32-byte content encryption key.
+    pub fn new(inner: Arc<dyn TokenProvider>, key: &[u8]) -> Result<Self, TokenError> {
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| "The token encryption key must be 32 bytes")?;
+        Ok(JweProvider { inner, cipher })
+    }
+}
+
+impl TokenProvider for JweProvider {
+    fn issue(&self, claims: &Claims) -> Result<String, TokenError> {
+        let signed = self.inner.issue(claims)?;
+        let header = URL_SAFE_NO_PAD.encode(JWE_HEADER);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        let mut ciphertext = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: signed.as_bytes(), aad: header.as_bytes() })
+            .map_err(|_| "Failed to encrypt token")?;
+        let tag = ciphertext.split_off(ciphertext.len() - JWE_TAG_LENGTH);
+
+        Ok(format!(
+            "{}..{}.{}.{}",
+            header,
+            URL_SAFE_NO_PAD.encode(nonce),
+            URL_SAFE_NO_PAD.encode(ciphertext),
+            URL_SAFE_NO_PAD.encode(tag)
+        ))
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, TokenError> {
+        let parts: Vec<&str> = token.split('.').collect();
+        let (header, nonce, ciphertext, tag) = match parts.as_slice() {
+            [header, "", nonce, ciphertext, tag] => (*header, *nonce, *ciphertext, *tag),
+            _ => return Err("Token is not a compact JWE".into()),
+        };
+
+        let decoded: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header)?)?;
+        if decoded["alg"] != "dir" || decoded["enc"] != "A256GCM" {
+            return Err("Unsupported JWE algorithm".into());
+        }
+
+        let nonce = URL_SAFE_NO_PAD.decode(nonce)?;
+        if nonce.len() != JWE_NONCE_LENGTH {
+            return Err("Invalid JWE initialization vector".into());
+        }
+        let mut message = URL_SAFE_NO_PAD.decode(ciphertext)?;
+        message.extend(URL_SAFE_NO_PAD.decode(tag)?);
+
+        let signed = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: &message, aad: header.as_bytes() })
+            .map_err(|_| "Failed to decrypt token")?;
+        self.inner.verify(std::str::from_utf8(&signed)?)
+    }
+
+    fn jwks(&self) -> Option<JwkSet> {
+        self.inner.jwks()
+    }
+}
+
+/// Builds the provider selected by `TOKEN_FORMAT`.
+///
+/// When `TOKEN_ENCRYPTION_KEY` (hex, 32 bytes) is set, JWTs are additionally
+/// encrypted with [`JweProvider`].
+///
+/// * `jwt` (default) - [`JwtProvider`], or [`AsymmetricJwtProvider`] when `JWT_ALGORITHM`
+///   is `RS256` or `ES256`.
+/// * `paseto-local` - [`PasetoLocalProvider`], keyed with the hex-encoded `PASETO_KEY` (32 bytes).
+/// * `paseto-public` - [`PasetoPublicProvider`], keyed with the hex-encoded `PASETO_KEY` (64 bytes).
+pub fn provider_from_env() -> Result<Arc<dyn TokenProvider>, TokenError> {
+    let format = env::var("TOKEN_FORMAT").unwrap_or_else(|_| "jwt".into());
+    let encryption_key = env::var("TOKEN_ENCRYPTION_KEY").ok();
+
+    match (format.to_lowercase().as_str(), encryption_key) {
+        ("jwt", Some(key)) => Ok(Arc::new(JweProvider::new(jwt_provider()?, &hex::decode(key.trim())?)?)),
+        (_, Some(_)) => Err("TOKEN_ENCRYPTION_KEY only applies to TOKEN_FORMAT=jwt".into()),
+        ("jwt", None) => jwt_provider(),
+        ("paseto-local", None) => Ok(Arc::new(PasetoLocalProvider::new(&paseto_key()?)?)),
+        ("paseto-public", None) => Ok(Arc::new(PasetoPublicProvider::new(&paseto_key()?)?)),
+        (other, None) => Err(format!("Unknown TOKEN_FORMAT `{}`", other).into()),
+    }
+}
+
+/// Returns the provider registered on the app, or [`JwtProvider`] if there is none.
+pub fn provider_or_default(data: Option<&web::Data<Arc<dyn TokenProvider>>>) -> Arc<dyn TokenProvider> {
+    match data {
+        Some(provider) => Arc::clone(provider.get_ref()),
+        None => Arc::new(JwtProvider),
+    }
+}
+
+/// Returns `true` when `JWT_ALGORITHM` selects RS256 or ES256, which need no `JWT_SECRET`.
+pub fn asymmetric_jwt(algorithm: Option<&str>) -> bool {
+    algorithm.is_some_and(|algorithm| matches!(algorithm.trim().to_uppercase().as_str(), "RS256" | "ES256"))
+}
+
+/// Builds the JWT provider for the `JWT_ALGORITHM` in use (`HS256` by default).
+fn jwt_provider() -> Result<Arc<dyn TokenProvider>, TokenError> {
+    match env::var("JWT_ALGORITHM").unwrap_or_else(|_| "HS256".into()).trim().to_uppercase().as_str() {
+        "HS256" => Ok(Arc::new(JwtProvider)),
+        "RS256" => Ok(Arc::new(AsymmetricJwtProvider::from_env(Algorithm::RS256)?)),
+        "ES256" => Ok(Arc::new(AsymmetricJwtProvider::from_env(Algorithm::ES256)?)),
+        other => Err(format!("Unknown JWT_ALGORITHM `{}`, expected HS256, RS256 or ES256", other).into()),
+    }
+}
+
+fn paseto_key() -> Result<Vec<u8>, TokenError> {
+    let key = env::var("PASETO_KEY").map_err(|_| "PASETO_KEY must be set for PASETO tokens")?;
+    Ok(hex::decode(key.trim())?)
+}
+
+fn to_paseto_claims(claims: &Claims) -> Result<PasetoClaims, TokenError> {
+    let expiration = Utc
+        .timestamp_opt(claims.exp as i64, 0)
+        .single()
+        .ok_or("Invalid expiration")?;
+
+    let mut paseto = PasetoClaims::new()?;
+    paseto.subject(&claims.sub)?;
+    paseto.expiration(&expiration.to_rfc3339())?;
+    if let Some(issuer) = &claims.iss {
+        paseto.issuer(issuer)?;
+    }
+    paseto.add_additional("ver", claims.ver)?;
+    paseto.add_additional("roles", claims.roles.clone())?;
+    if let Some(jti) = &claims.jti {
+        paseto.token_identifier(jti)?;
+    }
+    if let Some(sid) = &claims.sid {
+        paseto.add_additional("sid", sid.as_str())?;
+    }
+    if let Some(plan) = &claims.plan {
+        paseto.add_additional("plan", plan.as_str())?;
+    }
+    if !claims.ent.is_empty() {
+        paseto.add_additional("ent", claims.ent.clone())?;
+    }
+    Ok(paseto)
+}
+
+fn from_paseto_claims(paseto: &PasetoClaims) -> Result<Claims, TokenError> {
+    let text = |name: &str| paseto.get_claim(name).and_then(|value| value.as_str());
+
+    let expiration = DateTime::parse_from_rfc3339(text("exp").ok_or("Token has no expiration")?)?;
+    let list = |name: &str| -> Result<Vec<String>, TokenError> {
+        match paseto.get_claim(name) {
+            Some(values) => Ok(serde_json::from_value(values.clone())?),
+            None => Ok(Vec::new()),
+        }
+    };
+
+    Ok(Claims {
+        sub: text("sub").ok_or("Token has no subject")?.to_string(),
+        exp: expiration.timestamp() as usize,
+        iss: text("iss").map(String::from),
+        ver: paseto.get_claim("ver").and_then(|value| value.as_i64()).unwrap_or(0) as i32,
+        roles: list("roles")?,
+        jti: text("jti").map(String::from),
+        sid: text("sid").map(String::from),
+        plan: text("plan").map(String::from),
+        ent: list("ent")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims() -> Claims {
+        Claims {
+            sid: Some("session".to_string()),
+            ..Claims::new("tester", 2, &["admin".to_string()])
+        }
+    }
+
+    fn assert_roundtrip(provider: &dyn TokenProvider) {
+        let token = provider.issue(&claims()).unwrap();
+        let verified = provider.verify(&token).expect("Token should be valid");
+
+        assert_eq!(verified.sub, "tester");
+        assert_eq!(verified.ver, 2);
+        assert_eq!(verified.roles, vec!["admin"]);
+        assert!(verified.jti.is_some());
+        assert_eq!(verified.sid.as_deref(), Some("session"));
+        assert_eq!(verified.exp, claims().exp);
+    }
+
+    #[test]
+    fn test_jwt_and_paseto_roundtrip() {
+        assert_roundtrip(&JwtProvider);
+        assert_roundtrip(&PasetoLocalProvider::new(&[7u8; 32]).unwrap());
+
+        let mut secret = [0u8; 64];
+        let pair = <pasetors::keys::AsymmetricKeyPair<V4> as pasetors::keys::Generate<_, _>>::generate().unwrap();
+        secret.copy_from_slice(pair.secret.as_bytes());
+        assert_roundtrip(&PasetoPublicProvider::new(&secret).unwrap());
+    }
+
+    fn asymmetric(algorithm: Algorithm, kid: &str, signing_pem: &[u8], verification_keys: &[(&str, &[u8])]) -> Result<AsymmetricJwtProvider, TokenError> {
+        let keys: Vec<(String, Vec<u8>)> = verification_keys.iter().map(|(kid, pem)| (kid.to_string(), pem.to_vec())).collect();
+        AsymmetricJwtProvider::new(algorithm, kid, signing_pem, &keys)
+    }
+
+    #[test]
+    fn test_asymmetric_jwt_roundtrip_and_rotation() {
+        let rsa = asymmetric(Algorithm::RS256, "rsa", include_bytes!("../tests/keys/rs256.pem"), &[("rsa", include_bytes!("../tests/keys/rs256.pub.pem"))]).unwrap();
+        assert_roundtrip(&rsa);
+        let token = rsa.issue(&claims()).unwrap();
+        assert_eq!(decode_header(&token).unwrap().kid.as_deref(), Some("rsa"));
+        assert!(JwtProvider.verify(&token).is_err());
+
+        let current_pub: &[u8] = include_bytes!("../tests/keys/es256_current.pub.pem");
+        let previous_pub: &[u8] = include_bytes!("../tests/keys/es256_previous.pub.pem");
+        let previous = asymmetric(Algorithm::ES256, "previous", include_bytes!("../tests/keys/es256_previous.pem"), &[("previous", previous_pub)]).unwrap();
+        let current = asymmetric(Algorithm::ES256, "current", include_bytes!("../tests/keys/es256_current.pem"), &[("current", current_pub), ("previous", previous_pub)]).unwrap();
+        assert_roundtrip(&current);
+
+        let old_token = previous.issue(&claims()).unwrap();
+        assert!(current.verify(&old_token).is_ok());
+        assert!(previous.verify(&current.issue(&claims()).unwrap()).is_err());
+        assert!(current.verify(&token).is_err());
+    }
+
+    #[test]
+    fn test_jwks_verifies_issued_tokens() {
+        let rsa = asymmetric(Algorithm::RS256, "rsa", include_bytes!("../tests/keys/rs256.pem"), &[("rsa", include_bytes!("../tests/keys/rs256.pub.pem"))]).unwrap();
+        let ec = asymmetric(Algorithm::ES256, "current", include_bytes!("../tests/keys/es256_current.pem"), &[
+            ("current", include_bytes!("../tests/keys/es256_current.pub.pem")),
+            ("previous", include_bytes!("../tests/keys/es256_previous.pub.pem")),
+        ]).unwrap();
+
+        for (provider, algorithm) in [(&rsa, Algorithm::RS256), (&ec, Algorithm::ES256)] {
+            let token = provider.issue(&claims()).unwrap();
+            let kid = decode_header(&token).unwrap().kid.unwrap();
+            let jwks = provider.jwks().unwrap();
+            let key = DecodingKey::from_jwk(jwks.find(&kid).unwrap()).unwrap();
+            assert_eq!(decode::<Claims>(&token, &key, &Validation::new(algorithm)).unwrap().claims.sub, "tester");
+        }
+
+        assert_eq!(ec.jwks().unwrap().keys.len(), 2);
+        assert_eq!(serde_json::to_value(rsa.jwks().unwrap()).unwrap()["keys"][0]["e"], "AQAB");
+        assert!(JwtProvider.jwks().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_jwks_endpoint_never_publishes_shared_secrets() {
+        let provider: Arc<dyn TokenProvider> = Arc::new(JwtProvider);
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(web::Data::new(provider))
+                .route(JWKS_PATH, web::get().to(serve_jwks))
+        ).await;
+
+        let req = actix_web::test::TestRequest::get().uri(JWKS_PATH).to_request();
+        let body: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, serde_json::json!({ "keys": [] }));
+    }
+
+    #[test]
+    fn test_asymmetric_jwt_rejects_mismatched_keys() {
+        let current_private: &[u8] = include_bytes!("../tests/keys/es256_current.pem");
+        let previous_pub: &[u8] = include_bytes!("../tests/keys/es256_previous.pub.pem");
+
+        assert!(asymmetric(Algorithm::ES256, "current", current_private, &[("current", previous_pub)]).is_err());
+        assert!(asymmetric(Algorithm::ES256, "current", current_private, &[("previous", previous_pub)]).is_err());
+        assert!(asymmetric(Algorithm::HS256, "current", current_private, &[]).is_err());
+        assert!(asymmetric_jwt(Some("es256")) && !asymmetric_jwt(Some("HS256")) && !asymmetric_jwt(None));
+    }
+
+    #[test]
+    fn test_jwe_hides_claims_and_verifies_inner_token() {
+        let provider = JweProvider::new(Arc::new(JwtProvider), &[9u8; 32]).unwrap();
+        assert_roundtrip(&provider);
+
+        let token = provider.issue(&claims()).unwrap();
+        let parts: Vec<&str> = token.split('.').collect();
+        assert_eq!(parts.len(), 5);
+        assert!(!String::from_utf8_lossy(&URL_SAFE_NO_PAD.decode(parts[3]).unwrap()).contains("tester"));
+        assert!(JwtProvider.verify(&token).is_err());
+
+        let other = JweProvider::new(Arc::new(JwtProvider), &[1u8; 32]).unwrap();
+        assert!(other.verify(&token).is_err());
+
+        let tampered = format!("{}.{}.{}.{}.{}", parts[0], parts[1], parts[2], parts[3], URL_SAFE_NO_PAD.encode([0u8; 16]));
+        assert!(provider.verify(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_paseto_rejects_other_keys_and_expired_tokens() {
+        let provider = PasetoLocalProvider::new(&[7u8; 32]).unwrap();
+        let other = PasetoLocalProvider::new(&[8u8; 32]).unwrap();
+
+        let token = provider.issue(&claims()).unwrap();
+        assert!(other.verify(&token).is_err());
+        assert!(JwtProvider.verify(&token).is_err());
+
+        let expired = Claims { exp: (Utc::now().timestamp() - 60) as usize, ..claims() };
+        let token = provider.issue(&expired).unwrap();
+        assert!(provider.verify(&token).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use std::time::Instant;
+use uuid::Uuid;
+
+/// Header carrying the trace id of a request, accepted from the client and always
+/// set on the response.
+pub const TRACE_ID_HEADER: &str = "X-Trace-Id";
+
+/// Longest client-supplied trace id that is kept; longer ones are replaced.
+const MAX_TRACE_ID_LEN: usize = 64;
+
+tokio::task_local! {
+    static CURRENT_TRACE_ID: TraceId;
+}
+
+/// The id tying a request to its response, its error body and its log lines.
+///
+/// Available to handlers as a request extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceId(pub String);
+
+impl TraceId {
+    /// Takes the trace id from `X-Trace-Id`, or from the W3C `traceparent` header,
+    /// and draws a new one if neither holds a usable id.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use safe_user::trace::TraceId;
+    ///
+    /// let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    /// assert_eq!(TraceId::from_headers(None, Some(traceparent)).0, "4bf92f3577b34da6a3ce929d0e0e4736");
+    /// assert_eq!(TraceId::from_headers(Some("support-1234"), Some(traceparent)).0, "support-1234");
+    /// assert_eq!(TraceId::from_headers(Some("no spaces"), None).0.len(), 32);
+    /// ```
+    pub fn from_headers(trace_id: Option<&str>, traceparent: Option<&str>) -> Self {
+        let supplied = trace_id.filter(|id| {
+            !id.is_empty() && id.len() <= MAX_TRACE_ID_LEN && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        });
+        let propagated = traceparent
+            .and_then(|header| header.split('-').nth(1))
+            .filter(|id| id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit()) && id.chars().any(|c| c != '0'));
+
+        match supplied.or(propagated) {
+            Some(id) => TraceId(id.to_string()),
+            None => TraceId(Uuid::new_v4().simple().to_string()),
+        }
+    }
+
+    /// Returns the id of the request being handled, if it runs inside [`assign_trace_id`].
+    pub fn current() -> Option<TraceId> {
+        CURRENT_TRACE_ID.try_with(TraceId::clone).ok()
+    }
+}
+
+/// Middleware function giving every request a [`TraceId`].
+///
+/// The id is stored in the request extensions, included in every
+/// [`ErrorBody`](crate::error::ErrorBody) and server error log line, and returned in
+/// the `X-Trace-Id` header and, with the time spent, in `Server-Timing`. Wrap it
+/// around the whole app so responses from every other middleware carry it too.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{middleware::from_fn, App};
+/// use safe_user::trace::assign_trace_id;
+///
+/// let app = App::new().wrap(from_fn(assign_trace_id));
+/// ```
+pub async fn assign_trace_id(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+    let trace_id = TraceId::from_headers(header(TRACE_ID_HEADER), header("traceparent"));
+    req.extensions_mut().insert(trace_id.clone());
+    let started = Instant::now();
+
+    // Errors escaping the app are rendered here, still inside the scope, so their
+    // bodies carry the id as well.
+    let result = CURRENT_TRACE_ID
+        .scope(trace_id.clone(), async move {
+            next.call(req).await.map_err(|e| {
+                let response = e.error_response();
+                (e, response)
+            })
+        })
+        .await;
+
+    match result {
+        Ok(mut response) => {
+            set_trace_headers(response.headers_mut(), &trace_id, started);
+            Ok(response)
+        }
+        Err((e, mut response)) => {
+            set_trace_headers(response.headers_mut(), &trace_id, started);
+            Err(InternalError::from_response(e, response).into())
+        }
+    }
+}
+
+fn set_trace_headers(headers: &mut HeaderMap, trace_id: &TraceId, started: Instant) {
+    let server_timing = format!("total;dur={:.1}, trace;desc=\"{}\"", started.elapsed().as_secs_f64() * 1000.0, trace_id.0);
+    // Both values are made of visible ASCII, as checked by `TraceId::from_headers`.
+    if let Ok(value) = HeaderValue::from_str(&trace_id.0) {
+        headers.insert(HeaderName::from_static("x-trace-id"), value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&server_timing) {
+        headers.append(HeaderName::from_static("server-timing"), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use crate::error::{AppError, ErrorBody};
+
+    async fn missing() -> Result<HttpResponse, AppError> {
+        Err(AppError::NotFound("user"))
+    }
+
+    #[actix_web::test]
+    async fn test_errors_carry_the_trace_id() {
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(assign_trace_id))
+                .route("/missing", web::get().to(missing))
+        ).await;
+
+        let req = TestRequest::get().uri("/missing").insert_header((TRACE_ID_HEADER, "ticket-42")).to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.headers().get(TRACE_ID_HEADER).unwrap(), "ticket-42");
+        assert!(resp.headers().get("server-timing").unwrap().to_str().unwrap().contains("trace;desc=\"ticket-42\""));
+        let body: ErrorBody = read_body_json(resp).await;
+        assert_eq!(body.trace_id.as_deref(), Some("ticket-42"));
+
+        let resp = call_service(&app, TestRequest::get().uri("/missing").to_request()).await;
+        let generated = resp.headers().get(TRACE_ID_HEADER).unwrap().to_str().unwrap().to_string();
+        let body: ErrorBody = read_body_json(resp).await;
+        assert_eq!(body.trace_id, Some(generated));
+    }
+}
@@ -0,0 +1,200 @@
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use validator::{Validate, ValidationError};
+use crate::birthdate::Birthdate;
+use crate::error::AppError;
+
+/// Format birthdates are written in.
+pub const BIRTHDATE_FORMAT: &str = "%Y-%m-%d";
+
+/// A JSON body that has been deserialized and then checked with [`Validate`].
+///
+/// Use it in place of `web::Json<T>`: payloads breaking a rule are answered with
+/// `422 Unprocessable Entity` and the messages for each offending field, before the
+/// handler runs.
+///
+/// # Examples
+///
+/// ```
+/// use actix_web::{HttpResponse, Responder};
+/// use safe_user::models::CreateUserRequest;
+/// use safe_user::validation::ValidJson;
+///
+/// async fn create_user(user: ValidJson<CreateUserRequest>) -> impl Responder {
+///     HttpResponse::Ok().json(&user.email)
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ValidJson<T>(pub T);
+
+impl<T> ValidJson<T> {
+    /// Returns the validated payload.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for ValidJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> FromRequest for ValidJson<T>
+where
+    T: DeserializeOwned + Validate + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let json = web::Json::<T>::from_request(req, payload);
+
+        Box::pin(async move {
+            let value = json.await?.into_inner();
+            value.validate().map_err(AppError::InvalidFields)?;
+            Ok(ValidJson(value))
+        })
+    }
+}
+
+/// Accepts phone numbers of 7 to 15 digits, optionally starting with `+` and
+/// grouped with spaces, dashes or parentheses.
+pub fn validate_phone(phone: &str) -> Result<(), ValidationError> {
+    let digits = phone.chars().filter(char::is_ascii_digit).count();
+    let allowed = phone
+        .char_indices()
+        .all(|(i, c)| c.is_ascii_digit() || matches!(c, ' ' | '-' | '(' | ')') || (c == '+' && i == 0));
+
+    if allowed && (7..=15).contains(&digits) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("phone"))
+    }
+}
+
+/// Accepts birthdates that are not in the future. Malformed dates are already
+/// rejected when the [`Birthdate`] is deserialized.
+pub fn validate_birthdate(birthdate: &Birthdate) -> Result<(), ValidationError> {
+    if birthdate.is_in_future() {
+        Err(ValidationError::new("birthdate"))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreateUserRequest;
+    use actix_web::{http::StatusCode, test, App, HttpResponse, Responder};
+    use serde_json::json;
+
+    async fn accept(user: ValidJson<CreateUserRequest>) -> impl Responder {
+        HttpResponse::Ok().json(&user.email)
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_user_returns_422_with_field_messages() {
+        let app = test::init_service(App::new().route("/users", web::post().to(accept))).await;
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(json!({
+                "user_id": "891009",
+                "name": "Jhon",
+                "last_name": "Doe",
+                "email": "example@example.com",
+                "age": 240,
+                "birthdate": "2999-05-31"
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "invalid_fields");
+        for field in ["age", "birthdate"] {
+            assert!(body["details"][field][0].is_string(), "missing message for {}", field);
+        }
+        assert!(body["details"].get("name").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_valid_user_reaches_handler() {
+        let app = test::init_service(App::new().route("/users", web::post().to(accept))).await;
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(json!({
+                "user_id": "891009",
+                "name": "Jhon",
+                "last_name": "Doe",
+                "email": "example@example.com",
+                "age": 33,
+                "phone": "+34 (600) 123-456",
+                "birthdate": "1992-05-31",
+                "expires_at": "2030-01-31T19:00:00+01:00"
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_malformed_dates_are_rejected() {
+        let app = test::init_service(App::new().route("/users", web::post().to(accept))).await;
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(json!({
+                "user_id": "891009",
+                "name": "Jhon",
+                "last_name": "Doe",
+                "email": "example@example.com",
+                "birthdate": "1992-05-31",
+                "expires_at": "next month"
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .set_json(json!({
+                "user_id": "891009",
+                "name": "Jhon",
+                "last_name": "Doe",
+                "email": "example@example.com",
+                "birthdate": "31/05/1992"
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_malformed_emails_and_phones_are_rejected() {
+        let app = test::init_service(App::new().route("/users", web::post().to(accept))).await;
+
+        for (field, value) in [("email", "not-an-email"), ("phone", "call me"), ("user_id", "")] {
+            let mut user = json!({
+                "user_id": "891009",
+                "name": "Jhon",
+                "last_name": "Doe",
+                "email": "example@example.com",
+                "birthdate": "1992-05-31"
+            });
+            user[field] = json!(value);
+            let req = test::TestRequest::post().uri("/users").set_json(user).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", field);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use actix_web::dev::Payload;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Mssql, Pool};
+use std::future::Future;
+use std::pin::Pin;
+use crate::auth::Claims;
+use crate::models::UserResponse;
+use crate::rbac;
+
+/// Permission that lets a caller see the sensitive fields of other users.
+pub const READ_SENSITIVE: &str = "users:read_sensitive";
+
+/// User fields hidden from callers that are neither the user nor hold [`READ_SENSITIVE`].
+pub const SENSITIVE_FIELDS: &[&str] = &["phone", "address", "birthdate"];
+
+/// The caller a response is being rendered for.
+///
+/// `Viewer` is an extractor: add it to a handler's arguments and pass every user
+/// through [`Viewer::present`] (or [`Viewer::present_all`]) before serializing, so
+/// the visibility rules are applied in a single place.
+#[derive(Debug, Clone, Default)]
+pub struct Viewer {
+    /// The id of the authenticated caller, if any.
+    pub user_id: Option<String>,
+    /// Whether the caller holds [`READ_SENSITIVE`].
+    pub can_read_sensitive: bool,
+}
+
+impl Viewer {
+    /// Returns `true` if the sensitive fields of the user `user_id` may be shown.
+    pub fn can_see_sensitive_fields_of(&self, user_id: Option<&str>) -> bool {
+        if self.can_read_sensitive {
+            return true;
+        }
+        match (&self.user_id, user_id) {
+            (Some(viewer), Some(owner)) => viewer.eq_ignore_ascii_case(owner),
+            _ => false,
+        }
+    }
+
+    /// Serializes a user, removing the [`SENSITIVE_FIELDS`] the viewer may not see.
+    pub fn present(&self, user: &UserResponse) -> Value {
+        self.present_with_owner(user, Some(&user.id))
+    }
+
+    /// Serializes a list of users with [`Viewer::present`].
+    pub fn present_all(&self, users: &[UserResponse]) -> Vec<Value> {
+        users.iter().map(|user| self.present(user)).collect()
+    }
+
+    /// Serializes any user-shaped value owned by `owner_id`, removing the
+    /// [`SENSITIVE_FIELDS`] the viewer may not see.
+    pub fn present_with_owner<T: Serialize>(&self, value: &T, owner_id: Option<&str>) -> Value {
+        let mut json = serde_json::to_value(value).unwrap_or(Value::Null);
+        if !self.can_see_sensitive_fields_of(owner_id) {
+            if let Value::Object(fields) = &mut json {
+                for field in SENSITIVE_FIELDS {
+                    fields.remove(*field);
+                }
+            }
+        }
+        json
+    }
+}
+
+impl FromRequest for Viewer {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let user_id = req.extensions().get::<Claims>().map(|claims| claims.sub.clone());
+        let pool = req.app_data::<web::Data<Pool<Mssql>>>().cloned();
+
+        Box::pin(async move {
+            let can_read_sensitive = match (&user_id, pool) {
+                (Some(user_id), Some(pool)) => match rbac::permissions_for_user(&pool, user_id).await {
+                    Ok(granted) => rbac::grants(&granted, READ_SENSITIVE),
+                    Err(e) => {
+                        eprintln!("Error loading permissions: {:?}", e);
+                        false
+                    }
+                },
+                _ => false,
+            };
+
+            Ok(Viewer { user_id, can_read_sensitive })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str) -> UserResponse {
+        UserResponse {
+            id: id.to_string(),
+            user_id: "891009".parse().unwrap(),
+            name: "Jhon".to_string(),
+            last_name: "Doe".to_string(),
+            email: "example@example.com".parse().unwrap(),
+            age: Some(33),
+            phone: "123456789".parse().ok(),
+            address: Some("Street".to_string()),
+            birthdate: "1992-05-31".parse().unwrap(),
+            place_birth: None,
+            org_unit: None,
+            expires_at: None,
+            created_at: None,
+            updated_at: None,
+            last_seen_at: None,
+            deleted_at: None,
+            plan: None,
+        }
+    }
+
+    #[test]
+    fn test_sensitive_fields_hidden_from_others() {
+        let viewer = Viewer { user_id: Some("other".to_string()), can_read_sensitive: false };
+        let json = viewer.present(&user("813B6B04-DFBB-4EED-B820-2372216A2367"));
+
+        for field in SENSITIVE_FIELDS {
+            assert!(json.get(*field).is_none(), "{} should be hidden", field);
+        }
+        assert_eq!(json["email"], "example@example.com");
+    }
+
+    #[test]
+    fn test_sensitive_fields_visible_to_owner_and_privileged() {
+        let owner = Viewer { user_id: Some("813b6b04-dfbb-4eed-b820-2372216a2367".to_string()), can_read_sensitive: false };
+        let json = owner.present(&user("813B6B04-DFBB-4EED-B820-2372216A2367"));
+        assert_eq!(json["phone"], "123456789");
+
+        let admin = Viewer { user_id: Some("admin".to_string()), can_read_sensitive: true };
+        let json = admin.present(&user("813B6B04-DFBB-4EED-B820-2372216A2367"));
+        assert_eq!(json["birthdate"], "1992-05-31");
+    }
+}